@@ -16,9 +16,26 @@
    along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::error::{ErrorResponse, MsalError};
+use crate::authority::{
+    is_tenantless, normalize_authority, parse_authority, validate_authority, AuthorityType,
+};
+use crate::cache::{
+    deserialize_timestamp, find_app_token, find_obo_refresh_token, find_obo_token,
+    find_refresh_token, find_user_token, get_accounts, now, remove_account, save_app_token,
+    save_user_token, Account, InMemoryTokenCache, TokenCache, ACCESS_TOKEN_EXPIRY_BUFFER,
+};
+use crate::cloud::CloudInstance;
+use crate::error::{ErrorResponse, MsalError, PasswordChangeError};
+use crate::federation::{federated_assertion, SamlAssertion, UserRealmCache};
+use crate::oidc::{OpenIdConfiguration, OpenIdConfigurationCache};
+use crate::validation::TokenValidator;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::x509::X509;
 use reqwest::{header, Client, Url};
 use scraper::{Html, Selector};
 use serde::de::{self, MapAccess, Visitor};
@@ -26,8 +43,15 @@ use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{from_str as json_from_str, json, Value};
 use std::fmt;
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tracing::info;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Mutex, OnceCell};
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info};
 use urlencoding::encode as url_encode;
 use uuid::Uuid;
 use zeroize::{Zeroize, ZeroizeOnDrop};
@@ -56,14 +80,10 @@ use kanidm_hsm_crypto::{
 #[cfg(feature = "broker")]
 use kanidm_hsm_crypto::{LoadableMsOapxbcRsaKey, MsOapxbcRsaKey};
 #[cfg(feature = "broker")]
-use openssl::hash::{hash, MessageDigest};
-#[cfg(feature = "broker")]
 use openssl::pkey::Public;
 #[cfg(feature = "broker")]
 use openssl::rsa::Rsa;
 #[cfg(feature = "broker")]
-use openssl::x509::X509;
-#[cfg(feature = "broker")]
 use os_release::OsRelease;
 #[cfg(feature = "broker")]
 use regex::Regex;
@@ -72,9 +92,7 @@ use serde_json::{from_slice as json_from_slice, to_vec as json_to_vec};
 #[cfg(feature = "broker")]
 use std::convert::TryInto;
 #[cfg(feature = "broker")]
-use std::time::{SystemTime, UNIX_EPOCH};
-#[cfg(feature = "broker")]
-use tracing::debug;
+use std::time::UNIX_EPOCH;
 
 #[cfg(feature = "broker")]
 use crate::discovery::Services;
@@ -85,15 +103,15 @@ use base64::engine::general_purpose::STANDARD;
 #[cfg(feature = "broker")]
 use serde_json::to_string_pretty;
 
-#[derive(Serialize, Clone, Default)]
-struct JoinPayload {}
-
 #[cfg(feature = "broker")]
 const BROKER_CLIENT_IDENT: &str = "38aa3b87-a06d-4817-b275-7a316988d93b";
 #[cfg(feature = "broker")]
 pub const BROKER_APP_ID: &str = "29d9ed98-a469-4536-ade2-f981bc1d605e";
 #[cfg(feature = "broker")]
 const DRS_APP_ID: &str = "01cb2876-7ebd-4aa4-9cc9-d28bd4d359a9";
+/// Windows renews the PRT every 4 hours.
+#[cfg(feature = "broker")]
+const PRT_RENEWAL_INTERVAL: u64 = 4 * 60 * 60;
 
 /* RFC8628: 3.2. Device Authorization Response */
 #[derive(Default, Clone, Deserialize, Zeroize, ZeroizeOnDrop)]
@@ -106,15 +124,191 @@ pub struct DeviceAuthorizationResponse {
     pub expires_in: u32,
     pub interval: Option<u32>,
     pub message: Option<String>,
+    #[serde(skip)]
+    claims: Option<String>,
+    /// When the device code expires, in seconds since the epoch. This is
+    /// recorded when the flow is initiated.
+    #[serde(skip)]
+    expires_on: u64,
 }
 
-#[derive(Deserialize)]
-struct ArrUserProofs {
+/// The polling interval for a device flow, when the authorization server
+/// does not specify one (RFC8628: 3.5).
+const DEVICE_FLOW_DEFAULT_INTERVAL: u64 = 5;
+/// The increase of the polling interval after a `slow_down` response.
+const DEVICE_FLOW_SLOW_DOWN_INCREMENT: u64 = 5;
+
+/// Signals a long running operation, such as polling a device flow, to stop.
+/// Clones share the same cancellation state.
+#[derive(Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            sender: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    /// Cancel the operations using this token.
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Wait until the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender is held by self, so the channel cannot close.
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The progress of a device flow, reported while polling.
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceFlowProgress {
+    /// The user has not yet completed authentication. Polling continues until
+    /// the device code expires in `expires_in` seconds.
+    AuthorizationPending { poll_attempt: u32, expires_in: u64 },
+    /// The authorization server asked to poll less often. Polling continues
+    /// every `interval` seconds.
+    SlowDown { interval: u64 },
+}
+
+type DeviceFlowProgressCallback = dyn Fn(&DeviceFlowProgress) + Send + Sync;
+
+/// The outcome of polling a device flow.
+pub enum DeviceFlowOutcome {
+    /// The user authenticated, and a token was issued.
+    Success(Box<UserToken>),
+    /// The device code expired before the user authenticated.
+    Expired,
+    /// The user declined the authorization request.
+    Declined,
+    /// Polling was cancelled.
+    Cancelled,
+}
+
+/// An MFA method registered by the user.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MFAMethod {
+    /// The method id, which is passed to begin_mfa_auth.
     #[serde(rename = "authMethodId")]
-    auth_method_id: String,
+    pub auth_method_id: String,
     #[serde(rename = "isDefault")]
-    is_default: bool,
-    display: String,
+    pub is_default: bool,
+    /// A masked description of the method, such as a phone number.
+    pub display: String,
+}
+
+impl MFAMethod {
+    /// Whether this MFA method can be used in an MFA or passwordless flow.
+    pub fn is_supported(&self) -> bool {
+        matches!(
+            self.auth_method_id.as_str(),
+            "PhoneAppNotification"
+                | "PhoneAppOTP"
+                | "HardwareOTP"
+                | ACCESS_PASS_METHOD
+                | EMAIL_OTP_METHOD
+                | "ConsolidatedTelephony"
+                | "OneWaySMS"
+                | "TwoWayVoiceMobile"
+                | "TwoWayVoiceAlternateMobile"
+        )
+    }
+
+    /// Whether the user must enter a code for this MFA method, which is
+    /// passed as the `auth_data` of acquire_token_by_mfa_flow. Otherwise the
+    /// flow is completed by polling.
+    pub fn requires_input(&self) -> bool {
+        mfa_method_requires_input(&self.auth_method_id)
+    }
+
+    fn msg(&self, entropy: u8) -> Result<String, MsalError> {
+        Ok(match self.auth_method_id.as_str() {
+            "PhoneAppNotification" => format!(
+                "Open your Authenticator app, and enter the number '{}' to sign in.",
+                entropy
+            ),
+            "PhoneAppOTP" => {
+                "Please type in the code displayed on your authenticator app from your device:"
+                    .to_string()
+            }
+            "HardwareOTP" => {
+                "Please type in the code displayed on your hardware token:".to_string()
+            }
+            ACCESS_PASS_METHOD => "Please enter your Temporary Access Pass:".to_string(),
+            EMAIL_OTP_METHOD => format!(
+                "We emailed a code to {}. Please enter the code to sign in:",
+                self.display
+            ),
+            "ConsolidatedTelephony" | "OneWaySMS" => format!(
+                "We texted your phone {}. Please enter the code to sign in:",
+                self.display
+            ),
+            "TwoWayVoiceMobile" | "TwoWayVoiceAlternateMobile" => format!(
+                "We're calling your phone {}. Please answer it to continue.",
+                self.display
+            ),
+            method => {
+                return Err(MsalError::GeneralFailure(format!(
+                    "Unsupported MFA method {}",
+                    method
+                )))
+            }
+        })
+    }
+}
+
+fn mfa_method_requires_input(auth_method_id: &str) -> bool {
+    matches!(
+        auth_method_id,
+        "PhoneAppOTP"
+            | "HardwareOTP"
+            | ACCESS_PASS_METHOD
+            | EMAIL_OTP_METHOD
+            | "ConsolidatedTelephony"
+            | "OneWaySMS"
+    )
+}
+
+/// Whether an MFA method is a first factor of a passwordless flow, which
+/// signs in with a code in place of the password, rather than through
+/// BeginAuth and EndAuth.
+fn is_passwordless_method(auth_method_id: &str) -> bool {
+    matches!(auth_method_id, ACCESS_PASS_METHOD | EMAIL_OTP_METHOD)
+}
+
+/// The authorization code of a redirect which completes a sign in.
+fn redirect_authorization_code(resp: &reqwest::Response) -> Result<String, MsalError> {
+    let redirect = resp
+        .headers()
+        .get(header::LOCATION)
+        .ok_or(MsalError::InvalidParse(
+            "The sign in response is missing a redirect".to_string(),
+        ))?
+        .to_str()
+        .map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
+    let url = Url::parse(redirect).map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
+    let (_, code) = url
+        .query_pairs()
+        .find(|(k, _)| k == "code")
+        .ok_or(MsalError::InvalidParse(
+            "Authorization code missing from redirect".to_string(),
+        ))?;
+    Ok(code.to_string())
 }
 
 #[derive(Deserialize)]
@@ -134,7 +328,7 @@ struct AuthConfig {
     #[serde(rename = "iRemainingDaysToSkipMfaRegistration")]
     remaining_days_to_skip_mfa_reg: Option<u32>,
     #[serde(rename = "arrUserProofs")]
-    arr_user_proofs: Option<Vec<ArrUserProofs>>,
+    arr_user_proofs: Option<Vec<MFAMethod>>,
     #[serde(rename = "urlEndAuth")]
     url_end_auth: Option<String>,
     #[serde(rename = "urlBeginAuth")]
@@ -143,6 +337,12 @@ struct AuthConfig {
     max_poll_attempts: Option<u32>,
     #[serde(rename = "iPollingInterval")]
     polling_interval: Option<u32>,
+    #[serde(rename = "urlAsyncSsprBegin")]
+    url_async_sspr_begin: Option<String>,
+    #[serde(rename = "urlAsyncSsprPoll")]
+    url_async_sspr_poll: Option<String>,
+    #[serde(rename = "urlProofUpRedirect")]
+    url_proof_up_redirect: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -157,6 +357,203 @@ pub struct MFAAuthContinue {
     pub canary: String,
     pub url_end_auth: String,
     pub url_post: String,
+    #[serde(default)]
+    pub url_begin_auth: String,
+    /// The MFA methods registered by the user, from which one is chosen with
+    /// begin_mfa_auth.
+    #[serde(default)]
+    pub mfa_methods: Vec<MFAMethod>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    saml_assertion: Option<SamlAssertion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resource: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url_async_sspr_begin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url_async_sspr_poll: Option<String>,
+    /// The number of days the user may continue to skip MFA registration,
+    /// if the user has not registered an MFA method yet.
+    #[serde(default)]
+    pub mfa_registration_days_remaining: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url_proof_up: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    claims: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    one_time_code: Option<OneTimeCode>,
+}
+
+impl MFAAuthContinue {
+    /// Whether the user's password has expired, and must be changed with
+    /// change_password before the flow can continue.
+    pub fn requires_password_change(&self) -> bool {
+        self.mfa_method == CHANGE_PASSWORD_METHOD
+    }
+
+    /// Whether the user must register an MFA method with
+    /// begin_mfa_registration before the flow can continue.
+    pub fn requires_mfa_registration(&self) -> bool {
+        self.mfa_method == MFA_REGISTRATION_METHOD
+    }
+
+    /// Whether the user must enter a code for the MFA method of this flow,
+    /// which is passed as the `auth_data` of acquire_token_by_mfa_flow.
+    /// Otherwise the flow is completed by polling.
+    pub fn requires_input(&self) -> bool {
+        mfa_method_requires_input(&self.mfa_method)
+    }
+
+    /// The user's default MFA method. If the service did not mark a method as
+    /// the default, this is the first method.
+    pub fn default_mfa_method(&self) -> Option<&MFAMethod> {
+        self.mfa_methods
+            .iter()
+            .find(|method| method.is_default)
+            .or(self.mfa_methods.first())
+    }
+}
+
+/// The first factor methods of a passwordless flow. A Temporary Access Pass
+/// replaces the password, and a guest user may sign in with a one time code
+/// sent by email.
+const ACCESS_PASS_METHOD: &str = "AccessPass";
+const EMAIL_OTP_METHOD: &str = "Email";
+/// The PrefCredential of GetCredentialType for a user who signs in with a
+/// Temporary Access Pass.
+const ACCESS_PASS_CREDENTIAL: u32 = 13;
+/// The endpoint which sends a one time code, relative to the authority.
+const GET_ONE_TIME_CODE_PATH: &str = "GetOneTimeCode.srf";
+
+/// The pseudo MFA method of a flow which requires a password change.
+const CHANGE_PASSWORD_METHOD: &str = "ChangePassword";
+/// The number of times a password change is polled for completion.
+const CHANGE_PASSWORD_MAX_POLL_ATTEMPTS: u32 = 30;
+/// AADSTS50126: Invalid username or password.
+const INVALID_PASSWORD_ERROR_CODE: &str = "50126";
+/// Error codes with which a new password is rejected because it does not
+/// meet the complexity requirements of the password policy, or because it
+/// is in the password history.
+const PASSWORD_POLICY_ERROR_CODES: [&str; 3] = [
+    "PasswordPolicyError",
+    "ADPasswordPolicyError",
+    "PasswordInHistory",
+];
+
+/// The pseudo MFA method of a flow which requires the user to register an MFA
+/// method.
+const MFA_REGISTRATION_METHOD: &str = "RegisterMFA";
+/// The security info endpoints of the proof-up service, relative to the
+/// proof-up redirect url.
+const SECURITY_INFO_INITIALIZE_APP: &str =
+    "/api/authenticationmethods/InitializeMobileAppRegistration";
+const SECURITY_INFO_ADD: &str = "/api/authenticationmethods/AddSecurityInfo";
+const SECURITY_INFO_VERIFY: &str = "/api/authenticationmethods/VerifySecurityInfo";
+/// The issuer displayed by authenticator apps, if the service does not
+/// provide one.
+const AUTHENTICATOR_APP_DEFAULT_ISSUER: &str = "Microsoft";
+
+/// An MFA method which can be registered during an MFA flow.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum MFARegistrationMethod {
+    /// An authenticator app which displays time-based one-time codes (TOTP).
+    AuthenticatorApp,
+    /// Codes sent by text message to the given phone number.
+    Sms(String),
+}
+
+impl MFARegistrationMethod {
+    fn security_info_type(&self) -> &'static str {
+        match self {
+            MFARegistrationMethod::AuthenticatorApp => "AuthenticatorAppOtp",
+            MFARegistrationMethod::Sms(_) => "Sms",
+        }
+    }
+}
+
+/// An MFA method registration which has begun, and is completed with
+/// complete_mfa_registration once the user enters a verification code.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MFARegistration {
+    pub method: MFARegistrationMethod,
+    /// The message to display to the user.
+    pub msg: String,
+    /// The secret key to enter in the authenticator app, if the app cannot
+    /// scan the `otpauth_uri`.
+    pub secret_key: Option<String>,
+    /// An otpauth:// URI for the authenticator app, which is typically
+    /// displayed as a QR code.
+    pub otpauth_uri: Option<String>,
+    verification_context: String,
+}
+
+#[derive(Deserialize)]
+struct SecurityInfoResponse {
+    #[serde(rename = "Ctx")]
+    ctx: Option<String>,
+    #[serde(rename = "FlowToken")]
+    flow_token: Option<String>,
+    #[serde(rename = "ErrorCode", default)]
+    error_code: Option<Value>,
+    #[serde(rename = "Message")]
+    message: Option<String>,
+    #[serde(rename = "VerificationContext")]
+    verification_context: Option<String>,
+    #[serde(rename = "SecretKey")]
+    secret_key: Option<String>,
+    #[serde(rename = "AccountName")]
+    account_name: Option<String>,
+    #[serde(rename = "Issuer")]
+    issuer: Option<String>,
+}
+
+/// The error code of a service response, where an empty code or 0 indicates
+/// success.
+fn service_error_code(error_code: &Option<Value>) -> Option<String> {
+    match error_code {
+        None | Some(Value::Null) => None,
+        Some(Value::String(code)) if code.is_empty() || code == "0" => None,
+        Some(Value::Number(code)) if code.as_u64() == Some(0) => None,
+        Some(Value::String(code)) => Some(code.clone()),
+        Some(code) => Some(code.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct SsprResponse {
+    #[serde(rename = "Ctx")]
+    ctx: Option<String>,
+    #[serde(rename = "FlowToken")]
+    flow_token: Option<String>,
+    #[serde(rename = "IsCompleted", default)]
+    is_completed: bool,
+    #[serde(rename = "ErrorCode", default)]
+    error_code: Option<Value>,
+    #[serde(rename = "Message")]
+    message: Option<String>,
+}
+
+impl SsprResponse {
+    fn error_code(&self) -> Option<String> {
+        service_error_code(&self.error_code)
+    }
+
+    fn message(&self) -> String {
+        self.message
+            .clone()
+            .unwrap_or("Your password could not be changed.".to_string())
+    }
+
+    fn password_change_error(&self) -> Option<PasswordChangeError> {
+        let error_code = self.error_code()?;
+        debug!("Password change failed with error code {}", error_code);
+        Some(if error_code == INVALID_PASSWORD_ERROR_CODE {
+            PasswordChangeError::IncorrectPassword(self.message())
+        } else if PASSWORD_POLICY_ERROR_CODES.contains(&error_code.as_str()) {
+            PasswordChangeError::PolicyViolation(self.message())
+        } else {
+            PasswordChangeError::Failed(self.message())
+        })
+    }
 }
 
 #[derive(Deserialize)]
@@ -171,7 +568,7 @@ struct AuthResponse {
     ctx: String,
     #[serde(rename = "FlowToken")]
     flow_token: String,
-    #[serde(rename = "Entropy")]
+    #[serde(rename = "Entropy", default)]
     entropy: u8,
 }
 
@@ -181,6 +578,40 @@ struct Credentials {
     federation_redirect_url: Option<String>,
     #[serde(rename = "HasPassword")]
     has_password: bool,
+    #[serde(rename = "PrefCredential", default)]
+    pref_credential: u32,
+    #[serde(rename = "OtcLoginEligibleProofs", default)]
+    otc_login_eligible_proofs: Vec<OtcProof>,
+}
+
+/// A proof, such as an email address, to which a one time code can be sent
+/// to sign in.
+#[derive(Clone, Deserialize, Serialize)]
+struct OtcProof {
+    data: String,
+    display: String,
+    #[serde(rename = "otcSent", default)]
+    otc_sent: bool,
+    #[serde(rename = "isDefault", default)]
+    is_default: bool,
+}
+
+/// The one time code proof of a passwordless flow, and the user it is sent
+/// to.
+#[derive(Clone, Deserialize, Serialize)]
+struct OneTimeCode {
+    login: String,
+    proof: OtcProof,
+}
+
+#[derive(Deserialize)]
+struct OneTimeCodeResponse {
+    #[serde(rename = "FlowToken")]
+    flow_token: Option<String>,
+    #[serde(rename = "ErrorCode", default)]
+    error_code: Option<Value>,
+    #[serde(rename = "Message")]
+    message: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -195,12 +626,47 @@ struct CredType {
 
 #[derive(Default, Clone, Deserialize, Serialize)]
 pub struct IdToken {
+    #[serde(default)]
     pub name: String,
+    #[serde(default)]
     pub oid: String,
     pub preferred_username: Option<String>,
     pub puid: Option<String>,
     pub tenant_region_scope: Option<String>,
+    #[serde(default)]
     pub tid: String,
+    /// The user flow (policy) which issued an Azure AD B2C token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tfp: Option<String>,
+    /// The user flow of an Azure AD B2C token, when the user flow is
+    /// configured to issue the acr claim instead of tfp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>,
+    /// The email addresses of an Azure AD B2C user.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub emails: Vec<String>,
+    #[serde(skip)]
+    pub(crate) raw: Option<String>,
+}
+
+impl IdToken {
+    /// The Azure AD B2C user flow (policy) which issued the token, in
+    /// lowercase.
+    ///
+    /// # Returns
+    ///
+    /// * The policy, or None if the token was not issued by a B2C user flow.
+    pub fn policy(&self) -> Option<String> {
+        // Entra ID also issues an acr claim, which is not a B2C policy.
+        // B2C policy names always begin with B2C_1.
+        self.tfp
+            .as_ref()
+            .or(self
+                .acr
+                .as_ref()
+                .filter(|acr| acr.to_lowercase().starts_with("b2c_1")))
+            .map(|policy| policy.to_lowercase())
+    }
 }
 
 fn decode_string_or_struct<'de, T, D>(deserializer: D) -> Result<T, D::Error>
@@ -263,9 +729,10 @@ impl FromStr for IdToken {
                 ));
             }
         };
-        let payload: IdToken = json_from_str(&payload_str).map_err(|e| {
+        let mut payload: IdToken = json_from_str(&payload_str).map_err(|e| {
             MsalError::InvalidParse(format!("Failed parsing id_token from json: {}", e))
         })?;
+        payload.raw = Some(s.to_string());
         Ok(payload)
     }
 }
@@ -274,6 +741,23 @@ impl FromStr for IdToken {
 pub struct ClientInfo {
     pub uid: Option<Uuid>,
     pub utid: Option<Uuid>,
+    /// The user flow (policy) of an Azure AD B2C account. B2C appends the
+    /// policy to the uid, as in `{uid}-{policy}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+}
+
+/// Split a uid into the object id and the B2C policy which follows it.
+fn split_uid(uid: &str) -> Result<(Uuid, Option<String>), uuid::Error> {
+    match (
+        uid.get(..36),
+        uid.get(36..).and_then(|p| p.strip_prefix('-')),
+    ) {
+        (Some(oid), Some(policy)) if !policy.is_empty() => {
+            Ok((Uuid::parse_str(oid)?, Some(policy.to_lowercase())))
+        }
+        _ => Ok((Uuid::parse_str(uid)?, None)),
+    }
 }
 
 impl FromStr for ClientInfo {
@@ -294,7 +778,7 @@ impl FromStr for ClientInfo {
             })?;
 
         let uid_str = client_info["uid"].to_string();
-        let uid = Uuid::parse_str(uid_str.trim_matches('"'))
+        let (uid, policy) = split_uid(uid_str.trim_matches('"'))
             .map_err(|e| MsalError::InvalidParse(format!("Failed parsing client_info: {}", e)))?;
 
         let utid_str = client_info["utid"].to_string();
@@ -304,10 +788,36 @@ impl FromStr for ClientInfo {
         Ok(ClientInfo {
             uid: Some(uid),
             utid: Some(utid),
+            policy,
         })
     }
 }
 
+impl ClientInfo {
+    pub(crate) fn from_home_account_id(home_account_id: &str) -> Self {
+        let mut siter = home_account_id.splitn(2, '.');
+        let (uid, policy) = match siter.next().map(split_uid) {
+            Some(Ok((uid, policy))) => (Some(uid), policy),
+            _ => (None, None),
+        };
+        ClientInfo {
+            uid,
+            utid: siter.next().and_then(|utid| Uuid::parse_str(utid).ok()),
+            policy,
+        }
+    }
+
+    /// The home account id, in the form `uid.utid`, or `uid-policy.utid`
+    /// for an Azure AD B2C account.
+    pub(crate) fn home_account_id(&self) -> Option<String> {
+        match (self.uid, self.utid, &self.policy) {
+            (Some(uid), Some(utid), Some(policy)) => Some(format!("{}-{}.{}", uid, policy, utid)),
+            (Some(uid), Some(utid), None) => Some(format!("{}.{}", uid, utid)),
+            _ => None,
+        }
+    }
+}
+
 fn decode_number_from_string<'de, D>(d: D) -> Result<u32, D::Error>
 where
     D: Deserializer<'de>,
@@ -325,6 +835,13 @@ where
     }
 }
 
+fn decode_optional_number_from_string<'de, D>(d: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    decode_number_from_string(d).map(Some)
+}
+
 #[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct AccessTokenPayload {
     amr: Vec<String>,
@@ -332,15 +849,30 @@ pub struct AccessTokenPayload {
     upn: String,
 }
 
-#[derive(Clone, Deserialize, Zeroize, ZeroizeOnDrop)]
+#[derive(Clone, Deserialize, Serialize, Zeroize, ZeroizeOnDrop)]
 pub struct UserToken {
     pub token_type: String,
     pub scope: Option<String>,
     #[serde(deserialize_with = "decode_number_from_string")]
     pub expires_in: u32,
-    #[serde(deserialize_with = "decode_number_from_string")]
+    /// Not issued by Azure AD B2C.
+    #[serde(deserialize_with = "decode_number_from_string", default)]
     pub ext_expires_in: u32,
+    /// When the access token expires, in seconds since the epoch.
+    #[serde(deserialize_with = "deserialize_timestamp", default)]
+    pub expires_on: u64,
+    /// When the access token should be proactively refreshed, in seconds
+    /// since the epoch. A token which does not record this, such as one
+    /// stored by an earlier version, has it recomputed by stamp_expiry.
+    #[serde(default)]
+    pub refresh_on: u64,
+    /// The number of seconds after which the server suggests refreshing the
+    /// access token.
+    #[serde(deserialize_with = "decode_optional_number_from_string", default)]
+    pub refresh_in: Option<u32>,
     pub access_token: Option<String>,
+    /// Empty when no refresh token was issued, as for an app token.
+    #[serde(default)]
     pub refresh_token: String,
     #[serde(deserialize_with = "decode_string_or_struct", default)]
     #[zeroize(skip)]
@@ -348,12 +880,92 @@ pub struct UserToken {
     #[serde(deserialize_with = "decode_string_or_struct", default)]
     #[zeroize(skip)]
     pub client_info: ClientInfo,
+    /// The family id, when the client is part of the family of client ids.
+    #[serde(default)]
+    pub foci: Option<String>,
     #[cfg(feature = "broker")]
     #[zeroize(skip)]
     pub prt: Option<SealedData>,
 }
 
 impl UserToken {
+    /// Record when the token expires, and when it should be refreshed,
+    /// relative to the time the token response was received. Times which
+    /// are already recorded are kept.
+    pub(crate) fn stamp_expiry(&mut self) -> Result<(), MsalError> {
+        if self.expires_on == 0 {
+            self.expires_on = now()? + self.expires_in as u64;
+        }
+        if self.refresh_on == 0 {
+            let issued_on = self.expires_on.saturating_sub(self.expires_in as u64);
+            let refresh_in = self.refresh_in.unwrap_or(self.expires_in / 2);
+            self.refresh_on = std::cmp::min(issued_on + refresh_in as u64, self.expires_on);
+        }
+        Ok(())
+    }
+
+    /// Check whether the access token has expired
+    ///
+    /// # Arguments
+    ///
+    /// * `skew` - Consider the token expired if it expires within this
+    ///   duration, to allow for clock skew and request latency.
+    ///
+    /// # Returns
+    ///
+    /// * Whether the access token has expired. A token without a known expiry
+    ///   is considered expired.
+    pub fn is_expired(&self, skew: Duration) -> bool {
+        match now() {
+            Ok(now) => now + skew.as_secs() >= self.expires_on,
+            Err(_) => true,
+        }
+    }
+
+    /// Check whether the access token should be refreshed. This is the case
+    /// once the refresh_on time has passed, or if the token is about to
+    /// expire.
+    ///
+    /// # Returns
+    ///
+    /// * Whether the access token should be refreshed.
+    pub fn needs_refresh(&self) -> bool {
+        match now() {
+            Ok(now) => {
+                now >= self.refresh_on
+                    || self.is_expired(Duration::from_secs(ACCESS_TOKEN_EXPIRY_BUFFER))
+            }
+            Err(_) => true,
+        }
+    }
+
+    /// Fetch the home account id from the user token
+    ///
+    /// # Returns
+    ///
+    /// * Success: The home account id, in the form `uid.utid`, or
+    ///   `uid-policy.utid` for an Azure AD B2C account.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn home_account_id(&self) -> Result<String, MsalError> {
+        match self.client_info.home_account_id() {
+            Some(home_account_id) => Ok(home_account_id),
+            None => {
+                if !self.id_token.oid.is_empty() && !self.id_token.tid.is_empty() {
+                    Ok(match self.id_token.policy() {
+                        Some(policy) => {
+                            format!("{}-{}.{}", self.id_token.oid, policy, self.id_token.tid)
+                        }
+                        None => format!("{}.{}", self.id_token.oid, self.id_token.tid),
+                    })
+                } else {
+                    Err(MsalError::GeneralFailure(
+                        "No home account id available for UserToken".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+
     /// Fetch the tenant id from the user token
     ///
     /// # Returns
@@ -404,7 +1016,13 @@ impl UserToken {
     /// * Success: The user Azure spn
     /// * Failure: An MsalError, indicating the failure.
     pub fn spn(&self) -> Result<String, MsalError> {
-        match &self.id_token.preferred_username {
+        // A B2C user is known by their email address
+        match self
+            .id_token
+            .preferred_username
+            .as_ref()
+            .or(self.id_token.emails.first())
+        {
             Some(spn) => Ok(spn.to_string()),
             // If all else fails, extract the upn from the access_token
             None => match &self.access_token {
@@ -496,6 +1114,38 @@ impl UsernamePasswordAuthenticationPayload {
     }
 }
 
+#[cfg(feature = "broker")]
+#[derive(Serialize, Clone, Default, Zeroize, ZeroizeOnDrop)]
+struct SamlAssertionAuthenticationPayload {
+    client_id: String,
+    request_nonce: String,
+    scope: String,
+    win_ver: Option<String>,
+    grant_type: String,
+    assertion: String,
+}
+
+#[cfg(feature = "broker")]
+impl SamlAssertionAuthenticationPayload {
+    fn new(saml_assertion: &SamlAssertion, request_nonce: &str) -> Self {
+        let os_release = match OsRelease::new() {
+            Ok(os_release) => Some(format!(
+                "{} {}",
+                os_release.pretty_name, os_release.version_id
+            )),
+            Err(_) => None,
+        };
+        SamlAssertionAuthenticationPayload {
+            client_id: BROKER_CLIENT_IDENT.to_string(),
+            request_nonce: request_nonce.to_string(),
+            scope: "openid aza ugs".to_string(),
+            win_ver: os_release,
+            grant_type: saml_assertion.grant_type().to_string(),
+            assertion: saml_assertion.encoded(),
+        }
+    }
+}
+
 #[cfg(feature = "broker")]
 #[derive(Serialize, Clone, Default, Zeroize, ZeroizeOnDrop)]
 struct RefreshTokenAuthenticationPayload {
@@ -612,6 +1262,7 @@ impl ExchangePRTPayload {
         nonce: &str,
         resource: Option<String>,
         request_prt: bool,
+        aud: String,
     ) -> Result<Self, MsalError> {
         let mut scopes = "openid ugs".to_string();
         if request_prt {
@@ -633,7 +1284,7 @@ impl ExchangePRTPayload {
             iss: "aad:brokerplugin".to_string(),
             grant_type: "refresh_token".to_string(),
             client_id: BROKER_CLIENT_IDENT.to_string(),
-            aud: "login.microsoftonline.com".to_string(),
+            aud,
         })
     }
 }
@@ -787,6 +1438,78 @@ impl PrimaryRefreshToken {
     fn clone_session_key(&self, new_prt: &mut PrimaryRefreshToken) {
         new_prt.session_key_jwe.clone_from(&self.session_key_jwe);
     }
+
+    /// When the PRT was issued, in seconds since the epoch. The response
+    /// only contains the expiry of the accompanying token, and its lifetime.
+    fn issued_on(&self) -> Result<u64, MsalError> {
+        let expires_on = self
+            .expires_on
+            .parse::<u64>()
+            .map_err(|e| MsalError::InvalidParse(format!("Invalid PRT expires_on: {}", e)))?;
+        let expires_in = self
+            .expires_in
+            .parse::<u64>()
+            .map_err(|e| MsalError::InvalidParse(format!("Invalid PRT expires_in: {}", e)))?;
+        Ok(expires_on.saturating_sub(expires_in))
+    }
+
+    fn info(&self) -> Result<PrtInfo, MsalError> {
+        let issued_on = self.issued_on()?;
+        let home_account_id = self.client_info.home_account_id();
+        Ok(PrtInfo {
+            username: self.id_token.preferred_username.clone(),
+            name: self.id_token.name.clone(),
+            oid: self.id_token.oid.clone(),
+            tenant_id: self.id_token.tid.clone(),
+            home_account_id,
+            issued_on,
+            expires_on: issued_on + self.refresh_token_expires_in,
+            renew_on: issued_on
+                + std::cmp::min(self.refresh_token_expires_in / 2, PRT_RENEWAL_INTERVAL),
+            device_tenant_id: self.device_tenant_id.clone(),
+            kerberos_top_level_names: self.kerberos_top_level_names.clone(),
+            has_ad_tgt: self.tgt_ad.is_present(),
+            has_cloud_tgt: self.tgt_cloud.is_present(),
+        })
+    }
+}
+
+/// Non-secret metadata of a Primary Refresh Token (PRT). The refresh token
+/// and session key of the PRT are not exposed.
+#[cfg(feature = "broker")]
+#[derive(Clone, Debug)]
+pub struct PrtInfo {
+    /// The user principal name of the user the PRT was issued to.
+    pub username: Option<String>,
+    /// The display name of the user.
+    pub name: String,
+    /// The object id of the user.
+    pub oid: String,
+    /// The tenant of the user.
+    pub tenant_id: String,
+    /// The home account id of the user, in the form `uid.utid`.
+    pub home_account_id: Option<String>,
+    /// When the PRT was issued, in seconds since the epoch.
+    pub issued_on: u64,
+    /// When the PRT expires, in seconds since the epoch.
+    pub expires_on: u64,
+    /// When the PRT should be renewed, in seconds since the epoch.
+    pub renew_on: u64,
+    /// The tenant the device is enrolled in.
+    pub device_tenant_id: Option<String>,
+    /// The Kerberos realms which the cloud TGT may be used for.
+    pub kerberos_top_level_names: Option<String>,
+    /// Whether the PRT carries a TGT for the on-premises Active Directory.
+    pub has_ad_tgt: bool,
+    /// Whether the PRT carries a cloud (Entra ID Kerberos) TGT.
+    pub has_cloud_tgt: bool,
+}
+
+#[cfg(feature = "broker")]
+impl TGT {
+    fn is_present(&self) -> bool {
+        self.error.is_none() && self.message_buffer.is_some()
+    }
 }
 
 #[cfg(feature = "broker")]
@@ -838,284 +1561,588 @@ impl SessionKey {
     }
 }
 
-struct ClientApplication {
-    client: Client,
-    client_id: String,
-    authority: String,
+/// How long acquire_token_interactive waits for the browser to be redirected
+/// back to the loopback listener.
+const INTERACTIVE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The prompt behavior of an interactive authentication.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prompt {
+    /// Do not prompt the user. The request fails if interaction is required.
+    None,
+    /// Force the user to enter their credentials.
+    Login,
+    /// Ask the user to consent to the requested scopes.
+    Consent,
+    /// Let the user choose between the accounts they are signed in with.
+    SelectAccount,
+    /// Let the user create a new account.
+    Create,
 }
 
-impl ClientApplication {
-    fn new(client_id: &str, authority: Option<&str>) -> Result<Self, MsalError> {
-        let client = reqwest::Client::builder()
-            .cookie_store(true)
-            .build()
-            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        Ok(ClientApplication {
-            client,
-            client_id: client_id.to_string(),
-            authority: match authority {
-                Some(authority) => authority.to_string(),
-                None => "https://login.microsoftonline.com/common".to_string(),
-            },
-        })
+impl Prompt {
+    fn as_str(self) -> &'static str {
+        match self {
+            Prompt::None => "none",
+            Prompt::Login => "login",
+            Prompt::Consent => "consent",
+            Prompt::SelectAccount => "select_account",
+            Prompt::Create => "create",
+        }
     }
+}
 
-    async fn acquire_token_by_username_password(
-        &self,
-        username: &str,
-        password: &str,
-        scopes: Vec<&str>,
-    ) -> Result<UserToken, MsalError> {
-        let mut all_scopes = vec!["openid", "profile", "offline_access"];
-        all_scopes.extend(scopes);
-        let scopes_str = all_scopes.join(" ");
+/// Merge a claims challenge with the client capabilities of an application,
+/// which are declared in the `xms_cc` claim of the access token.
+fn merge_claims(
+    claims: Option<&str>,
+    client_capabilities: &[String],
+) -> Result<Option<String>, MsalError> {
+    if client_capabilities.is_empty() {
+        return Ok(claims.map(|claims| claims.to_string()));
+    }
+    let mut merged = match claims {
+        Some(claims) => json_from_str(claims)
+            .map_err(|e| MsalError::InvalidJson(format!("Invalid claims: {}", e)))?,
+        None => json!({}),
+    };
+    let access_token = merged
+        .as_object_mut()
+        .ok_or(MsalError::InvalidJson(
+            "Claims must be a JSON object".to_string(),
+        ))?
+        .entry("access_token")
+        .or_insert(json!({}));
+    access_token
+        .as_object_mut()
+        .ok_or(MsalError::InvalidJson(
+            "The access_token claims must be a JSON object".to_string(),
+        ))?
+        .insert(
+            "xms_cc".to_string(),
+            json!({ "values": client_capabilities }),
+        );
+    Ok(Some(merged.to_string()))
+}
 
-        let params = [
-            ("client_id", self.client_id.as_str()),
-            ("scope", &scopes_str),
-            ("username", username),
-            ("password", password),
-            ("grant_type", "password"),
-            ("client_info", "1"),
-        ];
-        let payload = params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
-            .collect::<Vec<String>>()
-            .join("&");
+/// Generate a PKCE (RFC7636) code verifier, and its S256 code challenge.
+fn pkce_challenge() -> Result<(String, String), MsalError> {
+    let mut buf = [0u8; 32];
+    rand_bytes(&mut buf).map_err(|e| MsalError::CryptoFail(format!("{}", e)))?;
+    let verifier = URL_SAFE_NO_PAD.encode(buf);
+    let challenge = URL_SAFE_NO_PAD.encode(
+        hash(MessageDigest::sha256(), verifier.as_bytes())
+            .map_err(|e| MsalError::CryptoFail(format!("{}", e)))?,
+    );
+    Ok((verifier, challenge))
+}
 
-        let resp = self
-            .client
-            .post(format!("{}/oauth2/v2.0/token", self.authority))
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .header(header::ACCEPT, "application/json")
-            .body(payload)
-            .send()
+const REDIRECT_RESPONSE_SUCCESS: &str =
+    "Authentication complete. You may close this window and return to the application.";
+const REDIRECT_RESPONSE_FAILURE: &str =
+    "Authentication failed. You may close this window and return to the application.";
+
+/// How long a connection to the loopback listener may take to send its
+/// request. Browsers open idle preconnect sockets, which must not hold up
+/// the redirect.
+const REDIRECT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the request line of an HTTP request, and reply with a short page.
+/// Returns the query parameters of the request, or None if the request was
+/// not a redirect for this authentication (such as a request for a favicon,
+/// or a request with a different state).
+async fn handle_redirect_request(
+    stream: &mut tokio::net::TcpStream,
+    state: &str,
+) -> Result<Option<Vec<(String, String)>>, MsalError> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < 16384 {
+        let n = stream
+            .read(&mut chunk)
             .await
             .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_success() {
-            let token: UserToken = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-
-            Ok(token)
-        } else {
-            let json_resp: ErrorResponse = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            Err(MsalError::AcquireTokenFailed(json_resp))
+        if n == 0 {
+            break;
         }
+        buf.extend_from_slice(&chunk[..n]);
     }
+    let request = String::from_utf8_lossy(&buf);
+    let target = request
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default();
+    let url = Url::parse(&format!("http://127.0.0.1{}", target))
+        .map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
+    let params: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+    let found = url.path() == "/"
+        && params.iter().any(|(k, v)| k == "state" && v == state)
+        && params.iter().any(|(k, _)| k == "code" || k == "error");
+    let (status, body) = match found {
+        true if params.iter().any(|(k, _)| k == "code") => ("200 OK", REDIRECT_RESPONSE_SUCCESS),
+        true => ("200 OK", REDIRECT_RESPONSE_FAILURE),
+        false => ("404 Not Found", ""),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+    let _ = stream.shutdown().await;
+    Ok(match found {
+        true => Some(params),
+        false => None,
+    })
+}
 
-    async fn acquire_token_by_refresh_token(
-        &self,
-        refresh_token: &str,
-        scopes: Vec<&str>,
-    ) -> Result<UserToken, MsalError> {
-        let mut all_scopes = vec!["openid", "profile", "offline_access"];
-        all_scopes.extend(scopes);
-        let scopes_str = all_scopes.join(" ");
-
-        let params = [
-            ("client_id", self.client_id.as_str()),
-            ("scope", &scopes_str),
-            ("grant_type", "refresh_token"),
-            ("refresh_token", refresh_token),
-            ("client_info", "1"),
-        ];
-        let payload = params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
-            .collect::<Vec<String>>()
-            .join("&");
-
-        let resp = self
-            .client
-            .post(format!("{}/oauth2/v2.0/token", self.authority))
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .header(header::ACCEPT, "application/json")
-            .body(payload)
-            .send()
-            .await
-            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_success() {
-            let token: UserToken = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-
-            Ok(token)
-        } else {
-            let json_resp: ErrorResponse = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            Err(MsalError::AcquireTokenFailed(json_resp))
+/// Wait for the browser to be redirected to the loopback listener, and
+/// return the authorization code from the redirect. Each connection is
+/// handled in its own task, so that an idle connection does not block the
+/// redirect, and requests which do not carry the state of this
+/// authentication are ignored.
+async fn wait_for_auth_code(listener: TcpListener, state: &str) -> Result<String, MsalError> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (mut stream, _) =
+                    accepted.map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+                let tx = tx.clone();
+                let state = state.to_string();
+                tokio::spawn(async move {
+                    match timeout(
+                        REDIRECT_READ_TIMEOUT,
+                        handle_redirect_request(&mut stream, &state),
+                    )
+                    .await
+                    {
+                        Ok(Ok(Some(params))) => {
+                            let _ = tx.send(params);
+                        }
+                        Ok(Ok(None)) => {}
+                        Ok(Err(e)) => debug!("Ignoring invalid redirect request: {:?}", e),
+                        Err(_) => debug!("Closing an idle connection to the redirect listener"),
+                    }
+                });
+            }
+            Some(params) = rx.recv() => {
+                let param = |name: &str| {
+                    params
+                        .iter()
+                        .find(|(k, _)| k == name)
+                        .map(|(_, v)| v.clone())
+                };
+                if let Some(error) = param("error") {
+                    return Err(MsalError::AcquireTokenFailed(ErrorResponse {
+                        error,
+                        error_description: param("error_description").unwrap_or_default(),
+                        error_codes: vec![],
+                        claims: param("claims"),
+                        suberror: param("suberror"),
+                    }));
+                }
+                if let Some(code) = param("code") {
+                    return Ok(code);
+                }
+            }
         }
     }
 }
 
-pub struct PublicClientApplication {
-    app: ClientApplication,
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// How long a client assertion remains valid, in seconds.
+const CLIENT_ASSERTION_LIFETIME: u64 = 10 * 60;
+
+/// The environment variable which names the federated token file of a
+/// workload identity.
+const FEDERATED_TOKEN_FILE_ENV: &str = "AZURE_FEDERATED_TOKEN_FILE";
+
+type AssertionCallback = dyn Fn() -> Result<String, MsalError> + Send + Sync;
+
+/// The credential a ConfidentialClientApplication uses to authenticate
+/// itself to the token endpoint.
+pub enum ClientCredential {
+    /// A client secret registered for the application on AAD.
+    Secret(String),
+    /// A certificate registered for the application on AAD, and its private
+    /// key. Requests are authenticated with a client assertion signed by the
+    /// key.
+    Certificate {
+        certificate: X509,
+        key: PKey<Private>,
+    },
+    /// A certificate registered for the application on AAD, whose private
+    /// key is a TPM protected IdentityKey. The IdentityKey must have an
+    /// associated certificate.
+    #[cfg(feature = "broker")]
+    TpmCertificate {
+        tpm: Arc<Mutex<BoxedDynTpm>>,
+        key: IdentityKey,
+    },
+    /// A client assertion returned by a callback, such as a federated token
+    /// issued by another identity provider. The callback is called for each
+    /// request.
+    Assertion(Box<AssertionCallback>),
+    /// A client assertion read from a file, such as the federated token
+    /// projected into a Kubernetes pod. The file is read again whenever it
+    /// changes.
+    AssertionFile {
+        path: PathBuf,
+        cached: StdMutex<Option<(SystemTime, u64, String)>>,
+    },
 }
 
-impl PublicClientApplication {
-    /// Create an instance of an application.
+impl ClientCredential {
+    /// Create a client secret credential.
     ///
     /// # Arguments
     ///
-    /// * `client_id` - Your app has a client_id after you register it on
-    ///   AAD.
-    ///
-    /// * `authority` - A URL that identifies a token authority. It should
-    ///   be of the format <https://login.microsoftonline.com/your_tenant> By
-    ///   default, we will use <https://login.microsoftonline.com/common>.
-    pub fn new(client_id: &str, authority: Option<&str>) -> Result<Self, MsalError> {
-        Ok(PublicClientApplication {
-            app: ClientApplication::new(client_id, authority)?,
-        })
-    }
-
-    fn client(&self) -> &Client {
-        &self.app.client
-    }
-
-    fn client_id(&self) -> &str {
-        &self.app.client_id
-    }
-
-    fn authority(&self) -> &str {
-        &self.app.authority
+    /// * `secret` - The client secret.
+    pub fn from_secret(secret: &str) -> Self {
+        ClientCredential::Secret(secret.to_string())
     }
 
-    /// Gets a token for a given resource via user credentials.
+    /// Create a certificate credential from PEM encoded data.
     ///
     /// # Arguments
     ///
-    /// * `username` - Typically a UPN in the form of an email address.
+    /// * `certificate` - The PEM encoded certificate.
     ///
-    /// * `password` - The password.
+    /// * `key` - The PEM encoded RSA private key. Client assertions are
+    ///   signed with RS256, so EC keys are not supported.
     ///
-    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    /// * `passphrase` - The passphrase of the private key, if it is
+    ///   encrypted.
     ///
     /// # Returns
-    /// * Success: A UserToken containing an access_token.
+    ///
+    /// * Success: A ClientCredential.
     /// * Failure: An MsalError, indicating the failure.
-    pub async fn acquire_token_by_username_password(
-        &self,
-        username: &str,
-        password: &str,
-        scopes: Vec<&str>,
-    ) -> Result<UserToken, MsalError> {
-        self.app
-            .acquire_token_by_username_password(username, password, scopes)
-            .await
+    pub fn from_certificate_pem(
+        certificate: &[u8],
+        key: &[u8],
+        passphrase: Option<&[u8]>,
+    ) -> Result<Self, MsalError> {
+        let certificate = X509::from_pem(certificate)
+            .map_err(|e| MsalError::CryptoFail(format!("Failed loading certificate: {}", e)))?;
+        let key = match passphrase {
+            Some(passphrase) => PKey::private_key_from_pem_passphrase(key, passphrase),
+            None => PKey::private_key_from_pem(key),
+        }
+        .map_err(|e| MsalError::CryptoFail(format!("Failed loading private key: {}", e)))?;
+        if key.id() != Id::RSA {
+            return Err(MsalError::CryptoFail(
+                "The certificate key must be an RSA key".to_string(),
+            ));
+        }
+        Ok(ClientCredential::Certificate { certificate, key })
     }
 
-    /// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
+    /// Create a certificate credential from a TPM protected IdentityKey.
     ///
     /// # Arguments
     ///
-    /// * `refresh_token` - The old refresh token, as a string.
+    /// * `tpm` - The TPM which holds the IdentityKey.
     ///
-    /// * `scopes` - The scopes associated with this old RT.
+    /// * `key` - The RSA IdentityKey, which must have an associated
+    ///   certificate registered for the application on AAD.
     ///
     /// # Returns
     ///
-    /// * Success: A UserToken, which means migration was successful.
+    /// * Success: A ClientCredential.
     /// * Failure: An MsalError, indicating the failure.
-    pub async fn acquire_token_by_refresh_token(
-        &self,
-        refresh_token: &str,
-        scopes: Vec<&str>,
-    ) -> Result<UserToken, MsalError> {
-        self.app
-            .acquire_token_by_refresh_token(refresh_token, scopes)
-            .await
+    #[cfg(feature = "broker")]
+    pub fn from_tpm_identity_key(
+        tpm: Arc<Mutex<BoxedDynTpm>>,
+        key: IdentityKey,
+    ) -> Result<Self, MsalError> {
+        match key.alg() {
+            KeyAlgorithm::Rsa2048 => Ok(ClientCredential::TpmCertificate { tpm, key }),
+            KeyAlgorithm::Ecdsa256 => Err(MsalError::CryptoFail(
+                "The certificate key must be an RSA key".to_string(),
+            )),
+        }
     }
 
-    /// Initiate a Device Flow instance, which will be used in
-    /// acquire_token_by_device_flow.
+    /// Create a client assertion credential, whose assertion is returned by
+    /// a callback.
     ///
     /// # Arguments
     ///
-    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    /// * `callback` - Called for each token request, returning the client
+    ///   assertion, such as a federated token issued by another identity
+    ///   provider.
+    pub fn from_assertion_callback<F>(callback: F) -> Self
+    where
+        F: Fn() -> Result<String, MsalError> + Send + Sync + 'static,
+    {
+        ClientCredential::Assertion(Box::new(callback))
+    }
+
+    /// Create a client assertion credential, whose assertion is read from a
+    /// file. The file is read again whenever it changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file containing the client assertion.
+    pub fn from_assertion_file(path: &Path) -> Self {
+        ClientCredential::AssertionFile {
+            path: path.to_path_buf(),
+            cached: StdMutex::new(None),
+        }
+    }
+
+    /// Create a workload identity federation credential, whose assertion is
+    /// read from the file named by the `AZURE_FEDERATED_TOKEN_FILE`
+    /// environment variable.
     ///
     /// # Returns
     ///
-    /// * Success: A DeviceAuthorizationResponse containing a user_code key,
-    ///   among others
-    /// * Failure: An MsalError, indicating the failure.
-    pub async fn initiate_device_flow(
+    /// * Success: A ClientCredential.
+    /// * Failure: An MsalError, indicating the environment variable is not
+    ///   set.
+    pub fn from_federated_token_file() -> Result<Self, MsalError> {
+        let path = std::env::var(FEDERATED_TOKEN_FILE_ENV).map_err(|e| {
+            MsalError::ConfigError(format!("{} is not set: {}", FEDERATED_TOKEN_FILE_ENV, e))
+        })?;
+        Ok(ClientCredential::from_assertion_file(Path::new(&path)))
+    }
+
+    /// The token request parameters which authenticate the client.
+    async fn params(
         &self,
-        scopes: Vec<&str>,
-    ) -> Result<DeviceAuthorizationResponse, MsalError> {
-        let mut all_scopes = vec!["openid", "profile", "offline_access"];
-        all_scopes.extend(scopes);
-        let scopes_str = all_scopes.join(" ");
+        client_id: &str,
+        audience: &str,
+    ) -> Result<Vec<(&'static str, String)>, MsalError> {
+        let assertion = match self {
+            ClientCredential::Secret(secret) => {
+                return Ok(vec![("client_secret", secret.clone())]);
+            }
+            ClientCredential::Certificate { certificate, key } => {
+                let der = certificate
+                    .to_der()
+                    .map_err(|e| MsalError::CryptoFail(format!("{}", e)))?;
+                let input = client_assertion_signing_input(&der, client_id, audience)?;
+                let signature = Signer::new(MessageDigest::sha256(), key)
+                    .and_then(|mut signer| signer.sign_oneshot_to_vec(input.as_bytes()))
+                    .map_err(|e| {
+                        MsalError::CryptoFail(format!("Failed signing client assertion: {}", e))
+                    })?;
+                format!("{}.{}", input, URL_SAFE_NO_PAD.encode(signature))
+            }
+            #[cfg(feature = "broker")]
+            ClientCredential::TpmCertificate { tpm, key } => {
+                let mut tpm = tpm.lock().await;
+                let der = tpm.identity_key_x509_as_der(key).map_err(|e| {
+                    MsalError::TPMFail(format!("Failed fetching certificate: {:?}", e))
+                })?;
+                let input = client_assertion_signing_input(&der, client_id, audience)?;
+                // The same signing operation as JwsTpmSigner, which is unable
+                // to add the x5t#S256 header.
+                let signature = tpm.identity_key_sign(key, input.as_bytes()).map_err(|e| {
+                    MsalError::TPMFail(format!("Failed signing client assertion: {:?}", e))
+                })?;
+                format!("{}.{}", input, URL_SAFE_NO_PAD.encode(signature))
+            }
+            ClientCredential::Assertion(callback) => callback()?,
+            ClientCredential::AssertionFile { path, cached } => read_assertion_file(path, cached)?,
+        };
+        Ok(vec![
+            ("client_assertion_type", CLIENT_ASSERTION_TYPE.to_string()),
+            ("client_assertion", assertion),
+        ])
+    }
+}
 
-        let params = [("client_id", self.client_id()), ("scope", &scopes_str)];
-        let payload = params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
-            .collect::<Vec<String>>()
-            .join("&");
+/// Read a client assertion from a file, unless it is unchanged since it was
+/// last read.
+fn read_assertion_file(
+    path: &Path,
+    cached: &StdMutex<Option<(SystemTime, u64, String)>>,
+) -> Result<String, MsalError> {
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| MsalError::ConfigError(format!("Failed reading {}: {}", path.display(), e)))?;
+    let modified = metadata
+        .modified()
+        .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+    let mut cached = cached
+        .lock()
+        .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+    if let Some((cached_modified, cached_len, assertion)) = cached.as_ref() {
+        if *cached_modified == modified && *cached_len == metadata.len() {
+            return Ok(assertion.clone());
+        }
+    }
+    debug!("Reading the client assertion from {}", path.display());
+    let assertion = std::fs::read_to_string(path)
+        .map_err(|e| MsalError::ConfigError(format!("Failed reading {}: {}", path.display(), e)))?
+        .trim()
+        .to_string();
+    *cached = Some((modified, metadata.len(), assertion.clone()));
+    Ok(assertion)
+}
 
-        let resp = self
-            .client()
-            .post(format!("{}/oauth2/v2.0/devicecode", self.authority()))
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .header(header::ACCEPT, "application/json")
-            .body(payload)
-            .send()
-            .await
+/// Build the signing input (the encoded header and claims) of a RFC7523
+/// client assertion, identifying the certificate by its thumbprints. Only
+/// RS256 assertions are built, as certificate keys are limited to RSA.
+fn client_assertion_signing_input(
+    der: &[u8],
+    client_id: &str,
+    audience: &str,
+) -> Result<String, MsalError> {
+    let sha1 =
+        hash(MessageDigest::sha1(), der).map_err(|e| MsalError::CryptoFail(format!("{}", e)))?;
+    let sha256 =
+        hash(MessageDigest::sha256(), der).map_err(|e| MsalError::CryptoFail(format!("{}", e)))?;
+    let header = json!({
+        "alg": "RS256",
+        "typ": "JWT",
+        "x5t": URL_SAFE_NO_PAD.encode(sha1),
+        "x5t#S256": URL_SAFE_NO_PAD.encode(sha256),
+    });
+    let iat = now()?;
+    let claims = json!({
+        "aud": audience,
+        "iss": client_id,
+        "sub": client_id,
+        "jti": Uuid::new_v4().to_string(),
+        "iat": iat,
+        "nbf": iat,
+        "exp": iat + CLIENT_ASSERTION_LIFETIME,
+    });
+    Ok(format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    ))
+}
+
+struct ClientApplication {
+    client: Client,
+    client_id: String,
+    authority: String,
+    cache: Arc<dyn TokenCache>,
+    credential: Option<ClientCredential>,
+    client_capabilities: Vec<String>,
+    openid_configuration: OpenIdConfigurationCache,
+    validate_authority: bool,
+    authority_validated: OnceCell<()>,
+    authority_type: AuthorityType,
+    user_realms: UserRealmCache,
+}
+
+impl ClientApplication {
+    fn new(client_id: &str, authority: Option<&str>) -> Result<Self, MsalError> {
+        let client = reqwest::Client::builder()
+            .cookie_store(true)
+            .build()
             .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_success() {
-            let json_resp: DeviceAuthorizationResponse = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            Ok(json_resp)
-        } else {
-            let json_resp: ErrorResponse = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            Err(MsalError::AcquireTokenFailed(json_resp))
+        let authority = match authority {
+            Some(authority) => normalize_authority(authority),
+            None => CloudInstance::Public.authority("common"),
+        };
+        parse_authority(&authority)?;
+        let authority_type = AuthorityType::from_authority(&authority)?;
+        Ok(ClientApplication {
+            client,
+            client_id: client_id.to_string(),
+            openid_configuration: OpenIdConfigurationCache::new(&authority),
+            authority,
+            cache: Arc::new(InMemoryTokenCache::new()),
+            credential: None,
+            client_capabilities: vec![],
+            validate_authority: true,
+            authority_validated: OnceCell::new(),
+            authority_type,
+            user_realms: UserRealmCache::default(),
+        })
+    }
+
+    /// Validate the authority before any request is sent to it, unless
+    /// validation was disabled. The authority is validated once.
+    async fn ensure_valid_authority(&self) -> Result<(), MsalError> {
+        if !self.validate_authority {
+            return Ok(());
         }
+        self.authority_validated
+            .get_or_try_init(|| validate_authority(&self.client, &self.authority))
+            .await?;
+        Ok(())
     }
 
-    /// Obtain token by a device flow object, with customizable polling effect.
-    ///
-    /// # Arguments
-    ///
-    /// * `flow` - A DeviceAuthorizationResponse previously generated by
-    /// initiate_device_flow.
-    ///
-    /// # Returns
-    ///
-    /// * Success: A UserToken containing an access_token.
-    /// * Failure: An MsalError, indicating the failure.
-    pub async fn acquire_token_by_device_flow(
+    /// The OpenID Connect metadata of the v2.0 endpoints of the authority,
+    /// which is discovered on first use.
+    async fn openid_configuration(&self) -> Result<&OpenIdConfiguration, MsalError> {
+        self.ensure_valid_authority().await?;
+        self.openid_configuration.v2(&self.client).await
+    }
+
+    /// The OpenID Connect metadata of the v1 endpoints of the authority,
+    /// which is discovered on first use.
+    async fn openid_configuration_v1(&self) -> Result<&OpenIdConfiguration, MsalError> {
+        self.ensure_valid_authority().await?;
+        self.openid_configuration.v1(&self.client).await
+    }
+
+    /// The `claims` parameter of a request, which combines the claims
+    /// challenge of the request with the client capabilities.
+    fn claims(&self, claims: Option<&str>) -> Result<Option<String>, MsalError> {
+        merge_claims(claims, &self.client_capabilities)
+    }
+
+    fn save_token(&self, scopes: &[&str], token: &UserToken) -> Result<(), MsalError> {
+        save_user_token(
+            self.cache.as_ref(),
+            &self.client_id,
+            &self.authority,
+            scopes,
+            token,
+            None,
+        )
+    }
+
+    /// POST a request to the token endpoint, and save the token to the token
+    /// cache.
+    async fn request_token(
         &self,
-        flow: DeviceAuthorizationResponse,
+        params: &[(&str, &str)],
+        scopes: &[&str],
     ) -> Result<UserToken, MsalError> {
-        let params = [
-            ("client_id", self.client_id()),
-            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
-            ("device_code", &flow.device_code),
-        ];
+        let token = self.post_token_request(params).await?;
+        self.save_token(scopes, &token)?;
+        Ok(token)
+    }
+
+    /// POST a request to the token endpoint. A confidential client
+    /// authenticates the request with its credential.
+    async fn post_token_request(&self, params: &[(&str, &str)]) -> Result<UserToken, MsalError> {
+        let token_endpoint = &self.openid_configuration().await?.token_endpoint;
+        let credential_params = match &self.credential {
+            Some(credential) => credential.params(&self.client_id, token_endpoint).await?,
+            None => vec![],
+        };
         let payload = params
             .iter()
+            .copied()
+            .chain(credential_params.iter().map(|(k, v)| (*k, v.as_str())))
             .map(|(k, v)| format!("{}={}", k, url_encode(v)))
             .collect::<Vec<String>>()
             .join("&");
 
         let resp = self
-            .client()
-            .post(format!("{}/oauth2/v2.0/token", self.authority()))
+            .client
+            .post(token_endpoint)
             .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
             .header(header::ACCEPT, "application/json")
             .body(payload)
@@ -1123,10 +2150,11 @@ impl PublicClientApplication {
             .await
             .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
         if resp.status().is_success() {
-            let token: UserToken = resp
+            let mut token: UserToken = resp
                 .json()
                 .await
                 .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            token.stamp_expiry()?;
 
             Ok(token)
         } else {
@@ -1138,721 +2166,3283 @@ impl PublicClientApplication {
         }
     }
 
-    fn parse_auth_config(&self, text: &str) -> Result<AuthConfig, MsalError> {
-        let document = Html::parse_document(text);
-        for script in
-            document.select(&Selector::parse("script").map_err(|e| {
-                MsalError::GeneralFailure(format!("Failed parsing auth config: {}", e))
-            })?)
-        {
-            let text = script.inner_html();
-            if let Some(config_index) = text.find(r"$Config=") {
-                let sconfig = &text[config_index + 8..];
-                if let Some(end_index) = sconfig.rfind(r"//]]&gt;") {
-                    let config = &sconfig[..end_index - 2];
-                    let auth_config: AuthConfig = json_from_str(config).map_err(|e| {
-                        MsalError::InvalidJson(format!("Failed parsing auth config: {}", e))
-                    })?;
-                    return Ok(auth_config);
+    fn get_accounts(&self) -> Result<Vec<Account>, MsalError> {
+        get_accounts(self.cache.as_ref(), &self.authority)
+    }
+
+    fn get_account(&self, username: &str) -> Result<Option<Account>, MsalError> {
+        Ok(self
+            .get_accounts()?
+            .into_iter()
+            .find(|account| account.username.to_lowercase() == username.to_lowercase()))
+    }
+
+    fn remove_account(&self, account: &Account) -> Result<(), MsalError> {
+        remove_account(
+            self.cache.as_ref(),
+            &self.client_id,
+            &self.authority,
+            account,
+        )
+    }
+
+    async fn acquire_token_silent(
+        &self,
+        scopes: Vec<&str>,
+        account: &Account,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        // A cached access token cannot satisfy a claims challenge.
+        let cached_token = match claims {
+            Some(_) => None,
+            None => find_user_token(
+                self.cache.as_ref(),
+                &self.client_id,
+                &self.authority,
+                &scopes,
+                account,
+            )?,
+        };
+        if let Some(token) = &cached_token {
+            if !token.needs_refresh() {
+                return Ok(token.clone());
+            }
+        }
+        let refresh_token = find_refresh_token(
+            self.cache.as_ref(),
+            &self.client_id,
+            &self.authority,
+            account,
+        )?;
+        match (refresh_token, cached_token) {
+            (Some(refresh_token), cached_token) => {
+                debug!("Refreshing the access token from the token cache");
+                let res = self
+                    .acquire_token_by_refresh_token(&refresh_token.secret, scopes, claims)
+                    .await;
+                match (res, cached_token) {
+                    // A proactive refresh failed, but the cached access token
+                    // is still valid.
+                    (Err(e), Some(cached_token)) => {
+                        debug!("Proactive refresh failed: {:?}", e);
+                        Ok(cached_token)
+                    }
+                    (res, _) => res,
                 }
             }
+            (None, Some(cached_token)) => Ok(cached_token),
+            (None, None) => Err(MsalError::CacheMiss),
         }
-        Err(MsalError::GeneralFailure(
-            "Auth config was not found".to_string(),
-        ))
     }
 
-    async fn handle_auth_config_req_internal(
+    async fn acquire_token_by_username_password(
         &self,
-        req_params: &[(&str, &str)],
-        auth_config: &AuthConfig,
-    ) -> Result<AuthConfig, MsalError> {
-        let payload = req_params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
-            .collect::<Vec<String>>()
-            .join("&");
-
-        let url_post = match &auth_config.url_post {
-            Some(url_post) => url_post.clone(),
-            None => {
-                return Err(MsalError::GeneralFailure(
-                    "urlPost missing from auth config".to_string(),
-                ))
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        let mut all_scopes = vec!["openid", "profile", "offline_access"];
+        all_scopes.extend(scopes);
+        let scopes_str = all_scopes.join(" ");
+        let claims = self.claims(claims)?;
+
+        // A federated user authenticates with their own identity provider,
+        // and the SAML assertion it issues is exchanged for tokens. B2C and
+        // External ID tenants have no home realm discovery.
+        self.ensure_valid_authority().await?;
+        let assertion = match self.authority_type {
+            AuthorityType::Aad => {
+                federated_assertion(
+                    &self.client,
+                    &self.authority,
+                    username,
+                    password,
+                    None,
+                    &self.user_realms,
+                )
+                .await?
             }
+            AuthorityType::B2C | AuthorityType::Ciam => None,
         };
-        let url = match url_post.starts_with('/') {
-            true => {
-                let authority = self.authority().to_string();
-                let index = authority.rfind('/').ok_or(MsalError::GeneralFailure(
-                    "Failed to splice auth config url".to_string(),
-                ))?;
-                format!("{}/{}", &authority[..index], &url_post)
+        if let Some(assertion) = assertion {
+            let encoded = assertion.encoded();
+            let mut params = vec![
+                ("client_id", self.client_id.as_str()),
+                ("scope", &scopes_str),
+                ("grant_type", assertion.grant_type()),
+                ("assertion", &encoded),
+                ("client_info", "1"),
+            ];
+            if let Some(claims) = &claims {
+                params.push(("claims", claims));
             }
-            false => url_post.clone(),
-        };
+            return self.request_token(&params, &all_scopes).await;
+        }
 
-        let resp = self
-            .client()
-            .post(url)
-            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .body(payload)
-            .send()
-            .await
-            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_success() {
-            let text = resp
-                .text()
-                .await
-                .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
-            self.parse_auth_config(&text)
-        } else {
-            Err(MsalError::GeneralFailure(
-                resp.text()
-                    .await
-                    .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?,
-            ))
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("scope", &scopes_str),
+            ("username", username),
+            ("password", password),
+            ("grant_type", "password"),
+            ("client_info", "1"),
+        ];
+        if let Some(claims) = &claims {
+            params.push(("claims", claims));
         }
+        self.request_token(&params, &all_scopes).await
     }
 
-    /// Check if a user exists in Azure Entra ID
-    ///
-    /// # Arguments
-    ///
-    /// * `username` - Typically a UPN in the form of an email address.
-    ///
-    /// # Returns
-    /// * Success: true|false
-    /// * Failure: An MsalError, indicating the failure.
-    pub async fn check_user_exists(&self, username: &str) -> Result<bool, MsalError> {
-        let request_id = Uuid::new_v4().to_string();
-        let auth_config = self
-            .request_auth_config_internal(vec![], &request_id, None)
-            .await?;
-        let cred_type = self
-            .get_cred_type(username, &auth_config, &request_id)
-            .await?;
-        Ok(cred_type.if_exists_result == 0)
+    async fn acquire_token_by_refresh_token(
+        &self,
+        refresh_token: &str,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        let mut all_scopes = vec!["openid", "profile", "offline_access"];
+        all_scopes.extend(scopes);
+        let scopes_str = all_scopes.join(" ");
+        let claims = self.claims(claims)?;
+
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("scope", &scopes_str),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_info", "1"),
+        ];
+        if let Some(claims) = &claims {
+            params.push(("claims", claims));
+        }
+        self.request_token(&params, &all_scopes).await
     }
 
-    /// Initiate an MFA flow via user credentials.
-    ///
-    /// # Arguments
-    ///
-    /// * `username` - Typically a UPN in the form of an email address.
-    ///
-    /// * `password` - The password.
-    ///
-    /// * `scopes` - Scopes requested to access a protected API (a resource).
-    ///
-    /// * `request_resource` - A resource for obtaining an access token.
-    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
-    ///
-    /// # Returns
-    /// * Success: A MFAAuthContinue containing the information needed to continue the
-    ///   authentication flow.
-    /// * Failure: An MsalError, indicating the failure.
-    pub async fn initiate_acquire_token_by_mfa_flow(
+    async fn acquire_token_for_client(
         &self,
-        username: &str,
-        password: &str,
         scopes: Vec<&str>,
-        resource: Option<&str>,
-    ) -> Result<MFAAuthContinue, MsalError> {
-        let request_id = Uuid::new_v4().to_string();
-        let auth_config = self
-            .request_auth_config_internal(scopes, &request_id, resource)
-            .await?;
-        let cred_type = self
-            .get_cred_type(username, &auth_config, &request_id)
-            .await?;
-        if cred_type.credentials.federation_redirect_url.is_some() {
-            return Err(MsalError::GeneralFailure(
-                "Federated identities are not supported.".to_string(),
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        if scopes.is_empty() {
+            return Err(MsalError::ConfigError(
+                "The client credentials grant requires a .default scope".to_string(),
             ));
         }
-        if cred_type.throttle_status == 1 {
-            return Err(MsalError::GeneralFailure(
-                "Authentication throttled. Wait a minute and try again.".to_string(),
-            ));
+        if is_tenantless(&parse_authority(&self.authority)?) {
+            return Err(MsalError::InvalidAuthority(format!(
+                "{}: The client credentials grant requires a tenant authority",
+                self.authority
+            )));
         }
-        if cred_type.if_exists_result != 0 {
-            return Err(MsalError::GeneralFailure(
-                "An account with that name does not exist.".to_string(),
-            ));
+        if let Some(scope) = scopes.iter().find(|scope| !scope.ends_with(".default")) {
+            return Err(MsalError::ConfigError(format!(
+                "The client credentials grant requires .default scopes, found {}",
+                scope
+            )));
         }
-        if !cred_type.credentials.has_password {
-            return Err(MsalError::GeneralFailure(
-                "Password authentication is not supported.".to_string(),
-            ));
+        // A cached app token cannot satisfy a claims challenge.
+        let cached_token = match claims {
+            Some(_) => None,
+            None => find_app_token(
+                self.cache.as_ref(),
+                &self.client_id,
+                &self.authority,
+                &scopes,
+            )?,
+        };
+        if let Some(token) = &cached_token {
+            if !token.needs_refresh() {
+                return Ok(token.clone());
+            }
         }
 
-        let sctx = match &auth_config.sctx {
-            Some(sctx) => sctx.clone(),
-            None => return Err(MsalError::GeneralFailure("sCtx is missing".to_string())),
-        };
-        let sft = match &auth_config.sft {
-            Some(sft) => sft.clone(),
-            None => return Err(MsalError::GeneralFailure("sFt is missing".to_string())),
-        };
-        let params = vec![
-            ("login", username),
-            ("passwd", password),
-            ("ctx", &sctx),
-            ("flowToken", &sft),
-            ("canary", &auth_config.canary),
-            ("client_id", self.client_id()),
-            ("client-request-id", &request_id),
+        let scopes_str = scopes.join(" ");
+        let claims = self.claims(claims)?;
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("scope", &scopes_str),
+            ("grant_type", "client_credentials"),
         ];
-        match self
-            .handle_auth_config_req_internal(&params, &auth_config)
-            .await
-        {
-            Ok(mut auth_config) => {
-                if let Some(msg) = auth_config.service_exception_msg {
-                    return Err(MsalError::GeneralFailure(msg));
-                }
-                if let Some(ref pgid) = auth_config.pgid {
-                    if pgid == "KmsiInterrupt" {
-                        let sctx = match &auth_config.sctx {
-                            Some(sctx) => sctx.clone(),
-                            None => {
-                                return Err(MsalError::GeneralFailure(
-                                    "sCtx is missing".to_string(),
-                                ))
-                            }
-                        };
-                        let sft = match &auth_config.sft {
-                            Some(sft) => sft.clone(),
-                            None => {
-                                return Err(MsalError::GeneralFailure("sFt is missing".to_string()))
-                            }
-                        };
-                        let params = vec![
-                            ("LoginOptions", "1"),
-                            ("ctx", &sctx),
-                            ("flowToken", &sft),
-                            ("canary", &auth_config.canary),
-                            ("client-request-id", &request_id),
-                        ];
-                        auth_config = self
-                            .handle_auth_config_req_internal(&params, &auth_config)
-                            .await?;
-                    }
-                }
-                if let Some(ref pgid) = auth_config.pgid {
-                    if pgid == "ConvergedProofUpRedirect" {
-                        if let Some(remaining_days) = auth_config.remaining_days_to_skip_mfa_reg {
-                            info!("MFA must be set up in {} days", remaining_days);
-                            let params = vec![
-                                ("LoginOptions", "1"),
-                                ("ctx", &sctx),
-                                ("flowToken", &sft),
-                                ("canary", &auth_config.canary),
-                                ("client-request-id", &request_id),
-                            ];
-                            auth_config = self
-                                .handle_auth_config_req_internal(&params, &auth_config)
-                                .await?;
-                        } else {
-                            return Err(MsalError::GeneralFailure(
-                                "MFA method must be registered.".to_string(),
-                            ));
-                        }
-                    }
-                }
-                if let Some(pgid) = auth_config.pgid {
-                    if pgid == "ConvergedChangePassword" {
-                        return Err(MsalError::GeneralFailure(
-                            "Password is expired!".to_string(),
-                        ));
-                    }
-                }
-                if let Some(arr_user_proofs) = auth_config.arr_user_proofs {
-                    let default_auth_method =
-                        match arr_user_proofs.iter().find(|proof| proof.is_default) {
-                            Some(default_auth_method) => default_auth_method,
-                            None => {
-                                if arr_user_proofs.is_empty() {
-                                    return Err(MsalError::GeneralFailure(
-                                        "No MFA methods found".to_string(),
-                                    ));
-                                } else {
-                                    // Sometimes MS fails to set is_default on
-                                    // any method. In this case, just choose
-                                    // the first one (which may be the only
-                                    // one).
-                                    &arr_user_proofs[0]
-                                }
-                            }
-                        };
-                    let sctx = match &auth_config.sctx {
-                        Some(sctx) => sctx.clone(),
-                        None => {
-                            return Err(MsalError::GeneralFailure("sCtx is missing".to_string()))
-                        }
-                    };
-                    let sft = match &auth_config.sft {
-                        Some(sft) => sft.clone(),
-                        None => {
-                            return Err(MsalError::GeneralFailure("sFt is missing".to_string()))
-                        }
-                    };
-                    let url_end_auth = match &auth_config.url_end_auth {
-                        Some(url_end_auth) => url_end_auth.clone(),
-                        None => {
-                            return Err(MsalError::GeneralFailure(
-                                "urlEndAuth is missing".to_string(),
-                            ))
-                        }
-                    };
-                    let url_begin_auth = match &auth_config.url_begin_auth {
-                        Some(url_begin_auth) => url_begin_auth.clone(),
-                        None => {
-                            return Err(MsalError::GeneralFailure(
-                                "urlBeginAuth is missing".to_string(),
-                            ))
-                        }
-                    };
-                    let url_post = match &auth_config.url_post {
-                        Some(url_post) => url_post.clone(),
-                        None => {
-                            return Err(MsalError::GeneralFailure(
-                                "urlBeginAuth is missing".to_string(),
-                            ))
-                        }
-                    };
-                    let auth_response = match self
-                        .mfa_begin_auth_internal(
-                            &default_auth_method.auth_method_id,
-                            &url_begin_auth,
-                            &sctx,
-                            &sft,
-                            &auth_config.canary,
-                        )
-                        .await
-                    {
-                        Ok(auth_response) => match auth_response.success {
-                            true => auth_response,
-                            false => {
-                                return Err(MsalError::GeneralFailure(
-                                    "Begin Auth failed".to_string(),
-                                ))
-                            }
-                        },
-                        Err(e) => return Err(e),
-                    };
-                    let msg = match default_auth_method.auth_method_id.as_str() {
-                            "PhoneAppNotification" => format!("Open your Authenticator app, and enter the number '{}' to sign in.", auth_response.entropy),
-                            "PhoneAppOTP" =>
-                                "Please type in the code displayed on your authenticator app from your device:".to_string(),
-                            "ConsolidatedTelephony" | "OneWaySMS" =>
-                                format!("We texted your phone {}. Please enter the code to sign in:", default_auth_method.display),
-                            "TwoWayVoiceMobile" =>
-                                format!("We're calling your phone {}. Please answer it to continue.", default_auth_method.display),
-                            "TwoWayVoiceAlternateMobile" =>
-                                format!("We're calling your phone {}. Please answer it to continue.", default_auth_method.display),
-                            method => return Err(MsalError::GeneralFailure(format!("Unsupported MFA method {}", method))),
-                        };
-                    Ok(MFAAuthContinue {
-                        mfa_method: default_auth_method.auth_method_id.clone(),
-                        msg,
-                        max_poll_attempts: auth_config.max_poll_attempts,
-                        polling_interval: auth_config.polling_interval,
-                        session_id: auth_config.session_id,
-                        flow_token: auth_response.flow_token,
-                        ctx: auth_response.ctx,
-                        canary: auth_config.canary,
-                        url_end_auth,
-                        url_post,
-                    })
-                } else {
-                    Err(MsalError::GeneralFailure(
-                        "No MFA methods found".to_string(),
-                    ))
-                }
+        if let Some(claims) = &claims {
+            params.push(("claims", claims));
+        }
+        match (self.post_token_request(&params).await, cached_token) {
+            (Ok(token), _) => {
+                save_app_token(
+                    self.cache.as_ref(),
+                    &self.client_id,
+                    &self.authority,
+                    &scopes,
+                    &token,
+                )?;
+                Ok(token)
+            }
+            // A proactive refresh failed, but the cached app token is still
+            // valid.
+            (Err(e), Some(cached_token)) => {
+                debug!("Proactive refresh failed: {:?}", e);
+                Ok(cached_token)
             }
-            Err(e) => Err(e),
+            (Err(e), None) => Err(e),
         }
     }
 
-    async fn get_cred_type(
+    async fn acquire_token_on_behalf_of(
         &self,
-        username: &str,
-        auth_config: &AuthConfig,
-        request_id: &str,
-    ) -> Result<CredType, MsalError> {
-        let payload = json!({
-            "username": username,
-            "isOtherIdpSupported": true,
-            "checkPhones": true,
-            "isRemoteNGCSupported": false,
-            "isCookieBannerShown": false,
-            "isFidoSupported": false,
-            "originalRequest": &auth_config.sctx,
-            "flowToken": &auth_config.sft,
-        });
+        user_assertion: &str,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        if self.credential.is_none() {
+            return Err(MsalError::ConfigError(
+                "The on-behalf-of flow requires a client credential".to_string(),
+            ));
+        }
+        let mut all_scopes = vec!["openid", "profile", "offline_access"];
+        all_scopes.extend(scopes);
+        let scopes_str = all_scopes.join(" ");
+        let user_assertion_hash = URL_SAFE_NO_PAD.encode(
+            hash(MessageDigest::sha256(), user_assertion.as_bytes())
+                .map_err(|e| MsalError::CryptoFail(format!("{}", e)))?,
+        );
 
-        let resp = self
-            .client()
-            .post(format!("{}/GetCredentialType", self.authority()))
-            .header(header::CONTENT_TYPE, "application/json; charset=UTF-8")
-            .header("client-request-id", request_id)
-            .json(&payload)
-            .send()
-            .await
-            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_success() {
-            let json_resp: CredType = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            Ok(json_resp)
-        } else {
-            let json_resp: ErrorResponse = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            Err(MsalError::AcquireTokenFailed(json_resp))
+        // A cached access token cannot satisfy a claims challenge.
+        if claims.is_none() {
+            if let Some(token) = find_obo_token(
+                self.cache.as_ref(),
+                &self.client_id,
+                &self.authority,
+                &all_scopes,
+                &user_assertion_hash,
+            )? {
+                if !token.needs_refresh() {
+                    return Ok(token);
+                }
+            }
+        }
+        let claims = self.claims(claims)?;
+
+        let refresh_token = find_obo_refresh_token(
+            self.cache.as_ref(),
+            &self.client_id,
+            &self.authority,
+            &user_assertion_hash,
+        )?;
+        let mut token = None;
+        if let Some(refresh_token) = refresh_token {
+            debug!("Refreshing the on-behalf-of token from the token cache");
+            let mut params = vec![
+                ("client_id", self.client_id.as_str()),
+                ("scope", &scopes_str),
+                ("grant_type", "refresh_token"),
+                ("refresh_token", &refresh_token.secret),
+                ("client_info", "1"),
+            ];
+            if let Some(claims) = &claims {
+                params.push(("claims", claims));
+            }
+            match self.post_token_request(&params).await {
+                Ok(res) => token = Some(res),
+                Err(e) => debug!("Refreshing the on-behalf-of token failed: {:?}", e),
+            }
         }
+        let token = match token {
+            Some(token) => token,
+            None => {
+                let mut params = vec![
+                    ("client_id", self.client_id.as_str()),
+                    ("scope", &scopes_str),
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", user_assertion),
+                    ("requested_token_use", "on_behalf_of"),
+                    ("client_info", "1"),
+                ];
+                if let Some(claims) = &claims {
+                    params.push(("claims", claims));
+                }
+                self.post_token_request(&params).await?
+            }
+        };
+        save_user_token(
+            self.cache.as_ref(),
+            &self.client_id,
+            &self.authority,
+            &all_scopes,
+            &token,
+            Some(&user_assertion_hash),
+        )?;
+        Ok(token)
     }
 
-    async fn request_auth_config_internal(
+    async fn acquire_token_by_authorization_code(
         &self,
+        code: &str,
         scopes: Vec<&str>,
-        request_id: &str,
-        resource: Option<&str>,
-    ) -> Result<AuthConfig, MsalError> {
-        let scope = format!("openid profile {}", scopes.join(" "));
-        let params = vec![
-            ("client_id", self.client_id()),
+        redirect_uri: &str,
+        code_verifier: Option<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        let mut all_scopes = vec!["openid", "profile", "offline_access"];
+        all_scopes.extend(scopes);
+        let scopes_str = all_scopes.join(" ");
+        let claims = self.claims(claims)?;
+
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("scope", &scopes_str),
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_info", "1"),
+        ];
+        if let Some(code_verifier) = code_verifier {
+            params.push(("code_verifier", code_verifier));
+        }
+        if let Some(claims) = &claims {
+            params.push(("claims", claims));
+        }
+        self.request_token(&params, &all_scopes).await
+    }
+
+    async fn acquire_token_interactive<F>(
+        &self,
+        scopes: Vec<&str>,
+        login_hint: Option<&str>,
+        domain_hint: Option<&str>,
+        prompt: Option<Prompt>,
+        extra_query_parameters: Vec<(&str, &str)>,
+        claims: Option<&str>,
+        open_browser: F,
+    ) -> Result<UserToken, MsalError>
+    where
+        F: FnOnce(&str) -> Result<(), MsalError>,
+    {
+        let mut all_scopes = vec!["openid", "profile", "offline_access"];
+        all_scopes.extend(scopes);
+        let scopes_str = all_scopes.join(" ");
+        let claims = self.claims(claims)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        let port = listener
+            .local_addr()
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?
+            .port();
+        // The listener is bound to the IPv4 loopback address, which
+        // localhost may not resolve to.
+        let redirect_uri = format!("http://127.0.0.1:{}", port);
+        let (code_verifier, code_challenge) = pkce_challenge()?;
+        let state = Uuid::new_v4().to_string();
+
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
             ("response_type", "code"),
-            ("redirect_uri", "urn:ietf:wg:oauth:2.0:oob"),
-            ("client-request-id", request_id),
-            ("prompt", "login"),
-            ("scope", &scope),
             ("response_mode", "query"),
-            ("sso_reload", "True"),
-            ("amr_values", "ngcmfa"),
-            (
-                "resource",
-                (resource.unwrap_or("00000002-0000-0000-c000-000000000000")),
-            ),
+            ("redirect_uri", &redirect_uri),
+            ("scope", &scopes_str),
+            ("state", &state),
+            ("code_challenge", &code_challenge),
+            ("code_challenge_method", "S256"),
         ];
+        if let Some(login_hint) = login_hint {
+            params.push(("login_hint", login_hint));
+        }
+        if let Some(domain_hint) = domain_hint {
+            params.push(("domain_hint", domain_hint));
+        }
+        if let Some(prompt) = prompt {
+            params.push(("prompt", prompt.as_str()));
+        }
+        if let Some(claims) = &claims {
+            params.push(("claims", claims));
+        }
+        params.extend(extra_query_parameters);
         let url = Url::parse_with_params(
-            &format!("{}/oauth2/authorize", self.authority()),
-            &params.to_vec(),
+            &self.openid_configuration().await?.authorization_endpoint,
+            &params,
         )
         .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
 
-        let resp = self
-            .client()
-            .get(url)
-            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
-            .send()
+        debug!("Opening the browser for interactive authentication");
+        open_browser(url.as_str())?;
+
+        let code = timeout(INTERACTIVE_TIMEOUT, wait_for_auth_code(listener, &state))
             .await
-            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_success() {
-            self.parse_auth_config(&resp.text().await.map_err(|e| {
-                MsalError::GeneralFailure(format!("Failed parsing auth config: {}", e))
-            })?)
-        } else {
-            Err(MsalError::GeneralFailure(
-                "Failed requesting auth config".to_string(),
-            ))
+            .map_err(|_| {
+                MsalError::GeneralFailure(
+                    "Timed out waiting for the interactive authentication".to_string(),
+                )
+            })??;
+
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("scope", &scopes_str),
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_uri),
+            ("code_verifier", &code_verifier),
+            ("client_info", "1"),
+        ];
+        if let Some(claims) = &claims {
+            params.push(("claims", claims));
         }
+        self.request_token(&params, &all_scopes).await
     }
+}
 
-    async fn mfa_begin_auth_internal(
-        &self,
-        mfa_method: &str,
-        url_begin_auth: &str,
-        ctx: &str,
-        flow_token: &str,
-        canary: &str,
-    ) -> Result<AuthResponse, MsalError> {
-        let payload = json!({
-            "AuthMethodId": mfa_method,
-            "ctx": ctx,
-            "flowToken": flow_token,
-            "Method": "BeginAuth",
-        });
+pub struct PublicClientApplication {
+    app: ClientApplication,
+}
 
-        let resp = self
-            .client()
-            .post(url_begin_auth)
-            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
-            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
-            .header("canary", canary)
-            .json(&payload)
-            .send()
-            .await
-            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_success() {
-            let text = resp
-                .text()
-                .await
-                .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
-            let auth_response: AuthResponse =
-                json_from_str(&text).map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            if auth_response.success {
-                Ok(auth_response)
-            } else if let Some(msg) = auth_response.message {
-                Err(MsalError::GeneralFailure(msg))
-            } else {
-                Err(MsalError::GeneralFailure("BeginAuth failed".to_string()))
-            }
-        } else {
-            Err(MsalError::GeneralFailure(
-                "BeginAuth Authentication request failed".to_string(),
-            ))
-        }
+impl PublicClientApplication {
+    /// Create an instance of an application.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - Your app has a client_id after you register it on
+    ///   AAD.
+    ///
+    /// * `authority` - A URL that identifies a token authority. It should
+    ///   be of the format <https://login.microsoftonline.com/your_tenant> By
+    ///   default, we will use <https://login.microsoftonline.com/common>.
+    pub fn new(client_id: &str, authority: Option<&str>) -> Result<Self, MsalError> {
+        Ok(PublicClientApplication {
+            app: ClientApplication::new(client_id, authority)?,
+        })
     }
 
-    async fn request_authorization_internal(
-        &self,
-        username: &str,
-        flow: &MFAAuthContinue,
-    ) -> Result<String, MsalError> {
-        let mfa_method = match flow.mfa_method.as_str() {
-            // ConsolidatedTelephony simply means OneWaySMS internally to Azure,
-            // it seems. If we don't swap them during the ProcessAuth though,
-            // this request is rejected. I observed this odd behavior in a
-            // browser auth to Azure also.
-            "ConsolidatedTelephony" => "OneWaySMS".to_string(),
-            other => other.to_string(),
-        };
-        let params = [
-            ("request", &flow.ctx),
-            ("mfaAuthMethod", &mfa_method),
-            ("login", &username.to_string()),
-            ("flowToken", &flow.flow_token),
-            ("canary", &flow.canary),
-        ];
-        let payload = params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
-            .collect::<Vec<String>>()
-            .join("&");
+    fn client(&self) -> &Client {
+        &self.app.client
+    }
 
-        let resp = self
-            .client()
-            .post(&flow.url_post)
-            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .body(payload)
-            .send()
-            .await
-            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_redirection() {
-            let redirect = resp.headers()["location"]
-                .to_str()
-                .map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
-            let url =
-                Url::parse(redirect).map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
-            let (_, code) =
-                url.query_pairs()
-                    .find(|(k, _)| k == "code")
-                    .ok_or(MsalError::InvalidParse(
-                        "Authorization code missing from redirect".to_string(),
-                    ))?;
-            Ok(code.to_string())
-        } else {
-            Err(MsalError::GeneralFailure(
-                "ProcessAuth Authorization request failed".to_string(),
-            ))
-        }
+    fn client_id(&self) -> &str {
+        &self.app.client_id
+    }
+
+    fn authority(&self) -> &str {
+        &self.app.authority
+    }
+
+    /// Replace the token cache used by this application.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache` - A TokenCache. By default, an InMemoryTokenCache is used,
+    ///   which is lost when the application exits.
+    pub fn set_token_cache(&mut self, cache: Arc<dyn TokenCache>) {
+        self.app.cache = cache;
+    }
+
+    /// Declare the capabilities of this application, which are sent as
+    /// claims with every token request. Declaring the `cp1` capability opts
+    /// into continuous access evaluation (CAE), and the application must then
+    /// handle claims challenges from resources.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_capabilities` - The capabilities, such as `cp1`.
+    pub fn set_client_capabilities(&mut self, client_capabilities: Vec<&str>) {
+        self.app.client_capabilities = client_capabilities
+            .into_iter()
+            .map(|capability| capability.to_string())
+            .collect();
+    }
+
+    /// Enable or disable validation of the authority. By default, an
+    /// authority must use https, and must be hosted by a known cloud or be
+    /// recognized by instance discovery. Only disable validation for a custom
+    /// authority which you trust, since credentials are sent to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `validate_authority` - Whether to validate the authority.
+    pub fn set_validate_authority(&mut self, validate_authority: bool) {
+        self.app.validate_authority = validate_authority;
+    }
+
+    /// Discover the OpenID Connect metadata of the authority, such as its
+    /// end session and JWKS endpoints. The metadata is cached by the
+    /// application, and used to locate the endpoints of every request.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The OpenID Connect metadata of the v2.0 endpoints of the
+    ///   authority.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn openid_configuration(&self) -> Result<OpenIdConfiguration, MsalError> {
+        self.app.openid_configuration().await.cloned()
+    }
+
+    /// Create a validator for the ID tokens and access tokens issued by the
+    /// authority of this application. The validator caches the signing keys
+    /// of the authority, so it should be kept for validating later tokens.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A TokenValidator for the authority.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn token_validator(&self) -> Result<TokenValidator, MsalError> {
+        TokenValidator::new(&self.app.authority)
+    }
+
+    /// List the accounts which have signed in to this application, and are
+    /// present in the token cache.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A list of Accounts, which may be empty.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn get_accounts(&self) -> Result<Vec<Account>, MsalError> {
+        self.app.get_accounts()
+    }
+
+    /// Find an account in the token cache by username.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The Account, or None if the account has not signed in.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn get_account(&self, username: &str) -> Result<Option<Account>, MsalError> {
+        self.app.get_account(username)
+    }
+
+    /// Sign an account out of this application, by removing the account and
+    /// the tokens cached for it from the token cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The Account to remove.
+    ///
+    /// # Returns
+    ///
+    /// * Success: ()
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn remove_account(&self, account: &Account) -> Result<(), MsalError> {
+        self.app.remove_account(account)
+    }
+
+    /// Acquire an access token for the account from the token cache. If the
+    /// cached access token has expired, or no cached access token covers the
+    /// requested scopes, the cached refresh token is used to acquire a new
+    /// access token.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `account` - An Account which previously signed in to this
+    ///   application.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure. If no usable token
+    ///   was found in the cache, MsalError::CacheMiss is returned, and an
+    ///   interactive authentication is required.
+    pub async fn acquire_token_silent(
+        &self,
+        scopes: Vec<&str>,
+        account: &Account,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_silent_with_claims(scopes, account, None)
+            .await
+    }
+
+    /// Acquire an access token for the account from the token cache. If the
+    /// cached access token has expired, or no cached access token covers the
+    /// requested scopes, the cached refresh token is used to acquire a new
+    /// access token.
+    ///
+    /// Unlike acquire_token_silent, this requests the given claims, such as a
+    /// claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `account` - An Account which previously signed in to this
+    ///   application.
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge. A cached access token
+    ///   cannot satisfy a claims challenge, so a new token is requested.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure. If no usable token
+    ///   was found in the cache, MsalError::CacheMiss is returned, and an
+    ///   interactive authentication is required.
+    pub async fn acquire_token_silent_with_claims(
+        &self,
+        scopes: Vec<&str>,
+        account: &Account,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.app.acquire_token_silent(scopes, account, claims).await
+    }
+
+    /// Gets a token for a given resource via user credentials.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// # Returns
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_username_password(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_by_username_password_with_claims(username, password, scopes, None)
+            .await
+    }
+
+    /// Gets a token for a given resource via user credentials.
+    ///
+    /// Unlike acquire_token_by_username_password, this requests the given
+    /// claims, such as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// # Returns
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_username_password_with_claims(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.app
+            .acquire_token_by_username_password(username, password, scopes, claims)
+            .await
+    }
+
+    /// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
+    ///
+    /// # Arguments
+    ///
+    /// * `refresh_token` - The old refresh token, as a string.
+    ///
+    /// * `scopes` - The scopes associated with this old RT.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken, which means migration was successful.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_refresh_token(
+        &self,
+        refresh_token: &str,
+        scopes: Vec<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_by_refresh_token_with_claims(refresh_token, scopes, None)
+            .await
+    }
+
+    /// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
+    ///
+    /// Unlike acquire_token_by_refresh_token, this requests the given claims,
+    /// such as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `refresh_token` - The old refresh token, as a string.
+    ///
+    /// * `scopes` - The scopes associated with this old RT.
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken, which means migration was successful.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_refresh_token_with_claims(
+        &self,
+        refresh_token: &str,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.app
+            .acquire_token_by_refresh_token(refresh_token, scopes, claims)
+            .await
+    }
+
+    /// Acquire a token interactively, using the authorization code flow with
+    /// PKCE. The user authenticates in a browser, which is redirected to a
+    /// listener on `http://127.0.0.1:<port>` when authentication completes.
+    /// The application must be registered with the `http://127.0.0.1`
+    /// redirect uri.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `login_hint` - Optionally, the username to pre-fill on the sign in
+    ///   page.
+    ///
+    /// * `domain_hint` - Optionally, the domain of the user, which is used
+    ///   to skip home realm discovery (for example `contoso.com`).
+    ///
+    /// * `prompt` - Optionally, the prompt behavior of the sign in page.
+    ///
+    /// * `extra_query_parameters` - Additional parameters to append to the
+    ///   authorization request.
+    ///
+    /// * `open_browser` - Called with the authorization url, which must be
+    ///   opened in a browser.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_interactive<F>(
+        &self,
+        scopes: Vec<&str>,
+        login_hint: Option<&str>,
+        domain_hint: Option<&str>,
+        prompt: Option<Prompt>,
+        extra_query_parameters: Vec<(&str, &str)>,
+        open_browser: F,
+    ) -> Result<UserToken, MsalError>
+    where
+        F: FnOnce(&str) -> Result<(), MsalError>,
+    {
+        self.acquire_token_interactive_with_claims(
+            scopes,
+            login_hint,
+            domain_hint,
+            prompt,
+            extra_query_parameters,
+            None,
+            open_browser,
+        )
+        .await
+    }
+
+    /// Acquire a token interactively, using the authorization code flow with
+    /// PKCE. The user authenticates in a browser, which is redirected to a
+    /// listener on `http://127.0.0.1:<port>` when authentication completes.
+    /// The application must be registered with the `http://127.0.0.1`
+    /// redirect uri.
+    ///
+    /// Unlike acquire_token_interactive, this requests the given claims, such
+    /// as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `login_hint` - Optionally, the username to pre-fill on the sign in
+    ///   page.
+    ///
+    /// * `domain_hint` - Optionally, the domain of the user, which is used
+    ///   to skip home realm discovery (for example `contoso.com`).
+    ///
+    /// * `prompt` - Optionally, the prompt behavior of the sign in page.
+    ///
+    /// * `extra_query_parameters` - Additional parameters to append to the
+    ///   authorization request.
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// * `open_browser` - Called with the authorization url, which must be
+    ///   opened in a browser.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_interactive_with_claims<F>(
+        &self,
+        scopes: Vec<&str>,
+        login_hint: Option<&str>,
+        domain_hint: Option<&str>,
+        prompt: Option<Prompt>,
+        extra_query_parameters: Vec<(&str, &str)>,
+        claims: Option<&str>,
+        open_browser: F,
+    ) -> Result<UserToken, MsalError>
+    where
+        F: FnOnce(&str) -> Result<(), MsalError>,
+    {
+        self.app
+            .acquire_token_interactive(
+                scopes,
+                login_hint,
+                domain_hint,
+                prompt,
+                extra_query_parameters,
+                claims,
+                open_browser,
+            )
+            .await
+    }
+
+    /// Initiate a Device Flow instance, which will be used in
+    /// acquire_token_by_device_flow.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// # Returns
+    ///
+    /// * Success: A DeviceAuthorizationResponse containing a user_code key,
+    ///   among others
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_device_flow(
+        &self,
+        scopes: Vec<&str>,
+    ) -> Result<DeviceAuthorizationResponse, MsalError> {
+        self.initiate_device_flow_with_claims(scopes, None).await
+    }
+
+    /// Initiate a Device Flow instance, which will be used in
+    /// acquire_token_by_device_flow.
+    ///
+    /// Unlike initiate_device_flow, this requests the given claims, such as a
+    /// claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A DeviceAuthorizationResponse containing a user_code key,
+    ///   among others
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_device_flow_with_claims(
+        &self,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<DeviceAuthorizationResponse, MsalError> {
+        let mut all_scopes = vec!["openid", "profile", "offline_access"];
+        all_scopes.extend(scopes);
+        let scopes_str = all_scopes.join(" ");
+        let claims = self.app.claims(claims)?;
+
+        let mut params = vec![("client_id", self.client_id()), ("scope", &scopes_str)];
+        if let Some(claims) = &claims {
+            params.push(("claims", claims));
+        }
+        let payload = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let device_authorization_endpoint = self
+            .app
+            .openid_configuration()
+            .await?
+            .device_authorization_endpoint
+            .as_ref()
+            .ok_or(MsalError::GeneralFailure(
+                "The authority does not support the device code flow".to_string(),
+            ))?;
+        let requested_on = now()?;
+        let resp = self
+            .client()
+            .post(device_authorization_endpoint)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
+            .body(payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            let mut json_resp: DeviceAuthorizationResponse = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            json_resp.claims = claims;
+            json_resp.expires_on = requested_on + u64::from(json_resp.expires_in);
+            Ok(json_resp)
+        } else {
+            let json_resp: ErrorResponse = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            Err(MsalError::AcquireTokenFailed(json_resp))
+        }
+    }
+
+    /// Obtain token by a device flow object, with customizable polling effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A DeviceAuthorizationResponse previously generated by
+    ///   initiate_device_flow.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_device_flow(
+        &self,
+        flow: DeviceAuthorizationResponse,
+    ) -> Result<UserToken, MsalError> {
+        self.device_flow_token_request(&flow).await
+    }
+
+    /// Obtain token by a device flow object, polling until the user completes
+    /// authentication. Polling honours the interval and expiry of the flow,
+    /// and slows down when requested by the authorization server.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A DeviceAuthorizationResponse previously generated by
+    ///   initiate_device_flow.
+    ///
+    /// * `cancellation` - A CancellationToken which stops polling when
+    ///   cancelled.
+    ///
+    /// * `progress` - An optional callback, called each time a poll finds
+    ///   that authentication is still pending.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A DeviceFlowOutcome, containing a UserToken if the user
+    ///   authenticated.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn poll_device_flow(
+        &self,
+        flow: &DeviceAuthorizationResponse,
+        cancellation: &CancellationToken,
+        progress: Option<&DeviceFlowProgressCallback>,
+    ) -> Result<DeviceFlowOutcome, MsalError> {
+        let mut interval = flow
+            .interval
+            .map(u64::from)
+            .unwrap_or(DEVICE_FLOW_DEFAULT_INTERVAL);
+        // A flow which was not initiated by initiate_device_flow expires
+        // relative to when polling starts
+        let expires_on = match flow.expires_on {
+            0 => now()? + u64::from(flow.expires_in),
+            expires_on => expires_on,
+        };
+        let mut poll_attempt = 0;
+        loop {
+            if cancellation.is_cancelled() {
+                return Ok(DeviceFlowOutcome::Cancelled);
+            }
+            if now()? >= expires_on {
+                return Ok(DeviceFlowOutcome::Expired);
+            }
+            let update = match self.device_flow_token_request(flow).await {
+                Ok(token) => return Ok(DeviceFlowOutcome::Success(Box::new(token))),
+                Err(MsalError::AcquireTokenFailed(resp)) => match resp.error.as_str() {
+                    "authorization_pending" => DeviceFlowProgress::AuthorizationPending {
+                        poll_attempt,
+                        expires_in: expires_on.saturating_sub(now()?),
+                    },
+                    "slow_down" => {
+                        interval += DEVICE_FLOW_SLOW_DOWN_INCREMENT;
+                        DeviceFlowProgress::SlowDown { interval }
+                    }
+                    "expired_token" | "code_expired" => return Ok(DeviceFlowOutcome::Expired),
+                    "authorization_declined" | "access_denied" => {
+                        return Ok(DeviceFlowOutcome::Declined)
+                    }
+                    _ => return Err(MsalError::AcquireTokenFailed(resp)),
+                },
+                Err(e) => return Err(e),
+            };
+            debug!("Device flow poll {}: {:?}", poll_attempt, update);
+            if let Some(progress) = progress {
+                progress(&update);
+            }
+            poll_attempt += 1;
+
+            tokio::select! {
+                _ = sleep(Duration::from_secs(interval)) => {}
+                _ = cancellation.cancelled() => return Ok(DeviceFlowOutcome::Cancelled),
+            }
+        }
+    }
+
+    async fn device_flow_token_request(
+        &self,
+        flow: &DeviceAuthorizationResponse,
+    ) -> Result<UserToken, MsalError> {
+        let mut params = vec![
+            ("client_id", self.client_id()),
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", &flow.device_code),
+            ("client_info", "1"),
+        ];
+        if let Some(claims) = &flow.claims {
+            params.push(("claims", claims));
+        }
+        let payload = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let resp = self
+            .client()
+            .post(&self.app.openid_configuration().await?.token_endpoint)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
+            .body(payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            let mut token: UserToken = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            token.stamp_expiry()?;
+            self.app.save_token(&[], &token)?;
+
+            Ok(token)
+        } else {
+            let json_resp: ErrorResponse = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            Err(MsalError::AcquireTokenFailed(json_resp))
+        }
+    }
+
+    fn parse_auth_config(&self, text: &str) -> Result<AuthConfig, MsalError> {
+        let document = Html::parse_document(text);
+        for script in
+            document.select(&Selector::parse("script").map_err(|e| {
+                MsalError::GeneralFailure(format!("Failed parsing auth config: {}", e))
+            })?)
+        {
+            let text = script.inner_html();
+            if let Some(config_index) = text.find(r"$Config=") {
+                let sconfig = &text[config_index + 8..];
+                if let Some(end_index) = sconfig.rfind(r"//]]&gt;") {
+                    let config = &sconfig[..end_index - 2];
+                    let auth_config: AuthConfig = json_from_str(config).map_err(|e| {
+                        MsalError::InvalidJson(format!("Failed parsing auth config: {}", e))
+                    })?;
+                    return Ok(auth_config);
+                }
+            }
+        }
+        Err(MsalError::GeneralFailure(
+            "Auth config was not found".to_string(),
+        ))
+    }
+
+    async fn handle_auth_config_req_internal(
+        &self,
+        req_params: &[(&str, &str)],
+        auth_config: &AuthConfig,
+    ) -> Result<AuthConfig, MsalError> {
+        let payload = req_params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let url_post = match &auth_config.url_post {
+            Some(url_post) => url_post.clone(),
+            None => {
+                return Err(MsalError::GeneralFailure(
+                    "urlPost missing from auth config".to_string(),
+                ))
+            }
+        };
+        self.post_auth_config_internal(&payload, &url_post).await
+    }
+
+    async fn post_auth_config_internal(
+        &self,
+        payload: &str,
+        url_post: &str,
+    ) -> Result<AuthConfig, MsalError> {
+        let resp = self.post_login_form_internal(payload, url_post).await?;
+        if resp.status().is_success() {
+            let text = resp
+                .text()
+                .await
+                .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+            self.parse_auth_config(&text)
+        } else {
+            Err(MsalError::GeneralFailure(
+                resp.text()
+                    .await
+                    .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?,
+            ))
+        }
+    }
+
+    async fn post_login_form_internal(
+        &self,
+        payload: &str,
+        url_post: &str,
+    ) -> Result<reqwest::Response, MsalError> {
+        let url = match url_post.starts_with('/') {
+            true => {
+                let authority = self.authority().to_string();
+                let index = authority.rfind('/').ok_or(MsalError::GeneralFailure(
+                    "Failed to splice auth config url".to_string(),
+                ))?;
+                format!("{}/{}", &authority[..index], &url_post)
+            }
+            false => url_post.to_string(),
+        };
+
+        self.client()
+            .post(url)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(payload.to_string())
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))
+    }
+
+    /// Check if a user exists in Azure Entra ID
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// # Returns
+    /// * Success: true|false
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn check_user_exists(&self, username: &str) -> Result<bool, MsalError> {
+        let request_id = Uuid::new_v4().to_string();
+        let auth_config = self
+            .request_auth_config_internal(vec![], &request_id, None, None)
+            .await?;
+        let cred_type = self
+            .get_cred_type(username, &auth_config, &request_id)
+            .await?;
+        Ok(cred_type.if_exists_result == 0)
+    }
+
+    /// Initiate an MFA flow via user credentials, using the user's default
+    /// MFA method.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    ///
+    /// # Returns
+    /// * Success: A MFAAuthContinue containing the information needed to continue the
+    ///   authentication flow. If the password has expired, the flow requires a
+    ///   password change (see change_password) before MFA begins.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_acquire_token_by_mfa_flow(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+        resource: Option<&str>,
+    ) -> Result<MFAAuthContinue, MsalError> {
+        self.initiate_acquire_token_by_mfa_flow_with_claims(
+            username, password, scopes, resource, None,
+        )
+        .await
+    }
+
+    /// Initiate an MFA flow via user credentials, using the user's default
+    /// MFA method.
+    ///
+    /// Unlike initiate_acquire_token_by_mfa_flow, this requests the given
+    /// claims, such as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// # Returns
+    /// * Success: A MFAAuthContinue containing the information needed to continue the
+    ///   authentication flow. If the password has expired, the flow requires a
+    ///   password change (see change_password) before MFA begins.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_acquire_token_by_mfa_flow_with_claims(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+        resource: Option<&str>,
+        claims: Option<&str>,
+    ) -> Result<MFAAuthContinue, MsalError> {
+        let mut flow = self
+            .initiate_acquire_token_by_mfa_method_selection_with_claims(
+                username, password, scopes, resource, claims,
+            )
+            .await?;
+        // A federated flow is already complete, and an expired password must
+        // be changed before MFA.
+        if flow.saml_assertion.is_none() && !flow.requires_password_change() {
+            let auth_method_id = flow
+                .default_mfa_method()
+                .map(|method| method.auth_method_id.clone())
+                .ok_or(MsalError::GeneralFailure(
+                    "No MFA methods found".to_string(),
+                ))?;
+            self.begin_mfa_auth(&mut flow, &auth_method_id).await?;
+        }
+        Ok(flow)
+    }
+
+    /// Initiate an MFA flow via user credentials, without choosing an MFA
+    /// method. The MFA methods registered by the user are listed in
+    /// `mfa_methods` of the returned flow, and the chosen method is started
+    /// with begin_mfa_auth.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    ///
+    /// # Returns
+    /// * Success: A MFAAuthContinue containing the MFA methods of the user. A
+    ///   federated user has no MFA methods, and the flow may be passed directly
+    ///   to acquire_token_by_mfa_flow.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_acquire_token_by_mfa_method_selection(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+        resource: Option<&str>,
+    ) -> Result<MFAAuthContinue, MsalError> {
+        self.initiate_acquire_token_by_mfa_method_selection_with_claims(
+            username, password, scopes, resource, None,
+        )
+        .await
+    }
+
+    /// Initiate an MFA flow via user credentials, without choosing an MFA
+    /// method. The MFA methods registered by the user are listed in
+    /// `mfa_methods` of the returned flow, and the chosen method is started
+    /// with begin_mfa_auth.
+    ///
+    /// Unlike initiate_acquire_token_by_mfa_method_selection, this requests the
+    /// given claims, such as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// # Returns
+    /// * Success: A MFAAuthContinue containing the MFA methods of the user. A
+    ///   federated user has no MFA methods, and the flow may be passed directly
+    ///   to acquire_token_by_mfa_flow.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_acquire_token_by_mfa_method_selection_with_claims(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+        resource: Option<&str>,
+        claims: Option<&str>,
+    ) -> Result<MFAAuthContinue, MsalError> {
+        let request_id = Uuid::new_v4().to_string();
+        let claims = self.app.claims(claims)?;
+        let auth_config = self
+            .request_auth_config_internal(scopes, &request_id, resource, claims.as_deref())
+            .await?;
+        let cred_type = self
+            .get_cred_type(username, &auth_config, &request_id)
+            .await?;
+        if let Some(federation_redirect_url) = &cred_type.credentials.federation_redirect_url {
+            // The identity provider of a federated user enforces its own MFA
+            // policy, so the flow completes with the SAML assertion it
+            // issues.
+            let saml_assertion = federated_assertion(
+                self.client(),
+                self.authority(),
+                username,
+                password,
+                Some(federation_redirect_url),
+                &self.app.user_realms,
+            )
+            .await?
+            .ok_or(MsalError::GeneralFailure(
+                "Federated identity provider does not support WS-Trust.".to_string(),
+            ))?;
+            return Ok(MFAAuthContinue {
+                mfa_method: "FederatedAssertion".to_string(),
+                msg: "Authenticated by your organization's identity provider.".to_string(),
+                max_poll_attempts: Some(1),
+                polling_interval: Some(0),
+                session_id: String::new(),
+                flow_token: String::new(),
+                ctx: String::new(),
+                canary: String::new(),
+                url_end_auth: String::new(),
+                url_post: String::new(),
+                url_begin_auth: String::new(),
+                mfa_methods: vec![],
+                saml_assertion: Some(saml_assertion),
+                resource: resource.map(|resource| resource.to_string()),
+                url_async_sspr_begin: None,
+                url_async_sspr_poll: None,
+                mfa_registration_days_remaining: None,
+                url_proof_up: None,
+                claims,
+                one_time_code: None,
+            });
+        }
+        if cred_type.throttle_status == 1 {
+            return Err(MsalError::GeneralFailure(
+                "Authentication throttled. Wait a minute and try again.".to_string(),
+            ));
+        }
+        if cred_type.if_exists_result != 0 {
+            return Err(MsalError::GeneralFailure(
+                "An account with that name does not exist.".to_string(),
+            ));
+        }
+        if !cred_type.credentials.has_password {
+            return Err(MsalError::GeneralFailure(
+                "Password authentication is not supported.".to_string(),
+            ));
+        }
+
+        let sctx = match &auth_config.sctx {
+            Some(sctx) => sctx.clone(),
+            None => return Err(MsalError::GeneralFailure("sCtx is missing".to_string())),
+        };
+        let sft = match &auth_config.sft {
+            Some(sft) => sft.clone(),
+            None => return Err(MsalError::GeneralFailure("sFt is missing".to_string())),
+        };
+        let params = vec![
+            ("login", username),
+            ("passwd", password),
+            ("ctx", &sctx),
+            ("flowToken", &sft),
+            ("canary", &auth_config.canary),
+            ("client_id", self.client_id()),
+            ("client-request-id", &request_id),
+        ];
+        let auth_config = self
+            .handle_auth_config_req_internal(&params, &auth_config)
+            .await?;
+        let mut flow = self
+            .mfa_flow_from_auth_config_internal(auth_config, &request_id)
+            .await?;
+        flow.claims = claims;
+        Ok(flow)
+    }
+
+    /// Initiate a passwordless flow, in which the user signs in with a
+    /// Temporary Access Pass, or, as a guest user, with a one time code sent
+    /// by email. The methods available to the user are listed in
+    /// `mfa_methods` of the returned flow. The chosen method is started with
+    /// begin_mfa_auth, and the code entered by the user is passed as the
+    /// `auth_data` of acquire_token_by_mfa_flow.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    ///
+    /// # Returns
+    /// * Success: A MFAAuthContinue containing the passwordless methods of
+    ///   the user.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_acquire_token_by_passwordless_flow(
+        &self,
+        username: &str,
+        scopes: Vec<&str>,
+        resource: Option<&str>,
+    ) -> Result<MFAAuthContinue, MsalError> {
+        let request_id = Uuid::new_v4().to_string();
+        let claims = self.app.claims(None)?;
+        let auth_config = self
+            .request_auth_config_internal(scopes, &request_id, resource, claims.as_deref())
+            .await?;
+        let cred_type = self
+            .get_cred_type(username, &auth_config, &request_id)
+            .await?;
+        if cred_type.throttle_status == 1 {
+            return Err(MsalError::GeneralFailure(
+                "Authentication throttled. Wait a minute and try again.".to_string(),
+            ));
+        }
+        if cred_type.if_exists_result != 0 {
+            return Err(MsalError::GeneralFailure(
+                "An account with that name does not exist.".to_string(),
+            ));
+        }
+
+        let mut mfa_methods = vec![];
+        if cred_type.credentials.pref_credential == ACCESS_PASS_CREDENTIAL {
+            mfa_methods.push(MFAMethod {
+                auth_method_id: ACCESS_PASS_METHOD.to_string(),
+                is_default: true,
+                display: username.to_string(),
+            });
+        }
+        let proofs = cred_type.credentials.otc_login_eligible_proofs;
+        let proof = proofs
+            .iter()
+            .find(|proof| proof.is_default)
+            .or(proofs.first())
+            .cloned();
+        if let Some(proof) = &proof {
+            mfa_methods.push(MFAMethod {
+                auth_method_id: EMAIL_OTP_METHOD.to_string(),
+                is_default: mfa_methods.is_empty(),
+                display: proof.display.clone(),
+            });
+        }
+        if mfa_methods.is_empty() {
+            return Err(MsalError::GeneralFailure(
+                "Passwordless sign in is not available for this account.".to_string(),
+            ));
+        }
+
+        let sctx = match &auth_config.sctx {
+            Some(sctx) => sctx.clone(),
+            None => return Err(MsalError::GeneralFailure("sCtx is missing".to_string())),
+        };
+        let sft = match &auth_config.sft {
+            Some(sft) => sft.clone(),
+            None => return Err(MsalError::GeneralFailure("sFt is missing".to_string())),
+        };
+        let url_post = match &auth_config.url_post {
+            Some(url_post) => url_post.clone(),
+            None => return Err(MsalError::GeneralFailure("urlPost is missing".to_string())),
+        };
+        Ok(MFAAuthContinue {
+            mfa_method: String::new(),
+            msg: String::new(),
+            max_poll_attempts: None,
+            polling_interval: None,
+            session_id: auth_config.session_id,
+            flow_token: sft,
+            ctx: sctx,
+            canary: auth_config.canary,
+            url_end_auth: String::new(),
+            url_post,
+            url_begin_auth: String::new(),
+            mfa_methods,
+            saml_assertion: None,
+            resource: None,
+            url_async_sspr_begin: None,
+            url_async_sspr_poll: None,
+            mfa_registration_days_remaining: None,
+            url_proof_up: None,
+            claims,
+            one_time_code: proof.map(|proof| OneTimeCode {
+                login: username.to_string(),
+                proof,
+            }),
+        })
+    }
+
+    async fn mfa_flow_from_auth_config_internal(
+        &self,
+        mut auth_config: AuthConfig,
+        request_id: &str,
+    ) -> Result<MFAAuthContinue, MsalError> {
+        if let Some(msg) = auth_config.service_exception_msg {
+            return Err(MsalError::GeneralFailure(msg));
+        }
+        let mut mfa_registration_days_remaining = None;
+        if let Some(ref pgid) = auth_config.pgid {
+            if pgid == "KmsiInterrupt" {
+                let sctx = match &auth_config.sctx {
+                    Some(sctx) => sctx.clone(),
+                    None => return Err(MsalError::GeneralFailure("sCtx is missing".to_string())),
+                };
+                let sft = match &auth_config.sft {
+                    Some(sft) => sft.clone(),
+                    None => return Err(MsalError::GeneralFailure("sFt is missing".to_string())),
+                };
+                let params = vec![
+                    ("LoginOptions", "1"),
+                    ("ctx", &sctx),
+                    ("flowToken", &sft),
+                    ("canary", &auth_config.canary),
+                    ("client-request-id", request_id),
+                ];
+                auth_config = self
+                    .handle_auth_config_req_internal(&params, &auth_config)
+                    .await?;
+            }
+        }
+        if let Some(ref pgid) = auth_config.pgid {
+            if pgid == "ConvergedProofUpRedirect" {
+                if let Some(remaining_days) = auth_config.remaining_days_to_skip_mfa_reg {
+                    info!("MFA must be set up in {} days", remaining_days);
+                    let sctx = match &auth_config.sctx {
+                        Some(sctx) => sctx.clone(),
+                        None => {
+                            return Err(MsalError::GeneralFailure("sCtx is missing".to_string()))
+                        }
+                    };
+                    let sft = match &auth_config.sft {
+                        Some(sft) => sft.clone(),
+                        None => {
+                            return Err(MsalError::GeneralFailure("sFt is missing".to_string()))
+                        }
+                    };
+                    let params = vec![
+                        ("LoginOptions", "1"),
+                        ("ctx", &sctx),
+                        ("flowToken", &sft),
+                        ("canary", &auth_config.canary),
+                        ("client-request-id", request_id),
+                    ];
+                    auth_config = self
+                        .handle_auth_config_req_internal(&params, &auth_config)
+                        .await?;
+                    mfa_registration_days_remaining = Some(remaining_days);
+                } else {
+                    let (sctx, sft, url_post, url_proof_up) = match (
+                        &auth_config.sctx,
+                        &auth_config.sft,
+                        &auth_config.url_post,
+                        &auth_config.url_proof_up_redirect,
+                    ) {
+                        (Some(sctx), Some(sft), Some(url_post), Some(url_proof_up)) => (
+                            sctx.clone(),
+                            sft.clone(),
+                            url_post.clone(),
+                            url_proof_up.clone(),
+                        ),
+                        _ => {
+                            return Err(MsalError::GeneralFailure(
+                                "MFA method must be registered.".to_string(),
+                            ))
+                        }
+                    };
+                    return Ok(MFAAuthContinue {
+                        mfa_method: MFA_REGISTRATION_METHOD.to_string(),
+                        msg: "You must register an MFA method before you can sign in.".to_string(),
+                        max_poll_attempts: None,
+                        polling_interval: None,
+                        session_id: auth_config.session_id,
+                        flow_token: sft,
+                        ctx: sctx,
+                        canary: auth_config.canary,
+                        url_end_auth: String::new(),
+                        url_post,
+                        url_begin_auth: String::new(),
+                        mfa_methods: vec![],
+                        saml_assertion: None,
+                        resource: None,
+                        url_async_sspr_begin: None,
+                        url_async_sspr_poll: None,
+                        mfa_registration_days_remaining: Some(0),
+                        url_proof_up: Some(url_proof_up),
+                        claims: None,
+                        one_time_code: None,
+                    });
+                }
+            }
+        }
+        if let Some(ref pgid) = auth_config.pgid {
+            if pgid == "ConvergedChangePassword" {
+                let sctx = match &auth_config.sctx {
+                    Some(sctx) => sctx.clone(),
+                    None => return Err(MsalError::GeneralFailure("sCtx is missing".to_string())),
+                };
+                let sft = match &auth_config.sft {
+                    Some(sft) => sft.clone(),
+                    None => return Err(MsalError::GeneralFailure("sFt is missing".to_string())),
+                };
+                let url_post = match &auth_config.url_post {
+                    Some(url_post) => url_post.clone(),
+                    None => {
+                        return Err(MsalError::GeneralFailure("urlPost is missing".to_string()))
+                    }
+                };
+                if auth_config.url_async_sspr_begin.is_none()
+                    || auth_config.url_async_sspr_poll.is_none()
+                {
+                    return Err(MsalError::GeneralFailure(
+                        "Password is expired, and cannot be changed.".to_string(),
+                    ));
+                }
+                return Ok(MFAAuthContinue {
+                    mfa_method: CHANGE_PASSWORD_METHOD.to_string(),
+                    msg: "Your password has expired. Please choose a new password.".to_string(),
+                    max_poll_attempts: None,
+                    polling_interval: None,
+                    session_id: auth_config.session_id,
+                    flow_token: sft,
+                    ctx: sctx,
+                    canary: auth_config.canary,
+                    url_end_auth: String::new(),
+                    url_post,
+                    url_begin_auth: String::new(),
+                    mfa_methods: vec![],
+                    saml_assertion: None,
+                    resource: None,
+                    url_async_sspr_begin: auth_config.url_async_sspr_begin,
+                    url_async_sspr_poll: auth_config.url_async_sspr_poll,
+                    mfa_registration_days_remaining,
+                    url_proof_up: None,
+                    claims: None,
+                    one_time_code: None,
+                });
+            }
+        }
+        if let Some(arr_user_proofs) = auth_config.arr_user_proofs {
+            if arr_user_proofs.is_empty() {
+                return Err(MsalError::GeneralFailure(
+                    "No MFA methods found".to_string(),
+                ));
+            }
+            let sctx = match &auth_config.sctx {
+                Some(sctx) => sctx.clone(),
+                None => return Err(MsalError::GeneralFailure("sCtx is missing".to_string())),
+            };
+            let sft = match &auth_config.sft {
+                Some(sft) => sft.clone(),
+                None => return Err(MsalError::GeneralFailure("sFt is missing".to_string())),
+            };
+            let url_end_auth = match &auth_config.url_end_auth {
+                Some(url_end_auth) => url_end_auth.clone(),
+                None => {
+                    return Err(MsalError::GeneralFailure(
+                        "urlEndAuth is missing".to_string(),
+                    ))
+                }
+            };
+            let url_begin_auth = match &auth_config.url_begin_auth {
+                Some(url_begin_auth) => url_begin_auth.clone(),
+                None => {
+                    return Err(MsalError::GeneralFailure(
+                        "urlBeginAuth is missing".to_string(),
+                    ))
+                }
+            };
+            let url_post = match &auth_config.url_post {
+                Some(url_post) => url_post.clone(),
+                None => return Err(MsalError::GeneralFailure("urlPost is missing".to_string())),
+            };
+            Ok(MFAAuthContinue {
+                mfa_method: String::new(),
+                msg: String::new(),
+                max_poll_attempts: auth_config.max_poll_attempts,
+                polling_interval: auth_config.polling_interval,
+                session_id: auth_config.session_id,
+                flow_token: sft,
+                ctx: sctx,
+                canary: auth_config.canary,
+                url_end_auth,
+                url_post,
+                url_begin_auth,
+                mfa_methods: arr_user_proofs,
+                saml_assertion: None,
+                resource: None,
+                url_async_sspr_begin: None,
+                url_async_sspr_poll: None,
+                mfa_registration_days_remaining,
+                url_proof_up: None,
+                claims: None,
+                one_time_code: None,
+            })
+        } else {
+            Err(MsalError::GeneralFailure(
+                "No MFA methods found".to_string(),
+            ))
+        }
+    }
+
+    /// Begin authentication with an MFA method chosen by the user. This may
+    /// also be called on a flow which has already begun, to switch to another
+    /// MFA method.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A MFAAuthContinue previously generated by
+    ///   initiate_acquire_token_by_mfa_method_selection.
+    ///
+    /// * `auth_method_id` - The `auth_method_id` of one of the `mfa_methods`
+    ///   of the flow.
+    ///
+    /// # Returns
+    /// * Success: The flow is updated with the message to display to the
+    ///   user, and may be passed to acquire_token_by_mfa_flow.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn begin_mfa_auth(
+        &self,
+        flow: &mut MFAAuthContinue,
+        auth_method_id: &str,
+    ) -> Result<(), MsalError> {
+        let method = flow
+            .mfa_methods
+            .iter()
+            .find(|method| method.auth_method_id == auth_method_id)
+            .cloned()
+            .ok_or(MsalError::GeneralFailure(format!(
+                "MFA method {} is not registered",
+                auth_method_id
+            )))?;
+        if !method.is_supported() {
+            return Err(MsalError::GeneralFailure(format!(
+                "Unsupported MFA method {}",
+                auth_method_id
+            )));
+        }
+        if is_passwordless_method(&method.auth_method_id) {
+            if method.auth_method_id == EMAIL_OTP_METHOD {
+                self.send_one_time_code_internal(flow).await?;
+            }
+            flow.msg = method.msg(0)?;
+            flow.mfa_method = method.auth_method_id;
+            return Ok(());
+        }
+        let auth_response = self
+            .mfa_begin_auth_internal(
+                &method.auth_method_id,
+                &flow.url_begin_auth,
+                &flow.ctx,
+                &flow.flow_token,
+                &flow.canary,
+            )
+            .await?;
+        flow.msg = method.msg(auth_response.entropy)?;
+        flow.mfa_method = method.auth_method_id;
+        flow.ctx = auth_response.ctx;
+        flow.flow_token = auth_response.flow_token;
+        Ok(())
+    }
+
+    /// Change an expired password during an MFA flow. When the password is
+    /// changed, the flow continues to MFA as if it was initiated with
+    /// initiate_acquire_token_by_mfa_method_selection.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A MFAAuthContinue which requires a password change.
+    ///
+    /// * `old_password` - The expired password.
+    ///
+    /// * `new_password` - The new password.
+    ///
+    /// # Returns
+    /// * Success: The flow is updated with the MFA methods of the user.
+    /// * Failure: An MsalError, indicating the failure. A PasswordChangeFailed
+    ///   error describes why the password was not changed, such as a password
+    ///   policy violation, which should be shown to the user.
+    pub async fn change_password(
+        &self,
+        flow: &mut MFAAuthContinue,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), MsalError> {
+        if !flow.requires_password_change() {
+            return Err(MsalError::GeneralFailure(
+                "The flow does not require a password change".to_string(),
+            ));
+        }
+        let (url_begin, url_poll) = match (&flow.url_async_sspr_begin, &flow.url_async_sspr_poll) {
+            (Some(url_begin), Some(url_poll)) => (url_begin.clone(), url_poll.clone()),
+            _ => {
+                return Err(MsalError::GeneralFailure(
+                    "Password change urls are missing".to_string(),
+                ))
+            }
+        };
+
+        let payload = json!({
+            "Ctx": &flow.ctx,
+            "FlowToken": &flow.flow_token,
+            "OldPassword": old_password,
+            "NewPassword": new_password,
+        });
+        let mut sspr_response = self
+            .sspr_request_internal(&url_begin, &payload, &flow.canary)
+            .await?;
+        let mut poll_attempt = 0;
+        loop {
+            if let Some(ctx) = sspr_response.ctx.take() {
+                flow.ctx = ctx;
+            }
+            if let Some(flow_token) = sspr_response.flow_token.take() {
+                flow.flow_token = flow_token;
+            }
+            if let Some(error) = sspr_response.password_change_error() {
+                return Err(MsalError::PasswordChangeFailed(error));
+            }
+            if sspr_response.is_completed {
+                break;
+            }
+            if poll_attempt >= CHANGE_PASSWORD_MAX_POLL_ATTEMPTS {
+                return Err(MsalError::PasswordChangeFailed(
+                    PasswordChangeError::Failed(
+                        "Timed out waiting for the password change".to_string(),
+                    ),
+                ));
+            }
+            poll_attempt += 1;
+            sleep(Duration::from_secs(1)).await;
+            let payload = json!({
+                "Ctx": &flow.ctx,
+                "FlowToken": &flow.flow_token,
+            });
+            sspr_response = self
+                .sspr_request_internal(&url_poll, &payload, &flow.canary)
+                .await?;
+        }
+        self.continue_mfa_flow_internal(flow).await
+    }
+
+    /// Begin registering an MFA method for a user who has not registered
+    /// one. For an authenticator app, the registration contains the secret
+    /// key to add to the app. For SMS, a verification code is texted to the
+    /// phone number.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A MFAAuthContinue which requires MFA registration.
+    ///
+    /// * `method` - The MFA method to register.
+    ///
+    /// # Returns
+    /// * Success: A MFARegistration containing the message to display to the
+    ///   user, which is passed to complete_mfa_registration with the
+    ///   verification code entered by the user.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn begin_mfa_registration(
+        &self,
+        flow: &mut MFAAuthContinue,
+        method: MFARegistrationMethod,
+    ) -> Result<MFARegistration, MsalError> {
+        if !flow.requires_mfa_registration() {
+            return Err(MsalError::GeneralFailure(
+                "The flow does not require MFA registration".to_string(),
+            ));
+        }
+        let (msg, secret_key, otpauth_uri, add_payload) = match &method {
+            MFARegistrationMethod::AuthenticatorApp => {
+                let resp = self
+                    .security_info_request_internal(flow, SECURITY_INFO_INITIALIZE_APP, json!({}))
+                    .await?;
+                let secret_key = resp.secret_key.ok_or(MsalError::GeneralFailure(
+                    "The authenticator app secret key is missing".to_string(),
+                ))?;
+                let issuer = resp
+                    .issuer
+                    .unwrap_or(AUTHENTICATOR_APP_DEFAULT_ISSUER.to_string());
+                let label = match resp.account_name {
+                    Some(account_name) => {
+                        format!("{}:{}", url_encode(&issuer), url_encode(&account_name))
+                    }
+                    None => url_encode(&issuer).to_string(),
+                };
+                let otpauth_uri = format!(
+                    "otpauth://totp/{}?secret={}&issuer={}",
+                    label,
+                    url_encode(&secret_key),
+                    url_encode(&issuer)
+                );
+                (
+                    "Add an account to your authenticator app with the secret key or QR code, then type in the code displayed on the app:".to_string(),
+                    Some(secret_key.clone()),
+                    Some(otpauth_uri),
+                    json!({
+                        "Type": method.security_info_type(),
+                        "SecretKey": secret_key,
+                    }),
+                )
+            }
+            MFARegistrationMethod::Sms(phone_number) => (
+                format!(
+                    "We texted your phone {}. Please enter the code to finish registration:",
+                    phone_number
+                ),
+                None,
+                None,
+                json!({
+                    "Type": method.security_info_type(),
+                    "PhoneNumber": phone_number,
+                }),
+            ),
+        };
+        let resp = self
+            .security_info_request_internal(flow, SECURITY_INFO_ADD, add_payload)
+            .await?;
+        let verification_context = resp.verification_context.ok_or(MsalError::GeneralFailure(
+            "The verification context is missing".to_string(),
+        ))?;
+        Ok(MFARegistration {
+            method,
+            msg,
+            secret_key,
+            otpauth_uri,
+            verification_context,
+        })
+    }
+
+    /// Complete the registration of an MFA method by verifying the code
+    /// entered by the user. When the method is registered, the flow
+    /// continues to MFA as if it was initiated with
+    /// initiate_acquire_token_by_mfa_method_selection.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A MFAAuthContinue which requires MFA registration.
+    ///
+    /// * `registration` - A MFARegistration previously generated by
+    ///   begin_mfa_registration.
+    ///
+    /// * `code` - The verification code entered by the user.
+    ///
+    /// # Returns
+    /// * Success: The flow is updated with the MFA methods of the user.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn complete_mfa_registration(
+        &self,
+        flow: &mut MFAAuthContinue,
+        registration: &MFARegistration,
+        code: &str,
+    ) -> Result<(), MsalError> {
+        if !flow.requires_mfa_registration() {
+            return Err(MsalError::GeneralFailure(
+                "The flow does not require MFA registration".to_string(),
+            ));
+        }
+        let payload = json!({
+            "Type": registration.method.security_info_type(),
+            "VerificationContext": &registration.verification_context,
+            "VerificationData": code,
+        });
+        self.security_info_request_internal(flow, SECURITY_INFO_VERIFY, payload)
+            .await?;
+        self.continue_mfa_flow_internal(flow).await
+    }
+
+    async fn security_info_request_internal(
+        &self,
+        flow: &mut MFAAuthContinue,
+        path: &str,
+        mut payload: Value,
+    ) -> Result<SecurityInfoResponse, MsalError> {
+        let url_proof_up = flow.url_proof_up.as_ref().ok_or(MsalError::GeneralFailure(
+            "The proof-up url is missing".to_string(),
+        ))?;
+        let url = Url::parse(url_proof_up)
+            .and_then(|url| url.join(path))
+            .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
+        payload["Ctx"] = json!(&flow.ctx);
+        payload["FlowToken"] = json!(&flow.flow_token);
+        let resp = self
+            .client()
+            .post(url)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .header("canary", &flow.canary)
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if !resp.status().is_success() {
+            return Err(MsalError::GeneralFailure(
+                "MFA registration request failed".to_string(),
+            ));
+        }
+        let mut resp: SecurityInfoResponse = resp
+            .json()
+            .await
+            .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+        if let Some(ctx) = resp.ctx.take() {
+            flow.ctx = ctx;
+        }
+        if let Some(flow_token) = resp.flow_token.take() {
+            flow.flow_token = flow_token;
+        }
+        if let Some(error_code) = service_error_code(&resp.error_code) {
+            debug!("MFA registration failed with error code {}", error_code);
+            return Err(MsalError::GeneralFailure(
+                resp.message
+                    .unwrap_or("The MFA method could not be registered.".to_string()),
+            ));
+        }
+        Ok(resp)
+    }
+
+    async fn continue_mfa_flow_internal(
+        &self,
+        flow: &mut MFAAuthContinue,
+    ) -> Result<(), MsalError> {
+        let request_id = Uuid::new_v4().to_string();
+        let params = [
+            ("ctx", flow.ctx.as_str()),
+            ("flowToken", &flow.flow_token),
+            ("canary", &flow.canary),
+            ("client-request-id", &request_id),
+        ];
+        let payload = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+        let auth_config = self
+            .post_auth_config_internal(&payload, &flow.url_post)
+            .await?;
+        let claims = flow.claims.take();
+        *flow = self
+            .mfa_flow_from_auth_config_internal(auth_config, &request_id)
+            .await?;
+        flow.claims = claims;
+        Ok(())
+    }
+
+    async fn sspr_request_internal(
+        &self,
+        url: &str,
+        payload: &Value,
+        canary: &str,
+    ) -> Result<SsprResponse, MsalError> {
+        let resp = self
+            .client()
+            .post(url)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .header("canary", canary)
+            .json(payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            resp.json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))
+        } else {
+            Err(MsalError::GeneralFailure(
+                "Password change request failed".to_string(),
+            ))
+        }
+    }
+
+    /// Send the one time code of a passwordless flow, unless the service
+    /// already sent it when the flow was initiated.
+    async fn send_one_time_code_internal(
+        &self,
+        flow: &mut MFAAuthContinue,
+    ) -> Result<(), MsalError> {
+        let one_time_code = flow.one_time_code.clone().ok_or(MsalError::GeneralFailure(
+            "The flow has no one time code proof".to_string(),
+        ))?;
+        if one_time_code.proof.otc_sent {
+            return Ok(());
+        }
+        let params = [
+            ("login", one_time_code.login.as_str()),
+            ("flowtoken", &flow.flow_token),
+            ("purpose", "eOTT_OneTimePassword"),
+            ("channel", "Email"),
+            ("AltEmailE", &one_time_code.proof.data),
+        ];
+        let payload = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let resp = self
+            .client()
+            .post(format!("{}/{}", self.authority(), GET_ONE_TIME_CODE_PATH))
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header("canary", &flow.canary)
+            .body(payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if !resp.status().is_success() {
+            return Err(MsalError::GeneralFailure(
+                "Sending the one time code failed".to_string(),
+            ));
+        }
+        let otc_response: OneTimeCodeResponse = resp
+            .json()
+            .await
+            .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+        if let Some(error_code) = service_error_code(&otc_response.error_code) {
+            debug!(
+                "Sending the one time code failed with error code {}",
+                error_code
+            );
+            return Err(MsalError::GeneralFailure(
+                otc_response
+                    .message
+                    .unwrap_or("Sending the one time code failed".to_string()),
+            ));
+        }
+        if let Some(flow_token) = otc_response.flow_token {
+            flow.flow_token = flow_token;
+        }
+        if let Some(one_time_code) = flow.one_time_code.as_mut() {
+            one_time_code.proof.otc_sent = true;
+        }
+        Ok(())
+    }
+
+    /// Sign in with the code of a passwordless flow, in place of a password.
+    /// The service may ask whether to stay signed in before it redirects
+    /// with the authorization code.
+    async fn sign_in_with_code_internal(
+        &self,
+        username: &str,
+        code: &str,
+        flow: &MFAAuthContinue,
+    ) -> Result<String, MsalError> {
+        let code_param = match flow.mfa_method.as_str() {
+            ACCESS_PASS_METHOD => "accesspass",
+            _ => "otc",
+        };
+        let params = [
+            ("login", username),
+            (code_param, code.trim()),
+            ("ctx", &flow.ctx),
+            ("flowToken", &flow.flow_token),
+            ("canary", &flow.canary),
+            ("client_id", self.client_id()),
+        ];
+        let payload = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+        let mut resp = self
+            .post_login_form_internal(&payload, &flow.url_post)
+            .await?;
+        if resp.status().is_success() {
+            let text = resp
+                .text()
+                .await
+                .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+            let auth_config = self.parse_auth_config(&text)?;
+            if let Some(msg) = auth_config.service_exception_msg {
+                return Err(MsalError::GeneralFailure(msg));
+            }
+            if auth_config.pgid.as_deref() != Some("KmsiInterrupt") {
+                return Err(MsalError::GeneralFailure(
+                    "The code was not accepted.".to_string(),
+                ));
+            }
+            let (sctx, sft, url_post) =
+                match (&auth_config.sctx, &auth_config.sft, &auth_config.url_post) {
+                    (Some(sctx), Some(sft), Some(url_post)) => (sctx, sft, url_post),
+                    _ => {
+                        return Err(MsalError::GeneralFailure(
+                            "sCtx, sFt or urlPost is missing".to_string(),
+                        ))
+                    }
+                };
+            let params = [
+                ("LoginOptions", "1"),
+                ("ctx", sctx),
+                ("flowToken", sft),
+                ("canary", &auth_config.canary),
+            ];
+            let payload = params
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+                .collect::<Vec<String>>()
+                .join("&");
+            resp = self.post_login_form_internal(&payload, url_post).await?;
+        }
+        if resp.status().is_redirection() {
+            redirect_authorization_code(&resp)
+        } else {
+            Err(MsalError::GeneralFailure(
+                "Passwordless sign in request failed".to_string(),
+            ))
+        }
+    }
+
+    async fn get_cred_type(
+        &self,
+        username: &str,
+        auth_config: &AuthConfig,
+        request_id: &str,
+    ) -> Result<CredType, MsalError> {
+        let payload = json!({
+            "username": username,
+            "isOtherIdpSupported": true,
+            "checkPhones": true,
+            "isRemoteNGCSupported": false,
+            "isCookieBannerShown": false,
+            "isFidoSupported": false,
+            "isAccessPassSupported": true,
+            "isOtcLoginSupported": true,
+            "originalRequest": &auth_config.sctx,
+            "flowToken": &auth_config.sft,
+        });
+
+        self.app.ensure_valid_authority().await?;
+        let resp = self
+            .client()
+            .post(format!("{}/GetCredentialType", self.authority()))
+            .header(header::CONTENT_TYPE, "application/json; charset=UTF-8")
+            .header("client-request-id", request_id)
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            let json_resp: CredType = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            Ok(json_resp)
+        } else {
+            let json_resp: ErrorResponse = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            Err(MsalError::AcquireTokenFailed(json_resp))
+        }
+    }
+
+    async fn request_auth_config_internal(
+        &self,
+        scopes: Vec<&str>,
+        request_id: &str,
+        resource: Option<&str>,
+        claims: Option<&str>,
+    ) -> Result<AuthConfig, MsalError> {
+        let scope = format!("openid profile {}", scopes.join(" "));
+        let mut params = vec![
+            ("client_id", self.client_id()),
+            ("response_type", "code"),
+            ("redirect_uri", "urn:ietf:wg:oauth:2.0:oob"),
+            ("client-request-id", request_id),
+            ("prompt", "login"),
+            ("scope", &scope),
+            ("response_mode", "query"),
+            ("sso_reload", "True"),
+            ("amr_values", "ngcmfa"),
+            (
+                "resource",
+                (resource.unwrap_or("00000002-0000-0000-c000-000000000000")),
+            ),
+        ];
+        if let Some(claims) = claims {
+            params.push(("claims", claims));
+        }
+        let url = Url::parse_with_params(
+            &self
+                .app
+                .openid_configuration_v1()
+                .await?
+                .authorization_endpoint,
+            &params.to_vec(),
+        )
+        .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
+
+        let resp = self
+            .client()
+            .get(url)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            self.parse_auth_config(&resp.text().await.map_err(|e| {
+                MsalError::GeneralFailure(format!("Failed parsing auth config: {}", e))
+            })?)
+        } else {
+            Err(MsalError::GeneralFailure(
+                "Failed requesting auth config".to_string(),
+            ))
+        }
+    }
+
+    async fn mfa_begin_auth_internal(
+        &self,
+        mfa_method: &str,
+        url_begin_auth: &str,
+        ctx: &str,
+        flow_token: &str,
+        canary: &str,
+    ) -> Result<AuthResponse, MsalError> {
+        let payload = json!({
+            "AuthMethodId": mfa_method,
+            "ctx": ctx,
+            "flowToken": flow_token,
+            "Method": "BeginAuth",
+        });
+
+        let resp = self
+            .client()
+            .post(url_begin_auth)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .header("canary", canary)
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            let text = resp
+                .text()
+                .await
+                .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+            let auth_response: AuthResponse =
+                json_from_str(&text).map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            if auth_response.success {
+                Ok(auth_response)
+            } else if let Some(msg) = auth_response.message {
+                Err(MsalError::GeneralFailure(msg))
+            } else {
+                Err(MsalError::GeneralFailure("BeginAuth failed".to_string()))
+            }
+        } else {
+            Err(MsalError::GeneralFailure(
+                "BeginAuth Authentication request failed".to_string(),
+            ))
+        }
+    }
+
+    async fn request_authorization_internal(
+        &self,
+        username: &str,
+        flow: &MFAAuthContinue,
+    ) -> Result<String, MsalError> {
+        let mfa_method = match flow.mfa_method.as_str() {
+            // ConsolidatedTelephony simply means OneWaySMS internally to Azure,
+            // it seems. If we don't swap them during the ProcessAuth though,
+            // this request is rejected. I observed this odd behavior in a
+            // browser auth to Azure also.
+            "ConsolidatedTelephony" => "OneWaySMS".to_string(),
+            other => other.to_string(),
+        };
+        let params = [
+            ("request", &flow.ctx),
+            ("mfaAuthMethod", &mfa_method),
+            ("login", &username.to_string()),
+            ("flowToken", &flow.flow_token),
+            ("canary", &flow.canary),
+        ];
+        let payload = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let resp = self
+            .client()
+            .post(&flow.url_post)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_redirection() {
+            redirect_authorization_code(&resp)
+        } else {
+            Err(MsalError::GeneralFailure(
+                "ProcessAuth Authorization request failed".to_string(),
+            ))
+        }
+    }
+
+    async fn exchange_authorization_code_for_access_token_internal(
+        &self,
+        authorization_code: String,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        let mut params = vec![
+            ("client_id", self.client_id()),
+            ("grant_type", "authorization_code"),
+            ("code", &authorization_code),
+            ("redirect_uri", "urn:ietf:wg:oauth:2.0:oob"),
+            ("client_info", "1"),
+        ];
+        if let Some(claims) = claims {
+            params.push(("claims", claims));
+        }
+        let payload = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let resp = self
+            .client()
+            .post(&self.app.openid_configuration_v1().await?.token_endpoint)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            let mut token: UserToken = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            token.stamp_expiry()?;
+            self.app.save_token(&[], &token)?;
+
+            Ok(token)
+        } else {
+            let json_resp: ErrorResponse = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            Err(MsalError::AcquireTokenFailed(json_resp))
+        }
+    }
+
+    async fn exchange_saml_assertion_for_access_token_internal(
+        &self,
+        saml_assertion: &SamlAssertion,
+        resource: Option<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        let encoded = saml_assertion.encoded();
+        let mut params = vec![
+            ("client_id", self.client_id()),
+            ("grant_type", saml_assertion.grant_type()),
+            ("assertion", &encoded),
+            (
+                "resource",
+                resource.unwrap_or("00000002-0000-0000-c000-000000000000"),
+            ),
+            ("scope", "openid"),
+            ("client_info", "1"),
+        ];
+        if let Some(claims) = claims {
+            params.push(("claims", claims));
+        }
+        let payload = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let resp = self
+            .client()
+            .post(&self.app.openid_configuration_v1().await?.token_endpoint)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(payload)
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            let mut token: UserToken = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            token.stamp_expiry()?;
+            self.app.save_token(&[], &token)?;
+
+            Ok(token)
+        } else {
+            let json_resp: ErrorResponse = resp
+                .json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            Err(MsalError::AcquireTokenFailed(json_resp))
+        }
+    }
+
+    /// Obtain token by a MFA flow object.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `auth_data` - An optional token received for the MFA flow (some MFA
+    ///   flows do not require input).
+    ///
+    /// * `poll_attempt` - The polling attempt number.
+    ///
+    /// * `flow` - A MFAAuthContinue previously generated by
+    ///   initiate_acquire_token_by_mfa_flow. A flow which requires a
+    ///   password change must first be passed to change_password, and a
+    ///   flow which requires MFA registration to complete_mfa_registration.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_mfa_flow(
+        &self,
+        username: &str,
+        auth_data: Option<&str>,
+        poll_attempt: Option<u32>,
+        flow: &mut MFAAuthContinue,
+    ) -> Result<UserToken, MsalError> {
+        if flow.requires_password_change() {
+            return Err(MsalError::GeneralFailure(
+                "The password has expired, and must be changed with change_password".to_string(),
+            ));
+        }
+        if flow.requires_mfa_registration() {
+            return Err(MsalError::GeneralFailure(
+                "An MFA method must be registered with begin_mfa_registration".to_string(),
+            ));
+        }
+        if let Some(saml_assertion) = &flow.saml_assertion {
+            return self
+                .exchange_saml_assertion_for_access_token_internal(
+                    saml_assertion,
+                    flow.resource.as_deref(),
+                    flow.claims.as_deref(),
+                )
+                .await;
+        }
+        if is_passwordless_method(&flow.mfa_method) {
+            let code = auth_data.ok_or(MsalError::GeneralFailure(format!(
+                "MFA method {} requires a code to be entered",
+                flow.mfa_method
+            )))?;
+            let auth_code = self
+                .sign_in_with_code_internal(username, code, flow)
+                .await?;
+            return self
+                .exchange_authorization_code_for_access_token_internal(
+                    auth_code,
+                    flow.claims.as_deref(),
+                )
+                .await;
+        }
+        match auth_data {
+            Some(auth_data) => {
+                let payload = json!({
+                    "AdditionalAuthData": auth_data.trim(),
+                    "AuthMethodId": &flow.mfa_method,
+                    "SessionId": &flow.session_id,
+                    "FlowToken": &flow.flow_token,
+                    "Ctx": &flow.ctx,
+                    "Method": "EndAuth",
+                });
+
+                let resp = self
+                    .client()
+                    .post(&flow.url_end_auth)
+                    .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+                    .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+                    .header("canary", &flow.canary)
+                    .json(&payload)
+                    .send()
+                    .await
+                    .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+                if resp.status().is_success() {
+                    let text = resp
+                        .text()
+                        .await
+                        .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+                    // Check for an error in an auth Config
+                    if let Ok(auth_config) = self.parse_auth_config(&text) {
+                        if let Some(service_exception_msg) = auth_config.service_exception_msg {
+                            return Err(MsalError::GeneralFailure(
+                                service_exception_msg.to_string(),
+                            ));
+                        }
+                    }
+                    // Parse what should be a json response otherwise
+                    let auth_response: AuthResponse = json_from_str(&text)
+                        .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+                    if auth_response.success {
+                        flow.ctx = auth_response.ctx;
+                        flow.flow_token = auth_response.flow_token;
+                        let auth_code = self.request_authorization_internal(username, flow).await?;
+                        self.exchange_authorization_code_for_access_token_internal(
+                            auth_code,
+                            flow.claims.as_deref(),
+                        )
+                        .await
+                    } else if let Some(msg) = auth_response.message {
+                        Err(MsalError::GeneralFailure(msg))
+                    } else {
+                        Err(MsalError::GeneralFailure("EndAuth failed".to_string()))
+                    }
+                } else {
+                    Err(MsalError::GeneralFailure(
+                        "EndAuth Authentication request failed".to_string(),
+                    ))
+                }
+            }
+            None => {
+                let url = Url::parse_with_params(
+                    &flow.url_end_auth,
+                    [
+                        ("authMethodId", &flow.mfa_method),
+                        (
+                            "pollCount",
+                            &format!(
+                                "{}",
+                                poll_attempt.ok_or(MsalError::GeneralFailure(
+                                    "Poll attempt required".to_string()
+                                ))?
+                            ),
+                        ),
+                    ],
+                )
+                .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
+
+                let resp = self
+                    .client()
+                    .get(url)
+                    .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+                    .header("x-ms-sessionId", &flow.session_id)
+                    .header("x-ms-flowToken", &flow.flow_token)
+                    .header("x-ms-ctx", &flow.ctx)
+                    .send()
+                    .await
+                    .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+                if resp.status().is_success() {
+                    let text = resp
+                        .text()
+                        .await
+                        .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+                    // Check for an error in an auth Config
+                    if let Ok(auth_config) = self.parse_auth_config(&text) {
+                        if let Some(service_exception_msg) = auth_config.service_exception_msg {
+                            return Err(MsalError::GeneralFailure(
+                                service_exception_msg.to_string(),
+                            ));
+                        }
+                    }
+                    // Parse what should be a json response otherwise
+                    let auth_response: AuthResponse = json_from_str(&text)
+                        .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+                    if auth_response.success {
+                        flow.ctx = auth_response.ctx;
+                        flow.flow_token = auth_response.flow_token;
+                        let auth_code = self.request_authorization_internal(username, flow).await?;
+                        return self
+                            .exchange_authorization_code_for_access_token_internal(
+                                auth_code,
+                                flow.claims.as_deref(),
+                            )
+                            .await;
+                    } else if !auth_response.retry.ok_or(MsalError::GeneralFailure(
+                        "Auth response Retry missing".to_string(),
+                    ))? {
+                        if let Some(msg) = auth_response.message {
+                            return Err(MsalError::GeneralFailure(msg));
+                        } else {
+                            return Err(MsalError::GeneralFailure("EndAuth failed".to_string()));
+                        }
+                    }
+                    Err(MsalError::MFAPollContinue)
+                } else {
+                    Err(MsalError::GeneralFailure(
+                        "EndAuth Authentication request failed".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// A confidential client application, such as a web app or a backend
+/// service, which authenticates itself to AAD with a client secret or a
+/// certificate.
+pub struct ConfidentialClientApplication {
+    app: ClientApplication,
+}
+
+impl ConfidentialClientApplication {
+    /// Create an instance of an application.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - Your app has a client_id after you register it on
+    ///   AAD.
+    ///
+    /// * `authority` - A URL that identifies a token authority. It should
+    ///   be of the format <https://login.microsoftonline.com/your_tenant> By
+    ///   default, we will use <https://login.microsoftonline.com/common>.
+    ///
+    /// * `credential` - The client secret or certificate registered for
+    ///   the app on AAD.
+    pub fn new(
+        client_id: &str,
+        authority: Option<&str>,
+        credential: ClientCredential,
+    ) -> Result<Self, MsalError> {
+        let mut app = ClientApplication::new(client_id, authority)?;
+        app.credential = Some(credential);
+        Ok(ConfidentialClientApplication { app })
+    }
+
+    /// Replace the token cache used by this application.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache` - A TokenCache. By default, an InMemoryTokenCache is used,
+    ///   which is lost when the application exits.
+    pub fn set_token_cache(&mut self, cache: Arc<dyn TokenCache>) {
+        self.app.cache = cache;
+    }
+
+    /// Declare the capabilities of this application, which are sent as
+    /// claims with every token request. Declaring the `cp1` capability opts
+    /// into continuous access evaluation (CAE), and the application must then
+    /// handle claims challenges from resources.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_capabilities` - The capabilities, such as `cp1`.
+    pub fn set_client_capabilities(&mut self, client_capabilities: Vec<&str>) {
+        self.app.client_capabilities = client_capabilities
+            .into_iter()
+            .map(|capability| capability.to_string())
+            .collect();
+    }
+
+    /// Enable or disable validation of the authority. By default, an
+    /// authority must use https, and must be hosted by a known cloud or be
+    /// recognized by instance discovery. Only disable validation for a custom
+    /// authority which you trust, since credentials are sent to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `validate_authority` - Whether to validate the authority.
+    pub fn set_validate_authority(&mut self, validate_authority: bool) {
+        self.app.validate_authority = validate_authority;
+    }
+
+    /// Discover the OpenID Connect metadata of the authority, such as its
+    /// end session and JWKS endpoints. The metadata is cached by the
+    /// application, and used to locate the endpoints of every request.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The OpenID Connect metadata of the v2.0 endpoints of the
+    ///   authority.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn openid_configuration(&self) -> Result<OpenIdConfiguration, MsalError> {
+        self.app.openid_configuration().await.cloned()
+    }
+
+    /// Create a validator for the ID tokens and access tokens issued by the
+    /// authority of this application. The validator caches the signing keys
+    /// of the authority, so it should be kept for validating later tokens.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A TokenValidator for the authority.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn token_validator(&self) -> Result<TokenValidator, MsalError> {
+        TokenValidator::new(&self.app.authority)
+    }
+
+    /// List the accounts which have signed in to this application, and are
+    /// present in the token cache.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A list of Accounts, which may be empty.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn get_accounts(&self) -> Result<Vec<Account>, MsalError> {
+        self.app.get_accounts()
+    }
+
+    /// Find an account in the token cache by username.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The Account, or None if the account has not signed in.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn get_account(&self, username: &str) -> Result<Option<Account>, MsalError> {
+        self.app.get_account(username)
+    }
+
+    /// Sign an account out of this application, by removing the account and
+    /// the tokens cached for it from the token cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The Account to remove.
+    ///
+    /// # Returns
+    ///
+    /// * Success: ()
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn remove_account(&self, account: &Account) -> Result<(), MsalError> {
+        self.app.remove_account(account)
+    }
+
+    /// Acquire an access token for the account from the token cache. If the
+    /// cached access token has expired, or no cached access token covers the
+    /// requested scopes, the cached refresh token is used to acquire a new
+    /// access token.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `account` - An Account which previously signed in to this
+    ///   application.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure. If no usable token
+    ///   was found in the cache, MsalError::CacheMiss is returned.
+    pub async fn acquire_token_silent(
+        &self,
+        scopes: Vec<&str>,
+        account: &Account,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_silent_with_claims(scopes, account, None)
+            .await
+    }
+
+    /// Acquire an access token for the account from the token cache. If the
+    /// cached access token has expired, or no cached access token covers the
+    /// requested scopes, the cached refresh token is used to acquire a new
+    /// access token.
+    ///
+    /// Unlike acquire_token_silent, this requests the given claims, such as a
+    /// claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `account` - An Account which previously signed in to this
+    ///   application.
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge. A cached access token
+    ///   cannot satisfy a claims challenge, so a new token is requested.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure. If no usable token
+    ///   was found in the cache, MsalError::CacheMiss is returned.
+    pub async fn acquire_token_silent_with_claims(
+        &self,
+        scopes: Vec<&str>,
+        account: &Account,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.app.acquire_token_silent(scopes, account, claims).await
+    }
+
+    /// Acquire a token for the application itself, rather than for a user,
+    /// using the client credentials grant. App tokens are cached per tenant
+    /// and scopes, and a cached token is returned until it needs refreshing.
+    /// The application must be created with a tenant authority, not
+    /// `common`, `organizations` or `consumers`.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - The `.default` scope of the resource, such as
+    ///   `https://graph.microsoft.com/.default`. The application is granted
+    ///   the application permissions consented to for the resource.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token. An app token has
+    ///   no refresh_token, id_token or account.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_for_client(
+        &self,
+        scopes: Vec<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_for_client_with_claims(scopes, None)
+            .await
+    }
+
+    /// Acquire a token for the application itself, rather than for a user,
+    /// using the client credentials grant. App tokens are cached per tenant
+    /// and scopes, and a cached token is returned until it needs refreshing.
+    /// The application must be created with a tenant authority, not
+    /// `common`, `organizations` or `consumers`.
+    ///
+    /// Unlike acquire_token_for_client, this requests the given claims, such as
+    /// a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `scopes` - The `.default` scope of the resource, such as
+    ///   `https://graph.microsoft.com/.default`. The application is granted
+    ///   the application permissions consented to for the resource.
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge. A cached app token
+    ///   cannot satisfy a claims challenge, so a new token is requested.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token. An app token has
+    ///   no refresh_token, id_token or account.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_for_client_with_claims(
+        &self,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.app.acquire_token_for_client(scopes, claims).await
+    }
+
+    /// Acquire a token for a downstream API on behalf of the user of a web
+    /// API, using the access token the web API was called with. Tokens are
+    /// cached per user assertion, and are refreshed using the cached refresh
+    /// token when it is available.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_assertion` - The access token the web API was called with.
+    ///
+    /// * `scopes` - Scopes requested to access the downstream API.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_on_behalf_of(
+        &self,
+        user_assertion: &str,
+        scopes: Vec<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_on_behalf_of_with_claims(user_assertion, scopes, None)
+            .await
+    }
+
+    /// Acquire a token for a downstream API on behalf of the user of a web
+    /// API, using the access token the web API was called with. Tokens are
+    /// cached per user assertion, and are refreshed using the cached refresh
+    /// token when it is available.
+    ///
+    /// Unlike acquire_token_on_behalf_of, this requests the given claims, such
+    /// as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_assertion` - The access token the web API was called with.
+    ///
+    /// * `scopes` - Scopes requested to access the downstream API.
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge. A cached access token
+    ///   cannot satisfy a claims challenge, so a new token is requested.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_on_behalf_of_with_claims(
+        &self,
+        user_assertion: &str,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.app
+            .acquire_token_on_behalf_of(user_assertion, scopes, claims)
+            .await
+    }
+
+    /// Acquire a token using the authorization code which the user's browser
+    /// was redirected to the application with.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The authorization code.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `redirect_uri` - The redirect uri used in the authorization
+    ///   request.
+    ///
+    /// * `code_verifier` - The PKCE code verifier, if a code challenge was
+    ///   sent in the authorization request.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_authorization_code(
+        &self,
+        code: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        code_verifier: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_by_authorization_code_with_claims(
+            code,
+            scopes,
+            redirect_uri,
+            code_verifier,
+            None,
+        )
+        .await
+    }
+
+    /// Acquire a token using the authorization code which the user's browser
+    /// was redirected to the application with.
+    ///
+    /// Unlike acquire_token_by_authorization_code, this requests the given
+    /// claims, such as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The authorization code.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `redirect_uri` - The redirect uri used in the authorization
+    ///   request.
+    ///
+    /// * `code_verifier` - The PKCE code verifier, if a code challenge was
+    ///   sent in the authorization request.
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_authorization_code_with_claims(
+        &self,
+        code: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        code_verifier: Option<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.app
+            .acquire_token_by_authorization_code(code, scopes, redirect_uri, code_verifier, claims)
+            .await
+    }
+
+    /// Gets a token for a given resource via user credentials.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// # Returns
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_username_password(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_by_username_password_with_claims(username, password, scopes, None)
+            .await
+    }
+
+    /// Gets a token for a given resource via user credentials.
+    ///
+    /// Unlike acquire_token_by_username_password, this requests the given
+    /// claims, such as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// # Returns
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_username_password_with_claims(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
+    ) -> Result<UserToken, MsalError> {
+        self.app
+            .acquire_token_by_username_password(username, password, scopes, claims)
+            .await
     }
 
-    async fn exchange_authorization_code_for_access_token_internal(
+    /// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
+    ///
+    /// # Arguments
+    ///
+    /// * `refresh_token` - The old refresh token, as a string.
+    ///
+    /// * `scopes` - The scopes associated with this old RT.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken, which means migration was successful.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_refresh_token(
         &self,
-        authorization_code: String,
+        refresh_token: &str,
+        scopes: Vec<&str>,
     ) -> Result<UserToken, MsalError> {
-        let params = [
-            ("client_id", self.client_id()),
-            ("grant_type", "authorization_code"),
-            ("code", &authorization_code),
-            ("redirect_uri", "urn:ietf:wg:oauth:2.0:oob"),
-        ];
-        let payload = params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect::<Vec<String>>()
-            .join("&");
-
-        let resp = self
-            .client()
-            .post(format!("{}/oauth2/token", self.authority()))
-            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .body(payload)
-            .send()
+        self.acquire_token_by_refresh_token_with_claims(refresh_token, scopes, None)
             .await
-            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-        if resp.status().is_success() {
-            let token: UserToken = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-
-            Ok(token)
-        } else {
-            let json_resp: ErrorResponse = resp
-                .json()
-                .await
-                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            Err(MsalError::AcquireTokenFailed(json_resp))
-        }
     }
 
-    /// Obtain token by a MFA flow object.
+    /// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
     ///
-    /// # Arguments
+    /// Unlike acquire_token_by_refresh_token, this requests the given claims,
+    /// such as a claims challenge from a resource.
     ///
-    /// * `username` - Typically a UPN in the form of an email address.
+    /// # Arguments
     ///
-    /// * `auth_data` - An optional token received for the MFA flow (some MFA
-    ///   flows do not require input).
+    /// * `refresh_token` - The old refresh token, as a string.
     ///
-    /// * `poll_attempt` - The polling attempt number.
+    /// * `scopes` - The scopes associated with this old RT.
     ///
-    /// * `flow` - A MFAAuthContinue previously generated by
-    /// initiate_acquire_token_by_mfa_flow.
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
     ///
     /// # Returns
     ///
-    /// * Success: A UserToken containing an access_token.
+    /// * Success: A UserToken, which means migration was successful.
     /// * Failure: An MsalError, indicating the failure.
-    pub async fn acquire_token_by_mfa_flow(
+    pub async fn acquire_token_by_refresh_token_with_claims(
         &self,
-        username: &str,
-        auth_data: Option<&str>,
-        poll_attempt: Option<u32>,
-        flow: &mut MFAAuthContinue,
+        refresh_token: &str,
+        scopes: Vec<&str>,
+        claims: Option<&str>,
     ) -> Result<UserToken, MsalError> {
-        match auth_data {
-            Some(auth_data) => {
-                let payload = json!({
-                    "AdditionalAuthData": auth_data.trim(),
-                    "AuthMethodId": &flow.mfa_method,
-                    "SessionId": &flow.session_id,
-                    "FlowToken": &flow.flow_token,
-                    "Ctx": &flow.ctx,
-                    "Method": "EndAuth",
-                });
+        self.app
+            .acquire_token_by_refresh_token(refresh_token, scopes, claims)
+            .await
+    }
+}
 
-                let resp = self
-                    .client()
-                    .post(&flow.url_end_auth)
-                    .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
-                    .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
-                    .header("canary", &flow.canary)
-                    .json(&payload)
-                    .send()
-                    .await
-                    .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-                if resp.status().is_success() {
-                    let text = resp
-                        .text()
-                        .await
-                        .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
-                    // Check for an error in an auth Config
-                    if let Ok(auth_config) = self.parse_auth_config(&text) {
-                        if let Some(service_exception_msg) = auth_config.service_exception_msg {
-                            return Err(MsalError::GeneralFailure(
-                                service_exception_msg.to_string(),
-                            ));
-                        }
-                    }
-                    // Parse what should be a json response otherwise
-                    let auth_response: AuthResponse = json_from_str(&text)
-                        .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-                    if auth_response.success {
-                        flow.ctx = auth_response.ctx;
-                        flow.flow_token = auth_response.flow_token;
-                        let auth_code = self.request_authorization_internal(username, flow).await?;
-                        self.exchange_authorization_code_for_access_token_internal(auth_code)
-                            .await
-                    } else if let Some(msg) = auth_response.message {
-                        Err(MsalError::GeneralFailure(msg))
-                    } else {
-                        Err(MsalError::GeneralFailure("EndAuth failed".to_string()))
-                    }
-                } else {
-                    Err(MsalError::GeneralFailure(
-                        "EndAuth Authentication request failed".to_string(),
-                    ))
-                }
-            }
-            None => {
-                let url = Url::parse_with_params(
-                    &flow.url_end_auth,
-                    [
-                        ("authMethodId", &flow.mfa_method),
-                        (
-                            "pollCount",
-                            &format!(
-                                "{}",
-                                poll_attempt.ok_or(MsalError::GeneralFailure(
-                                    "Poll attempt required".to_string()
-                                ))?
-                            ),
-                        ),
-                    ],
-                )
-                .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
+/// Holds a UserToken, and renews it using its refresh token when it is close
+/// to expiry.
+pub struct TokenProvider<'a> {
+    app: &'a PublicClientApplication,
+    scopes: Vec<String>,
+    token: Mutex<UserToken>,
+}
 
-                let resp = self
-                    .client()
-                    .get(url)
-                    .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
-                    .header("x-ms-sessionId", &flow.session_id)
-                    .header("x-ms-flowToken", &flow.flow_token)
-                    .header("x-ms-ctx", &flow.ctx)
-                    .send()
-                    .await
-                    .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
-                if resp.status().is_success() {
-                    let text = resp
-                        .text()
-                        .await
-                        .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
-                    // Check for an error in an auth Config
-                    if let Ok(auth_config) = self.parse_auth_config(&text) {
-                        if let Some(service_exception_msg) = auth_config.service_exception_msg {
-                            return Err(MsalError::GeneralFailure(
-                                service_exception_msg.to_string(),
-                            ));
-                        }
-                    }
-                    // Parse what should be a json response otherwise
-                    let auth_response: AuthResponse = json_from_str(&text)
-                        .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-                    if auth_response.success {
-                        flow.ctx = auth_response.ctx;
-                        flow.flow_token = auth_response.flow_token;
-                        let auth_code = self.request_authorization_internal(username, flow).await?;
-                        return self
-                            .exchange_authorization_code_for_access_token_internal(auth_code)
-                            .await;
-                    } else if !auth_response.retry.ok_or(MsalError::GeneralFailure(
-                        "Auth response Retry missing".to_string(),
-                    ))? {
-                        if let Some(msg) = auth_response.message {
-                            return Err(MsalError::GeneralFailure(msg));
-                        } else {
-                            return Err(MsalError::GeneralFailure("EndAuth failed".to_string()));
-                        }
-                    }
-                    Err(MsalError::MFAPollContinue)
-                } else {
-                    Err(MsalError::GeneralFailure(
-                        "EndAuth Authentication request failed".to_string(),
-                    ))
-                }
+impl<'a> TokenProvider<'a> {
+    /// Create a token provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application which acquired the token.
+    ///
+    /// * `token` - A UserToken containing a refresh_token.
+    ///
+    /// * `scopes` - Scopes requested when renewing the token.
+    pub fn new(app: &'a PublicClientApplication, mut token: UserToken, scopes: Vec<&str>) -> Self {
+        // A failure to read the clock leaves the token due for refresh.
+        let _ = token.stamp_expiry();
+        TokenProvider {
+            app,
+            scopes: scopes.into_iter().map(|s| s.to_string()).collect(),
+            token: Mutex::new(token),
+        }
+    }
+
+    /// Fetch a fresh token. If the held token needs to be refreshed, it is
+    /// renewed using its refresh token.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn token(&self) -> Result<UserToken, MsalError> {
+        let mut token = self.token.lock().await;
+        if token.needs_refresh() {
+            if token.refresh_token.is_empty() {
+                return Err(MsalError::GeneralFailure(
+                    "The token cannot be renewed without a refresh token".to_string(),
+                ));
             }
+            debug!("Renewing the token using the refresh token");
+            let scopes = self.scopes.iter().map(|s| s.as_str()).collect();
+            let mut new_token = self
+                .app
+                .acquire_token_by_refresh_token(&token.refresh_token, scopes)
+                .await?;
+            if new_token.refresh_token.is_empty() {
+                new_token.refresh_token.clone_from(&token.refresh_token);
+            }
+            *token = new_token;
         }
+        Ok(token.clone())
     }
 }
 
@@ -1861,6 +5451,7 @@ pub struct BrokerClientApplication {
     app: PublicClientApplication,
     transport_key: Option<LoadableMsOapxbcRsaKey>,
     cert_key: Option<LoadableIdentityKey>,
+    cloud: CloudInstance,
 }
 
 #[cfg(feature = "broker")]
@@ -1881,18 +5472,42 @@ impl BrokerClientApplication {
     ///
     /// NOTE: If `transport_key` and `cert_key` are not provided from a previous
     /// device enrollment, then enrollment will be required.
+    ///
+    /// The cloud of the device registration and enrollment services is found
+    /// from the host of the authority, and is otherwise the public cloud. Use
+    /// set_cloud_instance for the US Department of Defense cloud, or for a
+    /// cloud which is not built in.
     pub fn new(
         authority: Option<&str>,
         transport_key: Option<LoadableMsOapxbcRsaKey>,
         cert_key: Option<LoadableIdentityKey>,
     ) -> Result<Self, MsalError> {
+        let app = PublicClientApplication::new(BROKER_APP_ID, authority)?;
+        let cloud = CloudInstance::from_authority(app.authority())?.unwrap_or_default();
         Ok(BrokerClientApplication {
-            app: PublicClientApplication::new(BROKER_APP_ID, authority)?,
+            app,
             transport_key,
             cert_key,
+            cloud,
         })
     }
 
+    /// Set the cloud which hosts the device registration and enrollment
+    /// services. The login host of the cloud should be the host of the
+    /// authority.
+    ///
+    /// # Arguments
+    ///
+    /// * `cloud` - The cloud which hosts the tenant.
+    pub fn set_cloud_instance(&mut self, cloud: CloudInstance) {
+        self.cloud = cloud;
+    }
+
+    /// The cloud which hosts the device registration and enrollment services.
+    pub fn cloud_instance(&self) -> &CloudInstance {
+        &self.cloud
+    }
+
     fn client(&self) -> &Client {
         self.app.client()
     }
@@ -1945,14 +5560,117 @@ impl BrokerClientApplication {
         }
     }
 
-    /// Set the enrollment certificate key
+    /// Set the enrollment certificate key
+    ///
+    /// # Arguments
+    ///
+    /// * `cert_key` - An optional LoadableIdentityKey which was used to create
+    ///   the enrollment CSR.
+    pub fn set_cert_key(&mut self, cert_key: Option<LoadableIdentityKey>) {
+        self.cert_key = cert_key;
+    }
+
+    /// Replace the token cache used by this application.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache` - A TokenCache. By default, an InMemoryTokenCache is used,
+    ///   which is lost when the application exits.
+    pub fn set_token_cache(&mut self, cache: Arc<dyn TokenCache>) {
+        self.app.set_token_cache(cache);
+    }
+
+    /// Declare the capabilities of this application, which are sent as
+    /// claims with every token request. Declaring the `cp1` capability opts
+    /// into continuous access evaluation (CAE), and the application must then
+    /// handle claims challenges from resources.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_capabilities` - The capabilities, such as `cp1`.
+    pub fn set_client_capabilities(&mut self, client_capabilities: Vec<&str>) {
+        self.app.set_client_capabilities(client_capabilities);
+    }
+
+    /// Enable or disable validation of the authority. By default, an
+    /// authority must use https, and must be hosted by a known cloud or be
+    /// recognized by instance discovery. Only disable validation for a custom
+    /// authority which you trust, since credentials are sent to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `validate_authority` - Whether to validate the authority.
+    pub fn set_validate_authority(&mut self, validate_authority: bool) {
+        self.app.set_validate_authority(validate_authority);
+    }
+
+    /// Discover the OpenID Connect metadata of the authority, such as its
+    /// end session and JWKS endpoints. The metadata is cached by the
+    /// application, and used to locate the endpoints of every request.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The OpenID Connect metadata of the v2.0 endpoints of the
+    ///   authority.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn openid_configuration(&self) -> Result<OpenIdConfiguration, MsalError> {
+        self.app.openid_configuration().await
+    }
+
+    /// Create a validator for the ID tokens and access tokens issued by the
+    /// authority of this application. The validator caches the signing keys
+    /// of the authority, so it should be kept for validating later tokens.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A TokenValidator for the authority.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn token_validator(&self) -> Result<TokenValidator, MsalError> {
+        self.app.token_validator()
+    }
+
+    /// List the accounts which have signed in to this application, and are
+    /// present in the token cache.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A list of Accounts, which may be empty.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn get_accounts(&self) -> Result<Vec<Account>, MsalError> {
+        self.app.get_accounts()
+    }
+
+    /// Find an account in the token cache by username.
     ///
     /// # Arguments
     ///
-    /// * `cert_key` - An optional LoadableIdentityKey which was used to create
-    ///   the enrollment CSR.
-    pub fn set_cert_key(&mut self, cert_key: Option<LoadableIdentityKey>) {
-        self.cert_key = cert_key;
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The Account, or None if the account has not signed in.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn get_account(&self, username: &str) -> Result<Option<Account>, MsalError> {
+        self.app.get_account(username)
+    }
+
+    /// Sign an account out of this application, by removing the account, the
+    /// tokens cached for it and its sealed PRT from the token cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The Account to remove.
+    ///
+    /// # Returns
+    ///
+    /// * Success: ()
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn remove_account(&self, account: &Account) -> Result<(), MsalError> {
+        self.app.remove_account(account)
+    }
+
+    fn save_token(&self, scopes: &[&str], token: &UserToken) -> Result<(), MsalError> {
+        self.app.app.save_token(scopes, token)
     }
 
     /// Enroll the device in the directory.
@@ -1972,7 +5690,7 @@ impl BrokerClientApplication {
     /// # Returns
     ///
     /// * Success: A LoadableMsOapxbcRsaKey transport key, a LoadableIdentityKey certificate key,
-    /// and a `device_id`.
+    ///   and a `device_id`.
     /// * Failure: An MsalError, indicating the failure.
     pub async fn enroll_device(
         &mut self,
@@ -2071,7 +5789,7 @@ impl BrokerClientApplication {
         transport_key: &Rsa<Public>,
         csr_der: &Vec<u8>,
     ) -> Result<(X509, String), MsalError> {
-        let services = Services::new(access_token, &attrs.target_domain).await?;
+        let services = Services::new(access_token, &attrs.target_domain, &self.cloud).await?;
         services
             .enroll_device(access_token, attrs, transport_key, csr_der)
             .await
@@ -2105,6 +5823,54 @@ impl BrokerClientApplication {
         request_resource: Option<String>,
         tpm: &mut BoxedDynTpm,
         machine_key: &MachineKey,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_by_username_password_with_claims(
+            username,
+            password,
+            scopes,
+            request_resource,
+            None,
+            tpm,
+            machine_key,
+        )
+        .await
+    }
+
+    /// Gets a token for a given resource via user credentials.
+    ///
+    /// Unlike acquire_token_by_username_password, this requests the given
+    /// claims, such as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// * `scopes` - Scopes requested to access a protected API (a resource).
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// * `tpm` - The tpm object.
+    ///
+    /// * `machine_key` - The TPM MachineKey associated with this application.
+    ///
+    /// # Returns
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_username_password_with_claims(
+        &self,
+        username: &str,
+        password: &str,
+        scopes: Vec<&str>,
+        request_resource: Option<String>,
+        claims: Option<&str>,
+        tpm: &mut BoxedDynTpm,
+        machine_key: &MachineKey,
     ) -> Result<UserToken, MsalError> {
         let prt = self
             .acquire_user_prt_by_username_password_internal(username, password, tpm, machine_key)
@@ -2119,10 +5885,12 @@ impl BrokerClientApplication {
                 machine_key,
                 &session_key,
                 request_resource,
+                claims,
             )
             .await?;
         token.client_info = prt.client_info.clone();
         token.prt = Some(self.seal_user_prt(&prt, tpm, &transport_key)?);
+        self.save_token(&scopes, &token)?;
         Ok(token)
     }
 
@@ -2151,6 +5919,50 @@ impl BrokerClientApplication {
         request_resource: Option<String>,
         tpm: &mut BoxedDynTpm,
         machine_key: &MachineKey,
+    ) -> Result<UserToken, MsalError> {
+        self.acquire_token_by_refresh_token_with_claims(
+            refresh_token,
+            scopes,
+            request_resource,
+            None,
+            tpm,
+            machine_key,
+        )
+        .await
+    }
+
+    /// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
+    ///
+    /// Unlike acquire_token_by_refresh_token, this requests the given claims,
+    /// such as a claims challenge from a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `refresh_token` - The old refresh token, as a string.
+    ///
+    /// * `scopes` - The scopes associated with this old RT.
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
+    /// * `tpm` - The tpm object.
+    ///
+    /// * `machine_key` - The TPM MachineKey associated with this application.
+    ///
+    /// # Returns
+    /// * Success: A UserToken, which means migration was successful.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_by_refresh_token_with_claims(
+        &self,
+        refresh_token: &str,
+        scopes: Vec<&str>,
+        request_resource: Option<String>,
+        claims: Option<&str>,
+        tpm: &mut BoxedDynTpm,
+        machine_key: &MachineKey,
     ) -> Result<UserToken, MsalError> {
         let prt = self
             .acquire_user_prt_by_refresh_token_internal(refresh_token, tpm, machine_key)
@@ -2165,10 +5977,12 @@ impl BrokerClientApplication {
                 machine_key,
                 &session_key,
                 request_resource,
+                claims,
             )
             .await?;
         token.client_info = prt.client_info.clone();
         token.prt = Some(self.seal_user_prt(&prt, tpm, &transport_key)?);
+        self.save_token(&scopes, &token)?;
         Ok(token)
     }
 
@@ -2188,9 +6002,9 @@ impl BrokerClientApplication {
         username: &str,
         password: &str,
     ) -> Result<UserToken, MsalError> {
-        let drs_scope = "https://enrollment.manage.microsoft.com/.default";
+        let drs_scope = format!("{}.default", self.cloud.enrollment_resource());
         self.app
-            .acquire_token_by_username_password(username, password, vec![drs_scope])
+            .acquire_token_by_username_password(username, password, vec![&drs_scope])
             .await
     }
 
@@ -2215,8 +6029,8 @@ impl BrokerClientApplication {
     pub async fn initiate_device_flow_for_device_enrollment(
         &self,
     ) -> Result<DeviceAuthorizationResponse, MsalError> {
-        let drs_scope = "https://enrollment.manage.microsoft.com/.default";
-        self.app.initiate_device_flow(vec![drs_scope]).await
+        let drs_scope = format!("{}.default", self.cloud.enrollment_resource());
+        self.app.initiate_device_flow(vec![&drs_scope]).await
     }
 
     /// Obtain token for enrollment by a device flow object, with customizable
@@ -2225,7 +6039,7 @@ impl BrokerClientApplication {
     /// # Arguments
     ///
     /// * `flow` - A DeviceAuthorizationResponse previously generated by
-    /// initiate_device_flow.
+    ///   initiate_device_flow.
     ///
     /// # Returns
     ///
@@ -2238,6 +6052,36 @@ impl BrokerClientApplication {
         self.app.acquire_token_by_device_flow(flow).await
     }
 
+    /// Obtain token for enrollment by a device flow object, polling until the
+    /// user completes authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A DeviceAuthorizationResponse previously generated by
+    ///   initiate_device_flow_for_device_enrollment.
+    ///
+    /// * `cancellation` - A CancellationToken which stops polling when
+    ///   cancelled.
+    ///
+    /// * `progress` - An optional callback, called each time a poll finds
+    ///   that authentication is still pending.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A DeviceFlowOutcome, containing a UserToken if the user
+    ///   authenticated.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn poll_device_flow(
+        &self,
+        flow: &DeviceAuthorizationResponse,
+        cancellation: &CancellationToken,
+        progress: Option<&DeviceFlowProgressCallback>,
+    ) -> Result<DeviceFlowOutcome, MsalError> {
+        self.app
+            .poll_device_flow(flow, cancellation, progress)
+            .await
+    }
+
     /// Check if a user exists in Azure Entra ID
     ///
     /// # Arguments
@@ -2268,12 +6112,163 @@ impl BrokerClientApplication {
         username: &str,
         password: &str,
     ) -> Result<MFAAuthContinue, MsalError> {
-        let drs_resource = "https://enrollment.manage.microsoft.com/";
+        let drs_resource = self.cloud.enrollment_resource();
+        self.app
+            .initiate_acquire_token_by_mfa_flow(username, password, vec![], Some(&drs_resource))
+            .await
+    }
+
+    /// Initiate an MFA flow for enrollment via user credentials, without
+    /// choosing an MFA method. The MFA methods registered by the user are
+    /// listed in `mfa_methods` of the returned flow.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// * `password` - The password.
+    ///
+    /// # Returns
+    /// * Success: A MFAAuthContinue containing the MFA methods of the user.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_acquire_token_by_mfa_method_selection_for_device_enrollment(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<MFAAuthContinue, MsalError> {
+        let drs_resource = self.cloud.enrollment_resource();
+        self.app
+            .initiate_acquire_token_by_mfa_method_selection(
+                username,
+                password,
+                vec![],
+                Some(&drs_resource),
+            )
+            .await
+    }
+
+    /// Initiate a passwordless flow for enrollment, in which the user signs
+    /// in with a Temporary Access Pass, or, as a guest user, with a one time
+    /// code sent by email. The passwordless methods of the user are listed
+    /// in `mfa_methods` of the returned flow.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Typically a UPN in the form of an email address.
+    ///
+    /// # Returns
+    /// * Success: A MFAAuthContinue containing the passwordless methods of
+    ///   the user.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn initiate_acquire_token_by_passwordless_flow_for_device_enrollment(
+        &self,
+        username: &str,
+    ) -> Result<MFAAuthContinue, MsalError> {
+        let drs_resource = self.cloud.enrollment_resource();
+        self.app
+            .initiate_acquire_token_by_passwordless_flow(username, vec![], Some(&drs_resource))
+            .await
+    }
+
+    /// Change an expired password during an MFA flow. When the password is
+    /// changed, the flow continues to MFA method selection.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A MFAAuthContinue which requires a password change.
+    ///
+    /// * `old_password` - The expired password.
+    ///
+    /// * `new_password` - The new password.
+    ///
+    /// # Returns
+    /// * Success: The flow is updated with the MFA methods of the user.
+    /// * Failure: An MsalError, indicating the failure. A PasswordChangeFailed
+    ///   error describes why the password was not changed.
+    pub async fn change_password(
+        &self,
+        flow: &mut MFAAuthContinue,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), MsalError> {
+        self.app
+            .change_password(flow, old_password, new_password)
+            .await
+    }
+
+    /// Begin registering an MFA method for a user who has not registered
+    /// one.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A MFAAuthContinue which requires MFA registration.
+    ///
+    /// * `method` - The MFA method to register.
+    ///
+    /// # Returns
+    /// * Success: A MFARegistration containing the message to display to the
+    ///   user, which is passed to complete_mfa_registration with the
+    ///   verification code entered by the user.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn begin_mfa_registration(
+        &self,
+        flow: &mut MFAAuthContinue,
+        method: MFARegistrationMethod,
+    ) -> Result<MFARegistration, MsalError> {
+        self.app.begin_mfa_registration(flow, method).await
+    }
+
+    /// Complete the registration of an MFA method by verifying the code
+    /// entered by the user. When the method is registered, the flow
+    /// continues to MFA method selection.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A MFAAuthContinue which requires MFA registration.
+    ///
+    /// * `registration` - A MFARegistration previously generated by
+    ///   begin_mfa_registration.
+    ///
+    /// * `code` - The verification code entered by the user.
+    ///
+    /// # Returns
+    /// * Success: The flow is updated with the MFA methods of the user.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn complete_mfa_registration(
+        &self,
+        flow: &mut MFAAuthContinue,
+        registration: &MFARegistration,
+        code: &str,
+    ) -> Result<(), MsalError> {
         self.app
-            .initiate_acquire_token_by_mfa_flow(username, password, vec![], Some(drs_resource))
+            .complete_mfa_registration(flow, registration, code)
             .await
     }
 
+    /// Begin authentication with an MFA method chosen by the user. This may
+    /// also be called on a flow which has already begun, to switch to another
+    /// MFA method.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - A MFAAuthContinue previously generated by
+    ///   initiate_acquire_token_by_mfa_method_selection_for_device_enrollment.
+    ///
+    /// * `auth_method_id` - The `auth_method_id` of one of the `mfa_methods`
+    ///   of the flow.
+    ///
+    /// # Returns
+    /// * Success: The flow is updated with the message to display to the
+    ///   user, and may be passed to acquire_token_by_mfa_flow.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn begin_mfa_auth(
+        &self,
+        flow: &mut MFAAuthContinue,
+        auth_method_id: &str,
+    ) -> Result<(), MsalError> {
+        self.app.begin_mfa_auth(flow, auth_method_id).await
+    }
+
     /// Obtain token by a MFA flow object.
     ///
     /// # Arguments
@@ -2286,7 +6281,7 @@ impl BrokerClientApplication {
     /// * `poll_attempt` - The polling attempt number.
     ///
     /// * `flow` - A MFAAuthContinue previously generated by
-    /// initiate_acquire_token_by_mfa_flow.
+    ///   initiate_acquire_token_by_mfa_flow.
     ///
     /// # Returns
     ///
@@ -2307,7 +6302,7 @@ impl BrokerClientApplication {
     async fn request_nonce(&self) -> Result<String, MsalError> {
         let resp = self
             .client()
-            .post(format!("{}/oauth2/token", self.authority()))
+            .post(&self.app.app.openid_configuration_v1().await?.token_endpoint)
             .body("grant_type=srv_challenge")
             .send()
             .await
@@ -2363,6 +6358,27 @@ impl BrokerClientApplication {
         Ok(jwt)
     }
 
+    async fn build_jwt_by_saml_assertion(
+        &self,
+        saml_assertion: &SamlAssertion,
+    ) -> Result<Jws, MsalError> {
+        let nonce = self.request_nonce().await?;
+
+        let jwt = JwsBuilder::from(
+            serde_json::to_vec(&SamlAssertionAuthenticationPayload::new(
+                saml_assertion,
+                &nonce,
+            ))
+            .map_err(|e| {
+                MsalError::InvalidJson(format!("Failed serializing SamlAssertion JWT: {}", e))
+            })?,
+        )
+        .set_typ(Some("JWT"))
+        .build();
+
+        Ok(jwt)
+    }
+
     /// Gets a Primary Refresh Token (PRT) via user credentials.
     ///
     /// # Arguments
@@ -2401,9 +6417,23 @@ impl BrokerClientApplication {
     ) -> Result<PrimaryRefreshToken, MsalError> {
         debug!("Acquiring User PRT via Username/Password");
 
-        let jwt = self
-            .build_jwt_by_username_password(username, password, None)
-            .await?;
+        self.app.app.ensure_valid_authority().await?;
+        let jwt = match federated_assertion(
+            self.client(),
+            self.authority(),
+            username,
+            password,
+            None,
+            &self.app.app.user_realms,
+        )
+        .await?
+        {
+            Some(saml_assertion) => self.build_jwt_by_saml_assertion(&saml_assertion).await?,
+            None => {
+                self.build_jwt_by_username_password(username, password, None)
+                    .await?
+            }
+        };
         let signed_jwt = self.sign_jwt(&jwt, tpm, machine_key).await?;
 
         self.acquire_user_prt_jwt(&signed_jwt).await
@@ -2531,7 +6561,7 @@ impl BrokerClientApplication {
             .collect::<Vec<String>>()
             .join("&");
 
-        let url = format!("{}/oauth2/token", self.authority());
+        let url = &self.app.app.openid_configuration_v1().await?.token_endpoint;
 
         let mut debug_payload = params;
         debug_payload[2] = ("request", "**********");
@@ -2567,16 +6597,57 @@ impl BrokerClientApplication {
         jwt: &Jws,
         tpm: &mut BoxedDynTpm,
         machine_key: &MachineKey,
-        session_key: &SessionKey,
-    ) -> Result<String, MsalError> {
-        let transport_key = self.transport_key(tpm, machine_key)?;
-        let signed_jwt = session_key.sign(tpm, &transport_key, jwt)?;
-
-        Ok(format!("{}", signed_jwt))
+        session_key: &SessionKey,
+    ) -> Result<String, MsalError> {
+        let transport_key = self.transport_key(tpm, machine_key)?;
+        let signed_jwt = session_key.sign(tpm, &transport_key, jwt)?;
+
+        Ok(format!("{}", signed_jwt))
+    }
+
+    /// Given the primary refresh token, this method requests an access token.
+    ///
+    /// # Arguments
+    ///
+    /// * `sealed_prt` -  An encrypted primary refresh token that was
+    ///   previously received from the server.
+    ///
+    /// * `scope` - The scope that the client requests for the access token.
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    ///
+    /// * `tpm` - The tpm object.
+    ///
+    /// * `machine_key` - The TPM MachineKey associated with this application.
+    ///
+    /// # Returns
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn exchange_prt_for_access_token(
+        &self,
+        sealed_prt: &SealedData,
+        scope: Vec<&str>,
+        request_resource: Option<String>,
+        tpm: &mut BoxedDynTpm,
+        machine_key: &MachineKey,
+    ) -> Result<UserToken, MsalError> {
+        self.exchange_prt_for_access_token_with_claims(
+            sealed_prt,
+            scope,
+            request_resource,
+            None,
+            tpm,
+            machine_key,
+        )
+        .await
     }
 
     /// Given the primary refresh token, this method requests an access token.
     ///
+    /// Unlike exchange_prt_for_access_token, this requests the given claims,
+    /// such as a claims challenge from a resource.
+    ///
     /// # Arguments
     ///
     /// * `sealed_prt` -  An encrypted primary refresh token that was
@@ -2587,6 +6658,9 @@ impl BrokerClientApplication {
     /// * `request_resource` - A resource for obtaining an access token.
     ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
     ///
+    /// * `claims` - Optionally, a claims challenge from a resource, such as
+    ///   the claims returned by claims_challenge.
+    ///
     /// * `tpm` - The tpm object.
     ///
     /// * `machine_key` - The TPM MachineKey associated with this application.
@@ -2594,11 +6668,12 @@ impl BrokerClientApplication {
     /// # Returns
     /// * Success: A UserToken containing an access_token.
     /// * Failure: An MsalError, indicating the failure.
-    pub async fn exchange_prt_for_access_token(
+    pub async fn exchange_prt_for_access_token_with_claims(
         &self,
         sealed_prt: &SealedData,
         scope: Vec<&str>,
         request_resource: Option<String>,
+        claims: Option<&str>,
         tpm: &mut BoxedDynTpm,
         machine_key: &MachineKey,
     ) -> Result<UserToken, MsalError> {
@@ -2612,6 +6687,7 @@ impl BrokerClientApplication {
             machine_key,
             &session_key,
             request_resource,
+            claims,
         )
         .await
     }
@@ -2624,24 +6700,33 @@ impl BrokerClientApplication {
         machine_key: &MachineKey,
         session_key: &SessionKey,
         request_resource: Option<String>,
+        claims: Option<&str>,
     ) -> Result<UserToken, MsalError> {
         debug!("Exchanging a PRT for an Access Token");
 
         let request_id = Uuid::new_v4().to_string();
+        let claims = self.app.app.claims(claims)?;
+        let (signed_prt_payload, signed_device_payload) = self
+            .sign_prt_credentials(prt, session_key, tpm, machine_key)
+            .await?;
         let auth_code = self
-            .exchange_prt_for_auth_code(
-                prt,
+            .exchange_prt_for_auth_code_internal(
                 scope.clone(),
                 &request_id,
                 request_resource.as_deref(),
-                session_key,
-                tpm,
-                machine_key,
+                claims.as_deref(),
+                Some(signed_prt_payload),
+                Some(signed_device_payload),
             )
             .await?;
 
-        self.exchange_auth_code_for_access_token_internal(scope, &request_id, auth_code)
-            .await
+        self.exchange_auth_code_for_access_token_internal(
+            scope,
+            &request_id,
+            auth_code,
+            claims.as_deref(),
+        )
+        .await
     }
 
     /// Given the primary refresh token, this method requests a new primary
@@ -2671,12 +6756,59 @@ impl BrokerClientApplication {
     ) -> Result<SealedData, MsalError> {
         debug!("Exchanging a PRT for a new PRT");
 
+        let (nonce, aud) = self.exchange_prt_challenge().await?;
+        let (prt, session_key, signed_jwt) =
+            self.sign_exchange_prt_request(sealed_prt, tpm, machine_key, &nonce, aud)?;
+        let jwe = self.request_exchanged_prt(&signed_jwt, request_tgt).await?;
+        self.seal_exchanged_prt(&prt, &session_key, &jwe, tpm, machine_key)
+    }
+
+    /// Exchange a PRT for a new PRT, as exchange_prt_for_prt does, but lock
+    /// the shared tpm only while the PRT is unsealed, signed and sealed, and
+    /// not while waiting on the server.
+    pub(crate) async fn exchange_prt_for_prt_shared_tpm(
+        &self,
+        sealed_prt: &SealedData,
+        tpm: &Mutex<BoxedDynTpm>,
+        machine_key: &MachineKey,
+        request_tgt: bool,
+    ) -> Result<SealedData, MsalError> {
+        debug!("Exchanging a PRT for a new PRT");
+
+        let (nonce, aud) = self.exchange_prt_challenge().await?;
+        let (prt, session_key, signed_jwt) = {
+            let mut tpm = tpm.lock().await;
+            self.sign_exchange_prt_request(sealed_prt, &mut tpm, machine_key, &nonce, aud)?
+        };
+        let jwe = self.request_exchanged_prt(&signed_jwt, request_tgt).await?;
+        let mut tpm = tpm.lock().await;
+        self.seal_exchanged_prt(&prt, &session_key, &jwe, &mut tpm, machine_key)
+    }
+
+    async fn exchange_prt_challenge(&self) -> Result<(String, String), MsalError> {
+        let nonce = self.request_nonce().await?;
+        let aud = self
+            .app
+            .app
+            .openid_configuration_v1()
+            .await?
+            .token_endpoint_host()?;
+        Ok((nonce, aud))
+    }
+
+    fn sign_exchange_prt_request(
+        &self,
+        sealed_prt: &SealedData,
+        tpm: &mut BoxedDynTpm,
+        machine_key: &MachineKey,
+        nonce: &str,
+        aud: String,
+    ) -> Result<(PrimaryRefreshToken, SessionKey, String), MsalError> {
         let transport_key = self.transport_key(tpm, machine_key)?;
         let prt = self.unseal_user_prt(sealed_prt, tpm, &transport_key)?;
         let session_key = prt.session_key()?;
-        let nonce = self.request_nonce().await?;
         let jwt = JwsBuilder::from(
-            serde_json::to_vec(&ExchangePRTPayload::new(&prt, &nonce, None, true)?).map_err(
+            serde_json::to_vec(&ExchangePRTPayload::new(&prt, nonce, None, true, aud)?).map_err(
                 |e| MsalError::InvalidJson(format!("Failed serializing ExchangePRT JWT: {}", e)),
             )?,
         )
@@ -2690,14 +6822,19 @@ impl BrokerClientApplication {
             }
         }
 
-        let signed_jwt = self
-            .sign_session_key_jwt(&jwt, tpm, machine_key, &session_key)
-            .await?;
+        let signed_jwt = format!("{}", session_key.sign(tpm, &transport_key, &jwt)?);
+        Ok((prt, session_key, signed_jwt))
+    }
 
+    async fn request_exchanged_prt(
+        &self,
+        signed_jwt: &str,
+        request_tgt: bool,
+    ) -> Result<JweCompact, MsalError> {
         let mut params = vec![
             ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
             ("windows_api_version", "2.2"),
-            ("request", &signed_jwt),
+            ("request", signed_jwt),
             ("client_info", "1"),
         ];
         if request_tgt {
@@ -2709,7 +6846,7 @@ impl BrokerClientApplication {
             .collect::<Vec<String>>()
             .join("&");
 
-        let url = format!("{}/oauth2/token", self.authority());
+        let url = &self.app.app.openid_configuration_v1().await?.token_endpoint;
 
         let mut debug_payload = params.clone();
         debug_payload[2] = ("request", "**********");
@@ -2730,19 +6867,7 @@ impl BrokerClientApplication {
                 .text()
                 .await
                 .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
-            let jwe = JweCompact::from_str(&enc)
-                .map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
-            let mut new_prt: PrimaryRefreshToken = json_from_str(
-                std::str::from_utf8(
-                    session_key
-                        .decipher_prt_v2(tpm, &transport_key, &jwe)?
-                        .payload(),
-                )
-                .map_err(|e| MsalError::InvalidParse(format!("{}", e)))?,
-            )
-            .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
-            prt.clone_session_key(&mut new_prt);
-            self.seal_user_prt(&new_prt, tpm, &transport_key)
+            JweCompact::from_str(&enc).map_err(|e| MsalError::InvalidParse(format!("{}", e)))
         } else {
             let json_resp: ErrorResponse = resp
                 .json()
@@ -2752,6 +6877,55 @@ impl BrokerClientApplication {
         }
     }
 
+    fn seal_exchanged_prt(
+        &self,
+        prt: &PrimaryRefreshToken,
+        session_key: &SessionKey,
+        jwe: &JweCompact,
+        tpm: &mut BoxedDynTpm,
+        machine_key: &MachineKey,
+    ) -> Result<SealedData, MsalError> {
+        let transport_key = self.transport_key(tpm, machine_key)?;
+        let mut new_prt: PrimaryRefreshToken = json_from_str(
+            std::str::from_utf8(
+                session_key
+                    .decipher_prt_v2(tpm, &transport_key, jwe)?
+                    .payload(),
+            )
+            .map_err(|e| MsalError::InvalidParse(format!("{}", e)))?,
+        )
+        .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+        prt.clone_session_key(&mut new_prt);
+        self.seal_user_prt(&new_prt, tpm, &transport_key)
+    }
+
+    /// Inspect the non-secret metadata of a primary refresh token, such as
+    /// the user it belongs to and when it expires. No request is made to the
+    /// server.
+    ///
+    /// # Arguments
+    ///
+    /// * `sealed_prt` -  An encrypted primary refresh token that was
+    ///   previously received from the server.
+    ///
+    /// * `tpm` - The tpm object.
+    ///
+    /// * `machine_key` - The TPM MachineKey associated with this application.
+    ///
+    /// # Returns
+    /// * Success: A PrtInfo describing the PRT.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn inspect_prt(
+        &self,
+        sealed_prt: &SealedData,
+        tpm: &mut BoxedDynTpm,
+        machine_key: &MachineKey,
+    ) -> Result<PrtInfo, MsalError> {
+        let transport_key = self.transport_key(tpm, machine_key)?;
+        let prt = self.unseal_user_prt(sealed_prt, tpm, &transport_key)?;
+        prt.info()
+    }
+
     /// Provision a new Hello for Business Key
     ///
     /// # Arguments
@@ -2791,7 +6965,7 @@ impl BrokerClientApplication {
                 ))
             }
         };
-        let services = Services::new(&access_token, &token.tenant_id()?).await?;
+        let services = Services::new(&access_token, &token.tenant_id()?, &self.cloud).await?;
         let resource_id = services.key_provisioning_resource_id();
 
         // Acquire an access token for the key provisioning service
@@ -2896,10 +7070,12 @@ impl BrokerClientApplication {
                 machine_key,
                 &session_key,
                 request_resource,
+                None,
             )
             .await?;
         token.client_info = prt.client_info.clone();
         token.prt = Some(self.seal_user_prt(&prt, tpm, &transport_key)?);
+        self.save_token(&scopes, &token)?;
         Ok(token)
     }
 
@@ -3059,12 +7235,13 @@ impl BrokerClientApplication {
         scope: Vec<&str>,
         request_id: &str,
         resource: Option<&str>,
+        claims: Option<&str>,
         signed_prt_payload: Option<String>,
         signed_device_payload: Option<String>,
     ) -> Result<String, MsalError> {
         let scope = format!("openid profile {}", scope.join(" "));
 
-        let params = [
+        let mut params = vec![
             ("client_id", self.app.client_id()),
             ("response_type", "code"),
             ("redirect_uri", "urn:ietf:wg:oauth:2.0:oob"),
@@ -3075,13 +7252,24 @@ impl BrokerClientApplication {
                 (resource.unwrap_or("00000002-0000-0000-c000-000000000000")),
             ),
         ];
+        if let Some(claims) = claims {
+            params.push(("claims", claims));
+        }
         let payload = params
             .iter()
             .map(|(k, v)| format!("{}={}", k, url_encode(v)))
             .collect::<Vec<String>>()
             .join("&");
 
-        let url = format!("{}/oauth2/authorize?{}", self.authority(), payload);
+        let url = format!(
+            "{}?{}",
+            self.app
+                .app
+                .openid_configuration_v1()
+                .await?
+                .authorization_endpoint,
+            payload
+        );
         debug!("GET {}", url);
 
         let mut req = self.client().get(url).header(header::USER_AGENT, "");
@@ -3106,13 +7294,26 @@ impl BrokerClientApplication {
                 if let Some(redirect) = m.get(1) {
                     let redirect_decoded = Url::parse(&redirect.as_str().replace(r#"\u0026"#, "&"))
                         .map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
-                    for (k, v) in redirect_decoded.query_pairs().collect::<Vec<_>>() {
-                        if k == "code" {
-                            return Ok(v.to_string());
-                        }
-                        if k == "error_description" {
-                            return Err(MsalError::GeneralFailure(v.to_string()));
-                        }
+                    let param = |name: &str| {
+                        redirect_decoded
+                            .query_pairs()
+                            .find(|(k, _)| k == name)
+                            .map(|(_, v)| v.to_string())
+                    };
+                    if let Some(code) = param("code") {
+                        return Ok(code);
+                    }
+                    if let Some(error) = param("error") {
+                        return Err(MsalError::AcquireTokenFailed(ErrorResponse {
+                            error,
+                            error_description: param("error_description").unwrap_or_default(),
+                            error_codes: vec![],
+                            claims: param("claims"),
+                            suberror: param("suberror"),
+                        }));
+                    }
+                    if let Some(error_description) = param("error_description") {
+                        return Err(MsalError::GeneralFailure(error_description));
                     }
                 }
             }
@@ -3128,16 +7329,15 @@ impl BrokerClientApplication {
         }
     }
 
-    async fn exchange_prt_for_auth_code(
+    /// Sign the PRT and device credentials which authenticate a request for
+    /// an authorization code.
+    async fn sign_prt_credentials(
         &self,
         prt: &PrimaryRefreshToken,
-        scope: Vec<&str>,
-        request_id: &str,
-        resource: Option<&str>,
         session_key: &SessionKey,
         tpm: &mut BoxedDynTpm,
         machine_key: &MachineKey,
-    ) -> Result<String, MsalError> {
+    ) -> Result<(String, String), MsalError> {
         debug!("Exchanging a PRT for an Authorization Code");
 
         let nonce = self.request_nonce().await?;
@@ -3173,14 +7373,7 @@ impl BrokerClientApplication {
             }
         }
 
-        self.exchange_prt_for_auth_code_internal(
-            scope,
-            request_id,
-            resource,
-            Some(signed_prt_payload),
-            Some(signed_device_payload),
-        )
-        .await
+        Ok((signed_prt_payload, signed_device_payload))
     }
 
     async fn exchange_auth_code_for_access_token_internal(
@@ -3188,12 +7381,13 @@ impl BrokerClientApplication {
         scope: Vec<&str>,
         request_id: &str,
         authorization_code: String,
+        claims: Option<&str>,
     ) -> Result<UserToken, MsalError> {
         debug!("Exchanging an Authorization Code for an Access Token");
 
         let scopes_str = scope.join(" ");
 
-        let params = [
+        let mut params = vec![
             ("client_id", self.app.client_id()),
             ("grant_type", "authorization_code"),
             ("code", &authorization_code),
@@ -3201,13 +7395,16 @@ impl BrokerClientApplication {
             ("redirect_uri", "urn:ietf:wg:oauth:2.0:oob"),
             ("client-request-id", request_id),
         ];
+        if let Some(claims) = claims {
+            params.push(("claims", claims));
+        }
         let payload = params
             .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
+            .map(|(k, v)| format!("{}={}", k, url_encode(v)))
             .collect::<Vec<String>>()
             .join("&");
 
-        let url = format!("{}/oauth2/token", self.authority());
+        let url = &self.app.app.openid_configuration_v1().await?.token_endpoint;
         let mut debug_payload = params;
         debug_payload[2] = ("code", "**********");
         if let Ok(pretty) = to_string_pretty(&debug_payload) {
@@ -3223,10 +7420,11 @@ impl BrokerClientApplication {
             .await
             .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
         if resp.status().is_success() {
-            let token: UserToken = resp
+            let mut token: UserToken = resp
                 .json()
                 .await
                 .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            token.stamp_expiry()?;
 
             Ok(token)
         } else {
@@ -3263,3 +7461,623 @@ impl BrokerClientApplication {
             .map_err(|e| MsalError::InvalidJson(format!("Failed deserializing PRT {:?}", e)))
     }
 }
+
+/// Holds a UserToken acquired by a BrokerClientApplication, and renews it
+/// using its PRT (or its refresh token, if it has no PRT) when it is close
+/// to expiry.
+#[cfg(feature = "broker")]
+pub struct BrokerTokenProvider<'a> {
+    app: &'a BrokerClientApplication,
+    scopes: Vec<String>,
+    request_resource: Option<String>,
+    token: Mutex<UserToken>,
+}
+
+#[cfg(feature = "broker")]
+impl<'a> BrokerTokenProvider<'a> {
+    /// Create a token provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application which acquired the token.
+    ///
+    /// * `token` - A UserToken containing a prt or a refresh_token.
+    ///
+    /// * `scopes` - Scopes requested when renewing the token.
+    ///
+    /// * `request_resource` - A resource for obtaining an access token.
+    ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+    pub fn new(
+        app: &'a BrokerClientApplication,
+        mut token: UserToken,
+        scopes: Vec<&str>,
+        request_resource: Option<String>,
+    ) -> Self {
+        // A failure to read the clock leaves the token due for refresh.
+        let _ = token.stamp_expiry();
+        BrokerTokenProvider {
+            app,
+            scopes: scopes.into_iter().map(|s| s.to_string()).collect(),
+            request_resource,
+            token: Mutex::new(token),
+        }
+    }
+
+    /// Fetch a fresh token. If the held token needs to be refreshed, it is
+    /// renewed using its PRT, or its refresh token.
+    ///
+    /// # Arguments
+    ///
+    /// * `tpm` - The tpm object.
+    ///
+    /// * `machine_key` - The TPM MachineKey associated with this application.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn token(
+        &self,
+        tpm: &mut BoxedDynTpm,
+        machine_key: &MachineKey,
+    ) -> Result<UserToken, MsalError> {
+        let mut token = self.token.lock().await;
+        if token.needs_refresh() {
+            let scopes = self.scopes.iter().map(|s| s.as_str()).collect();
+            let new_token = match &token.prt {
+                Some(prt) => {
+                    debug!("Renewing the token using the PRT");
+                    let mut new_token = self
+                        .app
+                        .exchange_prt_for_access_token(
+                            prt,
+                            scopes,
+                            self.request_resource.clone(),
+                            tpm,
+                            machine_key,
+                        )
+                        .await?;
+                    new_token.prt = token.prt.clone();
+                    new_token.client_info = token.client_info.clone();
+                    new_token
+                }
+                None if !token.refresh_token.is_empty() => {
+                    debug!("Renewing the token using the refresh token");
+                    self.app
+                        .acquire_token_by_refresh_token(
+                            &token.refresh_token,
+                            scopes,
+                            self.request_resource.clone(),
+                            tpm,
+                            machine_key,
+                        )
+                        .await?
+                }
+                None => {
+                    return Err(MsalError::GeneralFailure(
+                        "The token cannot be renewed without a PRT or refresh token".to_string(),
+                    ))
+                }
+            };
+            *token = new_token;
+        }
+        Ok(token.clone())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::test_server::{
+        openid_configuration, self_signed_certificate_for, serve, serve_tls, user_token_response,
+        Response,
+    };
+
+    const CONVERGED_MFA: &str = include_str!("../tests/fixtures/mfa/converged_mfa.html");
+
+    fn user_proofs() -> Vec<MFAMethod> {
+        let app = PublicClientApplication::new("client_id", None).unwrap();
+        let auth_config = app.parse_auth_config(CONVERGED_MFA).unwrap();
+        auth_config.arr_user_proofs.unwrap()
+    }
+
+    fn method(auth_method_id: &str) -> MFAMethod {
+        user_proofs()
+            .into_iter()
+            .find(|method| method.auth_method_id == auth_method_id)
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_converged_mfa_config() {
+        let app = PublicClientApplication::new("client_id", None).unwrap();
+        let auth_config = app.parse_auth_config(CONVERGED_MFA).unwrap();
+        assert_eq!(auth_config.pgid.as_deref(), Some("ConvergedTFA"));
+        assert_eq!(
+            auth_config.url_begin_auth.as_deref(),
+            Some("https://login.microsoftonline.com/common/SAS/BeginAuth")
+        );
+        assert_eq!(auth_config.max_poll_attempts, Some(20));
+        let proofs = auth_config.arr_user_proofs.unwrap();
+        assert_eq!(proofs.len(), 5);
+        assert!(proofs[0].is_default);
+    }
+
+    #[test]
+    fn supported_mfa_methods() {
+        let supported: Vec<String> = user_proofs()
+            .into_iter()
+            .filter(MFAMethod::is_supported)
+            .map(|method| method.auth_method_id)
+            .collect();
+        assert_eq!(
+            supported,
+            vec![
+                "PhoneAppNotification",
+                "PhoneAppOTP",
+                "HardwareOTP",
+                "OneWaySMS",
+                "TwoWayVoiceMobile"
+            ]
+        );
+    }
+
+    #[test]
+    fn mfa_methods_requiring_input() {
+        assert!(!method("PhoneAppNotification").requires_input());
+        assert!(method("PhoneAppOTP").requires_input());
+        assert!(method("HardwareOTP").requires_input());
+        assert!(method("OneWaySMS").requires_input());
+        assert!(!method("TwoWayVoiceMobile").requires_input());
+    }
+
+    #[test]
+    fn mfa_method_prompts() {
+        assert_eq!(
+            method("PhoneAppNotification").msg(42).unwrap(),
+            "Open your Authenticator app, and enter the number '42' to sign in."
+        );
+        assert_eq!(
+            method("HardwareOTP").msg(0).unwrap(),
+            "Please type in the code displayed on your hardware token:"
+        );
+        assert_eq!(
+            method("OneWaySMS").msg(0).unwrap(),
+            "We texted your phone +X XXXXXXXX42. Please enter the code to sign in:"
+        );
+        let email = MFAMethod {
+            auth_method_id: EMAIL_OTP_METHOD.to_string(),
+            is_default: true,
+            display: "g****@fabrikam.com".to_string(),
+        };
+        assert_eq!(
+            email.msg(0).unwrap(),
+            "We emailed a code to g****@fabrikam.com. Please enter the code to sign in:"
+        );
+        assert!(email.requires_input());
+        let unknown = MFAMethod {
+            auth_method_id: "FidoKey".to_string(),
+            is_default: false,
+            display: String::new(),
+        };
+        assert!(unknown.msg(0).is_err());
+    }
+
+    #[test]
+    fn parse_begin_auth_responses() {
+        let notification: AuthResponse = json_from_str(include_str!(
+            "../tests/fixtures/mfa/begin_auth_notification.json"
+        ))
+        .unwrap();
+        assert!(notification.success);
+        assert_eq!(notification.entropy, 42);
+        assert_eq!(notification.ctx, "3c8a1f2e-begin-ctx");
+
+        // Only number matching returns an entropy
+        let otp: AuthResponse =
+            json_from_str(include_str!("../tests/fixtures/mfa/begin_auth_otp.json")).unwrap();
+        assert!(otp.success);
+        assert_eq!(otp.entropy, 0);
+    }
+
+    #[test]
+    fn parse_end_auth_responses() {
+        let pending: AuthResponse =
+            json_from_str(include_str!("../tests/fixtures/mfa/end_auth_pending.json")).unwrap();
+        assert!(!pending.success);
+        assert_eq!(pending.retry, Some(true));
+
+        let invalid: AuthResponse = json_from_str(include_str!(
+            "../tests/fixtures/mfa/end_auth_invalid_code.json"
+        ))
+        .unwrap();
+        assert!(!invalid.success);
+        assert_eq!(invalid.retry, Some(false));
+        assert_eq!(
+            invalid.message.as_deref(),
+            Some("The code you entered is incorrect.")
+        );
+    }
+
+    #[test]
+    fn user_token_keeps_refresh_on_when_stored() {
+        let mut token: UserToken = json_from_str(
+            r#"{
+                "token_type": "Bearer",
+                "expires_in": "3600",
+                "refresh_in": 1800,
+                "access_token": "access_token",
+                "refresh_token": "refresh_token"
+            }"#,
+        )
+        .unwrap();
+        token.stamp_expiry().unwrap();
+        let issued_on = token.expires_on - 3600;
+        assert_eq!(token.refresh_on, issued_on + 1800);
+
+        // A stored token is restored as it was stored
+        let stored = serde_json::to_value(&token).unwrap();
+        let mut restored: UserToken = serde_json::from_value(stored.clone()).unwrap();
+        assert_eq!(restored.expires_on, token.expires_on);
+        assert_eq!(restored.refresh_on, token.refresh_on);
+        restored.stamp_expiry().unwrap();
+        assert_eq!(restored.refresh_on, token.refresh_on);
+
+        // A token stored without refresh_on has it recomputed
+        let mut legacy = stored;
+        legacy.as_object_mut().unwrap().remove("refresh_on");
+        let mut restored: UserToken = serde_json::from_value(legacy).unwrap();
+        assert_eq!(restored.refresh_on, 0);
+        restored.stamp_expiry().unwrap();
+        assert_eq!(restored.refresh_on, issued_on + 1800);
+        assert!(!restored.needs_refresh());
+    }
+
+    fn sspr_response(fixture: &str) -> SsprResponse {
+        json_from_str(fixture).unwrap()
+    }
+
+    #[test]
+    fn password_change_errors() {
+        let error = sspr_response(include_str!(
+            "../tests/fixtures/sspr/incorrect_password.json"
+        ))
+        .password_change_error();
+        assert!(matches!(
+            error,
+            Some(PasswordChangeError::IncorrectPassword(msg))
+                if msg == "Your current password is incorrect."
+        ));
+
+        let error = sspr_response(include_str!(
+            "../tests/fixtures/sspr/password_in_history.json"
+        ))
+        .password_change_error();
+        assert!(matches!(
+            error,
+            Some(PasswordChangeError::PolicyViolation(msg))
+                if msg.starts_with("You can't reuse a recent password.")
+        ));
+
+        // An unknown error is not reported as a policy violation
+        let error = sspr_response(include_str!(
+            "../tests/fixtures/sspr/service_unavailable.json"
+        ))
+        .password_change_error();
+        assert!(matches!(
+            error,
+            Some(PasswordChangeError::Failed(msg))
+                if msg.starts_with("We're having trouble changing your password.")
+        ));
+
+        let completed = sspr_response(include_str!("../tests/fixtures/sspr/completed.json"));
+        assert!(completed.is_completed);
+        assert!(completed.password_change_error().is_none());
+    }
+
+    fn mfa_flow(mfa_method: &str) -> MFAAuthContinue {
+        serde_json::from_value(json!({
+            "mfa_method": mfa_method,
+            "msg": "",
+            "session_id": "session_id",
+            "flow_token": "flow_token",
+            "ctx": "ctx",
+            "canary": "canary",
+            "url_end_auth": "",
+            "url_post": "https://login.microsoftonline.com/common/login",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn certificate_signs_rs256_client_assertion() {
+        let key = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let certificate = self_signed_certificate_for(&key);
+        let credential = ClientCredential::from_certificate_pem(
+            &certificate.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+            None,
+        )
+        .unwrap();
+        let audience = "https://login.microsoftonline.com/tenant/oauth2/v2.0/token";
+        let params = credential.params("client_id", audience).await.unwrap();
+        assert_eq!(
+            params[0],
+            ("client_assertion_type", CLIENT_ASSERTION_TYPE.to_string())
+        );
+        let assertion = &params[1].1;
+        let parts: Vec<&str> = assertion.split('.').collect();
+        assert_eq!(parts.len(), 3);
+
+        let header: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[0]).unwrap()).unwrap();
+        let der = certificate.to_der().unwrap();
+        let sha256 = hash(MessageDigest::sha256(), &der).unwrap();
+        let sha1 = hash(MessageDigest::sha1(), &der).unwrap();
+        assert_eq!(header["alg"], "RS256");
+        assert_eq!(header["x5t#S256"], URL_SAFE_NO_PAD.encode(sha256));
+        assert_eq!(header["x5t"], URL_SAFE_NO_PAD.encode(sha1));
+
+        let claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        assert_eq!(claims["aud"], audience);
+        assert_eq!(claims["iss"], "client_id");
+        assert_eq!(claims["sub"], "client_id");
+
+        let public_key = certificate.public_key().unwrap();
+        let mut verifier =
+            openssl::sign::Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+        let input = format!("{}.{}", parts[0], parts[1]);
+        let signature = URL_SAFE_NO_PAD.decode(parts[2]).unwrap();
+        assert!(verifier
+            .verify_oneshot(&signature, input.as_bytes())
+            .unwrap());
+    }
+
+    #[test]
+    fn certificate_requires_rsa_key() {
+        let group =
+            openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(openssl::ec::EcKey::generate(&group).unwrap()).unwrap();
+        let certificate = self_signed_certificate_for(&key);
+        let res = ClientCredential::from_certificate_pem(
+            &certificate.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+            None,
+        );
+        assert!(matches!(res, Err(MsalError::CryptoFail(_))));
+    }
+
+    #[tokio::test]
+    async fn client_credentials_require_scopes_and_tenant() {
+        let app = ConfidentialClientApplication::new(
+            "client_id",
+            Some("https://login.microsoftonline.com/contoso.onmicrosoft.com"),
+            ClientCredential::from_secret("secret"),
+        )
+        .unwrap();
+        let res = app.acquire_token_for_client(vec![]).await;
+        assert!(matches!(res, Err(MsalError::ConfigError(_))));
+        let res = app
+            .acquire_token_for_client(vec!["https://graph.microsoft.com/User.Read"])
+            .await;
+        assert!(matches!(res, Err(MsalError::ConfigError(_))));
+
+        for tenant in ["common", "organizations", "Consumers"] {
+            let authority = format!("https://login.microsoftonline.com/{}", tenant);
+            let app = ConfidentialClientApplication::new(
+                "client_id",
+                Some(&authority),
+                ClientCredential::from_secret("secret"),
+            )
+            .unwrap();
+            let res = app
+                .acquire_token_for_client(vec!["https://graph.microsoft.com/.default"])
+                .await;
+            assert!(matches!(res, Err(MsalError::InvalidAuthority(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn mfa_flow_rejects_password_change() {
+        let app = PublicClientApplication::new("client_id", None).unwrap();
+        let mut flow = mfa_flow(CHANGE_PASSWORD_METHOD);
+        let res = app
+            .acquire_token_by_mfa_flow("user@contoso.com", None, None, &mut flow)
+            .await;
+        assert!(
+            matches!(res, Err(MsalError::GeneralFailure(msg)) if msg.contains("change_password"))
+        );
+    }
+
+    #[tokio::test]
+    async fn mfa_flow_requires_registration() {
+        let app = PublicClientApplication::new("client_id", None).unwrap();
+        let auth_config = app
+            .parse_auth_config(include_str!(
+                "../tests/fixtures/proofup/converged_proof_up.html"
+            ))
+            .unwrap();
+        let mut flow = app
+            .mfa_flow_from_auth_config_internal(auth_config, "request_id")
+            .await
+            .unwrap();
+        assert!(flow.requires_mfa_registration());
+        assert_eq!(flow.mfa_registration_days_remaining, Some(0));
+        assert_eq!(
+            flow.url_proof_up.as_deref(),
+            Some("https://mysignins.microsoft.com/register?csrf_token=proofup")
+        );
+        assert!(flow.url_begin_auth.is_empty());
+        assert!(flow.url_end_auth.is_empty());
+
+        let res = app
+            .acquire_token_by_mfa_flow("user@contoso.com", Some("123456"), None, &mut flow)
+            .await;
+        assert!(
+            matches!(res, Err(MsalError::GeneralFailure(msg)) if msg.contains("begin_mfa_registration"))
+        );
+    }
+
+    #[test]
+    fn parse_security_info_responses() {
+        let init: SecurityInfoResponse = json_from_str(include_str!(
+            "../tests/fixtures/proofup/initialize_mobile_app.json"
+        ))
+        .unwrap();
+        assert!(service_error_code(&init.error_code).is_none());
+        assert_eq!(init.secret_key.as_deref(), Some("JBSWY3DPEHPK3PXP"));
+        assert_eq!(init.account_name.as_deref(), Some("jdoe@contoso.com"));
+        assert_eq!(init.issuer.as_deref(), Some("Contoso"));
+
+        let add: SecurityInfoResponse = json_from_str(include_str!(
+            "../tests/fixtures/proofup/add_security_info.json"
+        ))
+        .unwrap();
+        assert_eq!(
+            add.verification_context.as_deref(),
+            Some("c2a8e5f4-verification-ctx")
+        );
+
+        let invalid: SecurityInfoResponse = json_from_str(include_str!(
+            "../tests/fixtures/proofup/verify_security_info_invalid_code.json"
+        ))
+        .unwrap();
+        assert_eq!(
+            service_error_code(&invalid.error_code).as_deref(),
+            Some("InvalidVerificationCode")
+        );
+    }
+
+    #[test]
+    fn merge_claims_with_client_capabilities() {
+        let capabilities = vec!["CP1".to_string()];
+        let challenge = r#"{"access_token":{"nbf":{"essential":true,"value":"1604106651"}}}"#;
+        let merged: Value = json_from_str(
+            &merge_claims(Some(challenge), &capabilities)
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            merged,
+            json!({
+                "access_token": {
+                    "nbf": {"essential": true, "value": "1604106651"},
+                    "xms_cc": {"values": ["CP1"]},
+                }
+            })
+        );
+
+        // Claims of the id_token are kept beside the capabilities
+        let merged: Value = json_from_str(
+            &merge_claims(
+                Some(r#"{"id_token":{"auth_time":{"essential":true}}}"#),
+                &capabilities,
+            )
+            .unwrap()
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            merged,
+            json!({
+                "id_token": {"auth_time": {"essential": true}},
+                "access_token": {"xms_cc": {"values": ["CP1"]}},
+            })
+        );
+
+        let merged: Value =
+            json_from_str(&merge_claims(None, &capabilities).unwrap().unwrap()).unwrap();
+        assert_eq!(
+            merged,
+            json!({"access_token": {"xms_cc": {"values": ["CP1"]}}})
+        );
+
+        // Without capabilities, the claims are passed through unchanged
+        assert_eq!(
+            merge_claims(Some(challenge), &[]).unwrap().as_deref(),
+            Some(challenge)
+        );
+        assert_eq!(merge_claims(None, &[]).unwrap(), None);
+
+        assert!(merge_claims(Some("[]"), &capabilities).is_err());
+        assert!(merge_claims(Some(r#"{"access_token":[]}"#), &capabilities).is_err());
+        assert!(merge_claims(Some("not json"), &capabilities).is_err());
+    }
+
+    #[tokio::test]
+    async fn federated_user_signs_in_with_saml_assertion() {
+        const RSTR_13_SAML11: &str = include_str!("../tests/fixtures/wstrust/rstr_13_saml11.xml");
+        let (sts_url, sts_requests) = serve_tls(|_, _| Response::html(RSTR_13_SAML11));
+        let endpoint = format!("{}/adfs/services/trust/13/usernamemixed", sts_url);
+        let (base_url, requests) = serve(move |request, base| match request.path() {
+            "/tenant/v2.0/.well-known/openid-configuration" => {
+                openid_configuration(&format!("{}/tenant", base))
+            }
+            "/common/userrealm/jdoe@contoso.com" => Response::json(json!({
+                "ver": "1.0",
+                "account_type": "Federated",
+                "domain_name": "contoso.com",
+                "federation_protocol": "WSTrust",
+                "federation_active_auth_url": endpoint,
+                "cloud_audience_urn": "urn:federation:MicrosoftOnline",
+            })),
+            "/tenant/oauth2/v2.0/token" => user_token_response("federated-access-token"),
+            _ => Response::status(404, json!({})),
+        })
+        .await;
+        let mut app =
+            PublicClientApplication::new("client_id", Some(&format!("{}/tenant", base_url)))
+                .unwrap();
+        app.set_validate_authority(false);
+        // The identity provider has a self-signed certificate
+        app.app.client = Client::builder()
+            .cookie_store(true)
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        for _ in 0..2 {
+            let token = app
+                .acquire_token_by_username_password(
+                    "jdoe@contoso.com",
+                    "password",
+                    vec!["https://graph.microsoft.com/User.Read"],
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                token.access_token.as_deref(),
+                Some("federated-access-token")
+            );
+        }
+
+        let requests = requests.lock().unwrap();
+        let grants: Vec<_> = requests
+            .iter()
+            .filter(|request| request.path() == "/tenant/oauth2/v2.0/token")
+            .collect();
+        assert_eq!(grants.len(), 2);
+        assert_eq!(
+            grants[0].param("grant_type").as_deref(),
+            Some("urn:ietf:params:oauth:grant-type:saml1_1-bearer")
+        );
+        let assertion = base64::engine::general_purpose::STANDARD
+            .decode(grants[0].param("assertion").unwrap())
+            .unwrap();
+        assert!(String::from_utf8(assertion)
+            .unwrap()
+            .starts_with("<saml:Assertion "));
+        // The password is only sent to the identity provider
+        assert!(grants[0].param("password").is_none());
+        assert_eq!(sts_requests.lock().unwrap().len(), 2);
+        // The user realm of the domain is discovered once
+        let realms = requests
+            .iter()
+            .filter(|request| request.path().starts_with("/common/userrealm/"))
+            .count();
+        assert_eq!(realms, 1);
+    }
+}
@@ -0,0 +1,467 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cloud::KNOWN_LOGIN_HOSTS;
+use crate::error::MsalError;
+use reqwest::{header, Client, Url};
+use serde::Deserialize;
+use tracing::debug;
+
+/// The instance discovery endpoint, which validates authorities which are
+/// not hosted by a known cloud.
+const INSTANCE_DISCOVERY_ENDPOINT: &str =
+    "https://login.microsoftonline.com/common/discovery/instance";
+const INSTANCE_DISCOVERY_API_VERSION: &str = "1.1";
+
+/// The host suffixes of Azure AD B2C tenants.
+const B2C_HOST_SUFFIXES: [&str; 3] = [".b2clogin.com", ".b2clogin.cn", ".b2clogin.us"];
+
+/// The host suffix of Microsoft Entra External ID (CIAM) tenants.
+const CIAM_HOST_SUFFIX: &str = ".ciamlogin.com";
+
+/// The path segment which precedes the tenant in a B2C authority.
+const B2C_TFP_SEGMENT: &str = "tfp";
+
+/// The tenants of an authority which name an audience of accounts rather
+/// than a specific tenant.
+const TENANTLESS_AUDIENCES: [&str; 3] = ["common", "organizations", "consumers"];
+
+/// The kind of identity provider which an authority belongs to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuthorityType {
+    /// A Microsoft Entra ID tenant.
+    #[default]
+    Aad,
+    /// An Azure AD B2C tenant, such as
+    /// `https://contoso.b2clogin.com/tfp/contoso.onmicrosoft.com/B2C_1_signin`.
+    /// The authority names the user flow (policy) of the tenant.
+    B2C,
+    /// A Microsoft Entra External ID tenant, such as
+    /// `https://contoso.ciamlogin.com`.
+    Ciam,
+}
+
+impl AuthorityType {
+    /// Find the kind of identity provider of an authority. A B2C authority
+    /// is recognized by its b2clogin host, or on a custom domain by its
+    /// `tfp` path.
+    ///
+    /// # Arguments
+    ///
+    /// * `authority` - A URL that identifies a token authority.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The kind of identity provider of the authority.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn from_authority(authority: &str) -> Result<Self, MsalError> {
+        let url = Url::parse(authority)
+            .map_err(|e| MsalError::InvalidAuthority(format!("{}: {}", authority, e)))?;
+        Ok(authority_type(&url))
+    }
+}
+
+fn authority_type(url: &Url) -> AuthorityType {
+    let host = url.host_str().unwrap_or_default();
+    if is_b2c_host(host) || first_segment(url).eq_ignore_ascii_case(B2C_TFP_SEGMENT) {
+        AuthorityType::B2C
+    } else if is_ciam_host(host) {
+        AuthorityType::Ciam
+    } else {
+        AuthorityType::Aad
+    }
+}
+
+fn ends_with_ignore_case(host: &str, suffix: &str) -> bool {
+    host.len() > suffix.len()
+        && host
+            .get(host.len() - suffix.len()..)
+            .map(|end| end.eq_ignore_ascii_case(suffix))
+            .unwrap_or(false)
+}
+
+fn is_b2c_host(host: &str) -> bool {
+    B2C_HOST_SUFFIXES
+        .iter()
+        .any(|suffix| ends_with_ignore_case(host, suffix))
+}
+
+fn is_ciam_host(host: &str) -> bool {
+    ends_with_ignore_case(host, CIAM_HOST_SUFFIX)
+}
+
+fn first_segment(url: &Url) -> &str {
+    url.path_segments()
+        .and_then(|mut segments| segments.next())
+        .unwrap_or_default()
+}
+
+/// The path segments of an authority which follow the host, without the
+/// `tfp` segment of a B2C authority. The first is the tenant, and in a B2C
+/// authority the second is the policy.
+fn tenant_segments(url: &Url) -> Vec<&str> {
+    let mut segments: Vec<&str> = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    if segments
+        .first()
+        .map(|s| s.eq_ignore_ascii_case(B2C_TFP_SEGMENT))
+        .unwrap_or(false)
+    {
+        segments.remove(0);
+    }
+    segments
+}
+
+/// The tenant named by an authority. The `tfp` segment of a B2C authority
+/// is skipped.
+pub(crate) fn authority_tenant(url: &Url) -> String {
+    tenant_segments(url)
+        .first()
+        .map(|tenant| tenant.to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Whether an authority names an audience of accounts, such as `common` or
+/// `organizations`, rather than a specific tenant.
+pub(crate) fn is_tenantless(url: &Url) -> bool {
+    TENANTLESS_AUDIENCES.contains(&authority_tenant(url).as_str())
+}
+
+/// The user flow (policy) named by a B2C authority, in lowercase as it
+/// appears in home account ids.
+///
+/// # Arguments
+///
+/// * `authority` - A URL that identifies a token authority.
+///
+/// # Returns
+///
+/// * The policy, or None if this is not a B2C authority.
+pub fn b2c_policy(authority: &str) -> Option<String> {
+    let url = Url::parse(authority).ok()?;
+    if authority_type(&url) != AuthorityType::B2C {
+        return None;
+    }
+    tenant_segments(&url)
+        .get(1)
+        .map(|policy| policy.to_lowercase())
+}
+
+/// Complete an External ID authority which only names the host, such as
+/// `https://contoso.ciamlogin.com`, with the tenant domain of the host.
+/// Other authorities are returned unchanged.
+pub(crate) fn normalize_authority(authority: &str) -> String {
+    let url = match Url::parse(authority) {
+        Ok(url) => url,
+        Err(_) => return authority.to_string(),
+    };
+    let host = url.host_str().unwrap_or_default();
+    if !is_ciam_host(host) || !first_segment(&url).is_empty() {
+        return authority.to_string();
+    }
+    let tenant = host
+        .get(..host.len() - CIAM_HOST_SUFFIX.len())
+        .unwrap_or_default();
+    format!(
+        "{}://{}/{}.onmicrosoft.com",
+        url.scheme(),
+        host,
+        tenant.to_lowercase()
+    )
+}
+
+/// Whether a login host belongs to a known cloud.
+pub fn is_known_host(host: &str) -> bool {
+    known_aliases(host).is_some()
+}
+
+fn known_aliases(host: &str) -> Option<&'static [&'static str]> {
+    KNOWN_LOGIN_HOSTS
+        .into_iter()
+        .map(|(_, aliases)| aliases)
+        .find(|aliases| aliases.iter().any(|alias| alias.eq_ignore_ascii_case(host)))
+}
+
+/// Whether two login hosts are the same, or are aliases of one another.
+pub(crate) fn is_alias(host: &str, other: &str) -> bool {
+    host.eq_ignore_ascii_case(other)
+        || known_aliases(host)
+            .map(|aliases| {
+                aliases
+                    .iter()
+                    .any(|alias| alias.eq_ignore_ascii_case(other))
+            })
+            .unwrap_or(false)
+}
+
+#[derive(Deserialize)]
+struct InstanceDiscoveryResponse {
+    tenant_discovery_endpoint: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Check that an authority is well formed, without contacting it.
+pub(crate) fn parse_authority(authority: &str) -> Result<Url, MsalError> {
+    let url = Url::parse(authority)
+        .map_err(|e| MsalError::InvalidAuthority(format!("{}: {}", authority, e)))?;
+    if url.host_str().is_none() {
+        return Err(MsalError::InvalidAuthority(format!(
+            "{}: The authority is missing a host",
+            authority
+        )));
+    }
+    if url
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .unwrap_or_default()
+        .is_empty()
+    {
+        return Err(MsalError::InvalidAuthority(format!(
+            "{}: The authority is missing a tenant",
+            authority
+        )));
+    }
+    if authority_type(&url) == AuthorityType::B2C && tenant_segments(&url).len() < 2 {
+        return Err(MsalError::InvalidAuthority(format!(
+            "{}: The B2C authority is missing a policy",
+            authority
+        )));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(MsalError::InvalidAuthority(format!(
+            "{}: The authority must not have a query or a fragment",
+            authority
+        )));
+    }
+    Ok(url)
+}
+
+/// Validate an authority before any credentials are sent to it. An authority
+/// must use https, and is valid if it is hosted by a known cloud. Otherwise
+/// the instance discovery endpoint is asked whether the authority is valid.
+/// B2C and External ID tenants are not known to instance discovery, so an
+/// authority hosted at b2clogin or ciamlogin is valid. A B2C authority on a
+/// custom domain requires that validation is disabled.
+pub(crate) async fn validate_authority(client: &Client, authority: &str) -> Result<(), MsalError> {
+    let url = parse_authority(authority)?;
+    if url.scheme() != "https" {
+        return Err(MsalError::InvalidAuthority(format!(
+            "{}: The authority must use https",
+            authority
+        )));
+    }
+    if url
+        .host_str()
+        .map(|host| is_known_host(host) || is_b2c_host(host) || is_ciam_host(host))
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+
+    debug!("Validating authority {} by instance discovery", authority);
+    let authorization_endpoint =
+        format!("{}/oauth2/v2.0/authorize", authority.trim_end_matches('/'));
+    let url = Url::parse_with_params(
+        INSTANCE_DISCOVERY_ENDPOINT,
+        &[
+            ("api-version", INSTANCE_DISCOVERY_API_VERSION),
+            ("authorization_endpoint", &authorization_endpoint),
+        ],
+    )
+    .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
+    let resp = client
+        .get(url)
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+    // An invalid instance is reported with an error status
+    let json_resp: InstanceDiscoveryResponse = resp
+        .json()
+        .await
+        .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+    match (json_resp.tenant_discovery_endpoint, json_resp.error) {
+        (Some(_), None) => Ok(()),
+        (_, error) => Err(MsalError::InvalidAuthority(format!(
+            "{}: {}",
+            authority,
+            json_resp
+                .error_description
+                .or(error)
+                .unwrap_or("The authority is not valid".to_string())
+        ))),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn invalid_authority(authority: &str) -> String {
+        match parse_authority(authority) {
+            Err(MsalError::InvalidAuthority(msg)) => msg,
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn parse_valid_authorities() {
+        for authority in [
+            "https://login.microsoftonline.com/common",
+            "https://login.microsoftonline.com/contoso.onmicrosoft.com/",
+            "https://contoso.b2clogin.com/tfp/contoso.onmicrosoft.com/B2C_1_signin",
+            "https://contoso.b2clogin.com/contoso.onmicrosoft.com/B2C_1_signin",
+            "https://contoso.ciamlogin.com/contoso.onmicrosoft.com",
+        ] {
+            assert!(parse_authority(authority).is_ok(), "{}", authority);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_authorities() {
+        assert!(invalid_authority("login.microsoftonline.com/common").contains("relative URL"));
+        for authority in [
+            "https://login.microsoftonline.com",
+            "https://login.microsoftonline.com/",
+        ] {
+            assert!(invalid_authority(authority).contains("missing a tenant"));
+        }
+        assert!(
+            invalid_authority("https://login.microsoftonline.com/common?tenant=contoso")
+                .contains("query or a fragment")
+        );
+        assert!(
+            invalid_authority("https://login.microsoftonline.com/common#fragment")
+                .contains("query or a fragment")
+        );
+        for authority in [
+            "https://contoso.b2clogin.com/tfp/contoso.onmicrosoft.com",
+            "https://contoso.b2clogin.com/contoso.onmicrosoft.com/",
+            "https://login.contoso.com/tfp/contoso.onmicrosoft.com",
+        ] {
+            assert!(invalid_authority(authority).contains("missing a policy"));
+        }
+    }
+
+    #[test]
+    fn authority_types() {
+        for (authority, authority_type) in [
+            (
+                "https://login.microsoftonline.com/common",
+                AuthorityType::Aad,
+            ),
+            (
+                "https://contoso.b2clogin.com/tfp/contoso/B2C_1_signin",
+                AuthorityType::B2C,
+            ),
+            (
+                "https://login.contoso.com/tfp/contoso/B2C_1_signin",
+                AuthorityType::B2C,
+            ),
+            (
+                "https://contoso.ciamlogin.com/contoso.onmicrosoft.com",
+                AuthorityType::Ciam,
+            ),
+            // A host which only ends with the suffix is not a B2C host
+            ("https://b2clogin.com/contoso", AuthorityType::Aad),
+        ] {
+            assert_eq!(
+                AuthorityType::from_authority(authority).unwrap(),
+                authority_type,
+                "{}",
+                authority
+            );
+        }
+    }
+
+    #[test]
+    fn host_aliases() {
+        assert!(is_alias("login.windows.net", "login.microsoftonline.com"));
+        assert!(is_alias("STS.windows.net", "login.microsoft.com"));
+        assert!(is_alias(
+            "login.chinacloudapi.cn",
+            "login.partner.microsoftonline.cn"
+        ));
+        assert!(is_alias(
+            "login.usgovcloudapi.net",
+            "login.microsoftonline.us"
+        ));
+        assert!(!is_alias(
+            "login.microsoftonline.us",
+            "login.microsoftonline.com"
+        ));
+        assert!(!is_alias(
+            "login-us.microsoftonline.com",
+            "login.microsoftonline.us"
+        ));
+        // Hosts of other identity providers are only aliases of themselves
+        assert!(is_alias("contoso.b2clogin.com", "Contoso.b2clogin.com"));
+        assert!(!is_alias("contoso.b2clogin.com", "fabrikam.b2clogin.com"));
+    }
+
+    #[test]
+    fn ciam_authority_is_completed_with_its_tenant() {
+        for authority in [
+            "https://contoso.ciamlogin.com",
+            "https://Contoso.ciamlogin.com/",
+        ] {
+            assert_eq!(
+                normalize_authority(authority),
+                "https://contoso.ciamlogin.com/contoso.onmicrosoft.com"
+            );
+        }
+        for authority in [
+            "https://contoso.ciamlogin.com/00000000-1111-2222-3333-444444444444",
+            "https://login.microsoftonline.com/common",
+            "not a url",
+        ] {
+            assert_eq!(normalize_authority(authority), authority);
+        }
+    }
+
+    #[test]
+    fn b2c_policies() {
+        assert_eq!(
+            b2c_policy("https://contoso.b2clogin.com/tfp/contoso.onmicrosoft.com/B2C_1_SignIn")
+                .as_deref(),
+            Some("b2c_1_signin")
+        );
+        assert_eq!(
+            b2c_policy("https://contoso.b2clogin.com/contoso.onmicrosoft.com/B2C_1_signin")
+                .as_deref(),
+            Some("b2c_1_signin")
+        );
+        assert_eq!(
+            b2c_policy("https://login.contoso.com/tfp/contoso.onmicrosoft.com/B2C_1_signin")
+                .as_deref(),
+            Some("b2c_1_signin")
+        );
+        assert_eq!(
+            b2c_policy("https://contoso.b2clogin.com/tfp/contoso.onmicrosoft.com"),
+            None
+        );
+        assert_eq!(
+            b2c_policy("https://login.microsoftonline.com/common/B2C_1_signin"),
+            None
+        );
+    }
+}
@@ -0,0 +1,1940 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::auth::{ClientInfo, IdToken, UserToken};
+use crate::authority::{authority_tenant, b2c_policy, is_alias};
+use crate::error::MsalError;
+use reqwest::Url;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{from_str as json_from_str, from_value, to_string, to_value, Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+use uuid::Uuid;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+#[cfg(feature = "broker")]
+use base64::engine::general_purpose::STANDARD;
+#[cfg(feature = "broker")]
+use base64::Engine;
+#[cfg(feature = "broker")]
+use kanidm_hsm_crypto::{
+    BoxedDynTpm, LoadableMsOapxbcRsaKey, MachineKey, MsOapxbcRsaKey, SealedData, Tpm,
+};
+#[cfg(feature = "broker")]
+use serde_json::{from_slice as json_from_slice, to_vec as json_to_vec};
+#[cfg(feature = "broker")]
+use std::fs::{self, File, OpenOptions};
+#[cfg(feature = "broker")]
+use std::io::{ErrorKind, Write};
+#[cfg(feature = "broker")]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+#[cfg(feature = "broker")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "broker")]
+use std::process;
+#[cfg(feature = "broker")]
+use std::sync::{Mutex, MutexGuard};
+
+/// Scopes which are implicitly added to every user token request, and are
+/// therefore ignored when matching cached access tokens.
+pub(crate) const RESERVED_SCOPES: [&str; 3] = ["openid", "profile", "offline_access"];
+
+/// Access tokens which expire within this many seconds are not returned from
+/// the cache.
+pub(crate) const ACCESS_TOKEN_EXPIRY_BUFFER: u64 = 300;
+
+pub(crate) fn now() -> Result<u64, MsalError> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| MsalError::GeneralFailure(format!("Failed fetching time: {}", e)))?
+        .as_secs())
+}
+
+/// Split an authority URL into the environment (the host) and the tenant
+/// (the first path segment, after the `tfp` segment of a B2C authority).
+pub(crate) fn split_authority(authority: &str) -> Result<(String, String), MsalError> {
+    let url = Url::parse(authority).map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
+    let environment = url
+        .host_str()
+        .ok_or(MsalError::InvalidParse(format!(
+            "Authority {} is missing a host",
+            authority
+        )))?
+        .to_lowercase();
+    Ok((environment, authority_tenant(&url)))
+}
+
+fn scope_set(scopes: &str) -> HashSet<String> {
+    scopes
+        .split_whitespace()
+        .map(|s| s.to_lowercase())
+        .filter(|s| !RESERVED_SCOPES.contains(&s.as_str()))
+        .collect()
+}
+
+fn cache_key(fields: &[&str]) -> String {
+    fields.join("-").to_lowercase()
+}
+
+/// The key of a token, which for an on-behalf-of token also includes the
+/// hash of the user assertion, so that it is stored separately from a token
+/// the user acquired for the same client.
+fn token_key(fields: &[&str], user_assertion_hash: Option<&str>) -> String {
+    match user_assertion_hash {
+        Some(user_assertion_hash) => format!("{}-{}", cache_key(fields), user_assertion_hash),
+        None => cache_key(fields),
+    }
+}
+
+/// MSAL implementations store timestamps as strings of seconds since the
+/// epoch, but some write them as numbers. Accept either.
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn serialize_timestamp<S>(value: &u64, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&value.to_string())
+}
+
+pub(crate) fn deserialize_timestamp<'de, D>(d: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v: Value = Deserialize::deserialize(d)?;
+    match v {
+        Value::Number(n) => n
+            .as_u64()
+            .ok_or(serde::de::Error::custom("Expected number or string")),
+        Value::String(s) => s
+            .parse::<u64>()
+            .map_err(|e| serde::de::Error::custom(format!("{}", e))),
+        _ => Err(serde::de::Error::custom("Expected number or string")),
+    }
+}
+
+fn serialize_optional_timestamp<S>(value: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match value {
+        Some(value) => serialize_timestamp(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub(crate) fn deserialize_optional_timestamp<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v: Option<Value> = Deserialize::deserialize(d)?;
+    match v {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => deserialize_timestamp(v)
+            .map(Some)
+            .map_err(|e| serde::de::Error::custom(format!("{}", e))),
+    }
+}
+
+/// An account which has previously signed in, and which may be passed to
+/// `acquire_token_silent`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    /// The unique identifier of the account, in the form `uid.utid`.
+    pub home_account_id: String,
+    /// The user principal name of the account.
+    pub username: String,
+    /// The tenant the account signed in to.
+    pub tenant_id: String,
+    /// The object id of the account within `tenant_id`.
+    pub local_account_id: String,
+}
+
+impl TryFrom<&UserToken> for Account {
+    type Error = MsalError;
+
+    fn try_from(token: &UserToken) -> Result<Self, Self::Error> {
+        Ok(Account {
+            home_account_id: token.home_account_id()?,
+            username: token.spn()?,
+            tenant_id: token.tenant_id()?,
+            local_account_id: token.id_token.oid.clone(),
+        })
+    }
+}
+
+impl From<&AccountEntry> for Account {
+    fn from(entry: &AccountEntry) -> Self {
+        Account {
+            home_account_id: entry.home_account_id.clone(),
+            username: entry.username.clone(),
+            tenant_id: entry.realm.clone(),
+            local_account_id: entry.local_account_id.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct AccessTokenEntry {
+    pub home_account_id: String,
+    pub environment: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub realm: String,
+    #[serde(default)]
+    pub target: String,
+    pub secret: String,
+    #[serde(
+        serialize_with = "serialize_timestamp",
+        deserialize_with = "deserialize_timestamp",
+        default
+    )]
+    pub cached_at: u64,
+    #[serde(
+        serialize_with = "serialize_timestamp",
+        deserialize_with = "deserialize_timestamp",
+        default
+    )]
+    pub expires_on: u64,
+    #[serde(
+        serialize_with = "serialize_timestamp",
+        deserialize_with = "deserialize_timestamp",
+        default
+    )]
+    pub extended_expires_on: u64,
+    /// When the access token should be proactively refreshed.
+    #[serde(
+        serialize_with = "serialize_optional_timestamp",
+        deserialize_with = "deserialize_optional_timestamp",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub refresh_on: Option<u64>,
+    /// The hash of the user assertion an on-behalf-of token was acquired
+    /// with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_assertion_hash: Option<String>,
+    /// Fields written by other MSAL implementations, which are preserved.
+    #[serde(flatten)]
+    #[zeroize(skip)]
+    pub additional_fields: Map<String, Value>,
+}
+
+impl AccessTokenEntry {
+    pub fn key(&self) -> String {
+        token_key(
+            &[
+                &self.home_account_id,
+                &self.environment,
+                "accesstoken",
+                &self.client_id,
+                &self.realm,
+                &self.target,
+            ],
+            self.user_assertion_hash.as_deref(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct RefreshTokenEntry {
+    pub home_account_id: String,
+    pub environment: String,
+    pub client_id: String,
+    pub secret: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family_id: Option<String>,
+    /// The hash of the user assertion an on-behalf-of token was acquired
+    /// with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_assertion_hash: Option<String>,
+    /// Fields written by other MSAL implementations, which are preserved.
+    #[serde(flatten)]
+    #[zeroize(skip)]
+    pub additional_fields: Map<String, Value>,
+}
+
+impl RefreshTokenEntry {
+    pub fn key(&self) -> String {
+        token_key(
+            &[
+                &self.home_account_id,
+                &self.environment,
+                "refreshtoken",
+                &self.client_id,
+                "",
+                "",
+            ],
+            self.user_assertion_hash.as_deref(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct IdTokenEntry {
+    pub home_account_id: String,
+    pub environment: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub realm: String,
+    pub secret: String,
+    /// Fields written by other MSAL implementations, which are preserved.
+    #[serde(flatten)]
+    #[zeroize(skip)]
+    pub additional_fields: Map<String, Value>,
+}
+
+impl IdTokenEntry {
+    pub fn key(&self) -> String {
+        cache_key(&[
+            &self.home_account_id,
+            &self.environment,
+            "idtoken",
+            &self.client_id,
+            &self.realm,
+            "",
+        ])
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountEntry {
+    pub home_account_id: String,
+    pub environment: String,
+    pub realm: String,
+    pub local_account_id: String,
+    pub username: String,
+    pub authority_type: String,
+    /// Fields written by other MSAL implementations, which are preserved.
+    #[serde(flatten)]
+    pub additional_fields: Map<String, Value>,
+}
+
+impl AccountEntry {
+    pub fn key(&self) -> String {
+        cache_key(&[&self.home_account_id, &self.environment, &self.realm])
+    }
+}
+
+/// A Primary Refresh Token acquired by a BrokerClientApplication. The secret
+/// is the sealed PRT, which can only be unsealed using the transport key of
+/// the enrolled device.
+#[derive(Clone, Debug, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct PrimaryRefreshTokenEntry {
+    pub home_account_id: String,
+    pub environment: String,
+    pub client_id: String,
+    pub secret: String,
+    /// Fields written by other MSAL implementations, which are preserved.
+    #[serde(flatten)]
+    #[zeroize(skip)]
+    pub additional_fields: Map<String, Value>,
+}
+
+impl PrimaryRefreshTokenEntry {
+    pub fn key(&self) -> String {
+        cache_key(&[
+            &self.home_account_id,
+            &self.environment,
+            "primaryrefreshtoken",
+            &self.client_id,
+            "",
+            "",
+        ])
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppMetadataEntry {
+    pub client_id: String,
+    pub environment: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family_id: Option<String>,
+    /// Fields written by other MSAL implementations, which are preserved.
+    #[serde(flatten)]
+    pub additional_fields: Map<String, Value>,
+}
+
+impl AppMetadataEntry {
+    pub fn key(&self) -> String {
+        format!("appmetadata-{}-{}", self.environment, self.client_id).to_lowercase()
+    }
+}
+
+/// Storage for tokens acquired by a client application.
+///
+/// The cache stores individual credentials (access tokens, refresh tokens,
+/// id tokens and accounts), each identified by the string returned from the
+/// `key()` method of the entry. Saving an entry whose key already exists
+/// replaces the existing entry. Lookups (matching accounts, authorities and
+/// scopes) are performed by the client application, so an implementation
+/// only needs to provide storage. Implement this trait to persist the cache
+/// somewhere other than process memory.
+pub trait TokenCache: Send + Sync {
+    fn access_tokens(&self) -> Result<Vec<AccessTokenEntry>, MsalError>;
+    fn refresh_tokens(&self) -> Result<Vec<RefreshTokenEntry>, MsalError>;
+    fn id_tokens(&self) -> Result<Vec<IdTokenEntry>, MsalError>;
+    fn accounts(&self) -> Result<Vec<AccountEntry>, MsalError>;
+    fn app_metadata(&self) -> Result<Vec<AppMetadataEntry>, MsalError>;
+    fn primary_refresh_tokens(&self) -> Result<Vec<PrimaryRefreshTokenEntry>, MsalError>;
+
+    fn save_access_token(&self, entry: AccessTokenEntry) -> Result<(), MsalError>;
+    fn save_refresh_token(&self, entry: RefreshTokenEntry) -> Result<(), MsalError>;
+    fn save_id_token(&self, entry: IdTokenEntry) -> Result<(), MsalError>;
+    fn save_account(&self, entry: AccountEntry) -> Result<(), MsalError>;
+    fn save_app_metadata(&self, entry: AppMetadataEntry) -> Result<(), MsalError>;
+    fn save_primary_refresh_token(&self, entry: PrimaryRefreshTokenEntry) -> Result<(), MsalError>;
+
+    fn remove_access_token(&self, key: &str) -> Result<(), MsalError>;
+    fn remove_refresh_token(&self, key: &str) -> Result<(), MsalError>;
+    fn remove_id_token(&self, key: &str) -> Result<(), MsalError>;
+    fn remove_account(&self, key: &str) -> Result<(), MsalError>;
+    fn remove_app_metadata(&self, key: &str) -> Result<(), MsalError>;
+    fn remove_primary_refresh_token(&self, key: &str) -> Result<(), MsalError>;
+
+    /// Serialize the cache into the MSAL unified cache schema.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A json string which can be loaded by `deserialize`, or by
+    ///   the MSAL implementations for other languages (such as the
+    ///   SerializableTokenCache of Python MSAL).
+    /// * Failure: An MsalError, indicating the failure.
+    fn serialize(&self) -> Result<String, MsalError> {
+        let data = CacheData {
+            access_tokens: entries_by_key(self.access_tokens()?, AccessTokenEntry::key),
+            refresh_tokens: entries_by_key(self.refresh_tokens()?, RefreshTokenEntry::key),
+            id_tokens: entries_by_key(self.id_tokens()?, IdTokenEntry::key),
+            accounts: entries_by_key(self.accounts()?, AccountEntry::key),
+            app_metadata: entries_by_key(self.app_metadata()?, AppMetadataEntry::key),
+            primary_refresh_tokens: entries_by_key(
+                self.primary_refresh_tokens()?,
+                PrimaryRefreshTokenEntry::key,
+            ),
+            additional_sections: Map::new(),
+        };
+        to_string(&data.to_value()?).map_err(|e| MsalError::InvalidJson(format!("{}", e)))
+    }
+
+    /// Replace the contents of the cache with a cache in the MSAL unified
+    /// cache schema.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - A json string previously produced by `serialize`, or by
+    ///   another MSAL implementation.
+    ///
+    /// # Returns
+    ///
+    /// * Success: ()
+    /// * Failure: An MsalError, indicating the failure.
+    ///
+    /// The state is parsed, and the existing entries are read, before the
+    /// cache is changed, so an invalid state leaves the cache as it was. The
+    /// entries are then removed and saved one at a time, so the replacement
+    /// is not atomic: if the storage fails part way through, the cache may
+    /// be left partially replaced. Implementations which can replace their
+    /// storage in a single step should override this method.
+    fn deserialize(&self, state: &str) -> Result<(), MsalError> {
+        let data = CacheData::from_json(state)?;
+        let access_tokens = self.access_tokens()?;
+        let refresh_tokens = self.refresh_tokens()?;
+        let id_tokens = self.id_tokens()?;
+        let accounts = self.accounts()?;
+        let app_metadata = self.app_metadata()?;
+        let primary_refresh_tokens = self.primary_refresh_tokens()?;
+
+        for entry in access_tokens {
+            self.remove_access_token(&entry.key())?;
+        }
+        for entry in refresh_tokens {
+            self.remove_refresh_token(&entry.key())?;
+        }
+        for entry in id_tokens {
+            self.remove_id_token(&entry.key())?;
+        }
+        for entry in accounts {
+            self.remove_account(&entry.key())?;
+        }
+        for entry in app_metadata {
+            self.remove_app_metadata(&entry.key())?;
+        }
+        for entry in primary_refresh_tokens {
+            self.remove_primary_refresh_token(&entry.key())?;
+        }
+        for entry in data.access_tokens.into_values() {
+            self.save_access_token(entry)?;
+        }
+        for entry in data.refresh_tokens.into_values() {
+            self.save_refresh_token(entry)?;
+        }
+        for entry in data.id_tokens.into_values() {
+            self.save_id_token(entry)?;
+        }
+        for entry in data.accounts.into_values() {
+            self.save_account(entry)?;
+        }
+        for entry in data.app_metadata.into_values() {
+            self.save_app_metadata(entry)?;
+        }
+        for entry in data.primary_refresh_tokens.into_values() {
+            self.save_primary_refresh_token(entry)?;
+        }
+        Ok(())
+    }
+}
+
+fn entries_by_key<T, F>(entries: Vec<T>, key: F) -> BTreeMap<String, T>
+where
+    F: Fn(&T) -> String,
+{
+    entries
+        .into_iter()
+        .map(|entry| (key(&entry), entry))
+        .collect()
+}
+
+#[derive(Default)]
+pub(crate) struct CacheData {
+    pub(crate) access_tokens: BTreeMap<String, AccessTokenEntry>,
+    pub(crate) refresh_tokens: BTreeMap<String, RefreshTokenEntry>,
+    pub(crate) id_tokens: BTreeMap<String, IdTokenEntry>,
+    pub(crate) accounts: BTreeMap<String, AccountEntry>,
+    pub(crate) app_metadata: BTreeMap<String, AppMetadataEntry>,
+    pub(crate) primary_refresh_tokens: BTreeMap<String, PrimaryRefreshTokenEntry>,
+    /// Sections written by other MSAL implementations, which are preserved.
+    pub(crate) additional_sections: Map<String, Value>,
+}
+
+const ACCESS_TOKEN_SECTION: &str = "AccessToken";
+const REFRESH_TOKEN_SECTION: &str = "RefreshToken";
+const ID_TOKEN_SECTION: &str = "IdToken";
+const ACCOUNT_SECTION: &str = "Account";
+const APP_METADATA_SECTION: &str = "AppMetadata";
+/// This section is not part of the MSAL unified cache schema. Other MSAL
+/// implementations preserve it as an unknown section.
+const PRIMARY_REFRESH_TOKEN_SECTION: &str = "PrimaryRefreshToken";
+
+fn section_to_value<T: Serialize>(
+    entries: &BTreeMap<String, T>,
+    credential_type: Option<&str>,
+) -> Result<Value, MsalError> {
+    let mut section = Map::new();
+    for (key, entry) in entries {
+        let mut value = to_value(entry).map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+        if let (Some(credential_type), Value::Object(obj)) = (credential_type, &mut value) {
+            obj.insert(
+                "credential_type".to_string(),
+                Value::String(credential_type.to_string()),
+            );
+        }
+        section.insert(key.clone(), value);
+    }
+    Ok(Value::Object(section))
+}
+
+fn section_from_value<T, F>(
+    section: Option<Value>,
+    key: F,
+) -> Result<BTreeMap<String, T>, MsalError>
+where
+    T: for<'de> Deserialize<'de>,
+    F: Fn(&T) -> String,
+{
+    let mut entries = BTreeMap::new();
+    if let Some(Value::Object(section)) = section {
+        for (_, mut value) in section {
+            if let Value::Object(obj) = &mut value {
+                obj.remove("credential_type");
+            }
+            let entry: T =
+                from_value(value).map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+            entries.insert(key(&entry), entry);
+        }
+    }
+    Ok(entries)
+}
+
+impl CacheData {
+    /// Represent the cache in the MSAL unified cache schema, which is shared
+    /// by the MSAL implementations for other languages and the Azure CLI.
+    pub(crate) fn to_value(&self) -> Result<Value, MsalError> {
+        let mut data = self.additional_sections.clone();
+        data.insert(
+            ACCESS_TOKEN_SECTION.to_string(),
+            section_to_value(&self.access_tokens, Some("AccessToken"))?,
+        );
+        data.insert(
+            REFRESH_TOKEN_SECTION.to_string(),
+            section_to_value(&self.refresh_tokens, Some("RefreshToken"))?,
+        );
+        data.insert(
+            ID_TOKEN_SECTION.to_string(),
+            section_to_value(&self.id_tokens, Some("IdToken"))?,
+        );
+        data.insert(
+            ACCOUNT_SECTION.to_string(),
+            section_to_value(&self.accounts, None)?,
+        );
+        data.insert(
+            APP_METADATA_SECTION.to_string(),
+            section_to_value(&self.app_metadata, None)?,
+        );
+        // Only caches with PRTs carry the section, so that other caches are
+        // written exactly as other MSAL implementations write them.
+        if !self.primary_refresh_tokens.is_empty() {
+            data.insert(
+                PRIMARY_REFRESH_TOKEN_SECTION.to_string(),
+                section_to_value(&self.primary_refresh_tokens, Some("PrimaryRefreshToken"))?,
+            );
+        }
+        Ok(Value::Object(data))
+    }
+
+    /// Load a cache which is in the MSAL unified cache schema, from json.
+    pub(crate) fn from_json(state: &str) -> Result<Self, MsalError> {
+        let value: Value =
+            json_from_str(state).map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+        CacheData::from_value(value)
+    }
+
+    /// Load a cache which is in the MSAL unified cache schema.
+    pub(crate) fn from_value(value: Value) -> Result<Self, MsalError> {
+        let mut data = match value {
+            Value::Object(data) => data,
+            _ => {
+                return Err(MsalError::InvalidJson(
+                    "The token cache is not a json object".to_string(),
+                ))
+            }
+        };
+        Ok(CacheData {
+            access_tokens: section_from_value(
+                data.remove(ACCESS_TOKEN_SECTION),
+                AccessTokenEntry::key,
+            )?,
+            refresh_tokens: section_from_value(
+                data.remove(REFRESH_TOKEN_SECTION),
+                RefreshTokenEntry::key,
+            )?,
+            id_tokens: section_from_value(data.remove(ID_TOKEN_SECTION), IdTokenEntry::key)?,
+            accounts: section_from_value(data.remove(ACCOUNT_SECTION), AccountEntry::key)?,
+            app_metadata: section_from_value(
+                data.remove(APP_METADATA_SECTION),
+                AppMetadataEntry::key,
+            )?,
+            primary_refresh_tokens: section_from_value(
+                data.remove(PRIMARY_REFRESH_TOKEN_SECTION),
+                PrimaryRefreshTokenEntry::key,
+            )?,
+            additional_sections: data,
+        })
+    }
+}
+
+/// A TokenCache which is held in process memory, and is lost when the
+/// application exits. This is the default cache of a client application.
+#[derive(Default)]
+pub struct InMemoryTokenCache {
+    data: RwLock<CacheData>,
+}
+
+macro_rules! cache_read {
+    ($self:ident, $field:ident) => {{
+        let data = $self
+            .data
+            .read()
+            .map_err(|e| MsalError::GeneralFailure(format!("Token cache poisoned: {}", e)))?;
+        Ok(data.$field.values().cloned().collect())
+    }};
+}
+
+macro_rules! cache_write {
+    ($self:ident, $field:ident, $entry:ident) => {{
+        let mut data = $self
+            .data
+            .write()
+            .map_err(|e| MsalError::GeneralFailure(format!("Token cache poisoned: {}", e)))?;
+        data.$field.insert($entry.key(), $entry);
+        Ok(())
+    }};
+}
+
+macro_rules! cache_remove {
+    ($self:ident, $field:ident, $key:ident) => {{
+        let mut data = $self
+            .data
+            .write()
+            .map_err(|e| MsalError::GeneralFailure(format!("Token cache poisoned: {}", e)))?;
+        data.$field.remove($key);
+        Ok(())
+    }};
+}
+
+impl InMemoryTokenCache {
+    pub fn new() -> Self {
+        InMemoryTokenCache::default()
+    }
+}
+
+impl TokenCache for InMemoryTokenCache {
+    fn access_tokens(&self) -> Result<Vec<AccessTokenEntry>, MsalError> {
+        cache_read!(self, access_tokens)
+    }
+
+    fn refresh_tokens(&self) -> Result<Vec<RefreshTokenEntry>, MsalError> {
+        cache_read!(self, refresh_tokens)
+    }
+
+    fn id_tokens(&self) -> Result<Vec<IdTokenEntry>, MsalError> {
+        cache_read!(self, id_tokens)
+    }
+
+    fn accounts(&self) -> Result<Vec<AccountEntry>, MsalError> {
+        cache_read!(self, accounts)
+    }
+
+    fn app_metadata(&self) -> Result<Vec<AppMetadataEntry>, MsalError> {
+        cache_read!(self, app_metadata)
+    }
+
+    fn primary_refresh_tokens(&self) -> Result<Vec<PrimaryRefreshTokenEntry>, MsalError> {
+        cache_read!(self, primary_refresh_tokens)
+    }
+
+    fn save_access_token(&self, entry: AccessTokenEntry) -> Result<(), MsalError> {
+        cache_write!(self, access_tokens, entry)
+    }
+
+    fn save_refresh_token(&self, entry: RefreshTokenEntry) -> Result<(), MsalError> {
+        cache_write!(self, refresh_tokens, entry)
+    }
+
+    fn save_id_token(&self, entry: IdTokenEntry) -> Result<(), MsalError> {
+        cache_write!(self, id_tokens, entry)
+    }
+
+    fn save_account(&self, entry: AccountEntry) -> Result<(), MsalError> {
+        cache_write!(self, accounts, entry)
+    }
+
+    fn save_app_metadata(&self, entry: AppMetadataEntry) -> Result<(), MsalError> {
+        cache_write!(self, app_metadata, entry)
+    }
+
+    fn save_primary_refresh_token(&self, entry: PrimaryRefreshTokenEntry) -> Result<(), MsalError> {
+        cache_write!(self, primary_refresh_tokens, entry)
+    }
+
+    fn remove_access_token(&self, key: &str) -> Result<(), MsalError> {
+        cache_remove!(self, access_tokens, key)
+    }
+
+    fn remove_refresh_token(&self, key: &str) -> Result<(), MsalError> {
+        cache_remove!(self, refresh_tokens, key)
+    }
+
+    fn remove_id_token(&self, key: &str) -> Result<(), MsalError> {
+        cache_remove!(self, id_tokens, key)
+    }
+
+    fn remove_account(&self, key: &str) -> Result<(), MsalError> {
+        cache_remove!(self, accounts, key)
+    }
+
+    fn remove_app_metadata(&self, key: &str) -> Result<(), MsalError> {
+        cache_remove!(self, app_metadata, key)
+    }
+
+    fn remove_primary_refresh_token(&self, key: &str) -> Result<(), MsalError> {
+        cache_remove!(self, primary_refresh_tokens, key)
+    }
+
+    /// Serialize the cache, including any sections written by other MSAL
+    /// implementations.
+    fn serialize(&self) -> Result<String, MsalError> {
+        let data = self
+            .data
+            .read()
+            .map_err(|e| MsalError::GeneralFailure(format!("Token cache poisoned: {}", e)))?;
+        to_string(&data.to_value()?).map_err(|e| MsalError::InvalidJson(format!("{}", e)))
+    }
+
+    fn deserialize(&self, state: &str) -> Result<(), MsalError> {
+        let new_data = CacheData::from_json(state)?;
+        let mut data = self
+            .data
+            .write()
+            .map_err(|e| MsalError::GeneralFailure(format!("Token cache poisoned: {}", e)))?;
+        *data = new_data;
+        Ok(())
+    }
+}
+
+/// Encode SealedData as a string, so that it can be stored as the secret of
+/// a cache entry.
+#[cfg(feature = "broker")]
+pub(crate) fn encode_sealed_data(sealed: &SealedData) -> Result<String, MsalError> {
+    let sealed = json_to_vec(sealed)
+        .map_err(|e| MsalError::InvalidJson(format!("Failed serializing sealed data {:?}", e)))?;
+    Ok(STANDARD.encode(sealed))
+}
+
+#[cfg(feature = "broker")]
+pub(crate) fn decode_sealed_data(sealed: &str) -> Result<SealedData, MsalError> {
+    let sealed = STANDARD
+        .decode(sealed)
+        .map_err(|e| MsalError::InvalidParse(format!("Failed decoding sealed data {:?}", e)))?;
+    json_from_slice(&sealed)
+        .map_err(|e| MsalError::InvalidJson(format!("Failed deserializing sealed data {:?}", e)))
+}
+
+#[cfg(feature = "broker")]
+#[derive(Serialize, Deserialize)]
+struct SealedCacheFile {
+    sealing_key: LoadableMsOapxbcRsaKey,
+    cache: Value,
+}
+
+#[cfg(feature = "broker")]
+struct SealedCacheState {
+    tpm: BoxedDynTpm,
+    sealing_key: MsOapxbcRsaKey,
+    data: CacheData,
+    /// The inode, modification time and length of the cache file when it
+    /// was last read or written by this process.
+    version: Option<(u64, SystemTime, u64)>,
+}
+
+#[cfg(feature = "broker")]
+impl SealedCacheState {
+    fn seal(&mut self, secret: &str) -> Result<String, MsalError> {
+        let sealed = self
+            .tpm
+            .msoapxbc_rsa_seal_data(&self.sealing_key, secret.as_bytes())
+            .map_err(|e| MsalError::TPMFail(format!("Failed sealing token {:?}", e)))?;
+        encode_sealed_data(&sealed)
+    }
+
+    fn unseal(&mut self, sealed: &str) -> Result<String, MsalError> {
+        let sealed = decode_sealed_data(sealed)?;
+        let secret = self
+            .tpm
+            .msoapxbc_rsa_unseal_data(&self.sealing_key, &sealed)
+            .map_err(|e| MsalError::TPMFail(format!("Failed unsealing token {:?}", e)))?;
+        String::from_utf8(secret.to_vec())
+            .map_err(|e| MsalError::InvalidParse(format!("Failed decoding token {:?}", e)))
+    }
+
+    /// Seal (or unseal) the secret of every access token and refresh token
+    /// in a cache which is in the MSAL unified cache schema.
+    fn map_secrets<F>(&mut self, cache: &mut Value, f: F) -> Result<(), MsalError>
+    where
+        F: Fn(&mut Self, &str) -> Result<String, MsalError>,
+    {
+        for section in [ACCESS_TOKEN_SECTION, REFRESH_TOKEN_SECTION] {
+            if let Some(Value::Object(entries)) = cache.get_mut(section) {
+                for entry in entries.values_mut() {
+                    if let Some(Value::String(secret)) = entry.get_mut("secret") {
+                        let mut new_secret = f(self, secret)?;
+                        std::mem::swap(secret, &mut new_secret);
+                        new_secret.zeroize();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "broker")]
+fn file_version(path: &Path) -> Result<Option<(u64, SystemTime, u64)>, MsalError> {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let mtime = metadata
+                .modified()
+                .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+            Ok(Some((metadata.ino(), mtime, metadata.len())))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(MsalError::GeneralFailure(format!(
+            "Failed reading token cache {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+#[cfg(feature = "broker")]
+fn read_sealed_cache_file(path: &Path) -> Result<SealedCacheFile, MsalError> {
+    let contents = fs::read(path).map_err(|e| {
+        MsalError::GeneralFailure(format!(
+            "Failed reading token cache {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    json_from_slice(&contents).map_err(|e| MsalError::InvalidJson(format!("{}", e)))
+}
+
+/// A TokenCache which is persisted to a file, with the secret of every
+/// access token and refresh token sealed by the TPM.
+///
+/// The tokens are sealed using a key which is bound to the machine key, and
+/// which is stored (in its loadable form) in the cache file. Id tokens and
+/// accounts are stored unsealed, so that accounts can be listed without the
+/// TPM. The file is replaced atomically on every write, and file locks are
+/// used so that several processes may share the same cache. Changes written
+/// by other processes are picked up on the next read.
+#[cfg(feature = "broker")]
+pub struct SealedFileTokenCache {
+    path: PathBuf,
+    lock_path: PathBuf,
+    loadable_key: LoadableMsOapxbcRsaKey,
+    state: Mutex<SealedCacheState>,
+}
+
+#[cfg(feature = "broker")]
+impl SealedFileTokenCache {
+    /// Open a sealed token cache, creating it if it does not exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the cache file. A lock file named after the
+    ///   cache file, with a `.lock` extension appended, is created next to it.
+    ///
+    /// * `tpm` - The tpm object used to seal and unseal tokens.
+    ///
+    /// * `machine_key` - The TPM MachineKey associated with this application.
+    ///   This must be the same machine key which the cache was created with.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A SealedFileTokenCache.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn new(
+        path: &Path,
+        mut tpm: BoxedDynTpm,
+        machine_key: &MachineKey,
+    ) -> Result<Self, MsalError> {
+        let mut lock_path = path.as_os_str().to_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        let _lock = lock_file(&lock_path, true)?;
+        let (loadable_key, sealing_key) = if file_version(path)?.is_some() {
+            let file = read_sealed_cache_file(path)?;
+            let sealing_key = tpm
+                .msoapxbc_rsa_key_load(machine_key, &file.sealing_key)
+                .map_err(|e| {
+                    MsalError::TPMFail(format!("Failed loading token cache key: {:?}", e))
+                })?;
+            (file.sealing_key, sealing_key)
+        } else {
+            let loadable_key = tpm.msoapxbc_rsa_key_create(machine_key).map_err(|e| {
+                MsalError::TPMFail(format!("Failed creating token cache key: {:?}", e))
+            })?;
+            let sealing_key = tpm
+                .msoapxbc_rsa_key_load(machine_key, &loadable_key)
+                .map_err(|e| {
+                    MsalError::TPMFail(format!("Failed loading token cache key: {:?}", e))
+                })?;
+            (loadable_key, sealing_key)
+        };
+
+        let cache = SealedFileTokenCache {
+            path: path.to_path_buf(),
+            lock_path,
+            loadable_key,
+            state: Mutex::new(SealedCacheState {
+                tpm,
+                sealing_key,
+                data: CacheData::default(),
+                version: None,
+            }),
+        };
+        {
+            let mut state = cache.lock_state()?;
+            if file_version(path)?.is_some() {
+                cache.reload(&mut state)?;
+            } else {
+                cache.persist(&mut state)?;
+            }
+        }
+        Ok(cache)
+    }
+
+    fn lock_state(&self) -> Result<MutexGuard<'_, SealedCacheState>, MsalError> {
+        self.state
+            .lock()
+            .map_err(|e| MsalError::GeneralFailure(format!("Token cache poisoned: {}", e)))
+    }
+
+    /// Re-read the cache file if it was changed since it was last read or
+    /// written by this process. The caller must hold the lock file.
+    fn reload(&self, state: &mut SealedCacheState) -> Result<(), MsalError> {
+        let version = file_version(&self.path)?;
+        if version.is_none() || version == state.version {
+            return Ok(());
+        }
+        debug!("Reloading token cache {}", self.path.display());
+        let mut file = read_sealed_cache_file(&self.path)?;
+        state.map_secrets(&mut file.cache, SealedCacheState::unseal)?;
+        state.data = CacheData::from_value(file.cache)?;
+        state.version = version;
+        Ok(())
+    }
+
+    /// Atomically replace the cache file with the contents of the cache.
+    /// The caller must hold the lock file exclusively.
+    fn persist(&self, state: &mut SealedCacheState) -> Result<(), MsalError> {
+        let mut cache = state.data.to_value()?;
+        state.map_secrets(&mut cache, SealedCacheState::seal)?;
+        let contents = json_to_vec(&SealedCacheFile {
+            sealing_key: self.loadable_key.clone(),
+            cache,
+        })
+        .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+
+        let mut tmp_path = self.path.as_os_str().to_os_string();
+        tmp_path.push(format!(".{}.tmp", process::id()));
+        let tmp_path = PathBuf::from(tmp_path);
+        let write_err = |e: std::io::Error| {
+            MsalError::GeneralFailure(format!(
+                "Failed writing token cache {}: {}",
+                self.path.display(),
+                e
+            ))
+        };
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .map_err(write_err)?;
+        if let Err(e) = file.write_all(&contents).and_then(|_| file.sync_all()) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(write_err(e));
+        }
+        if let Err(e) = fs::rename(&tmp_path, &self.path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(write_err(e));
+        }
+        state.version = file_version(&self.path)?;
+        Ok(())
+    }
+
+    fn read<T, F>(&self, f: F) -> Result<T, MsalError>
+    where
+        F: FnOnce(&CacheData) -> T,
+    {
+        let mut state = self.lock_state()?;
+        let _lock = lock_file(&self.lock_path, false)?;
+        self.reload(&mut state)?;
+        Ok(f(&state.data))
+    }
+
+    fn write<F>(&self, f: F) -> Result<(), MsalError>
+    where
+        F: FnOnce(&mut CacheData),
+    {
+        let mut state = self.lock_state()?;
+        let _lock = lock_file(&self.lock_path, true)?;
+        self.reload(&mut state)?;
+        f(&mut state.data);
+        self.persist(&mut state)
+    }
+}
+
+/// Open and lock the lock file of a cache file. The lock is released when
+/// the returned file is dropped.
+#[cfg(feature = "broker")]
+fn lock_file(path: &Path, exclusive: bool) -> Result<File, MsalError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| {
+            MsalError::GeneralFailure(format!(
+                "Failed opening token cache lock {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+    let res = if exclusive {
+        file.lock()
+    } else {
+        file.lock_shared()
+    };
+    res.map_err(|e| {
+        MsalError::GeneralFailure(format!(
+            "Failed locking token cache lock {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    Ok(file)
+}
+
+#[cfg(feature = "broker")]
+macro_rules! sealed_cache_read {
+    ($self:ident, $field:ident) => {{
+        $self.read(|data| data.$field.values().cloned().collect())
+    }};
+}
+
+#[cfg(feature = "broker")]
+macro_rules! sealed_cache_write {
+    ($self:ident, $field:ident, $entry:ident) => {{
+        $self.write(|data| {
+            data.$field.insert($entry.key(), $entry);
+        })
+    }};
+}
+
+#[cfg(feature = "broker")]
+macro_rules! sealed_cache_remove {
+    ($self:ident, $field:ident, $key:ident) => {{
+        $self.write(|data| {
+            data.$field.remove($key);
+        })
+    }};
+}
+
+#[cfg(feature = "broker")]
+impl TokenCache for SealedFileTokenCache {
+    fn access_tokens(&self) -> Result<Vec<AccessTokenEntry>, MsalError> {
+        sealed_cache_read!(self, access_tokens)
+    }
+
+    fn refresh_tokens(&self) -> Result<Vec<RefreshTokenEntry>, MsalError> {
+        sealed_cache_read!(self, refresh_tokens)
+    }
+
+    fn id_tokens(&self) -> Result<Vec<IdTokenEntry>, MsalError> {
+        sealed_cache_read!(self, id_tokens)
+    }
+
+    fn accounts(&self) -> Result<Vec<AccountEntry>, MsalError> {
+        sealed_cache_read!(self, accounts)
+    }
+
+    fn app_metadata(&self) -> Result<Vec<AppMetadataEntry>, MsalError> {
+        sealed_cache_read!(self, app_metadata)
+    }
+
+    fn primary_refresh_tokens(&self) -> Result<Vec<PrimaryRefreshTokenEntry>, MsalError> {
+        sealed_cache_read!(self, primary_refresh_tokens)
+    }
+
+    fn save_access_token(&self, entry: AccessTokenEntry) -> Result<(), MsalError> {
+        sealed_cache_write!(self, access_tokens, entry)
+    }
+
+    fn save_refresh_token(&self, entry: RefreshTokenEntry) -> Result<(), MsalError> {
+        sealed_cache_write!(self, refresh_tokens, entry)
+    }
+
+    fn save_id_token(&self, entry: IdTokenEntry) -> Result<(), MsalError> {
+        sealed_cache_write!(self, id_tokens, entry)
+    }
+
+    fn save_account(&self, entry: AccountEntry) -> Result<(), MsalError> {
+        sealed_cache_write!(self, accounts, entry)
+    }
+
+    fn save_app_metadata(&self, entry: AppMetadataEntry) -> Result<(), MsalError> {
+        sealed_cache_write!(self, app_metadata, entry)
+    }
+
+    fn save_primary_refresh_token(&self, entry: PrimaryRefreshTokenEntry) -> Result<(), MsalError> {
+        sealed_cache_write!(self, primary_refresh_tokens, entry)
+    }
+
+    fn remove_access_token(&self, key: &str) -> Result<(), MsalError> {
+        sealed_cache_remove!(self, access_tokens, key)
+    }
+
+    fn remove_refresh_token(&self, key: &str) -> Result<(), MsalError> {
+        sealed_cache_remove!(self, refresh_tokens, key)
+    }
+
+    fn remove_id_token(&self, key: &str) -> Result<(), MsalError> {
+        sealed_cache_remove!(self, id_tokens, key)
+    }
+
+    fn remove_account(&self, key: &str) -> Result<(), MsalError> {
+        sealed_cache_remove!(self, accounts, key)
+    }
+
+    fn remove_app_metadata(&self, key: &str) -> Result<(), MsalError> {
+        sealed_cache_remove!(self, app_metadata, key)
+    }
+
+    fn remove_primary_refresh_token(&self, key: &str) -> Result<(), MsalError> {
+        sealed_cache_remove!(self, primary_refresh_tokens, key)
+    }
+
+    /// Serialize the cache, including any sections written by other MSAL
+    /// implementations. The secrets of the serialized cache are not sealed,
+    /// so that it can be loaded by other MSAL implementations.
+    fn serialize(&self) -> Result<String, MsalError> {
+        self.read(|data| data.to_value())?.and_then(|value| {
+            to_string(&value).map_err(|e| MsalError::InvalidJson(format!("{}", e)))
+        })
+    }
+
+    /// Replace the contents of the cache, sealing the secrets of the loaded
+    /// cache before it is written to the cache file.
+    fn deserialize(&self, state: &str) -> Result<(), MsalError> {
+        let new_data = CacheData::from_json(state)?;
+        self.write(|data| *data = new_data)
+    }
+}
+
+/// Store the credentials found in a UserToken in the cache.
+///
+/// # Arguments
+///
+/// * `cache` - The cache to store the token in.
+///
+/// * `client_id` - The client_id of the application which acquired the token.
+///
+/// * `authority` - The authority the token was acquired from.
+///
+/// * `scopes` - The scopes which were requested. These are only used when
+///   the token response does not include a scope.
+pub(crate) fn save_user_token(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    scopes: &[&str],
+    token: &UserToken,
+    user_assertion_hash: Option<&str>,
+) -> Result<(), MsalError> {
+    let home_account_id = match token.home_account_id() {
+        Ok(home_account_id) => home_account_id,
+        Err(e) => {
+            debug!("Token not cached, the account is unknown: {:?}", e);
+            return Ok(());
+        }
+    };
+    let (environment, authority_tenant) = split_authority(authority)?;
+    let realm = match token.tenant_id() {
+        Ok(tenant_id) => tenant_id.to_lowercase(),
+        Err(_) => authority_tenant,
+    };
+    let cached_at = now()?;
+
+    if let Some(access_token) = &token.access_token {
+        let target = match &token.scope {
+            Some(scope) => scope.clone(),
+            None => scopes
+                .iter()
+                .filter(|s| !RESERVED_SCOPES.contains(s))
+                .cloned()
+                .collect::<Vec<&str>>()
+                .join(" "),
+        };
+        cache.save_access_token(AccessTokenEntry {
+            home_account_id: home_account_id.clone(),
+            environment: environment.clone(),
+            client_id: client_id.to_string(),
+            realm: realm.clone(),
+            target,
+            secret: access_token.clone(),
+            cached_at,
+            expires_on: match token.expires_on {
+                0 => cached_at + token.expires_in as u64,
+                expires_on => expires_on,
+            },
+            extended_expires_on: cached_at + token.ext_expires_in as u64,
+            refresh_on: match token.refresh_on {
+                0 => None,
+                refresh_on => Some(refresh_on),
+            },
+            user_assertion_hash: user_assertion_hash.map(|hash| hash.to_string()),
+            additional_fields: Map::new(),
+        })?;
+    }
+
+    if !token.refresh_token.is_empty() {
+        cache.save_refresh_token(RefreshTokenEntry {
+            home_account_id: home_account_id.clone(),
+            environment: environment.clone(),
+            client_id: client_id.to_string(),
+            secret: token.refresh_token.clone(),
+            family_id: token.foci.clone(),
+            user_assertion_hash: user_assertion_hash.map(|hash| hash.to_string()),
+            additional_fields: Map::new(),
+        })?;
+    }
+
+    // An on-behalf-of token was acquired for the user of a web API, who has
+    // not signed in to this application.
+    if user_assertion_hash.is_some() {
+        return Ok(());
+    }
+
+    if let Some(raw) = &token.id_token.raw {
+        cache.save_id_token(IdTokenEntry {
+            home_account_id: home_account_id.clone(),
+            environment: environment.clone(),
+            client_id: client_id.to_string(),
+            realm: realm.clone(),
+            secret: raw.clone(),
+            additional_fields: Map::new(),
+        })?;
+    }
+
+    #[cfg(feature = "broker")]
+    if let Some(prt) = &token.prt {
+        cache.save_primary_refresh_token(PrimaryRefreshTokenEntry {
+            home_account_id: home_account_id.clone(),
+            environment: environment.clone(),
+            client_id: client_id.to_string(),
+            secret: encode_sealed_data(prt)?,
+            additional_fields: Map::new(),
+        })?;
+    }
+
+    cache.save_app_metadata(AppMetadataEntry {
+        client_id: client_id.to_string(),
+        environment: environment.clone(),
+        family_id: token.foci.clone(),
+        ..Default::default()
+    })?;
+
+    cache.save_account(AccountEntry {
+        home_account_id,
+        environment,
+        realm,
+        local_account_id: token.id_token.oid.clone(),
+        username: token.spn().unwrap_or_default(),
+        authority_type: "MSSTS".to_string(),
+        ..Default::default()
+    })
+}
+
+/// Find an unexpired access token for the account which covers all of the
+/// requested scopes, and rebuild a UserToken from it.
+pub(crate) fn find_user_token(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    scopes: &[&str],
+    account: &Account,
+) -> Result<Option<UserToken>, MsalError> {
+    let (environment, authority_tenant) = split_authority(authority)?;
+    // Tokens are cached under the tenant id, so an authority which names
+    // the tenant by domain matches the tenant of the account.
+    let realm = match Uuid::parse_str(&authority_tenant) {
+        Ok(_) => authority_tenant,
+        Err(_) => account.tenant_id.to_lowercase(),
+    };
+    let home_account_id = account.home_account_id.to_lowercase();
+    let requested = scope_set(&scopes.join(" "));
+    let now = now()?;
+
+    let access_token = cache.access_tokens()?.into_iter().find(|entry| {
+        entry.home_account_id.to_lowercase() == home_account_id
+            && is_alias(&entry.environment, &environment)
+            && entry.client_id.to_lowercase() == client_id.to_lowercase()
+            && entry.realm.to_lowercase() == realm
+            && entry.user_assertion_hash.is_none()
+            && entry.expires_on > now + ACCESS_TOKEN_EXPIRY_BUFFER
+            && requested.is_subset(&scope_set(&entry.target))
+    });
+    let access_token = match access_token {
+        Some(access_token) => access_token,
+        None => return Ok(None),
+    };
+    debug!("Access token found in the token cache");
+
+    let refresh_token = find_refresh_token(cache, client_id, authority, account)?;
+    let id_token = cache
+        .id_tokens()?
+        .into_iter()
+        .find(|entry| {
+            entry.home_account_id.to_lowercase() == home_account_id
+                && is_alias(&entry.environment, &environment)
+                && entry.client_id.to_lowercase() == client_id.to_lowercase()
+                && entry.realm.to_lowercase() == realm
+        })
+        .map(|entry| IdToken::from_str(&entry.secret))
+        .transpose()?
+        .unwrap_or_default();
+    #[cfg(feature = "broker")]
+    let prt = find_primary_refresh_token(cache, client_id, authority, account)?;
+
+    Ok(Some(UserToken {
+        token_type: "Bearer".to_string(),
+        scope: Some(access_token.target.clone()),
+        expires_in: (access_token.expires_on - now) as u32,
+        ext_expires_in: access_token.extended_expires_on.saturating_sub(now) as u32,
+        expires_on: access_token.expires_on,
+        refresh_on: access_token.refresh_on.unwrap_or(access_token.expires_on),
+        refresh_in: None,
+        access_token: Some(access_token.secret.clone()),
+        refresh_token: refresh_token
+            .as_ref()
+            .map(|entry| entry.secret.clone())
+            .unwrap_or_default(),
+        id_token,
+        client_info: ClientInfo::from_home_account_id(&account.home_account_id),
+        foci: refresh_token
+            .as_ref()
+            .and_then(|entry| entry.family_id.clone()),
+        #[cfg(feature = "broker")]
+        prt,
+    }))
+}
+
+/// Save an app token, acquired by a confidential client for itself. App
+/// tokens are cached as access tokens with an empty home_account_id, under
+/// the tenant of the authority and the requested scopes.
+pub(crate) fn save_app_token(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    scopes: &[&str],
+    token: &UserToken,
+) -> Result<(), MsalError> {
+    let access_token = match &token.access_token {
+        Some(access_token) => access_token,
+        None => return Ok(()),
+    };
+    let (environment, realm) = split_authority(authority)?;
+    let cached_at = now()?;
+    cache.save_access_token(AccessTokenEntry {
+        home_account_id: "".to_string(),
+        environment,
+        client_id: client_id.to_string(),
+        realm,
+        target: match &token.scope {
+            Some(scope) => scope.clone(),
+            None => scopes.join(" "),
+        },
+        secret: access_token.clone(),
+        cached_at,
+        expires_on: match token.expires_on {
+            0 => cached_at + token.expires_in as u64,
+            expires_on => expires_on,
+        },
+        extended_expires_on: cached_at + token.ext_expires_in as u64,
+        refresh_on: match token.refresh_on {
+            0 => None,
+            refresh_on => Some(refresh_on),
+        },
+        user_assertion_hash: None,
+        additional_fields: Map::new(),
+    })
+}
+
+/// Find an unexpired app token for the tenant of the authority which covers
+/// all of the requested scopes, and rebuild a UserToken from it.
+pub(crate) fn find_app_token(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    scopes: &[&str],
+) -> Result<Option<UserToken>, MsalError> {
+    let (environment, realm) = split_authority(authority)?;
+    let requested = scope_set(&scopes.join(" "));
+    let now = now()?;
+
+    let access_token = cache.access_tokens()?.into_iter().find(|entry| {
+        entry.home_account_id.is_empty()
+            && is_alias(&entry.environment, &environment)
+            && entry.client_id.to_lowercase() == client_id.to_lowercase()
+            && entry.realm.to_lowercase() == realm
+            && entry.user_assertion_hash.is_none()
+            && entry.expires_on > now + ACCESS_TOKEN_EXPIRY_BUFFER
+            && requested.is_subset(&scope_set(&entry.target))
+    });
+    let access_token = match access_token {
+        Some(access_token) => access_token,
+        None => return Ok(None),
+    };
+    debug!("App token found in the token cache");
+
+    Ok(Some(UserToken {
+        token_type: "Bearer".to_string(),
+        scope: Some(access_token.target.clone()),
+        expires_in: (access_token.expires_on - now) as u32,
+        ext_expires_in: access_token.extended_expires_on.saturating_sub(now) as u32,
+        expires_on: access_token.expires_on,
+        refresh_on: access_token.refresh_on.unwrap_or(access_token.expires_on),
+        refresh_in: None,
+        access_token: Some(access_token.secret.clone()),
+        refresh_token: "".to_string(),
+        id_token: IdToken::default(),
+        client_info: ClientInfo::default(),
+        foci: None,
+        #[cfg(feature = "broker")]
+        prt: None,
+    }))
+}
+
+/// Find a refresh token for the account.
+pub(crate) fn find_refresh_token(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    account: &Account,
+) -> Result<Option<RefreshTokenEntry>, MsalError> {
+    let (environment, _) = split_authority(authority)?;
+    let home_account_id = account.home_account_id.to_lowercase();
+    Ok(cache.refresh_tokens()?.into_iter().find(|entry| {
+        entry.home_account_id.to_lowercase() == home_account_id
+            && is_alias(&entry.environment, &environment)
+            && entry.client_id.to_lowercase() == client_id.to_lowercase()
+            && entry.user_assertion_hash.is_none()
+    }))
+}
+
+/// Find an unexpired on-behalf-of access token, acquired with the user
+/// assertion, which covers all of the requested scopes, and rebuild a
+/// UserToken from it.
+pub(crate) fn find_obo_token(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    scopes: &[&str],
+    user_assertion_hash: &str,
+) -> Result<Option<UserToken>, MsalError> {
+    let (environment, _) = split_authority(authority)?;
+    let requested = scope_set(&scopes.join(" "));
+    let now = now()?;
+
+    let access_token = cache.access_tokens()?.into_iter().find(|entry| {
+        entry.user_assertion_hash.as_deref() == Some(user_assertion_hash)
+            && is_alias(&entry.environment, &environment)
+            && entry.client_id.to_lowercase() == client_id.to_lowercase()
+            && entry.expires_on > now + ACCESS_TOKEN_EXPIRY_BUFFER
+            && requested.is_subset(&scope_set(&entry.target))
+    });
+    let access_token = match access_token {
+        Some(access_token) => access_token,
+        None => return Ok(None),
+    };
+    debug!("On-behalf-of token found in the token cache");
+
+    let refresh_token = find_obo_refresh_token(cache, client_id, authority, user_assertion_hash)?;
+    Ok(Some(UserToken {
+        token_type: "Bearer".to_string(),
+        scope: Some(access_token.target.clone()),
+        expires_in: (access_token.expires_on - now) as u32,
+        ext_expires_in: access_token.extended_expires_on.saturating_sub(now) as u32,
+        expires_on: access_token.expires_on,
+        refresh_on: access_token.refresh_on.unwrap_or(access_token.expires_on),
+        refresh_in: None,
+        access_token: Some(access_token.secret.clone()),
+        refresh_token: refresh_token
+            .as_ref()
+            .map(|entry| entry.secret.clone())
+            .unwrap_or_default(),
+        id_token: IdToken::default(),
+        client_info: ClientInfo::from_home_account_id(&access_token.home_account_id),
+        foci: None,
+        #[cfg(feature = "broker")]
+        prt: None,
+    }))
+}
+
+/// Find the refresh token of an on-behalf-of token, acquired with the user
+/// assertion.
+pub(crate) fn find_obo_refresh_token(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    user_assertion_hash: &str,
+) -> Result<Option<RefreshTokenEntry>, MsalError> {
+    let (environment, _) = split_authority(authority)?;
+    Ok(cache.refresh_tokens()?.into_iter().find(|entry| {
+        entry.user_assertion_hash.as_deref() == Some(user_assertion_hash)
+            && is_alias(&entry.environment, &environment)
+            && entry.client_id.to_lowercase() == client_id.to_lowercase()
+    }))
+}
+
+/// Find the sealed Primary Refresh Token of the account.
+#[cfg(feature = "broker")]
+pub(crate) fn find_primary_refresh_token(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    account: &Account,
+) -> Result<Option<SealedData>, MsalError> {
+    let (environment, _) = split_authority(authority)?;
+    let home_account_id = account.home_account_id.to_lowercase();
+    cache
+        .primary_refresh_tokens()?
+        .into_iter()
+        .find(|entry| {
+            entry.home_account_id.to_lowercase() == home_account_id
+                && is_alias(&entry.environment, &environment)
+                && entry.client_id.to_lowercase() == client_id.to_lowercase()
+        })
+        .map(|entry| decode_sealed_data(&entry.secret))
+        .transpose()
+}
+
+/// List the accounts in the cache which signed in to the environment of the
+/// authority. A B2C account is only listed for the policy it signed in with.
+pub(crate) fn get_accounts(
+    cache: &dyn TokenCache,
+    authority: &str,
+) -> Result<Vec<Account>, MsalError> {
+    let (environment, _) = split_authority(authority)?;
+    let policy = b2c_policy(authority);
+    Ok(cache
+        .accounts()?
+        .iter()
+        .filter(|entry| is_alias(&entry.environment, &environment))
+        .filter(|entry| match &policy {
+            Some(policy) => {
+                ClientInfo::from_home_account_id(&entry.home_account_id)
+                    .policy
+                    .as_ref()
+                    == Some(policy)
+            }
+            None => true,
+        })
+        .map(Account::from)
+        .collect())
+}
+
+/// Remove an account, and every token the client application cached for
+/// it, from the cache.
+pub(crate) fn remove_account(
+    cache: &dyn TokenCache,
+    client_id: &str,
+    authority: &str,
+    account: &Account,
+) -> Result<(), MsalError> {
+    let (environment, _) = split_authority(authority)?;
+    let home_account_id = account.home_account_id.to_lowercase();
+    let client_id = client_id.to_lowercase();
+    macro_rules! matches_account {
+        ($entry:ident) => {
+            $entry.home_account_id.to_lowercase() == home_account_id
+                && is_alias(&$entry.environment, &environment)
+        };
+    }
+
+    for entry in cache.access_tokens()? {
+        if matches_account!(entry) && entry.client_id.to_lowercase() == client_id {
+            cache.remove_access_token(&entry.key())?;
+        }
+    }
+    for entry in cache.refresh_tokens()? {
+        if matches_account!(entry) && entry.client_id.to_lowercase() == client_id {
+            cache.remove_refresh_token(&entry.key())?;
+        }
+    }
+    for entry in cache.id_tokens()? {
+        if matches_account!(entry) && entry.client_id.to_lowercase() == client_id {
+            cache.remove_id_token(&entry.key())?;
+        }
+    }
+    for entry in cache.primary_refresh_tokens()? {
+        if matches_account!(entry) && entry.client_id.to_lowercase() == client_id {
+            cache.remove_primary_refresh_token(&entry.key())?;
+        }
+    }
+    for entry in cache.accounts()? {
+        if matches_account!(entry) {
+            cache.remove_account(&entry.key())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use serde_json::json;
+
+    const CLIENT_ID: &str = "9a5e4d3c-2b1a-4f0e-8d7c-6b5a4f3e2d1c";
+    const UID: &str = "00000000-0000-0000-1111-222222222222";
+    const UTID: &str = "33333333-4444-5555-6666-777777777777";
+
+    fn authority() -> String {
+        format!("https://login.microsoftonline.com/{}", UTID)
+    }
+
+    fn user_token(access_token: &str, refresh_token: &str) -> UserToken {
+        let client_info = URL_SAFE_NO_PAD.encode(json!({"uid": UID, "utid": UTID}).to_string());
+        from_value(json!({
+            "token_type": "Bearer",
+            "scope": "https://graph.microsoft.com/User.Read",
+            "expires_in": 3600,
+            "ext_expires_in": 3600,
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "client_info": client_info,
+        }))
+        .unwrap()
+    }
+
+    fn account() -> Account {
+        Account {
+            home_account_id: format!("{}.{}", UID, UTID),
+            username: "user@contoso.onmicrosoft.com".to_string(),
+            tenant_id: UTID.to_string(),
+            local_account_id: UID.to_string(),
+        }
+    }
+
+    #[test]
+    fn obo_tokens_are_cached_apart_from_user_tokens() {
+        let cache = InMemoryTokenCache::new();
+        let scopes = ["https://graph.microsoft.com/User.Read"];
+        save_user_token(
+            &cache,
+            CLIENT_ID,
+            &authority(),
+            &scopes,
+            &user_token("user_at", "user_rt"),
+            None,
+        )
+        .unwrap();
+        save_user_token(
+            &cache,
+            CLIENT_ID,
+            &authority(),
+            &scopes,
+            &user_token("obo_at", "obo_rt"),
+            Some("assertion_hash"),
+        )
+        .unwrap();
+        assert_eq!(cache.access_tokens().unwrap().len(), 2);
+        assert_eq!(cache.refresh_tokens().unwrap().len(), 2);
+
+        let refresh_token = find_refresh_token(&cache, CLIENT_ID, &authority(), &account())
+            .unwrap()
+            .unwrap();
+        assert_eq!(refresh_token.secret, "user_rt");
+        let token = find_user_token(&cache, CLIENT_ID, &authority(), &scopes, &account())
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.access_token.as_deref(), Some("user_at"));
+
+        let refresh_token =
+            find_obo_refresh_token(&cache, CLIENT_ID, &authority(), "assertion_hash")
+                .unwrap()
+                .unwrap();
+        assert_eq!(refresh_token.secret, "obo_rt");
+        let token = find_obo_token(&cache, CLIENT_ID, &authority(), &scopes, "assertion_hash")
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.access_token.as_deref(), Some("obo_at"));
+    }
+
+    const MSAL_PYTHON_CACHE: &str = include_str!("../tests/fixtures/cache/msal_python.json");
+    const MSAL_DOTNET_CACHE: &str = include_str!("../tests/fixtures/cache/msal_dotnet.json");
+
+    fn json(state: &str) -> Value {
+        json_from_str(state).unwrap()
+    }
+
+    #[test]
+    fn load_caches_of_other_msal_implementations() {
+        let cache = InMemoryTokenCache::new();
+        cache.deserialize(MSAL_PYTHON_CACHE).unwrap();
+        let access_tokens = cache.access_tokens().unwrap();
+        assert_eq!(access_tokens.len(), 1);
+        assert_eq!(access_tokens[0].expires_on, 1735694182);
+        assert_eq!(access_tokens[0].refresh_on, Some(1735691891));
+        let refresh_tokens = cache.refresh_tokens().unwrap();
+        assert_eq!(refresh_tokens[0].family_id.as_deref(), Some("1"));
+        let accounts = cache.accounts().unwrap();
+        assert_eq!(accounts[0].username, "jdoe@contoso.onmicrosoft.com");
+        // The cache is written back as it was read
+        assert_eq!(json(&cache.serialize().unwrap()), json(MSAL_PYTHON_CACHE));
+
+        cache.deserialize(MSAL_DOTNET_CACHE).unwrap();
+        let access_tokens = cache.access_tokens().unwrap();
+        assert_eq!(access_tokens.len(), 1);
+        assert_eq!(
+            access_tokens[0].client_id,
+            "1950a258-227b-4e31-a9cf-717495945fc2"
+        );
+        assert_eq!(access_tokens[0].refresh_on, None);
+        assert_eq!(json(&cache.serialize().unwrap()), json(MSAL_DOTNET_CACHE));
+    }
+
+    #[test]
+    fn null_timestamps_are_absent() {
+        let mut cache = json(MSAL_PYTHON_CACHE);
+        for entry in cache["AccessToken"].as_object_mut().unwrap().values_mut() {
+            entry["refresh_on"] = Value::Null;
+        }
+        let data = CacheData::from_value(cache).unwrap();
+        let entry = data.access_tokens.values().next().unwrap();
+        assert_eq!(entry.refresh_on, None);
+        assert_eq!(entry.expires_on, 1735694182);
+    }
+
+    /// A cache which relies on the default serialization of the TokenCache
+    /// trait.
+    struct StorageOnlyCache(InMemoryTokenCache);
+
+    impl TokenCache for StorageOnlyCache {
+        fn access_tokens(&self) -> Result<Vec<AccessTokenEntry>, MsalError> {
+            self.0.access_tokens()
+        }
+        fn refresh_tokens(&self) -> Result<Vec<RefreshTokenEntry>, MsalError> {
+            self.0.refresh_tokens()
+        }
+        fn id_tokens(&self) -> Result<Vec<IdTokenEntry>, MsalError> {
+            self.0.id_tokens()
+        }
+        fn accounts(&self) -> Result<Vec<AccountEntry>, MsalError> {
+            self.0.accounts()
+        }
+        fn app_metadata(&self) -> Result<Vec<AppMetadataEntry>, MsalError> {
+            self.0.app_metadata()
+        }
+        fn primary_refresh_tokens(&self) -> Result<Vec<PrimaryRefreshTokenEntry>, MsalError> {
+            self.0.primary_refresh_tokens()
+        }
+        fn save_access_token(&self, entry: AccessTokenEntry) -> Result<(), MsalError> {
+            self.0.save_access_token(entry)
+        }
+        fn save_refresh_token(&self, entry: RefreshTokenEntry) -> Result<(), MsalError> {
+            self.0.save_refresh_token(entry)
+        }
+        fn save_id_token(&self, entry: IdTokenEntry) -> Result<(), MsalError> {
+            self.0.save_id_token(entry)
+        }
+        fn save_account(&self, entry: AccountEntry) -> Result<(), MsalError> {
+            self.0.save_account(entry)
+        }
+        fn save_app_metadata(&self, entry: AppMetadataEntry) -> Result<(), MsalError> {
+            self.0.save_app_metadata(entry)
+        }
+        fn save_primary_refresh_token(
+            &self,
+            entry: PrimaryRefreshTokenEntry,
+        ) -> Result<(), MsalError> {
+            self.0.save_primary_refresh_token(entry)
+        }
+        fn remove_access_token(&self, key: &str) -> Result<(), MsalError> {
+            self.0.remove_access_token(key)
+        }
+        fn remove_refresh_token(&self, key: &str) -> Result<(), MsalError> {
+            self.0.remove_refresh_token(key)
+        }
+        fn remove_id_token(&self, key: &str) -> Result<(), MsalError> {
+            self.0.remove_id_token(key)
+        }
+        fn remove_account(&self, key: &str) -> Result<(), MsalError> {
+            self.0.remove_account(key)
+        }
+        fn remove_app_metadata(&self, key: &str) -> Result<(), MsalError> {
+            self.0.remove_app_metadata(key)
+        }
+        fn remove_primary_refresh_token(&self, key: &str) -> Result<(), MsalError> {
+            self.0.remove_primary_refresh_token(key)
+        }
+    }
+
+    #[test]
+    fn default_serialization_round_trip() {
+        let cache = StorageOnlyCache(InMemoryTokenCache::new());
+        cache.deserialize(MSAL_DOTNET_CACHE).unwrap();
+        cache.deserialize(MSAL_PYTHON_CACHE).unwrap();
+        // The tokens of the previous cache are replaced
+        assert_eq!(cache.access_tokens().unwrap().len(), 1);
+        let expected = json(MSAL_PYTHON_CACHE);
+        let serialized = json(&cache.serialize().unwrap());
+        for section in [
+            "AccessToken",
+            "RefreshToken",
+            "IdToken",
+            "Account",
+            "AppMetadata",
+        ] {
+            assert_eq!(serialized[section], expected[section]);
+        }
+    }
+
+    #[test]
+    fn invalid_state_leaves_cache_unchanged() {
+        let cache = StorageOnlyCache(InMemoryTokenCache::new());
+        cache.deserialize(MSAL_PYTHON_CACHE).unwrap();
+        let mut invalid = json(MSAL_DOTNET_CACHE);
+        for entry in invalid["AccessToken"].as_object_mut().unwrap().values_mut() {
+            entry["expires_on"] = "soon".into();
+        }
+        assert!(cache.deserialize(&invalid.to_string()).is_err());
+        assert_eq!(
+            json(&cache.serialize().unwrap())["AccessToken"],
+            json(MSAL_PYTHON_CACHE)["AccessToken"]
+        );
+    }
+
+    #[cfg(feature = "broker")]
+    #[test]
+    fn sealed_cache_round_trip() {
+        use kanidm_hsm_crypto::soft::SoftTpm;
+        use kanidm_hsm_crypto::{AuthValue, Tpm};
+
+        let mut tpm = BoxedDynTpm::new(SoftTpm::new());
+        let auth_value = AuthValue::ephemeral().unwrap();
+        let loadable_machine_key = tpm.machine_key_create(&auth_value).unwrap();
+        let machine_key = tpm
+            .machine_key_load(&auth_value, &loadable_machine_key)
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("msal-cache-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("token_cache.json");
+
+        let cache = SealedFileTokenCache::new(&path, tpm, &machine_key).unwrap();
+        cache.deserialize(MSAL_PYTHON_CACHE).unwrap();
+        assert_eq!(json(&cache.serialize().unwrap()), json(MSAL_PYTHON_CACHE));
+        // The secrets are sealed in the cache file
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("python-refresh-token"));
+        assert!(contents.contains("jdoe@contoso.onmicrosoft.com"));
+
+        // The cache is loaded from the file by another instance
+        let tpm = BoxedDynTpm::new(SoftTpm::new());
+        let reopened = SealedFileTokenCache::new(&path, tpm, &machine_key).unwrap();
+        assert_eq!(
+            json(&reopened.serialize().unwrap()),
+            json(MSAL_PYTHON_CACHE)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::MsalError;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use regex::Regex;
+use serde_json::{from_slice as json_from_slice, Value};
+use std::collections::HashMap;
+
+/// The error of a challenge which requires additional claims, such as a
+/// continuous access evaluation (CAE) revocation.
+const INSUFFICIENT_CLAIMS: &str = "insufficient_claims";
+
+/// A challenge from the `WWW-Authenticate` header of a resource's 401
+/// response.
+#[derive(Debug, Clone, Default)]
+pub struct WwwAuthenticateParameters {
+    /// The authentication scheme, typically Bearer.
+    pub scheme: String,
+    pub realm: Option<String>,
+    /// The authority which issues tokens for the resource.
+    pub authorization_uri: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+    /// The decoded claims challenge, which is passed as the `claims` of an
+    /// acquire token request.
+    pub claims: Option<String>,
+    /// All parameters of the challenge, by lowercase name.
+    pub parameters: HashMap<String, String>,
+}
+
+impl WwwAuthenticateParameters {
+    /// Parse the challenges of a `WWW-Authenticate` header.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The value of the `WWW-Authenticate` header.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The challenges of the header, in order.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn parse(header: &str) -> Result<Vec<Self>, MsalError> {
+        let re = Regex::new(r#"([A-Za-z0-9_.\-]+)(?:\s*=\s*(?:"((?:[^"\\]|\\.)*)"|([^\s,]*)))?"#)
+            .map_err(|e| MsalError::InvalidRegex(format!("{}", e)))?;
+        let mut challenges: Vec<WwwAuthenticateParameters> = vec![];
+        for cap in re.captures_iter(header) {
+            let name = match cap.get(1) {
+                Some(name) => name.as_str(),
+                None => continue,
+            };
+            let value = match (cap.get(2), cap.get(3)) {
+                (Some(quoted), _) => Some(quoted.as_str().replace("\\\"", "\"")),
+                (None, Some(token)) => Some(token.as_str().to_string()),
+                (None, None) => None,
+            };
+            match (value, challenges.last_mut()) {
+                (Some(value), Some(challenge)) => {
+                    challenge.parameters.insert(name.to_lowercase(), value);
+                }
+                (Some(_), None) => {
+                    return Err(MsalError::InvalidParse(
+                        "WWW-Authenticate parameter without a scheme".to_string(),
+                    ))
+                }
+                (None, _) => challenges.push(WwwAuthenticateParameters {
+                    scheme: name.to_string(),
+                    ..Default::default()
+                }),
+            }
+        }
+        for challenge in challenges.iter_mut() {
+            let param = |name: &str| challenge.parameters.get(name).cloned();
+            challenge.realm = param("realm");
+            challenge.authorization_uri = param("authorization_uri");
+            challenge.error = param("error");
+            challenge.error_description = param("error_description");
+            challenge.claims = match param("claims") {
+                Some(claims) => Some(decode_claims(&claims)?),
+                None => None,
+            };
+        }
+        Ok(challenges)
+    }
+}
+
+/// Extract the claims challenge from the `WWW-Authenticate` header of a
+/// resource's 401 response. The claims are passed as the `claims` of an
+/// acquire token request, to acquire a token which satisfies the challenge.
+///
+/// # Arguments
+///
+/// * `header` - The value of the `WWW-Authenticate` header.
+///
+/// # Returns
+///
+/// * Success: The decoded claims challenge, or None if the header does not
+///   contain one.
+/// * Failure: An MsalError, indicating the failure.
+pub fn claims_challenge(header: &str) -> Result<Option<String>, MsalError> {
+    let challenges = WwwAuthenticateParameters::parse(header)?;
+    Ok(challenges
+        .iter()
+        .find(|challenge| {
+            challenge.claims.is_some() && challenge.error.as_deref() == Some(INSUFFICIENT_CLAIMS)
+        })
+        .or(challenges
+            .iter()
+            .find(|challenge| challenge.claims.is_some()))
+        .and_then(|challenge| challenge.claims.clone()))
+}
+
+/// Claims challenges are base64 encoded JSON, although some resources send
+/// the JSON unencoded.
+fn decode_claims(claims: &str) -> Result<String, MsalError> {
+    if claims.trim_start().starts_with('{') {
+        return Ok(claims.to_string());
+    }
+    for engine in [&STANDARD, &URL_SAFE, &STANDARD_NO_PAD, &URL_SAFE_NO_PAD] {
+        if let Ok(decoded) = engine.decode(claims) {
+            if json_from_slice::<Value>(&decoded).is_ok() {
+                return String::from_utf8(decoded)
+                    .map_err(|e| MsalError::InvalidParse(format!("{}", e)));
+            }
+        }
+    }
+    Err(MsalError::InvalidBase64(
+        "Failed decoding the claims challenge".to_string(),
+    ))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// The claims of a continuous access evaluation challenge.
+    const CAE_CLAIMS: &str = r#"{"access_token":{"nbf":{"essential":true, "value":"1604106651"}}}"#;
+    const CAE_CHALLENGE: &str = r#"Bearer realm="", authorization_uri="https://login.microsoftonline.com/common/oauth2/authorize", client_id="00000003-0000-0000-c000-000000000000", error="insufficient_claims", claims="eyJhY2Nlc3NfdG9rZW4iOnsibmJmIjp7ImVzc2VudGlhbCI6dHJ1ZSwgInZhbHVlIjoiMTYwNDEwNjY1MSJ9fX0=""#;
+
+    #[test]
+    fn parse_challenge() {
+        let challenges = WwwAuthenticateParameters::parse(CAE_CHALLENGE).unwrap();
+        assert_eq!(challenges.len(), 1);
+        let challenge = &challenges[0];
+        assert_eq!(challenge.scheme, "Bearer");
+        assert_eq!(challenge.realm.as_deref(), Some(""));
+        assert_eq!(
+            challenge.authorization_uri.as_deref(),
+            Some("https://login.microsoftonline.com/common/oauth2/authorize")
+        );
+        assert_eq!(challenge.error.as_deref(), Some("insufficient_claims"));
+        assert_eq!(challenge.claims.as_deref(), Some(CAE_CLAIMS));
+        assert_eq!(
+            challenge.parameters["client_id"],
+            "00000003-0000-0000-c000-000000000000"
+        );
+    }
+
+    #[test]
+    fn parse_multiple_challenges() {
+        let header = r#"Basic realm="contoso", Bearer Realm=graph, error=invalid_token, error_description="The token has \"expired\", sign in again", PoP nonce="abc""#;
+        let challenges = WwwAuthenticateParameters::parse(header).unwrap();
+        let schemes: Vec<&str> = challenges
+            .iter()
+            .map(|challenge| challenge.scheme.as_str())
+            .collect();
+        assert_eq!(schemes, vec!["Basic", "Bearer", "PoP"]);
+        assert_eq!(challenges[0].realm.as_deref(), Some("contoso"));
+        // Parameter names are case insensitive, and values may be tokens
+        assert_eq!(challenges[1].realm.as_deref(), Some("graph"));
+        assert_eq!(challenges[1].error.as_deref(), Some("invalid_token"));
+        assert_eq!(
+            challenges[1].error_description.as_deref(),
+            Some(r#"The token has "expired", sign in again"#)
+        );
+        assert_eq!(challenges[2].parameters["nonce"], "abc");
+        assert!(challenges
+            .iter()
+            .all(|challenge| challenge.claims.is_none()));
+
+        assert!(WwwAuthenticateParameters::parse(r#"realm="contoso""#).is_err());
+    }
+
+    #[test]
+    fn decode_claims_encodings() {
+        let claims = r#"{"access_token":{"acrs":{"essential":true,"value":"c1"}}}"#;
+        // Base64 with padding, and without padding
+        assert_eq!(
+            decode_claims(
+                "eyJhY2Nlc3NfdG9rZW4iOnsibmJmIjp7ImVzc2VudGlhbCI6dHJ1ZSwgInZhbHVlIjoiMTYwNDEwNjY1MSJ9fX0="
+            )
+            .unwrap(),
+            CAE_CLAIMS
+        );
+        assert_eq!(
+            decode_claims(
+                "eyJhY2Nlc3NfdG9rZW4iOnsibmJmIjp7ImVzc2VudGlhbCI6dHJ1ZSwgInZhbHVlIjoiMTYwNDEwNjY1MSJ9fX0"
+            )
+            .unwrap(),
+            CAE_CLAIMS
+        );
+        assert_eq!(
+            decode_claims(&URL_SAFE_NO_PAD.encode(claims)).unwrap(),
+            claims
+        );
+        // Unencoded JSON is passed through
+        assert_eq!(decode_claims(claims).unwrap(), claims);
+        assert!(matches!(
+            decode_claims("not claims"),
+            Err(MsalError::InvalidBase64(_))
+        ));
+        // Base64 which is not JSON is not a claims challenge
+        assert!(decode_claims(&STANDARD.encode("not json")).is_err());
+    }
+
+    #[test]
+    fn claims_challenge_prefers_insufficient_claims() {
+        let header = format!(
+            r#"Bearer error="invalid_token", claims="{}", {}"#,
+            STANDARD.encode(r#"{"id_token":{"auth_time":{"essential":true}}}"#),
+            CAE_CHALLENGE
+        );
+        assert_eq!(
+            claims_challenge(&header).unwrap().as_deref(),
+            Some(CAE_CLAIMS)
+        );
+
+        // Otherwise the first challenge with claims is used
+        let header = format!(
+            r#"Basic realm="contoso", Bearer error="invalid_token", claims="{}""#,
+            STANDARD.encode(r#"{"id_token":{"auth_time":{"essential":true}}}"#)
+        );
+        assert_eq!(
+            claims_challenge(&header).unwrap().as_deref(),
+            Some(r#"{"id_token":{"auth_time":{"essential":true}}}"#)
+        );
+        assert_eq!(
+            claims_challenge(r#"Bearer realm="", error="invalid_token""#).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,279 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::MsalError;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+/// The login hosts of the built in clouds. The hosts of each group are
+/// aliases, which issue tokens for the same accounts. The US Government
+/// clouds share their login hosts.
+pub(crate) const KNOWN_LOGIN_HOSTS: [(CloudInstance, &[&str]); 4] = [
+    (
+        CloudInstance::Public,
+        &[
+            "login.microsoftonline.com",
+            "login.windows.net",
+            "login.microsoft.com",
+            "sts.windows.net",
+        ],
+    ),
+    (
+        CloudInstance::China,
+        &["login.partner.microsoftonline.cn", "login.chinacloudapi.cn"],
+    ),
+    (
+        CloudInstance::USGov,
+        &["login.microsoftonline.us", "login.usgovcloudapi.net"],
+    ),
+    (CloudInstance::USGov, &["login-us.microsoftonline.com"]),
+];
+
+/// The hosts of a cloud which is not built in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CloudEndpoints {
+    /// The login host, such as `login.microsoftonline.com`.
+    pub login_host: String,
+    /// The Device Registration Service host, such as
+    /// `enterpriseregistration.windows.net`.
+    pub drs_host: String,
+    /// The Microsoft Graph host, such as `graph.microsoft.com`.
+    pub graph_host: String,
+    /// The Intune enrollment host, such as `enrollment.manage.microsoft.com`.
+    pub enrollment_host: String,
+}
+
+/// The Azure cloud which hosts a tenant.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CloudInstance {
+    /// The Azure public cloud.
+    #[default]
+    Public,
+    /// Azure Government, used by GCC High.
+    USGov,
+    /// Azure Government for the US Department of Defense.
+    USGovDoD,
+    /// Azure China, operated by 21Vianet.
+    China,
+    /// A cloud which is not built in.
+    Custom(CloudEndpoints),
+}
+
+impl CloudInstance {
+    /// Find the built in cloud which uses a login host. The US Government
+    /// clouds share a login host, which is reported as USGov.
+    ///
+    /// # Arguments
+    ///
+    /// * `login_host` - A login host, such as `login.microsoftonline.us`.
+    ///
+    /// # Returns
+    ///
+    /// * The cloud, or None if the login host is not that of a built in cloud.
+    pub fn from_login_host(login_host: &str) -> Option<Self> {
+        KNOWN_LOGIN_HOSTS
+            .into_iter()
+            .find(|(_, hosts)| {
+                hosts
+                    .iter()
+                    .any(|host| host.eq_ignore_ascii_case(login_host))
+            })
+            .map(|(cloud, _)| cloud)
+    }
+
+    /// Find the built in cloud of an authority.
+    ///
+    /// # Arguments
+    ///
+    /// * `authority` - A URL that identifies a token authority.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The cloud, or None if the authority is not hosted by a
+    ///   built in cloud.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn from_authority(authority: &str) -> Result<Option<Self>, MsalError> {
+        let url = Url::parse(authority).map_err(|e| MsalError::InvalidParse(format!("{}", e)))?;
+        Ok(url.host_str().and_then(CloudInstance::from_login_host))
+    }
+
+    pub fn login_host(&self) -> &str {
+        match self {
+            CloudInstance::Public => "login.microsoftonline.com",
+            CloudInstance::USGov | CloudInstance::USGovDoD => "login.microsoftonline.us",
+            CloudInstance::China => "login.chinacloudapi.cn",
+            CloudInstance::Custom(endpoints) => &endpoints.login_host,
+        }
+    }
+
+    pub fn drs_host(&self) -> &str {
+        match self {
+            CloudInstance::Public => "enterpriseregistration.windows.net",
+            CloudInstance::USGov | CloudInstance::USGovDoD => {
+                "enterpriseregistration.microsoftonline.us"
+            }
+            CloudInstance::China => "enterpriseregistration.partner.microsoftonline.cn",
+            CloudInstance::Custom(endpoints) => &endpoints.drs_host,
+        }
+    }
+
+    pub fn graph_host(&self) -> &str {
+        match self {
+            CloudInstance::Public => "graph.microsoft.com",
+            CloudInstance::USGov => "graph.microsoft.us",
+            CloudInstance::USGovDoD => "dod-graph.microsoft.us",
+            CloudInstance::China => "microsoftgraph.chinacloudapi.cn",
+            CloudInstance::Custom(endpoints) => &endpoints.graph_host,
+        }
+    }
+
+    pub fn enrollment_host(&self) -> &str {
+        match self {
+            CloudInstance::Public => "enrollment.manage.microsoft.com",
+            CloudInstance::USGov | CloudInstance::USGovDoD => "enrollment.manage.microsoft.us",
+            CloudInstance::China => "enrollment.manage.microsoftonline.cn",
+            CloudInstance::Custom(endpoints) => &endpoints.enrollment_host,
+        }
+    }
+
+    /// The authority of a tenant in this cloud.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - A tenant id or domain, or `common`, `organizations` or
+    ///   `consumers`.
+    pub fn authority(&self, tenant: &str) -> String {
+        format!("https://{}/{}", self.login_host(), tenant)
+    }
+
+    /// The URL of the Device Registration Service.
+    pub fn drs_url(&self) -> String {
+        format!("https://{}", self.drs_host())
+    }
+
+    /// The resource id of the Device Registration Service.
+    pub fn drs_resource_id(&self) -> String {
+        format!("urn:ms-drs:{}", self.drs_host())
+    }
+
+    /// The URL of the Microsoft Graph API.
+    pub fn graph_url(&self) -> String {
+        format!("https://{}", self.graph_host())
+    }
+
+    /// The resource of the Intune enrollment service, which issues tokens
+    /// for device enrollment.
+    pub fn enrollment_resource(&self) -> String {
+        format!("https://{}/", self.enrollment_host())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::authority::is_known_host;
+
+    #[test]
+    fn login_hosts_map_to_their_cloud() {
+        for (host, cloud) in [
+            ("login.microsoftonline.com", CloudInstance::Public),
+            ("login.windows.net", CloudInstance::Public),
+            ("login.microsoft.com", CloudInstance::Public),
+            ("sts.windows.net", CloudInstance::Public),
+            ("LOGIN.MicrosoftOnline.com", CloudInstance::Public),
+            ("login.microsoftonline.us", CloudInstance::USGov),
+            ("login.usgovcloudapi.net", CloudInstance::USGov),
+            ("login-us.microsoftonline.com", CloudInstance::USGov),
+            ("login.chinacloudapi.cn", CloudInstance::China),
+            ("login.partner.microsoftonline.cn", CloudInstance::China),
+        ] {
+            assert_eq!(
+                CloudInstance::from_login_host(host),
+                Some(cloud),
+                "{}",
+                host
+            );
+            // The hosts of a cloud are the hosts which are known aliases
+            assert!(is_known_host(host), "{}", host);
+        }
+        assert_eq!(CloudInstance::from_login_host("login.contoso.com"), None);
+        assert!(!is_known_host("login.contoso.com"));
+    }
+
+    #[test]
+    fn each_cloud_is_found_from_its_authority() {
+        for cloud in [
+            CloudInstance::Public,
+            CloudInstance::USGov,
+            CloudInstance::China,
+        ] {
+            let authority = cloud.authority("contoso.onmicrosoft.com");
+            assert_eq!(
+                CloudInstance::from_authority(&authority).unwrap(),
+                Some(cloud)
+            );
+        }
+        // The US Government clouds share a login host
+        let authority = CloudInstance::USGovDoD.authority("common");
+        assert_eq!(
+            CloudInstance::from_authority(&authority).unwrap(),
+            Some(CloudInstance::USGov)
+        );
+        assert_eq!(
+            CloudInstance::from_authority("https://contoso.b2clogin.com/tfp/contoso/B2C_1_signin")
+                .unwrap(),
+            None
+        );
+        assert!(CloudInstance::from_authority("not a url").is_err());
+    }
+
+    #[test]
+    fn cloud_service_hosts() {
+        let gov = CloudInstance::USGov;
+        let dod = CloudInstance::USGovDoD;
+        assert_eq!(
+            gov.drs_url(),
+            "https://enterpriseregistration.microsoftonline.us"
+        );
+        assert_eq!(dod.drs_host(), gov.drs_host());
+        assert_eq!(gov.graph_url(), "https://graph.microsoft.us");
+        assert_eq!(dod.graph_url(), "https://dod-graph.microsoft.us");
+        assert_eq!(
+            CloudInstance::China.drs_resource_id(),
+            "urn:ms-drs:enterpriseregistration.partner.microsoftonline.cn"
+        );
+        assert_eq!(
+            CloudInstance::Public.enrollment_resource(),
+            "https://enrollment.manage.microsoft.com/"
+        );
+
+        let custom = CloudInstance::Custom(CloudEndpoints {
+            login_host: "login.contoso.com".to_string(),
+            drs_host: "drs.contoso.com".to_string(),
+            graph_host: "graph.contoso.com".to_string(),
+            enrollment_host: "enrollment.contoso.com".to_string(),
+        });
+        assert_eq!(
+            custom.authority("common"),
+            "https://login.contoso.com/common"
+        );
+        assert_eq!(custom.drs_url(), "https://drs.contoso.com");
+        assert_eq!(custom.graph_url(), "https://graph.contoso.com");
+    }
+}
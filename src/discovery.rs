@@ -16,6 +16,7 @@
    along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::cloud::CloudInstance;
 use crate::error::MsalError;
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
@@ -32,6 +33,8 @@ use zeroize::{Zeroize, ZeroizeOnDrop};
 
 pub const DRS_CLIENT_NAME_HEADER_FIELD: &str = "ocp-adrs-client-name";
 pub const DRS_CLIENT_VERSION_HEADER_FIELD: &str = "ocp-adrs-client-version";
+/// The Device Registration Service of the public cloud. The service of
+/// other clouds is found with CloudInstance::drs_url.
 pub const DISCOVERY_URL: &str = "https://enterpriseregistration.windows.net";
 const DRS_PROTOCOL_VERSION: &str = "1.9";
 
@@ -333,6 +336,8 @@ impl EnrollAttrs {
 pub struct Services {
     #[serde(skip_deserializing)]
     client: Client,
+    #[serde(skip_deserializing)]
+    cloud: CloudInstance,
     #[serde(rename = "ServicesService")]
     pub discovery_service: Option<ServicesService>,
     #[serde(rename = "DeviceRegistrationService")]
@@ -366,9 +371,13 @@ pub struct Services {
 }
 
 impl Services {
-    pub async fn new(access_token: &str, domain_name: &str) -> Result<Self, MsalError> {
+    pub async fn new(
+        access_token: &str,
+        domain_name: &str,
+        cloud: &CloudInstance,
+    ) -> Result<Self, MsalError> {
         let url = Url::parse_with_params(
-            &format!("{}/{}/Discover", DISCOVERY_URL, domain_name),
+            &format!("{}/{}/Discover", cloud.drs_url(), domain_name),
             &[("api-version", DRS_PROTOCOL_VERSION), ("managed", "True")],
         )
         .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
@@ -393,6 +402,7 @@ impl Services {
                 .await
                 .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
             json_resp.client = client;
+            json_resp.cloud = cloud.clone();
             Ok(json_resp)
         } else {
             Err(MsalError::GeneralFailure(
@@ -408,7 +418,11 @@ impl Services {
         tenant_id: &str,
         access_token: &str,
     ) -> Result<String, MsalError> {
-        let fallback_endpoint = format!("{}/EnrollmentServer/nonce/{}/", DISCOVERY_URL, tenant_id);
+        let fallback_endpoint = format!(
+            "{}/EnrollmentServer/nonce/{}/",
+            self.cloud.drs_url(),
+            tenant_id
+        );
         let url = match &self.nonce_service {
             Some(nonce_service) => {
                 let endpoint = match &nonce_service.endpoint {
@@ -452,7 +466,7 @@ impl Services {
         transport_key: &Rsa<Public>,
         csr_der: &Vec<u8>,
     ) -> Result<(X509, String), MsalError> {
-        let fallback_endpoint = format!("{}/EnrollmentServer/device/", DISCOVERY_URL);
+        let fallback_endpoint = format!("{}/EnrollmentServer/device/", self.cloud.drs_url());
         let (join_endpoint, service_version) = match &self.device_join_service {
             Some(device_join_service) => {
                 let join_endpoint = match &device_join_service.endpoint {
@@ -526,7 +540,7 @@ impl Services {
             let device_id = match subject_name.entries().next() {
                 Some(entry) => entry
                     .data()
-                    .as_utf8()
+                    .to_string()
                     .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?,
                 None => {
                     return Err(MsalError::GeneralFailure(
@@ -548,9 +562,9 @@ impl Services {
         match &self.key_provisioning_service {
             Some(key_provisioning_service) => match &key_provisioning_service.resource_id {
                 Some(resource_id) => resource_id.clone(),
-                None => "urn:ms-drs:enterpriseregistration.windows.net".to_string(),
+                None => self.cloud.drs_resource_id(),
             },
-            None => "urn:ms-drs:enterpriseregistration.windows.net".to_string(),
+            None => self.cloud.drs_resource_id(),
         }
     }
 
@@ -559,7 +573,7 @@ impl Services {
         access_token: &str,
         pub_key: &Rsa<Public>,
     ) -> Result<(), MsalError> {
-        let fallback_endpoint = format!("{}/EnrollmentServer/key/", DISCOVERY_URL);
+        let fallback_endpoint = format!("{}/EnrollmentServer/key/", self.cloud.drs_url());
         let (endpoint, service_version) = match &self.key_provisioning_service {
             Some(key_provisioning_service) => {
                 let endpoint = match &key_provisioning_service.endpoint {
@@ -33,6 +33,51 @@ pub struct ErrorResponse {
     pub error: String,
     pub error_description: String,
     pub error_codes: Vec<u32>,
+    /// A claims challenge, which is passed as the `claims` of the next
+    /// acquire token request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claims: Option<String>,
+    /// Refines the error, for example `basic_action` when the user must
+    /// sign in again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suberror: Option<String>,
+}
+
+impl ErrorResponse {
+    /// Whether the user must authenticate interactively (or with MFA) to
+    /// satisfy the request, for example because of a claims challenge or a
+    /// Conditional Access policy. A silent retry will fail again.
+    pub fn interaction_required(&self) -> bool {
+        match self.error.as_str() {
+            "interaction_required" | "login_required" | "consent_required" => true,
+            "invalid_grant" => {
+                self.claims.is_some()
+                    || matches!(
+                        self.suberror.as_deref(),
+                        Some(
+                            "basic_action"
+                                | "additional_action"
+                                | "message_only"
+                                | "consent_required"
+                                | "user_password_expired"
+                        )
+                    )
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The reason an expired password could not be changed
+#[derive(Serialize, Debug, Clone)]
+pub enum PasswordChangeError {
+    /// The current password is incorrect
+    IncorrectPassword(String),
+    /// The new password does not satisfy the password policy of the tenant,
+    /// for example because it is too short, too simple, or was used recently
+    PolicyViolation(String),
+    /// The password change failed for another reason, or did not complete
+    Failed(String),
 }
 
 #[derive(Serialize, Debug)]
@@ -67,6 +112,14 @@ pub enum MsalError {
     ConfigError(String),
     /// Continuing polling for an MFA auth
     MFAPollContinue,
+    /// No usable token was found in the token cache
+    CacheMiss,
+    /// An expired password could not be changed
+    PasswordChangeFailed(PasswordChangeError),
+    /// The authority is malformed, or is not a trusted authority
+    InvalidAuthority(String),
+    /// A token failed validation of its signature or claims
+    InvalidToken(String),
 }
 
 #[repr(C)]
@@ -90,6 +143,12 @@ pub enum MSAL_ERROR {
     SUCCESS,
     INVALID_POINTER,
     NO_MEMORY,
+    // New values are appended, so that the values of existing errors do not
+    // change for C consumers.
+    CACHE_MISS,
+    PASSWORD_CHANGE_FAILED,
+    INVALID_AUTHORITY,
+    INVALID_TOKEN,
 }
 
 impl From<MsalError> for MSAL_ERROR {
@@ -110,6 +169,10 @@ impl From<MsalError> for MSAL_ERROR {
             MsalError::NotImplemented => MSAL_ERROR::NOT_IMPLEMENTED,
             MsalError::ConfigError(_) => MSAL_ERROR::CONFIG_ERROR,
             MsalError::MFAPollContinue => MSAL_ERROR::MFA_POLL_CONTINUE,
+            MsalError::CacheMiss => MSAL_ERROR::CACHE_MISS,
+            MsalError::PasswordChangeFailed(_) => MSAL_ERROR::PASSWORD_CHANGE_FAILED,
+            MsalError::InvalidAuthority(_) => MSAL_ERROR::INVALID_AUTHORITY,
+            MsalError::InvalidToken(_) => MSAL_ERROR::INVALID_TOKEN,
         }
     }
 }
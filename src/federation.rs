@@ -0,0 +1,613 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cache::now;
+use crate::error::MsalError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use regex::Regex;
+use reqwest::{header, Client, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+use uuid::Uuid;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// How long a WS-Trust request is valid, in seconds.
+const WSTRUST_REQUEST_LIFETIME: u64 = 10 * 60;
+/// The audience of SAML assertions issued for AAD, when the user realm does
+/// not name one.
+const DEFAULT_CLOUD_AUDIENCE_URN: &str = "urn:federation:MicrosoftOnline";
+
+/// The WS-Trust 1.3 usernamemixed endpoint of an AD FS server.
+const ADFS_WSTRUST_ENDPOINT: &str = "/adfs/services/trust/13/usernamemixed";
+
+const SAML1_1_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:saml1_1-bearer";
+const SAML2_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:saml2-bearer";
+
+/// The result of home realm discovery for a user.
+#[derive(Debug, Deserialize)]
+pub(crate) struct UserRealm {
+    account_type: String,
+    #[serde(default)]
+    federation_protocol: Option<String>,
+    #[serde(default)]
+    federation_active_auth_url: Option<String>,
+    #[serde(default)]
+    cloud_audience_urn: Option<String>,
+}
+
+impl UserRealm {
+    /// The WS-Trust usernamemixed endpoint of a federated user, or None if
+    /// the user is managed by AAD.
+    fn wstrust_endpoint(&self) -> Option<&str> {
+        if self.account_type != "Federated" {
+            return None;
+        }
+        match self.federation_protocol.as_deref() {
+            Some("WSTrust") | None => self.federation_active_auth_url.as_deref(),
+            Some(_) => None,
+        }
+    }
+}
+
+/// The WS-Trust endpoint of an AD FS server, derived from the passive
+/// sign-in url which AAD redirects a federated user to. Other identity
+/// providers do not have a well known WS-Trust endpoint.
+fn adfs_wstrust_endpoint(federation_redirect_url: &str) -> Option<String> {
+    let url = Url::parse(federation_redirect_url).ok()?;
+    if !url.path().to_lowercase().starts_with("/adfs/") {
+        return None;
+    }
+    url.join(ADFS_WSTRUST_ENDPOINT)
+        .ok()
+        .map(|endpoint| endpoint.to_string())
+}
+
+/// The user realms discovered for the domains of users, so that the realm of
+/// a domain is only discovered once. Failed discoveries are not cached.
+#[derive(Default)]
+pub(crate) struct UserRealmCache {
+    realms: Mutex<HashMap<String, Arc<UserRealm>>>,
+}
+
+impl UserRealmCache {
+    fn realms(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<String, Arc<UserRealm>>>, MsalError> {
+        self.realms
+            .lock()
+            .map_err(|e| MsalError::GeneralFailure(format!("User realm cache poisoned: {}", e)))
+    }
+
+    /// The user realm of the domain of a user, which is discovered if it is
+    /// not cached.
+    async fn get(
+        &self,
+        client: &Client,
+        authority: &str,
+        username: &str,
+    ) -> Result<Arc<UserRealm>, MsalError> {
+        let domain = username
+            .rsplit_once('@')
+            .map_or(username, |(_, domain)| domain)
+            .to_lowercase();
+        if let Some(realm) = self.realms()?.get(&domain) {
+            return Ok(realm.clone());
+        }
+        let realm = Arc::new(discover_user_realm(client, authority, username).await?);
+        self.realms()?.insert(domain, realm.clone());
+        Ok(realm)
+    }
+}
+
+/// Discover the home realm of a user from the `userrealm` endpoint of the
+/// authority host.
+pub(crate) async fn discover_user_realm(
+    client: &Client,
+    authority: &str,
+    username: &str,
+) -> Result<UserRealm, MsalError> {
+    let mut url = Url::parse(authority)
+        .and_then(|url| url.join("/common/userrealm/"))
+        .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
+    url.path_segments_mut()
+        .map_err(|_| MsalError::URLFormatFailed("Invalid authority".to_string()))?
+        .pop_if_empty()
+        .push(username);
+    url.query_pairs_mut().append_pair("api-version", "1.0");
+
+    let resp = client
+        .get(url)
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+    if resp.status().is_success() {
+        let realm: UserRealm = resp
+            .json()
+            .await
+            .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+        debug!("Discovered user realm: {:?}", realm);
+        Ok(realm)
+    } else {
+        Err(MsalError::RequestFailed(format!(
+            "User realm discovery failed: {}",
+            resp.status()
+        )))
+    }
+}
+
+/// A SAML assertion issued by a federated identity provider, which can be
+/// exchanged for tokens.
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct SamlAssertion {
+    assertion: String,
+    saml2: bool,
+}
+
+impl SamlAssertion {
+    /// The OAuth2 grant type used to exchange the assertion.
+    pub(crate) fn grant_type(&self) -> &'static str {
+        if self.saml2 {
+            SAML2_BEARER_GRANT
+        } else {
+            SAML1_1_BEARER_GRANT
+        }
+    }
+
+    /// The assertion, encoded for a token request.
+    pub(crate) fn encoded(&self) -> String {
+        STANDARD.encode(&self.assertion)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum WsTrustVersion {
+    WsTrust13,
+    WsTrust2005,
+}
+
+impl WsTrustVersion {
+    fn from_endpoint(endpoint: &str) -> Self {
+        if endpoint.to_lowercase().contains("/trust/13/") {
+            WsTrustVersion::WsTrust13
+        } else {
+            WsTrustVersion::WsTrust2005
+        }
+    }
+
+    fn action(self) -> &'static str {
+        match self {
+            WsTrustVersion::WsTrust13 => {
+                "http://docs.oasis-open.org/ws-sx/ws-trust/200512/RST/Issue"
+            }
+            WsTrustVersion::WsTrust2005 => "http://schemas.xmlsoap.org/ws/2005/02/trust/RST/Issue",
+        }
+    }
+
+    fn namespace(self) -> &'static str {
+        match self {
+            WsTrustVersion::WsTrust13 => "http://docs.oasis-open.org/ws-sx/ws-trust/200512",
+            WsTrustVersion::WsTrust2005 => "http://schemas.xmlsoap.org/ws/2005/02/trust",
+        }
+    }
+
+    fn key_type(self) -> &'static str {
+        match self {
+            WsTrustVersion::WsTrust13 => "http://docs.oasis-open.org/ws-sx/ws-trust/200512/Bearer",
+            WsTrustVersion::WsTrust2005 => {
+                "http://schemas.xmlsoap.org/ws/2005/05/identity/NoProofKey"
+            }
+        }
+    }
+
+    fn request_type(self) -> &'static str {
+        match self {
+            WsTrustVersion::WsTrust13 => "http://docs.oasis-open.org/ws-sx/ws-trust/200512/Issue",
+            WsTrustVersion::WsTrust2005 => "http://schemas.xmlsoap.org/ws/2005/02/trust/Issue",
+        }
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Format seconds since the epoch as an xsd:dateTime in UTC.
+fn xml_datetime(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Convert days since the epoch to a civil date (Howard Hinnant's
+    // days_from_civil algorithm, inverted).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+fn wstrust_request(
+    version: WsTrustVersion,
+    endpoint: &str,
+    applies_to: &str,
+    username: &str,
+    password: &str,
+) -> Result<String, MsalError> {
+    let created = now()?;
+    Ok(format!(
+        "<s:Envelope xmlns:s='http://www.w3.org/2003/05/soap-envelope' \
+xmlns:wsa='http://www.w3.org/2005/08/addressing' \
+xmlns:wsu='http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd'>\
+<s:Header>\
+<wsa:Action s:mustUnderstand='1'>{action}</wsa:Action>\
+<wsa:MessageID>urn:uuid:{message_id}</wsa:MessageID>\
+<wsa:ReplyTo><wsa:Address>http://www.w3.org/2005/08/addressing/anonymous</wsa:Address></wsa:ReplyTo>\
+<wsa:To s:mustUnderstand='1'>{endpoint}</wsa:To>\
+<wsse:Security s:mustUnderstand='1' \
+xmlns:wsse='http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd'>\
+<wsu:Timestamp wsu:Id='_0'>\
+<wsu:Created>{created}</wsu:Created>\
+<wsu:Expires>{expires}</wsu:Expires>\
+</wsu:Timestamp>\
+<wsse:UsernameToken wsu:Id='UsernameToken'>\
+<wsse:Username>{username}</wsse:Username>\
+<wsse:Password>{password}</wsse:Password>\
+</wsse:UsernameToken>\
+</wsse:Security>\
+</s:Header>\
+<s:Body>\
+<wst:RequestSecurityToken xmlns:wst='{namespace}'>\
+<wsp:AppliesTo xmlns:wsp='http://schemas.xmlsoap.org/ws/2004/09/policy'>\
+<wsa:EndpointReference><wsa:Address>{applies_to}</wsa:Address></wsa:EndpointReference>\
+</wsp:AppliesTo>\
+<wst:KeyType>{key_type}</wst:KeyType>\
+<wst:RequestType>{request_type}</wst:RequestType>\
+</wst:RequestSecurityToken>\
+</s:Body>\
+</s:Envelope>",
+        action = version.action(),
+        message_id = Uuid::new_v4(),
+        endpoint = xml_escape(endpoint),
+        created = xml_datetime(created),
+        expires = xml_datetime(created + WSTRUST_REQUEST_LIFETIME),
+        username = xml_escape(username),
+        password = xml_escape(password),
+        namespace = version.namespace(),
+        applies_to = xml_escape(applies_to),
+        key_type = version.key_type(),
+        request_type = version.request_type(),
+    ))
+}
+
+/// Extract the SAML assertion from a WS-Trust RequestSecurityTokenResponse.
+/// The assertion is signed, so it is returned exactly as it was received.
+fn parse_wstrust_response(text: &str) -> Result<SamlAssertion, MsalError> {
+    let fault =
+        Regex::new(r"(?s)<(?:\w+:)?Fault\b.*?<(?:\w+:)?(?:Text|faultstring)\b[^>]*>(.*?)</")
+            .map_err(|e| MsalError::InvalidRegex(format!("{}", e)))?;
+    if let Some(captures) = fault.captures(text) {
+        return Err(MsalError::GeneralFailure(format!(
+            "WS-Trust request failed: {}",
+            captures[1].trim()
+        )));
+    }
+    let token_type = Regex::new(r"<(?:\w+:)?TokenType>([^<]*)</")
+        .map_err(|e| MsalError::InvalidRegex(format!("{}", e)))?;
+    let saml2 = match token_type.captures(text) {
+        Some(captures) => {
+            let token_type = captures[1].trim();
+            if token_type.contains("SAML:2.0") || token_type.ends_with("#SAMLV2.0") {
+                true
+            } else if token_type.contains("SAML:1.0") || token_type.ends_with("#SAMLV1.1") {
+                false
+            } else {
+                return Err(MsalError::GeneralFailure(format!(
+                    "Unsupported WS-Trust token type {}",
+                    token_type
+                )));
+            }
+        }
+        None => {
+            return Err(MsalError::InvalidParse(
+                "WS-Trust response is missing the TokenType".to_string(),
+            ))
+        }
+    };
+    let token = Regex::new(
+        r"(?s)<(?:\w+:)?RequestedSecurityToken\b[^>]*>\s*(.*?)\s*</(?:\w+:)?RequestedSecurityToken>",
+    )
+    .map_err(|e| MsalError::InvalidRegex(format!("{}", e)))?;
+    match token.captures(text) {
+        Some(captures) => Ok(SamlAssertion {
+            assertion: captures[1].to_string(),
+            saml2,
+        }),
+        None => Err(MsalError::InvalidParse(
+            "WS-Trust response is missing the RequestedSecurityToken".to_string(),
+        )),
+    }
+}
+
+/// Request a SAML assertion for a federated user from the WS-Trust
+/// usernamemixed endpoint of their identity provider.
+async fn acquire_saml_assertion(
+    client: &Client,
+    cloud_audience_urn: Option<&str>,
+    endpoint: &str,
+    username: &str,
+    password: &str,
+) -> Result<SamlAssertion, MsalError> {
+    // The password is sent in the request, so it must not be sent in the
+    // clear.
+    let scheme = Url::parse(endpoint)
+        .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?
+        .scheme()
+        .to_string();
+    if scheme != "https" {
+        return Err(MsalError::GeneralFailure(format!(
+            "Refusing to send credentials to the insecure WS-Trust endpoint {}",
+            endpoint
+        )));
+    }
+    let version = WsTrustVersion::from_endpoint(endpoint);
+    let applies_to = cloud_audience_urn.unwrap_or(DEFAULT_CLOUD_AUDIENCE_URN);
+    let request = wstrust_request(version, endpoint, applies_to, username, password)?;
+
+    debug!("Requesting a SAML assertion from {}", endpoint);
+    let resp = client
+        .post(endpoint)
+        .header(header::CONTENT_TYPE, "application/soap+xml; charset=utf-8")
+        .header("SOAPAction", version.action())
+        .body(request)
+        .send()
+        .await
+        .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+    // Faults are returned with an error status, and are parsed from the body.
+    let text = resp
+        .text()
+        .await
+        .map_err(|e| MsalError::GeneralFailure(format!("{}", e)))?;
+    parse_wstrust_response(&text)
+}
+
+/// Authenticate a federated user with their identity provider.
+///
+/// # Arguments
+///
+/// * `federation_redirect_url` - The federation redirect url of the user,
+///   if it is already known from GetCredentialType. The WS-Trust endpoint of
+///   an AD FS server is derived from it, without discovering the user realm.
+///
+/// * `realms` - The user realms previously discovered for the authority.
+///
+/// # Returns
+///
+/// * Success: The SAML assertion issued for a federated user, or None if the
+///   user is managed by AAD.
+/// * Failure: An MsalError, indicating the user realm could not be
+///   discovered, or the identity provider failed to authenticate the
+///   federated user.
+pub(crate) async fn federated_assertion(
+    client: &Client,
+    authority: &str,
+    username: &str,
+    password: &str,
+    federation_redirect_url: Option<&str>,
+    realms: &UserRealmCache,
+) -> Result<Option<SamlAssertion>, MsalError> {
+    if let Some(endpoint) = federation_redirect_url.and_then(adfs_wstrust_endpoint) {
+        return acquire_saml_assertion(client, None, &endpoint, username, password)
+            .await
+            .map(Some);
+    }
+    let realm = realms.get(client, authority, username).await?;
+    match realm.wstrust_endpoint() {
+        Some(endpoint) => acquire_saml_assertion(
+            client,
+            realm.cloud_audience_urn.as_deref(),
+            endpoint,
+            username,
+            password,
+        )
+        .await
+        .map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::test_server::{serve, serve_tls, Requests, Response};
+    use serde_json::json;
+
+    const RSTR_13_SAML11: &str = include_str!("../tests/fixtures/wstrust/rstr_13_saml11.xml");
+    const RSTR_2005_SAML2: &str = include_str!("../tests/fixtures/wstrust/rstr_2005_saml2.xml");
+
+    /// A client of stand-in servers with self-signed certificates.
+    fn client() -> Client {
+        Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap()
+    }
+
+    fn user_realm_requests(requests: &Requests) -> usize {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.path().starts_with("/common/userrealm/"))
+            .count()
+    }
+
+    #[test]
+    fn parse_wstrust_13_saml11_response() {
+        let assertion = parse_wstrust_response(RSTR_13_SAML11).unwrap();
+        assert_eq!(assertion.grant_type(), SAML1_1_BEARER_GRANT);
+        assert!(assertion.assertion.starts_with("<saml:Assertion "));
+        assert!(assertion.assertion.ends_with("</saml:Assertion>"));
+        // The signed assertion is exchanged exactly as it was issued
+        let decoded = STANDARD.decode(assertion.encoded()).unwrap();
+        assert!(RSTR_13_SAML11.contains(std::str::from_utf8(&decoded).unwrap()));
+    }
+
+    #[test]
+    fn parse_wstrust_2005_saml2_response() {
+        let assertion = parse_wstrust_response(RSTR_2005_SAML2).unwrap();
+        assert_eq!(assertion.grant_type(), SAML2_BEARER_GRANT);
+        assert!(assertion.assertion.starts_with("<Assertion "));
+        assert!(assertion.assertion.contains("jdoe@fabrikam.com"));
+    }
+
+    #[tokio::test]
+    async fn federated_user_receives_saml_assertion() {
+        for (path, rstr, action) in [
+            (
+                "/adfs/services/trust/13/usernamemixed",
+                RSTR_13_SAML11,
+                "http://docs.oasis-open.org/ws-sx/ws-trust/200512/RST/Issue",
+            ),
+            (
+                "/adfs/services/trust/2005/usernamemixed",
+                RSTR_2005_SAML2,
+                "http://schemas.xmlsoap.org/ws/2005/02/trust/RST/Issue",
+            ),
+        ] {
+            let (sts_url, sts_requests) = serve_tls(move |_, _| Response::html(rstr));
+            let endpoint = format!("{}{}", sts_url, path);
+            let (authority_url, _) = serve(move |_, _| {
+                Response::json(json!({
+                    "ver": "1.0",
+                    "account_type": "Federated",
+                    "domain_name": "contoso.com",
+                    "federation_protocol": "WSTrust",
+                    "federation_active_auth_url": endpoint,
+                    "cloud_audience_urn": "urn:federation:MicrosoftOnline",
+                }))
+            })
+            .await;
+            let assertion = federated_assertion(
+                &client(),
+                &format!("{}/tenant", authority_url),
+                "jdoe@contoso.com",
+                "p&ssword",
+                None,
+                &UserRealmCache::default(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(
+                assertion.assertion,
+                parse_wstrust_response(rstr).unwrap().assertion
+            );
+
+            let requests = sts_requests.lock().unwrap();
+            assert_eq!(requests[0].header("SOAPAction"), Some(action));
+            assert!(requests[0]
+                .body
+                .contains("<wsse:Password>p&amp;ssword</wsse:Password>"));
+            assert!(requests[0]
+                .body
+                .contains("<wsa:Address>urn:federation:MicrosoftOnline</wsa:Address>"));
+        }
+    }
+
+    #[tokio::test]
+    async fn user_realm_is_discovered_once_per_domain() {
+        let (base_url, requests) = serve(|request, _| {
+            if request.path().ends_with("@fabrikam.com") {
+                return Response::status(503, json!({"error": "temporarily_unavailable"}));
+            }
+            Response::json(json!({
+                "ver": "1.0",
+                "account_type": "Managed",
+                "domain_name": "contoso.com",
+                "cloud_instance_name": "microsoftonline.com",
+            }))
+        })
+        .await;
+        let authority = format!("{}/tenant", base_url);
+        let realms = UserRealmCache::default();
+        for username in ["jdoe@contoso.com", "ADMIN@Contoso.com"] {
+            let realm = realms.get(&client(), &authority, username).await.unwrap();
+            assert!(realm.wstrust_endpoint().is_none());
+        }
+        assert_eq!(user_realm_requests(&requests), 1);
+
+        // Failures are not cached
+        for _ in 0..2 {
+            assert!(realms
+                .get(&client(), &authority, "guest@fabrikam.com")
+                .await
+                .is_err());
+        }
+        assert_eq!(user_realm_requests(&requests), 3);
+    }
+
+    #[test]
+    fn wstrust_endpoint_from_adfs_redirect_url() {
+        assert_eq!(
+            adfs_wstrust_endpoint(
+                "https://sts.contoso.com/adfs/ls/?username=jdoe%40contoso.com&wa=wsignin1.0"
+            )
+            .as_deref(),
+            Some("https://sts.contoso.com/adfs/services/trust/13/usernamemixed")
+        );
+        // Other identity providers have no well known WS-Trust endpoint
+        assert!(
+            adfs_wstrust_endpoint("https://contoso.okta.com/app/office365/sso/wsfed/passive")
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn wstrust_endpoint_must_use_https() {
+        let res = acquire_saml_assertion(
+            &Client::new(),
+            None,
+            "http://sts.contoso.com/adfs/services/trust/13/usernamemixed",
+            "jdoe@contoso.com",
+            "password",
+        )
+        .await;
+        assert!(matches!(res, Err(MsalError::GeneralFailure(msg)) if msg.contains("insecure")));
+    }
+}
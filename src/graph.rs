@@ -16,6 +16,7 @@
    along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::cloud::CloudInstance;
 use crate::error::MsalError;
 use reqwest::{header, Client, Url};
 use serde::Deserialize;
@@ -126,6 +127,24 @@ impl Graph {
         self.authority_host.clone()
     }
 
+    /// The cloud which hosts the tenant, found from the discovered authority
+    /// host and Graph URL. This is None if the tenant is not hosted by a
+    /// built in cloud.
+    pub fn cloud_instance(&self) -> Option<CloudInstance> {
+        let authority_host = self
+            .authority_host
+            .trim_start_matches("https://")
+            .trim_end_matches('/');
+        match CloudInstance::from_login_host(authority_host)? {
+            CloudInstance::USGov
+                if self.graph_url.trim_end_matches('/') == CloudInstance::USGovDoD.graph_url() =>
+            {
+                Some(CloudInstance::USGovDoD)
+            }
+            cloud => Some(cloud),
+        }
+    }
+
     pub fn tenant_id(&self) -> String {
         self.tenant_id.clone()
     }
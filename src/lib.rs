@@ -39,7 +39,7 @@ use kanidm_hsm_crypto::{
     MachineKey as MachineKeyIn, SealedData as SealedDataIn, Tpm,
 };
 use std::ffi::CString;
-use std::os::raw::{c_char, c_int};
+use std::os::raw::{c_char, c_int, c_void};
 #[cfg(feature = "broker")]
 use std::str::FromStr;
 #[cfg(feature = "broker")]
@@ -53,10 +53,42 @@ use c_helper::*;
 
 pub mod error;
 use crate::error::MSAL_ERROR;
+#[cfg(feature = "broker")]
+use crate::error::{MsalError, PasswordChangeError};
 
 pub mod auth;
 pub use auth::*;
 
+pub mod authority;
+pub use authority::AuthorityType;
+
+pub mod cache;
+#[cfg(feature = "broker")]
+pub use cache::SealedFileTokenCache;
+pub use cache::{Account, InMemoryTokenCache, TokenCache};
+
+pub mod challenge;
+pub use challenge::{claims_challenge, WwwAuthenticateParameters};
+
+pub mod cloud;
+pub use cloud::{CloudEndpoints, CloudInstance};
+
+pub mod federation;
+
+pub mod managed_identity;
+pub use managed_identity::{ManagedIdentity, ManagedIdentityApplication, ManagedIdentitySource};
+
+pub mod oidc;
+pub use oidc::OpenIdConfiguration;
+
+pub mod validation;
+pub use validation::TokenValidator;
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::needless_pass_by_value)]
+#[path = "../tests/common/mod.rs"]
+mod test_server;
+
 #[cfg(feature = "broker")]
 pub struct BoxedDynTpm(BoxedDynTpmIn);
 #[cfg(feature = "broker")]
@@ -91,6 +123,65 @@ impl From<TracingLevel> for Level {
     }
 }
 
+/// The outcome of polling a device flow.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub enum DEVICE_FLOW_OUTCOME {
+    DEVICE_FLOW_SUCCESS,
+    DEVICE_FLOW_EXPIRED,
+    DEVICE_FLOW_DECLINED,
+    DEVICE_FLOW_CANCELLED,
+}
+
+/// An MFA method which can be registered during an MFA flow.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub enum MFA_REGISTRATION_METHOD {
+    MFA_REGISTRATION_AUTHENTICATOR_APP,
+    MFA_REGISTRATION_SMS,
+}
+
+/// An Azure cloud which is built in.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub enum CLOUD_INSTANCE {
+    CLOUD_INSTANCE_PUBLIC,
+    CLOUD_INSTANCE_US_GOV,
+    CLOUD_INSTANCE_US_GOV_DOD,
+    CLOUD_INSTANCE_CHINA,
+}
+
+/// The progress of a device flow, reported while polling.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub enum DEVICE_FLOW_PROGRESS {
+    /// Authentication is pending. The value is the number of seconds until
+    /// the device code expires.
+    DEVICE_FLOW_AUTHORIZATION_PENDING,
+    /// Polling slowed down. The value is the new polling interval in seconds.
+    DEVICE_FLOW_SLOW_DOWN,
+}
+
+pub type DeviceFlowProgressFn = Option<
+    unsafe extern "C" fn(progress: DEVICE_FLOW_PROGRESS, value: c_int, user_data: *mut c_void),
+>;
+
+/// The user_data passed to a DeviceFlowProgressFn. The caller of
+/// `broker_poll_device_flow` is responsible for its thread safety.
+#[cfg(feature = "broker")]
+struct CallbackUserData(*mut c_void);
+#[cfg(feature = "broker")]
+unsafe impl Send for CallbackUserData {}
+#[cfg(feature = "broker")]
+unsafe impl Sync for CallbackUserData {}
+
+#[cfg(feature = "broker")]
+impl CallbackUserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
 /// # Safety
 #[no_mangle]
 pub unsafe extern "C" fn set_global_tracing_level(level: TracingLevel) -> MSAL_ERROR {
@@ -327,6 +418,170 @@ pub unsafe extern "C" fn broker_init(
     }
 }
 
+/// Declare the capabilities of the application, which are sent as claims with
+/// every token request. Declaring the `cp1` capability opts into continuous
+/// access evaluation (CAE).
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `client_capabilities` - An array of capabilities, such as `cp1`.
+///
+/// * `client_capabilities_len` - The length of the `client_capabilities`
+///   array.
+///
+/// # Safety
+///
+/// The calling function must ensure that `client` is a valid pointer to a
+/// BrokerClientApplication, and that `client_capabilities` is an array of
+/// valid c strings of length `client_capabilities_len`.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_set_client_capabilities(
+    client: *mut BrokerClientApplication,
+    client_capabilities: *const *const c_char,
+    client_capabilities_len: c_int,
+) -> MSAL_ERROR {
+    if client.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    let client_capabilities = match str_array_to_vec(client_capabilities, client_capabilities_len) {
+        Ok(client_capabilities) => client_capabilities,
+        Err(e) => return e,
+    };
+    client.set_client_capabilities(str_vec_ref!(client_capabilities));
+    MSAL_ERROR::SUCCESS
+}
+
+/// Enable or disable validation of the authority. By default, an authority
+/// must use https, and must be hosted by a known cloud or be recognized by
+/// instance discovery. Only disable validation for a custom authority which
+/// you trust.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `validate_authority` - Whether to validate the authority.
+///
+/// # Safety
+///
+/// The calling function must ensure that `client` is a valid pointer to a
+/// BrokerClientApplication.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_set_validate_authority(
+    client: *mut BrokerClientApplication,
+    validate_authority: bool,
+) -> MSAL_ERROR {
+    if client.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    client.set_validate_authority(validate_authority);
+    MSAL_ERROR::SUCCESS
+}
+
+/// Set the cloud which hosts the device registration and enrollment services.
+/// By default, the cloud is found from the host of the authority.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `cloud` - The cloud which hosts the tenant.
+///
+/// # Safety
+///
+/// The calling function must ensure that `client` is a valid pointer to a
+/// BrokerClientApplication.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_set_cloud_instance(
+    client: *mut BrokerClientApplication,
+    cloud: CLOUD_INSTANCE,
+) -> MSAL_ERROR {
+    if client.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    client.set_cloud_instance(match cloud {
+        CLOUD_INSTANCE::CLOUD_INSTANCE_PUBLIC => CloudInstance::Public,
+        CLOUD_INSTANCE::CLOUD_INSTANCE_US_GOV => CloudInstance::USGov,
+        CLOUD_INSTANCE::CLOUD_INSTANCE_US_GOV_DOD => CloudInstance::USGovDoD,
+        CLOUD_INSTANCE::CLOUD_INSTANCE_CHINA => CloudInstance::China,
+    });
+    MSAL_ERROR::SUCCESS
+}
+
+/// Set a cloud which is not built in, which hosts the device registration and
+/// enrollment services.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `login_host` - The login host, such as `login.microsoftonline.com`.
+///
+/// * `drs_host` - The Device Registration Service host, such as
+///   `enterpriseregistration.windows.net`.
+///
+/// * `graph_host` - The Microsoft Graph host, such as `graph.microsoft.com`.
+///
+/// * `enrollment_host` - The Intune enrollment host, such as
+///   `enrollment.manage.microsoft.com`.
+///
+/// # Safety
+///
+/// The calling function must ensure that `client` is a valid pointer to a
+/// BrokerClientApplication, and that `login_host`, `drs_host`, `graph_host`
+/// and `enrollment_host` are valid c strings.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_set_custom_cloud_instance(
+    client: *mut BrokerClientApplication,
+    login_host: *const c_char,
+    drs_host: *const c_char,
+    graph_host: *const c_char,
+    enrollment_host: *const c_char,
+) -> MSAL_ERROR {
+    if client.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    let endpoints = match (
+        wrap_c_char(login_host),
+        wrap_c_char(drs_host),
+        wrap_c_char(graph_host),
+        wrap_c_char(enrollment_host),
+    ) {
+        (Some(login_host), Some(drs_host), Some(graph_host), Some(enrollment_host)) => {
+            CloudEndpoints {
+                login_host,
+                drs_host,
+                graph_host,
+                enrollment_host,
+            }
+        }
+        _ => {
+            error!("Invalid input hosts!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    client.set_cloud_instance(CloudInstance::Custom(endpoints));
+    MSAL_ERROR::SUCCESS
+}
+
 /// Initialize attributes for device enrollment
 ///
 /// # Arguments
@@ -577,38 +832,49 @@ pub unsafe extern "C" fn broker_acquire_token_by_username_password(
     MSAL_ERROR::SUCCESS
 }
 
-/// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
+/// Gets a token for a given resource via user credentials.
+///
+/// Unlike `broker_acquire_token_by_username_password`, this requests the given
+/// claims, such as a claims challenge from a resource.
 ///
 /// # Arguments
 ///
 /// * `client` - A BrokerClientApplication created by a call to
 ///   `broker_init`.
 ///
-/// * `refresh_token` - The old refresh token, as a string.
+/// * `username` - Typically a UPN in the form of an email address.
 ///
-/// * `scopes` - The scopes associated with this old RT.
+/// * `password` - The password.
+///
+/// * `scopes` - An array of scopes requested to access a protected API (a
+///   resource).
 ///
 /// * `request_resource` - A resource for obtaining an access token.
 ///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
 ///
+/// * `claims` - Optionally, a claims challenge from a resource, such as the
+///   claims returned by claims_challenge. May be NULL.
+///
 /// * `tpm` - The tpm object.
 ///
 /// * `machine_key` - The TPM MachineKey associated with this application.
 ///
-/// * `out` - A UserToken, which means migration was successful.
+/// * `out` - A UserToken containing an access_token.
 ///
 /// # Safety
 ///
-/// The calling function must ensure that `client`, `tpm`, `machine_key`, are
-/// valid pointers to their respective types.
+/// The calling function should ensure that `client`, `username`, `password`,
+/// `scopes`, `tpm`, and `machine_key` are valid pointers to their respective types.
 #[cfg(feature = "broker")]
 #[no_mangle]
-pub unsafe extern "C" fn broker_acquire_token_by_refresh_token(
+pub unsafe extern "C" fn broker_acquire_token_by_username_password_with_claims(
     client: *mut BrokerClientApplication,
-    refresh_token: *const c_char,
+    username: *const c_char,
+    password: *const c_char,
     scopes: *const *const c_char,
     scopes_len: c_int,
     request_resource: *const c_char,
+    claims: *const c_char,
     tpm: *mut BoxedDynTpm,
     machine_key: *mut MachineKey,
     out: *mut *mut UserToken,
@@ -624,10 +890,17 @@ pub unsafe extern "C" fn broker_acquire_token_by_refresh_token(
     }
 
     let client = unsafe { &mut *client };
-    let refresh_token = match wrap_c_char(refresh_token) {
-        Some(refresh_token) => refresh_token,
+    let username = match wrap_c_char(username) {
+        Some(username) => username,
         None => {
-            error!("Invalid input refresh_token!");
+            error!("Invalid input username!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let password = match wrap_c_char(password) {
+        Some(password) => password,
+        None => {
+            error!("Invalid input password!");
             return MSAL_ERROR::INVALID_POINTER;
         }
     };
@@ -636,14 +909,17 @@ pub unsafe extern "C" fn broker_acquire_token_by_refresh_token(
         Err(e) => return e,
     };
     let request_resource = wrap_c_char(request_resource);
+    let claims = wrap_c_char(claims);
     let tpm = unsafe { &mut *tpm };
     let machine_key = unsafe { &mut *machine_key };
     let resp = match run_async!(
         client,
-        acquire_token_by_refresh_token,
-        &refresh_token,
+        acquire_token_by_username_password_with_claims,
+        &username,
+        &password,
         str_vec_ref!(scopes),
         request_resource,
+        claims.as_deref(),
         &mut tpm.0,
         &machine_key.0,
     ) {
@@ -656,31 +932,43 @@ pub unsafe extern "C" fn broker_acquire_token_by_refresh_token(
     MSAL_ERROR::SUCCESS
 }
 
-/// Gets a token for enrollment via user credentials.
+/// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
 ///
 /// # Arguments
 ///
 /// * `client` - A BrokerClientApplication created by a call to
 ///   `broker_init`.
 ///
-/// * `username` - Typically a UPN in the form of an email address.
+/// * `refresh_token` - The old refresh token, as a string.
 ///
-/// * `password` - The password.
+/// * `scopes` - The scopes associated with this old RT.
 ///
-/// * `out` - A UserToken containing an access_token.
+/// * `request_resource` - A resource for obtaining an access token.
+///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+///
+/// * `tpm` - The tpm object.
+///
+/// * `machine_key` - The TPM MachineKey associated with this application.
+///
+/// * `out` - A UserToken, which means migration was successful.
 ///
 /// # Safety
 ///
-/// The calling function must ensure that `client` is a valid pointer.
+/// The calling function must ensure that `client`, `tpm`, `machine_key`, are
+/// valid pointers to their respective types.
 #[cfg(feature = "broker")]
 #[no_mangle]
-pub unsafe extern "C" fn broker_acquire_token_by_username_password_for_device_enrollment(
+pub unsafe extern "C" fn broker_acquire_token_by_refresh_token(
     client: *mut BrokerClientApplication,
-    username: *const c_char,
-    password: *const c_char,
+    refresh_token: *const c_char,
+    scopes: *const *const c_char,
+    scopes_len: c_int,
+    request_resource: *const c_char,
+    tpm: *mut BoxedDynTpm,
+    machine_key: *mut MachineKey,
     out: *mut *mut UserToken,
 ) -> MSAL_ERROR {
-    if client.is_null() {
+    if client.is_null() || tpm.is_null() || machine_key.is_null() {
         error!("Invalid input parameters!");
         return MSAL_ERROR::INVALID_POINTER;
     }
@@ -691,25 +979,28 @@ pub unsafe extern "C" fn broker_acquire_token_by_username_password_for_device_en
     }
 
     let client = unsafe { &mut *client };
-    let username = match wrap_c_char(username) {
-        Some(username) => username,
+    let refresh_token = match wrap_c_char(refresh_token) {
+        Some(refresh_token) => refresh_token,
         None => {
-            error!("Invalid input username!");
+            error!("Invalid input refresh_token!");
             return MSAL_ERROR::INVALID_POINTER;
         }
     };
-    let password = match wrap_c_char(password) {
-        Some(password) => password,
-        None => {
-            error!("Invalid input password!");
-            return MSAL_ERROR::INVALID_POINTER;
-        }
+    let scopes = match str_array_to_vec(scopes, scopes_len) {
+        Ok(scopes) => scopes,
+        Err(e) => return e,
     };
+    let request_resource = wrap_c_char(request_resource);
+    let tpm = unsafe { &mut *tpm };
+    let machine_key = unsafe { &mut *machine_key };
     let resp = match run_async!(
         client,
-        acquire_token_by_username_password_for_device_enrollment,
-        &username,
-        &password,
+        acquire_token_by_refresh_token,
+        &refresh_token,
+        str_vec_ref!(scopes),
+        request_resource,
+        &mut tpm.0,
+        &machine_key.0,
     ) {
         Ok(resp) => resp,
         Err(e) => return e,
@@ -720,27 +1011,179 @@ pub unsafe extern "C" fn broker_acquire_token_by_username_password_for_device_en
     MSAL_ERROR::SUCCESS
 }
 
-/// Initiate a Device Flow instance for enrollment, which will be
-/// used in acquire_token_by_device_flow.
+/// Acquire token(s) based on a refresh token (RT) obtained from elsewhere.
+///
+/// Unlike `broker_acquire_token_by_refresh_token`, this requests the given
+/// claims, such as a claims challenge from a resource.
 ///
 /// # Arguments
 ///
 /// * `client` - A BrokerClientApplication created by a call to
 ///   `broker_init`.
 ///
-/// * `out` - A DeviceAuthorizationResponse containing a user_code key,
-///   among others
+/// * `refresh_token` - The old refresh token, as a string.
+///
+/// * `scopes` - The scopes associated with this old RT.
+///
+/// * `request_resource` - A resource for obtaining an access token.
+///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+///
+/// * `claims` - Optionally, a claims challenge from a resource, such as the
+///   claims returned by claims_challenge. May be NULL.
+///
+/// * `tpm` - The tpm object.
+///
+/// * `machine_key` - The TPM MachineKey associated with this application.
+///
+/// * `out` - A UserToken, which means migration was successful.
 ///
 /// # Safety
 ///
-/// The calling function must ensure that `client` is a valid pointer.
+/// The calling function must ensure that `client`, `tpm`, `machine_key`, are
+/// valid pointers to their respective types.
 #[cfg(feature = "broker")]
 #[no_mangle]
-pub unsafe extern "C" fn broker_initiate_device_flow_for_device_enrollment(
+pub unsafe extern "C" fn broker_acquire_token_by_refresh_token_with_claims(
     client: *mut BrokerClientApplication,
-    out: *mut *mut DeviceAuthorizationResponse,
+    refresh_token: *const c_char,
+    scopes: *const *const c_char,
+    scopes_len: c_int,
+    request_resource: *const c_char,
+    claims: *const c_char,
+    tpm: *mut BoxedDynTpm,
+    machine_key: *mut MachineKey,
+    out: *mut *mut UserToken,
 ) -> MSAL_ERROR {
-    if client.is_null() {
+    if client.is_null() || tpm.is_null() || machine_key.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    // Ensure our out parameter is not NULL
+    if out.is_null() {
+        error!("Invalid output parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+
+    let client = unsafe { &mut *client };
+    let refresh_token = match wrap_c_char(refresh_token) {
+        Some(refresh_token) => refresh_token,
+        None => {
+            error!("Invalid input refresh_token!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let scopes = match str_array_to_vec(scopes, scopes_len) {
+        Ok(scopes) => scopes,
+        Err(e) => return e,
+    };
+    let request_resource = wrap_c_char(request_resource);
+    let claims = wrap_c_char(claims);
+    let tpm = unsafe { &mut *tpm };
+    let machine_key = unsafe { &mut *machine_key };
+    let resp = match run_async!(
+        client,
+        acquire_token_by_refresh_token_with_claims,
+        &refresh_token,
+        str_vec_ref!(scopes),
+        request_resource,
+        claims.as_deref(),
+        &mut tpm.0,
+        &machine_key.0,
+    ) {
+        Ok(resp) => resp,
+        Err(e) => return e,
+    };
+    unsafe {
+        *out = Box::into_raw(Box::new(resp));
+    }
+    MSAL_ERROR::SUCCESS
+}
+
+/// Gets a token for enrollment via user credentials.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `username` - Typically a UPN in the form of an email address.
+///
+/// * `password` - The password.
+///
+/// * `out` - A UserToken containing an access_token.
+///
+/// # Safety
+///
+/// The calling function must ensure that `client` is a valid pointer.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_acquire_token_by_username_password_for_device_enrollment(
+    client: *mut BrokerClientApplication,
+    username: *const c_char,
+    password: *const c_char,
+    out: *mut *mut UserToken,
+) -> MSAL_ERROR {
+    if client.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    // Ensure our out parameter is not NULL
+    if out.is_null() {
+        error!("Invalid output parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+
+    let client = unsafe { &mut *client };
+    let username = match wrap_c_char(username) {
+        Some(username) => username,
+        None => {
+            error!("Invalid input username!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let password = match wrap_c_char(password) {
+        Some(password) => password,
+        None => {
+            error!("Invalid input password!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let resp = match run_async!(
+        client,
+        acquire_token_by_username_password_for_device_enrollment,
+        &username,
+        &password,
+    ) {
+        Ok(resp) => resp,
+        Err(e) => return e,
+    };
+    unsafe {
+        *out = Box::into_raw(Box::new(resp));
+    }
+    MSAL_ERROR::SUCCESS
+}
+
+/// Initiate a Device Flow instance for enrollment, which will be
+/// used in acquire_token_by_device_flow.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `out` - A DeviceAuthorizationResponse containing a user_code key,
+///   among others
+///
+/// # Safety
+///
+/// The calling function must ensure that `client` is a valid pointer.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_initiate_device_flow_for_device_enrollment(
+    client: *mut BrokerClientApplication,
+    out: *mut *mut DeviceAuthorizationResponse,
+) -> MSAL_ERROR {
+    if client.is_null() {
         error!("Invalid input parameter!");
         return MSAL_ERROR::INVALID_POINTER;
     }
@@ -770,7 +1213,7 @@ pub unsafe extern "C" fn broker_initiate_device_flow_for_device_enrollment(
 ///   `broker_init`.
 ///
 /// * `flow` - A DeviceAuthorizationResponse previously generated by
-/// initiate_device_flow.
+///   initiate_device_flow.
 ///
 /// * `out` - A UserToken containing an access_token.
 ///
@@ -806,6 +1249,183 @@ pub unsafe extern "C" fn broker_acquire_token_by_device_flow(
     MSAL_ERROR::SUCCESS
 }
 
+/// Obtain token for enrollment by a device flow object, polling until the
+/// user completes authentication.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `flow` - A DeviceAuthorizationResponse previously generated by
+///   broker_initiate_device_flow_for_device_enrollment.
+///
+/// * `cancellation` - An optional CancellationToken created by a call to
+///   `cancellation_token_init`, which stops polling when cancelled from
+///   another thread.
+///
+/// * `progress` - An optional callback, called each time a poll finds that
+///   authentication is still pending.
+///
+/// * `user_data` - An opaque pointer passed to the `progress` callback.
+///
+/// * `outcome` - The outcome of polling.
+///
+/// * `out` - A UserToken containing an access_token, set only if the
+///   `outcome` is DEVICE_FLOW_SUCCESS.
+///
+/// # Safety
+///
+/// The calling function must ensure that `client` and `flow` are valid
+/// pointers, that `cancellation` is a valid pointer or NULL, and that
+/// `outcome` and `out` are valid output pointers.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_poll_device_flow(
+    client: *mut BrokerClientApplication,
+    flow: *mut DeviceAuthorizationResponse,
+    cancellation: *mut CancellationToken,
+    progress: DeviceFlowProgressFn,
+    user_data: *mut c_void,
+    outcome: *mut DEVICE_FLOW_OUTCOME,
+    out: *mut *mut UserToken,
+) -> MSAL_ERROR {
+    if client.is_null() || flow.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    // Ensure our out parameters are not NULL
+    if outcome.is_null() || out.is_null() {
+        error!("Invalid output parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+
+    let client = unsafe { &mut *client };
+    let flow = unsafe { &*flow };
+    let cancellation = match cancellation.is_null() {
+        true => CancellationToken::new(),
+        false => unsafe { &*cancellation }.clone(),
+    };
+    let user_data = CallbackUserData(user_data);
+    let callback = progress.map(|progress| {
+        move |update: &auth::DeviceFlowProgress| {
+            let (kind, value) = match *update {
+                auth::DeviceFlowProgress::AuthorizationPending { expires_in, .. } => (
+                    DEVICE_FLOW_PROGRESS::DEVICE_FLOW_AUTHORIZATION_PENDING,
+                    expires_in,
+                ),
+                auth::DeviceFlowProgress::SlowDown { interval } => {
+                    (DEVICE_FLOW_PROGRESS::DEVICE_FLOW_SLOW_DOWN, interval)
+                }
+            };
+            unsafe { progress(kind, value as c_int, user_data.get()) }
+        }
+    });
+    let resp = match run_async!(
+        client,
+        poll_device_flow,
+        flow,
+        &cancellation,
+        callback
+            .as_ref()
+            .map(|callback| callback as &(dyn Fn(&auth::DeviceFlowProgress) + Send + Sync)),
+    ) {
+        Ok(resp) => resp,
+        Err(e) => return e,
+    };
+    unsafe {
+        *outcome = match resp {
+            DeviceFlowOutcome::Success(token) => {
+                *out = Box::into_raw(token);
+                DEVICE_FLOW_OUTCOME::DEVICE_FLOW_SUCCESS
+            }
+            DeviceFlowOutcome::Expired => DEVICE_FLOW_OUTCOME::DEVICE_FLOW_EXPIRED,
+            DeviceFlowOutcome::Declined => DEVICE_FLOW_OUTCOME::DEVICE_FLOW_DECLINED,
+            DeviceFlowOutcome::Cancelled => DEVICE_FLOW_OUTCOME::DEVICE_FLOW_CANCELLED,
+        };
+    }
+    MSAL_ERROR::SUCCESS
+}
+
+/// Create a CancellationToken, for stopping a long running operation.
+///
+/// # Safety
+///
+/// The calling function must ensure that `out` is a valid c
+/// CancellationToken double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn cancellation_token_init(out: *mut *mut CancellationToken) -> MSAL_ERROR {
+    if out.is_null() {
+        error!("Invalid output parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    unsafe {
+        *out = Box::into_raw(Box::new(CancellationToken::new()));
+    }
+    MSAL_ERROR::SUCCESS
+}
+
+/// Cancel the operations using a CancellationToken. This may be called from
+/// a different thread than the operation.
+///
+/// # Safety
+///
+/// The calling function must ensure that `token` is a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn cancellation_token_cancel(token: *mut CancellationToken) -> MSAL_ERROR {
+    if token.is_null() {
+        error!("Invalid input parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    unsafe { &*token }.cancel();
+    MSAL_ERROR::SUCCESS
+}
+
+/// Get the message to display to the user from a DeviceAuthorizationResponse
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid
+/// DeviceAuthorizationResponse pointer, and that `out` is a valid c char
+/// double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn device_authorization_response_message(
+    flow: *mut DeviceAuthorizationResponse,
+    out: *mut *mut c_char,
+) -> MSAL_ERROR {
+    c_str_from_object_option_string!(flow, message, out)
+}
+
+/// Get the user_code from a DeviceAuthorizationResponse
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid
+/// DeviceAuthorizationResponse pointer, and that `out` is a valid c char
+/// double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn device_authorization_response_user_code(
+    flow: *mut DeviceAuthorizationResponse,
+    out: *mut *mut c_char,
+) -> MSAL_ERROR {
+    c_str_from_object_string!(flow, user_code, out)
+}
+
+/// Get the verification_uri from a DeviceAuthorizationResponse
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid
+/// DeviceAuthorizationResponse pointer, and that `out` is a valid c char
+/// double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn device_authorization_response_verification_uri(
+    flow: *mut DeviceAuthorizationResponse,
+    out: *mut *mut c_char,
+) -> MSAL_ERROR {
+    c_str_from_object_string!(flow, verification_uri, out)
+}
+
 /// Check if a user exists in Azure Entra ID
 ///
 /// # Arguments
@@ -877,43 +1497,395 @@ pub unsafe extern "C" fn broker_check_user_exists(
 #[no_mangle]
 pub unsafe extern "C" fn broker_initiate_acquire_token_by_mfa_flow_for_device_enrollment(
     client: *mut BrokerClientApplication,
-    username: *const c_char,
-    password: *const c_char,
-    out: *mut *mut MFAAuthContinue,
+    username: *const c_char,
+    password: *const c_char,
+    out: *mut *mut MFAAuthContinue,
+) -> MSAL_ERROR {
+    // Ensure our out parameter is not NULL
+    if out.is_null() {
+        error!("Invalid output parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    let username = match wrap_c_char(username) {
+        Some(username) => username,
+        None => {
+            error!("Invalid input username!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let password = match wrap_c_char(password) {
+        Some(password) => password,
+        None => {
+            error!("Invalid input password!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let flow = match run_async!(
+        client,
+        initiate_acquire_token_by_mfa_flow_for_device_enrollment,
+        &username,
+        &password,
+    ) {
+        Ok(resp) => resp,
+        Err(e) => return e,
+    };
+    unsafe {
+        *out = Box::into_raw(Box::new(flow));
+    }
+    MSAL_ERROR::SUCCESS
+}
+
+/// Initiate an MFA flow for enrollment via user credentials, without choosing
+/// an MFA method. The MFA methods of the user can be listed with
+/// `mfa_auth_continue_mfa_methods_len` and the related functions, and the
+/// chosen method is started with `broker_begin_mfa_auth`.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `username` - Typically a UPN in the form of an email address.
+///
+/// * `password` - The password.
+///
+/// * `out` - A MFAAuthContinue containing the MFA methods of the user.
+///
+/// # Safety
+///
+/// The calling function should ensure that `client`, `username`, and
+/// `password`, are valid pointers to their respective types.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_initiate_acquire_token_by_mfa_method_selection_for_device_enrollment(
+    client: *mut BrokerClientApplication,
+    username: *const c_char,
+    password: *const c_char,
+    out: *mut *mut MFAAuthContinue,
+) -> MSAL_ERROR {
+    // Ensure our out parameter is not NULL
+    if out.is_null() {
+        error!("Invalid output parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    let username = match wrap_c_char(username) {
+        Some(username) => username,
+        None => {
+            error!("Invalid input username!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let password = match wrap_c_char(password) {
+        Some(password) => password,
+        None => {
+            error!("Invalid input password!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let flow = match run_async!(
+        client,
+        initiate_acquire_token_by_mfa_method_selection_for_device_enrollment,
+        &username,
+        &password,
+    ) {
+        Ok(resp) => resp,
+        Err(e) => return e,
+    };
+    unsafe {
+        *out = Box::into_raw(Box::new(flow));
+    }
+    MSAL_ERROR::SUCCESS
+}
+
+/// Initiate a passwordless flow for enrollment, in which the user signs in
+/// with a Temporary Access Pass, or, as a guest user, with a one time code
+/// sent by email. The passwordless methods of the user can be listed with
+/// `mfa_auth_continue_mfa_methods_len` and the related functions, the chosen
+/// method is started with `broker_begin_mfa_auth`, and the code is passed as
+/// the `auth_data` of `broker_acquire_token_by_mfa_flow`.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `username` - Typically a UPN in the form of an email address.
+///
+/// * `out` - A MFAAuthContinue containing the passwordless methods of the
+///   user.
+///
+/// # Safety
+///
+/// The calling function should ensure that `client` and `username` are valid
+/// pointers to their respective types.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_initiate_acquire_token_by_passwordless_flow_for_device_enrollment(
+    client: *mut BrokerClientApplication,
+    username: *const c_char,
+    out: *mut *mut MFAAuthContinue,
+) -> MSAL_ERROR {
+    // Ensure our out parameter is not NULL
+    if out.is_null() {
+        error!("Invalid output parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    let username = match wrap_c_char(username) {
+        Some(username) => username,
+        None => {
+            error!("Invalid input username!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let flow = match run_async!(
+        client,
+        initiate_acquire_token_by_passwordless_flow_for_device_enrollment,
+        &username,
+    ) {
+        Ok(resp) => resp,
+        Err(e) => return e,
+    };
+    unsafe {
+        *out = Box::into_raw(Box::new(flow));
+    }
+    MSAL_ERROR::SUCCESS
+}
+
+/// Change an expired password during an MFA flow. When the password is
+/// changed, the flow continues to MFA method selection.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `flow` - A MFAAuthContinue which requires a password change.
+///
+/// * `old_password` - The expired password.
+///
+/// * `new_password` - The new password.
+///
+/// * `error_msg` - An optional output parameter. If the password could not be
+///   changed (PASSWORD_CHANGE_FAILED), this contains the reason, which should
+///   be shown to the user.
+///
+/// # Safety
+///
+/// The calling function should ensure that `client`, `flow`, `old_password`,
+/// and `new_password` are valid pointers to their respective types, and that
+/// `error_msg` is a valid c char double pointer or NULL.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_change_password(
+    client: *mut BrokerClientApplication,
+    flow: *mut MFAAuthContinue,
+    old_password: *const c_char,
+    new_password: *const c_char,
+    error_msg: *mut *mut c_char,
+) -> MSAL_ERROR {
+    if client.is_null() || flow.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    let flow = unsafe { &mut *flow };
+    let old_password = match wrap_c_char(old_password) {
+        Some(old_password) => old_password,
+        None => {
+            error!("Invalid input old_password!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let new_password = match wrap_c_char(new_password) {
+        Some(new_password) => new_password,
+        None => {
+            error!("Invalid input new_password!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    let rt = match runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            error!("{:?}", e);
+            return MSAL_ERROR::NO_MEMORY;
+        }
+    };
+    match rt.block_on(client.change_password(flow, &old_password, &new_password)) {
+        Ok(()) => MSAL_ERROR::SUCCESS,
+        Err(e) => {
+            error!("{:?}", e);
+            if let MsalError::PasswordChangeFailed(
+                PasswordChangeError::IncorrectPassword(ref msg)
+                | PasswordChangeError::PolicyViolation(ref msg)
+                | PasswordChangeError::Failed(ref msg),
+            ) = e
+            {
+                if !error_msg.is_null() {
+                    unsafe {
+                        *error_msg = wrap_string(msg);
+                    }
+                }
+            }
+            MSAL_ERROR::from(e)
+        }
+    }
+}
+
+/// Begin registering an MFA method for a user who has not registered one.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `flow` - A MFAAuthContinue which requires MFA registration.
+///
+/// * `method` - The MFA method to register.
+///
+/// * `phone_number` - The phone number to register for
+///   MFA_REGISTRATION_SMS. This is ignored for other methods, and may be
+///   NULL.
+///
+/// * `out` - A MFARegistration containing the message to display to the
+///   user, which is passed to `broker_complete_mfa_registration` with the
+///   verification code entered by the user.
+///
+/// # Safety
+///
+/// The calling function should ensure that `client` and `flow` are valid
+/// pointers to their respective types, that `phone_number` is a valid c
+/// char pointer or NULL, and that `out` is a valid MFARegistration double
+/// pointer.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_begin_mfa_registration(
+    client: *mut BrokerClientApplication,
+    flow: *mut MFAAuthContinue,
+    method: MFA_REGISTRATION_METHOD,
+    phone_number: *const c_char,
+    out: *mut *mut MFARegistration,
+) -> MSAL_ERROR {
+    if client.is_null() || flow.is_null() || out.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    let flow = unsafe { &mut *flow };
+    let method = match method {
+        MFA_REGISTRATION_METHOD::MFA_REGISTRATION_AUTHENTICATOR_APP => {
+            MFARegistrationMethod::AuthenticatorApp
+        }
+        MFA_REGISTRATION_METHOD::MFA_REGISTRATION_SMS => match wrap_c_char(phone_number) {
+            Some(phone_number) => MFARegistrationMethod::Sms(phone_number),
+            None => {
+                error!("Invalid input phone_number!");
+                return MSAL_ERROR::INVALID_POINTER;
+            }
+        },
+    };
+    let registration = match run_async!(client, begin_mfa_registration, flow, method) {
+        Ok(registration) => registration,
+        Err(e) => return e,
+    };
+    unsafe {
+        *out = Box::into_raw(Box::new(registration));
+    }
+    MSAL_ERROR::SUCCESS
+}
+
+/// Complete the registration of an MFA method by verifying the code entered
+/// by the user. When the method is registered, the flow continues to MFA
+/// method selection.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `flow` - A MFAAuthContinue which requires MFA registration.
+///
+/// * `registration` - A MFARegistration previously generated by
+///   `broker_begin_mfa_registration`.
+///
+/// * `code` - The verification code entered by the user.
+///
+/// # Safety
+///
+/// The calling function should ensure that `client`, `flow`,
+/// `registration`, and `code` are valid pointers to their respective types.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_complete_mfa_registration(
+    client: *mut BrokerClientApplication,
+    flow: *mut MFAAuthContinue,
+    registration: *mut MFARegistration,
+    code: *const c_char,
+) -> MSAL_ERROR {
+    if client.is_null() || flow.is_null() || registration.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    let client = unsafe { &mut *client };
+    let flow = unsafe { &mut *flow };
+    let registration = unsafe { &*registration };
+    let code = match wrap_c_char(code) {
+        Some(code) => code,
+        None => {
+            error!("Invalid input code!");
+            return MSAL_ERROR::INVALID_POINTER;
+        }
+    };
+    match run_async!(client, complete_mfa_registration, flow, registration, &code) {
+        Ok(()) => MSAL_ERROR::SUCCESS,
+        Err(e) => e,
+    }
+}
+
+/// Begin authentication with an MFA method chosen by the user. This may also
+/// be called on a flow which has already begun, to switch to another MFA
+/// method.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `flow` - A MFAAuthContinue previously generated by
+///   broker_initiate_acquire_token_by_mfa_method_selection_for_device_enrollment.
+///
+/// * `auth_method_id` - The id of one of the MFA methods of the flow.
+///
+/// # Safety
+///
+/// The calling function should ensure that `client`, `flow`, and
+/// `auth_method_id` are valid pointers to their respective types.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_begin_mfa_auth(
+    client: *mut BrokerClientApplication,
+    flow: *mut MFAAuthContinue,
+    auth_method_id: *const c_char,
 ) -> MSAL_ERROR {
-    // Ensure our out parameter is not NULL
-    if out.is_null() {
-        error!("Invalid output parameter!");
+    if client.is_null() || flow.is_null() {
+        error!("Invalid input parameters!");
         return MSAL_ERROR::INVALID_POINTER;
     }
     let client = unsafe { &mut *client };
-    let username = match wrap_c_char(username) {
-        Some(username) => username,
-        None => {
-            error!("Invalid input username!");
-            return MSAL_ERROR::INVALID_POINTER;
-        }
-    };
-    let password = match wrap_c_char(password) {
-        Some(password) => password,
+    let flow = unsafe { &mut *flow };
+    let auth_method_id = match wrap_c_char(auth_method_id) {
+        Some(auth_method_id) => auth_method_id,
         None => {
-            error!("Invalid input password!");
+            error!("Invalid input auth_method_id!");
             return MSAL_ERROR::INVALID_POINTER;
         }
     };
-    let flow = match run_async!(
-        client,
-        initiate_acquire_token_by_mfa_flow_for_device_enrollment,
-        &username,
-        &password,
-    ) {
-        Ok(resp) => resp,
-        Err(e) => return e,
-    };
-    unsafe {
-        *out = Box::into_raw(Box::new(flow));
+    match run_async!(client, begin_mfa_auth, flow, &auth_method_id) {
+        Ok(()) => MSAL_ERROR::SUCCESS,
+        Err(e) => e,
     }
-    MSAL_ERROR::SUCCESS
 }
 
 /// Obtain token by a MFA flow object.
@@ -933,7 +1905,7 @@ pub unsafe extern "C" fn broker_initiate_acquire_token_by_mfa_flow_for_device_en
 ///   input, this should be 0.
 ///
 /// * `flow` - A MFAAuthContinue previously generated by
-/// initiate_acquire_token_by_mfa_flow.
+///   initiate_acquire_token_by_mfa_flow.
 ///
 /// * `out` - A UserToken containing an access_token.
 /// # Safety
@@ -1048,6 +2020,200 @@ pub unsafe extern "C" fn mfa_auth_continue_max_poll_attempts(flow: *mut MFAAuthC
     }
 }
 
+/// Check whether the MFA method of a MFAAuthContinue flow requires the user to
+/// enter a code, which is passed as the `auth_data` of
+/// `broker_acquire_token_by_mfa_flow`. Otherwise the flow is completed by
+/// polling.
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid MFAAuthContinue
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_auth_continue_requires_input(flow: *mut MFAAuthContinue) -> bool {
+    let flow = unsafe { &mut *flow };
+    flow.requires_input()
+}
+
+/// Check whether the password of a MFAAuthContinue flow has expired, and must
+/// be changed with `broker_change_password` before the flow can continue.
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid MFAAuthContinue
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_auth_continue_requires_password_change(
+    flow: *mut MFAAuthContinue,
+) -> bool {
+    let flow = unsafe { &mut *flow };
+    flow.requires_password_change()
+}
+
+/// Check whether the user of a MFAAuthContinue flow must register an MFA
+/// method with `broker_begin_mfa_registration` before the flow can continue.
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid MFAAuthContinue
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_auth_continue_requires_mfa_registration(
+    flow: *mut MFAAuthContinue,
+) -> bool {
+    let flow = unsafe { &mut *flow };
+    flow.requires_mfa_registration()
+}
+
+/// Get the number of days the user of a MFAAuthContinue flow may continue to
+/// skip MFA registration. This is -1 if the user does not need to register
+/// an MFA method.
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid MFAAuthContinue
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_auth_continue_mfa_registration_days_remaining(
+    flow: *mut MFAAuthContinue,
+) -> c_int {
+    let flow = unsafe { &mut *flow };
+    match flow.mfa_registration_days_remaining {
+        Some(days) => days as c_int,
+        None => -1,
+    }
+}
+
+/// Get the message to display to the user from a MFARegistration
+///
+/// # Safety
+///
+/// The calling function should ensure that `registration` is a valid
+/// MFARegistration pointer, and that `out` is a valid c char double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_registration_msg(
+    registration: *mut MFARegistration,
+    out: *mut *mut c_char,
+) -> MSAL_ERROR {
+    c_str_from_object_string!(registration, msg, out)
+}
+
+/// Get the authenticator app secret key from a MFARegistration
+///
+/// # Safety
+///
+/// The calling function should ensure that `registration` is a valid
+/// MFARegistration pointer, and that `out` is a valid c char double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_registration_secret_key(
+    registration: *mut MFARegistration,
+    out: *mut *mut c_char,
+) -> MSAL_ERROR {
+    c_str_from_object_option_string!(registration, secret_key, out)
+}
+
+/// Get the authenticator app otpauth:// URI from a MFARegistration, which is
+/// typically displayed as a QR code.
+///
+/// # Safety
+///
+/// The calling function should ensure that `registration` is a valid
+/// MFARegistration pointer, and that `out` is a valid c char double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_registration_otpauth_uri(
+    registration: *mut MFARegistration,
+    out: *mut *mut c_char,
+) -> MSAL_ERROR {
+    c_str_from_object_option_string!(registration, otpauth_uri, out)
+}
+
+/// Get the number of MFA methods in a MFAAuthContinue flow
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid MFAAuthContinue
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_auth_continue_mfa_methods_len(flow: *mut MFAAuthContinue) -> c_int {
+    let flow = unsafe { &mut *flow };
+    flow.mfa_methods.len() as c_int
+}
+
+/// Get the id of an MFA method from a MFAAuthContinue flow, which is passed
+/// to `broker_begin_mfa_auth`.
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid MFAAuthContinue
+/// pointer, and that `out` is a valid c char double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_auth_continue_mfa_method_id(
+    flow: *mut MFAAuthContinue,
+    index: c_int,
+    out: *mut *mut c_char,
+) -> MSAL_ERROR {
+    let flow = unsafe { &mut *flow };
+    match flow.mfa_methods.get(index as usize) {
+        Some(method) => {
+            unsafe {
+                *out = wrap_string(&method.auth_method_id);
+            }
+            MSAL_ERROR::SUCCESS
+        }
+        None => {
+            error!("Invalid MFA method index {}", index);
+            MSAL_ERROR::INVALID_POINTER
+        }
+    }
+}
+
+/// Get the masked display of an MFA method (such as a phone number) from a
+/// MFAAuthContinue flow
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid MFAAuthContinue
+/// pointer, and that `out` is a valid c char double pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_auth_continue_mfa_method_display(
+    flow: *mut MFAAuthContinue,
+    index: c_int,
+    out: *mut *mut c_char,
+) -> MSAL_ERROR {
+    let flow = unsafe { &mut *flow };
+    match flow.mfa_methods.get(index as usize) {
+        Some(method) => {
+            unsafe {
+                *out = wrap_string(&method.display);
+            }
+            MSAL_ERROR::SUCCESS
+        }
+        None => {
+            error!("Invalid MFA method index {}", index);
+            MSAL_ERROR::INVALID_POINTER
+        }
+    }
+}
+
+/// Check whether an MFA method from a MFAAuthContinue flow is the user's
+/// default method
+///
+/// # Safety
+///
+/// The calling function should ensure that `flow` is a valid MFAAuthContinue
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_auth_continue_mfa_method_is_default(
+    flow: *mut MFAAuthContinue,
+    index: c_int,
+) -> bool {
+    let flow = unsafe { &mut *flow };
+    match flow.mfa_methods.get(index as usize) {
+        Some(method) => method.is_default,
+        None => false,
+    }
+}
+
 /// Gets a Primary Refresh Token (PRT) via user credentials.
 ///
 /// # Arguments
@@ -1262,6 +2428,90 @@ pub unsafe extern "C" fn broker_exchange_prt_for_access_token(
     MSAL_ERROR::SUCCESS
 }
 
+/// Given the primary refresh token, this method requests an access token.
+///
+/// Unlike `broker_exchange_prt_for_access_token`, this requests the given
+/// claims, such as a claims challenge from a resource.
+///
+/// # Arguments
+///
+/// * `client` - A BrokerClientApplication created by a call to
+///   `broker_init`.
+///
+/// * `sealed_prt` -  An encrypted primary refresh token that was
+///   previously received from the server.
+///
+/// * `scopes` - The scopes that the client requests for the access token.
+///
+/// * `request_resource` - A resource for obtaining an access token.
+///   Default is the MS Graph API (00000002-0000-0000-c000-000000000000).
+///
+/// * `claims` - Optionally, a claims challenge from a resource, such as the
+///   claims returned by claims_challenge. May be NULL.
+///
+/// * `tpm` - The tpm object.
+///
+/// * `machine_key` - The TPM MachineKey associated with this application.
+///
+/// * `out` - A UserToken containing an access_token.
+///
+/// # Safety
+///
+/// The calling function should ensure that `client`, `sealed_prt`, `scope`,
+/// `request_resource`, `tpm`, and `machine_key` are valid pointers to their
+/// respective types.
+#[cfg(feature = "broker")]
+#[no_mangle]
+pub unsafe extern "C" fn broker_exchange_prt_for_access_token_with_claims(
+    client: *mut BrokerClientApplication,
+    sealed_prt: *mut SealedData,
+    scopes: *const *const c_char,
+    scopes_len: c_int,
+    request_resource: *const c_char,
+    claims: *const c_char,
+    tpm: *mut BoxedDynTpm,
+    machine_key: *mut MachineKey,
+    out: *mut *mut UserToken,
+) -> MSAL_ERROR {
+    if client.is_null() || tpm.is_null() || machine_key.is_null() {
+        error!("Invalid input parameters!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+    // Ensure our out parameter is not NULL
+    if out.is_null() {
+        error!("Invalid output parameter!");
+        return MSAL_ERROR::INVALID_POINTER;
+    }
+
+    let client = unsafe { &mut *client };
+    let sealed_prt = unsafe { &mut *sealed_prt };
+    let scopes = match str_array_to_vec(scopes, scopes_len) {
+        Ok(scopes) => scopes,
+        Err(e) => return e,
+    };
+    let request_resource = wrap_c_char(request_resource);
+    let claims = wrap_c_char(claims);
+    let tpm = unsafe { &mut *tpm };
+    let machine_key = unsafe { &mut *machine_key };
+    let resp = match run_async!(
+        client,
+        exchange_prt_for_access_token_with_claims,
+        &sealed_prt.0,
+        str_vec_ref!(scopes),
+        request_resource,
+        claims.as_deref(),
+        &mut tpm.0,
+        &machine_key.0,
+    ) {
+        Ok(resp) => resp,
+        Err(e) => return e,
+    };
+    unsafe {
+        *out = Box::into_raw(Box::new(resp));
+    }
+    MSAL_ERROR::SUCCESS
+}
+
 /// Given the primary refresh token, this method requests a new primary
 /// refresh token
 ///
@@ -1767,6 +3017,35 @@ pub unsafe extern "C" fn mfa_auth_continue_free(input: *mut MFAAuthContinue) {
     free_object!(input);
 }
 
+/// # Safety
+///
+/// The calling function must ensure that the `input` raw pointer is valid and
+/// can be dereferenced.
+#[no_mangle]
+pub unsafe extern "C" fn mfa_registration_free(input: *mut MFARegistration) {
+    free_object!(input);
+}
+
+/// # Safety
+///
+/// The calling function must ensure that the `input` raw pointer is valid and
+/// can be dereferenced.
+#[no_mangle]
+pub unsafe extern "C" fn device_authorization_response_free(
+    input: *mut DeviceAuthorizationResponse,
+) {
+    free_object!(input);
+}
+
+/// # Safety
+///
+/// The calling function must ensure that the `input` raw pointer is valid and
+/// can be dereferenced.
+#[no_mangle]
+pub unsafe extern "C" fn cancellation_token_free(input: *mut CancellationToken) {
+    free_object!(input);
+}
+
 /// # Safety
 ///
 /// The calling function must ensure that the `input` raw pointer is valid and
@@ -1779,7 +3058,11 @@ pub unsafe extern "C" fn sealed_data_free(input: *mut SealedData) {
 
 #[cfg(feature = "broker")]
 pub mod discovery;
+#[cfg(feature = "broker")]
+pub mod renewal;
 
 pub mod graph;
 #[cfg(feature = "broker")]
 pub use discovery::EnrollAttrs;
+#[cfg(feature = "broker")]
+pub use renewal::PrtRenewalScheduler;
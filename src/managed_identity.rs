@@ -0,0 +1,596 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::auth::{ClientInfo, IdToken, UserToken};
+use crate::cache::{
+    deserialize_optional_timestamp, deserialize_timestamp, find_app_token, save_app_token,
+    InMemoryTokenCache, TokenCache,
+};
+use crate::error::{ErrorResponse, MsalError};
+use reqwest::{header, Client, Response, StatusCode, Url};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::debug;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// The Azure Instance Metadata Service token endpoint.
+pub const IMDS_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+const IMDS_API_VERSION: &str = "2018-02-01";
+/// The Azure Arc Hybrid Instance Metadata Service token endpoint.
+pub const AZURE_ARC_ENDPOINT: &str = "http://localhost:40342/metadata/identity/oauth2/token";
+const AZURE_ARC_API_VERSION: &str = "2019-11-01";
+/// The HIMDS executable, which is present on Azure Arc enabled servers.
+const HIMDS_EXECUTABLE: &str = "/opt/azcmagent/bin/himds";
+/// The directory HIMDS writes its challenge files to.
+const AZURE_ARC_TOKEN_DIR: &str = "/var/opt/azcmagent/tokens";
+/// The maximum size of an Azure Arc challenge file.
+const AZURE_ARC_MAX_KEY_SIZE: u64 = 4096;
+
+/// The number of times a failed IMDS request is retried.
+const IMDS_MAX_RETRIES: u32 = 3;
+/// The delay before the first retry of a failed IMDS request, in
+/// milliseconds. The delay doubles with each retry.
+const IMDS_RETRY_DELAY: u64 = 1000;
+
+/// The cache client id of a system-assigned managed identity.
+const SYSTEM_ASSIGNED_CLIENT_ID: &str = "system_assigned_managed_identity";
+
+/// A managed identity of an Azure VM or Azure Arc enabled server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManagedIdentity {
+    /// The identity assigned to the machine itself.
+    SystemAssigned,
+    /// A user-assigned identity, identified by its client id.
+    UserAssignedClientId(String),
+    /// A user-assigned identity, identified by its object id.
+    UserAssignedObjectId(String),
+    /// A user-assigned identity, identified by its Azure resource id.
+    UserAssignedResourceId(String),
+}
+
+impl ManagedIdentity {
+    fn query_param(&self) -> Option<(&'static str, &str)> {
+        match self {
+            ManagedIdentity::SystemAssigned => None,
+            ManagedIdentity::UserAssignedClientId(id) => Some(("client_id", id)),
+            ManagedIdentity::UserAssignedObjectId(id) => Some(("object_id", id)),
+            ManagedIdentity::UserAssignedResourceId(id) => Some(("msi_res_id", id)),
+        }
+    }
+
+    fn cache_client_id(&self) -> &str {
+        match self {
+            ManagedIdentity::SystemAssigned => SYSTEM_ASSIGNED_CLIENT_ID,
+            ManagedIdentity::UserAssignedClientId(id)
+            | ManagedIdentity::UserAssignedObjectId(id)
+            | ManagedIdentity::UserAssignedResourceId(id) => id,
+        }
+    }
+}
+
+/// The service which issues managed identity tokens, and its token endpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManagedIdentitySource {
+    /// The Azure Instance Metadata Service, available to Azure VMs.
+    Imds(String),
+    /// The Azure Arc Hybrid Instance Metadata Service, available to Azure Arc
+    /// enabled servers.
+    AzureArc(String),
+}
+
+impl ManagedIdentitySource {
+    /// Detect the managed identity source from the environment. Azure Arc is
+    /// detected by the `IDENTITY_ENDPOINT` and `IMDS_ENDPOINT` environment
+    /// variables, or by the presence of the HIMDS executable. Otherwise IMDS
+    /// is used, at the host in `AZURE_POD_IDENTITY_AUTHORITY_HOST` if it is
+    /// set.
+    ///
+    /// # Returns
+    ///
+    /// * The detected ManagedIdentitySource.
+    pub fn detect() -> Self {
+        match (env::var("IDENTITY_ENDPOINT"), env::var("IMDS_ENDPOINT")) {
+            (Ok(endpoint), Ok(_)) => ManagedIdentitySource::AzureArc(endpoint),
+            _ if Path::new(HIMDS_EXECUTABLE).exists() => {
+                ManagedIdentitySource::AzureArc(AZURE_ARC_ENDPOINT.to_string())
+            }
+            _ => match env::var("AZURE_POD_IDENTITY_AUTHORITY_HOST") {
+                Ok(host) => ManagedIdentitySource::Imds(format!(
+                    "{}/metadata/identity/oauth2/token",
+                    host.trim_end_matches('/')
+                )),
+                Err(_) => ManagedIdentitySource::Imds(IMDS_ENDPOINT.to_string()),
+            },
+        }
+    }
+
+    fn endpoint(&self) -> &str {
+        match self {
+            ManagedIdentitySource::Imds(endpoint) | ManagedIdentitySource::AzureArc(endpoint) => {
+                endpoint
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
+struct ManagedIdentityTokenResponse {
+    access_token: String,
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    expires_in: u64,
+    #[serde(deserialize_with = "deserialize_timestamp", default)]
+    expires_on: u64,
+    #[serde(deserialize_with = "deserialize_optional_timestamp", default)]
+    refresh_in: Option<u64>,
+    token_type: String,
+}
+
+#[derive(Deserialize)]
+struct ManagedIdentityErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
+
+/// An application which acquires tokens for the managed identity of the
+/// Azure VM or Azure Arc enabled server it runs on, without any secret.
+pub struct ManagedIdentityApplication {
+    client: Client,
+    identity: ManagedIdentity,
+    source: ManagedIdentitySource,
+    cache: Arc<dyn TokenCache>,
+    azure_arc_token_dir: PathBuf,
+}
+
+impl ManagedIdentityApplication {
+    /// Create an instance of an application.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The system-assigned identity, or a user-assigned
+    ///   identity of the machine.
+    ///
+    /// * `source` - The managed identity source, and its token endpoint. By
+    ///   default, the source is detected from the environment.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A ManagedIdentityApplication.
+    /// * Failure: An MsalError, indicating the failure. Azure Arc only
+    ///   supports the system-assigned identity.
+    pub fn new(
+        identity: ManagedIdentity,
+        source: Option<ManagedIdentitySource>,
+    ) -> Result<Self, MsalError> {
+        let source = source.unwrap_or_else(ManagedIdentitySource::detect);
+        if let (ManagedIdentitySource::AzureArc(_), Some(_)) = (&source, identity.query_param()) {
+            return Err(MsalError::ConfigError(
+                "Azure Arc does not support user-assigned managed identities".to_string(),
+            ));
+        }
+        let client = reqwest::Client::builder()
+            .build()
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        Ok(ManagedIdentityApplication {
+            client,
+            identity,
+            source,
+            cache: Arc::new(InMemoryTokenCache::new()),
+            azure_arc_token_dir: PathBuf::from(AZURE_ARC_TOKEN_DIR),
+        })
+    }
+
+    /// The managed identity source tokens are acquired from.
+    pub fn source(&self) -> &ManagedIdentitySource {
+        &self.source
+    }
+
+    /// Replace the token cache used by this application.
+    ///
+    /// # Arguments
+    ///
+    /// * `cache` - A TokenCache. By default, an InMemoryTokenCache is used,
+    ///   which is lost when the application exits.
+    pub fn set_token_cache(&mut self, cache: Arc<dyn TokenCache>) {
+        self.cache = cache;
+    }
+
+    /// The authority app tokens of the managed identity are cached under.
+    fn cache_authority(&self) -> Result<String, MsalError> {
+        let url = Url::parse(self.source.endpoint())
+            .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
+        Ok(format!(
+            "{}://{}/managed_identity",
+            url.scheme(),
+            url.host_str().unwrap_or_default()
+        ))
+    }
+
+    /// Acquire a token for the managed identity. Tokens are cached per
+    /// resource, and a cached token is returned until it needs refreshing.
+    ///
+    /// # Arguments
+    ///
+    /// * `resource` - The resource the token is for, such as
+    ///   `https://graph.microsoft.com`. A `.default` scope is also accepted.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A UserToken containing an access_token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn acquire_token_for_client(&self, resource: &str) -> Result<UserToken, MsalError> {
+        let resource = resource.trim_end_matches("/.default");
+        let client_id = self.identity.cache_client_id();
+        let authority = self.cache_authority()?;
+        let cached_token = find_app_token(self.cache.as_ref(), client_id, &authority, &[resource])?;
+        if let Some(token) = &cached_token {
+            if !token.needs_refresh() {
+                return Ok(token.clone());
+            }
+        }
+
+        let res = match &self.source {
+            ManagedIdentitySource::Imds(endpoint) => self.request_imds(endpoint, resource).await,
+            ManagedIdentitySource::AzureArc(endpoint) => {
+                self.request_azure_arc(endpoint, resource).await
+            }
+        };
+        match (res, cached_token) {
+            (Ok(token), _) => {
+                save_app_token(
+                    self.cache.as_ref(),
+                    client_id,
+                    &authority,
+                    &[resource],
+                    &token,
+                )?;
+                Ok(token)
+            }
+            // A proactive refresh failed, but the cached token is still
+            // valid.
+            (Err(e), Some(cached_token)) => {
+                debug!("Proactive refresh failed: {:?}", e);
+                Ok(cached_token)
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    fn token_url(
+        &self,
+        endpoint: &str,
+        api_version: &str,
+        resource: &str,
+    ) -> Result<Url, MsalError> {
+        let mut params = vec![("api-version", api_version), ("resource", resource)];
+        if let Some(param) = self.identity.query_param() {
+            params.push(param);
+        }
+        Url::parse_with_params(endpoint, &params)
+            .map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))
+    }
+
+    async fn request_imds(&self, endpoint: &str, resource: &str) -> Result<UserToken, MsalError> {
+        let url = self.token_url(endpoint, IMDS_API_VERSION, resource)?;
+        let mut retries = 0;
+        loop {
+            let resp = self
+                .client
+                .get(url.clone())
+                .header("Metadata", "true")
+                .send()
+                .await
+                .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+            let status = resp.status();
+            let retryable = matches!(
+                status,
+                StatusCode::NOT_FOUND
+                    | StatusCode::REQUEST_TIMEOUT
+                    | StatusCode::GONE
+                    | StatusCode::TOO_MANY_REQUESTS
+            ) || status.is_server_error();
+            if retryable && retries < IMDS_MAX_RETRIES {
+                let delay = IMDS_RETRY_DELAY << retries;
+                debug!(
+                    "IMDS request failed with {}, retrying in {}ms",
+                    status, delay
+                );
+                sleep(Duration::from_millis(delay)).await;
+                retries += 1;
+                continue;
+            }
+            return parse_token_response(resp, resource).await;
+        }
+    }
+
+    async fn request_azure_arc(
+        &self,
+        endpoint: &str,
+        resource: &str,
+    ) -> Result<UserToken, MsalError> {
+        let url = self.token_url(endpoint, AZURE_ARC_API_VERSION, resource)?;
+        let resp = self
+            .client
+            .get(url.clone())
+            .header("Metadata", "true")
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status() != StatusCode::UNAUTHORIZED {
+            return parse_token_response(resp, resource).await;
+        }
+
+        // HIMDS challenges the request with the path of a file which only a
+        // privileged user can read. Its contents authenticate the retry.
+        let challenge = resp
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic realm="))
+            .ok_or(MsalError::RequestFailed(
+                "Azure Arc response is missing the challenge".to_string(),
+            ))?;
+        let secret = read_azure_arc_challenge(&self.azure_arc_token_dir, challenge)?;
+        let resp = self
+            .client
+            .get(url)
+            .header("Metadata", "true")
+            .header(header::AUTHORIZATION, format!("Basic {}", secret.trim()))
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        parse_token_response(resp, resource).await
+    }
+}
+
+/// Read an Azure Arc challenge file, after checking that it is a key file in
+/// the HIMDS token directory.
+fn read_azure_arc_challenge(token_dir: &Path, challenge: &str) -> Result<String, MsalError> {
+    let path = Path::new(challenge);
+    let canonical = fs::canonicalize(path).map_err(|e| {
+        MsalError::GeneralFailure(format!("Invalid Azure Arc challenge {}: {}", challenge, e))
+    })?;
+    if canonical.parent() != Some(token_dir)
+        || canonical.extension().and_then(|ext| ext.to_str()) != Some("key")
+    {
+        return Err(MsalError::GeneralFailure(format!(
+            "Invalid Azure Arc challenge file {}",
+            challenge
+        )));
+    }
+    let metadata = fs::metadata(&canonical).map_err(|e| {
+        MsalError::GeneralFailure(format!("Invalid Azure Arc challenge {}: {}", challenge, e))
+    })?;
+    if metadata.len() > AZURE_ARC_MAX_KEY_SIZE {
+        return Err(MsalError::GeneralFailure(format!(
+            "Azure Arc challenge file {} is too large",
+            challenge
+        )));
+    }
+    fs::read_to_string(&canonical).map_err(|e| {
+        MsalError::GeneralFailure(format!(
+            "Failed reading Azure Arc challenge {}: {}",
+            challenge, e
+        ))
+    })
+}
+
+async fn parse_token_response(resp: Response, resource: &str) -> Result<UserToken, MsalError> {
+    if resp.status().is_success() {
+        let json_resp: ManagedIdentityTokenResponse = resp
+            .json()
+            .await
+            .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+        let mut token = UserToken {
+            token_type: json_resp.token_type.clone(),
+            scope: Some(resource.to_string()),
+            expires_in: json_resp.expires_in as u32,
+            ext_expires_in: json_resp.expires_in as u32,
+            expires_on: json_resp.expires_on,
+            refresh_on: 0,
+            refresh_in: json_resp.refresh_in.map(|refresh_in| refresh_in as u32),
+            access_token: Some(json_resp.access_token.clone()),
+            refresh_token: "".to_string(),
+            id_token: IdToken::default(),
+            client_info: ClientInfo::default(),
+            foci: None,
+            #[cfg(feature = "broker")]
+            prt: None,
+        };
+        token.stamp_expiry()?;
+        Ok(token)
+    } else {
+        let status = resp.status();
+        let json_resp: ManagedIdentityErrorResponse = resp.json().await.map_err(|e| {
+            MsalError::InvalidJson(format!(
+                "Managed identity request failed ({}): {}",
+                status, e
+            ))
+        })?;
+        Err(MsalError::AcquireTokenFailed(ErrorResponse {
+            error: json_resp.error,
+            error_description: json_resp.error_description,
+            error_codes: vec![],
+            claims: None,
+            suberror: None,
+        }))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::test_server::{serve, Requests, Response};
+
+    const RESOURCE: &str = "https://management.azure.com";
+
+    fn token_response() -> Response {
+        Response::json(json!({
+            "access_token": "managed_identity_access_token",
+            "expires_in": "86399",
+            "expires_on": "1735776000",
+            "ext_expires_in": "86399",
+            "not_before": "1735689300",
+            "resource": RESOURCE,
+            "token_type": "Bearer",
+        }))
+    }
+
+    #[tokio::test]
+    async fn imds_retries_transient_failures() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let (base_url, requests) = serve(move |_, _| {
+            if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                Response::status(503, json!({"error": "service_unavailable"}))
+            } else {
+                token_response()
+            }
+        })
+        .await;
+        let app = ManagedIdentityApplication::new(
+            ManagedIdentity::UserAssignedClientId("client_id".to_string()),
+            Some(ManagedIdentitySource::Imds(format!(
+                "{}/metadata/identity/oauth2/token",
+                base_url
+            ))),
+        )
+        .unwrap();
+        let token = app.acquire_token_for_client(RESOURCE).await.unwrap();
+        assert_eq!(
+            token.access_token.as_deref(),
+            Some("managed_identity_access_token")
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            assert_eq!(request.path(), "/metadata/identity/oauth2/token");
+            assert_eq!(request.header("Metadata"), Some("true"));
+            assert_eq!(
+                request.param("api-version").as_deref(),
+                Some(IMDS_API_VERSION)
+            );
+            assert_eq!(request.param("resource").as_deref(), Some(RESOURCE));
+            assert_eq!(request.param("client_id").as_deref(), Some("client_id"));
+        }
+    }
+
+    #[tokio::test]
+    async fn imds_does_not_retry_client_errors() {
+        let (base_url, requests) = serve(|_, _| {
+            Response::status(
+                400,
+                json!({
+                    "error": "invalid_request",
+                    "error_description": "Identity not found",
+                }),
+            )
+        })
+        .await;
+        let app = ManagedIdentityApplication::new(
+            ManagedIdentity::SystemAssigned,
+            Some(ManagedIdentitySource::Imds(format!(
+                "{}/metadata/identity/oauth2/token",
+                base_url
+            ))),
+        )
+        .unwrap();
+        assert!(app.acquire_token_for_client(RESOURCE).await.is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    async fn azure_arc_app(
+        challenge: PathBuf,
+        token_dir: &Path,
+    ) -> (ManagedIdentityApplication, Requests) {
+        let (base_url, requests) = serve(move |request, _| match request.header("Authorization") {
+            Some("Basic challenge-secret") => token_response(),
+            _ => Response::status(401, json!({})).header(
+                "WWW-Authenticate",
+                &format!("Basic realm={}", challenge.display()),
+            ),
+        })
+        .await;
+        let mut app = ManagedIdentityApplication::new(
+            ManagedIdentity::SystemAssigned,
+            Some(ManagedIdentitySource::AzureArc(format!(
+                "{}/metadata/identity/oauth2/token",
+                base_url
+            ))),
+        )
+        .unwrap();
+        app.azure_arc_token_dir = token_dir.to_path_buf();
+        (app, requests)
+    }
+
+    fn token_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("msal-arc-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("tokens")).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn azure_arc_challenge_handshake() {
+        let dir = token_dir();
+        let token_dir = dir.join("tokens");
+        let challenge = token_dir.join("7f3c9a2e.key");
+        fs::write(&challenge, "challenge-secret\n").unwrap();
+
+        let (app, requests) = azure_arc_app(challenge, &token_dir).await;
+        let token = app.acquire_token_for_client(RESOURCE).await.unwrap();
+        assert_eq!(
+            token.access_token.as_deref(),
+            Some("managed_identity_access_token")
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].header("Authorization"), None);
+        assert_eq!(
+            requests[1].header("Authorization"),
+            Some("Basic challenge-secret")
+        );
+        assert_eq!(
+            requests[1].param("api-version").as_deref(),
+            Some(AZURE_ARC_API_VERSION)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn azure_arc_challenge_outside_token_dir() {
+        let dir = token_dir();
+        // Only files in the HIMDS token directory are read
+        let challenge = dir.join("7f3c9a2e.key");
+        fs::write(&challenge, "challenge-secret\n").unwrap();
+
+        let (app, requests) = azure_arc_app(challenge, &dir.join("tokens")).await;
+        let res = app.acquire_token_for_client(RESOURCE).await;
+        assert!(matches!(res, Err(MsalError::GeneralFailure(msg)) if msg.contains("Invalid")));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::MsalError;
+use reqwest::{header, Client, Url};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+const OPENID_CONFIGURATION_PATH: &str = ".well-known/openid-configuration";
+
+/// The OpenID Connect metadata of an authority, from its
+/// `/.well-known/openid-configuration` document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenIdConfiguration {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_authorization_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_session_endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: String,
+}
+
+impl OpenIdConfiguration {
+    /// Fetch the OpenID Connect metadata of an authority.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The HTTP client used for the request.
+    ///
+    /// * `authority` - A URL that identifies a token authority.
+    ///
+    /// * `v2` - Whether to fetch the metadata of the v2.0 endpoints, or of
+    ///   the v1 endpoints.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The OpenID Connect metadata of the authority.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn discover(client: &Client, authority: &str, v2: bool) -> Result<Self, MsalError> {
+        let url = openid_configuration_url(authority, v2)?;
+        debug!("Discovering OpenID Connect metadata from {}", url);
+        let resp = client
+            .get(url)
+            .header(header::ACCEPT, "application/json")
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if resp.status().is_success() {
+            resp.json()
+                .await
+                .map_err(|e| MsalError::InvalidJson(format!("{}", e)))
+        } else {
+            Err(MsalError::RequestFailed(format!(
+                "OpenID Connect metadata discovery failed: {}",
+                resp.status()
+            )))
+        }
+    }
+
+    /// The host of the token endpoint, which is the audience of assertions
+    /// sent to the authority.
+    pub fn token_endpoint_host(&self) -> Result<String, MsalError> {
+        Url::parse(&self.token_endpoint)
+            .map_err(|e| MsalError::InvalidParse(format!("{}", e)))?
+            .host_str()
+            .map(|host| host.to_string())
+            .ok_or(MsalError::InvalidParse(
+                "The token endpoint has no host".to_string(),
+            ))
+    }
+}
+
+/// The location of the OpenID Connect metadata of an authority. The metadata
+/// of the v2.0 endpoints is found beneath the `v2.0` path of the authority.
+fn openid_configuration_url(authority: &str, v2: bool) -> Result<Url, MsalError> {
+    let mut url =
+        Url::parse(authority).map_err(|e| MsalError::URLFormatFailed(format!("{}", e)))?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| MsalError::URLFormatFailed("Invalid authority".to_string()))?;
+        segments.pop_if_empty();
+        if v2 {
+            segments.push("v2.0");
+        }
+        segments.extend(OPENID_CONFIGURATION_PATH.split('/'));
+    }
+    Ok(url)
+}
+
+/// The OpenID Connect metadata of a single authority, which is discovered
+/// on first use and then cached.
+pub(crate) struct OpenIdConfigurationCache {
+    authority: String,
+    v2: OnceCell<OpenIdConfiguration>,
+    v1: OnceCell<OpenIdConfiguration>,
+}
+
+impl OpenIdConfigurationCache {
+    pub(crate) fn new(authority: &str) -> Self {
+        OpenIdConfigurationCache {
+            authority: authority.to_string(),
+            v2: OnceCell::new(),
+            v1: OnceCell::new(),
+        }
+    }
+
+    /// The metadata of the v2.0 endpoints of the authority.
+    pub(crate) async fn v2(&self, client: &Client) -> Result<&OpenIdConfiguration, MsalError> {
+        self.v2
+            .get_or_try_init(|| OpenIdConfiguration::discover(client, &self.authority, true))
+            .await
+    }
+
+    /// The metadata of the v1 endpoints of the authority.
+    pub(crate) async fn v1(&self, client: &Client) -> Result<&OpenIdConfiguration, MsalError> {
+        self.v1
+            .get_or_try_init(|| OpenIdConfiguration::discover(client, &self.authority, false))
+            .await
+    }
+}
@@ -0,0 +1,479 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::auth::BrokerClientApplication;
+use crate::cache::now;
+use crate::error::MsalError;
+use kanidm_hsm_crypto::{BoxedDynTpm, MachineKey, SealedData};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// The delay before the first retry of a failed renewal. The delay doubles
+/// with each consecutive failure.
+const RETRY_BASE_DELAY: u64 = 30;
+/// The maximum delay between retries of a failed renewal.
+const RETRY_MAX_DELAY: u64 = 60 * 60;
+/// The minimum delay between passes over the schedule, so that a PRT which
+/// is still due after it was renewed is not renewed in a busy loop.
+const MIN_DELAY: u64 = 30;
+/// Renewals are delayed by a random amount of up to this fraction of the
+/// delay, so that many PRTs issued together are not renewed together.
+const JITTER_DIVISOR: u64 = 10;
+
+type RenewalCallback = dyn Fn(&str, &SealedData) + Send + Sync;
+
+struct ScheduledPrt {
+    sealed_prt: SealedData,
+    renew_on: u64,
+    failures: u32,
+    /// Identifies the PRT which was scheduled, so that a renewal does not
+    /// overwrite a PRT inserted while the renewal was in flight.
+    generation: u64,
+}
+
+struct SchedulerInner {
+    app: Arc<BrokerClientApplication>,
+    tpm: Arc<Mutex<BoxedDynTpm>>,
+    machine_key: MachineKey,
+    prts: StdMutex<HashMap<String, ScheduledPrt>>,
+    generation: AtomicU64,
+    notify: Notify,
+    callback: Box<RenewalCallback>,
+}
+
+fn jitter(delay: u64) -> u64 {
+    let max = delay / JITTER_DIVISOR;
+    if max == 0 {
+        return 0;
+    }
+    (Uuid::new_v4().as_u128() % (max as u128 + 1)) as u64
+}
+
+fn retry_delay(failures: u32) -> u64 {
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(
+            1u64.checked_shl(failures.saturating_sub(1))
+                .unwrap_or(u64::MAX),
+        )
+        .min(RETRY_MAX_DELAY);
+    delay + jitter(delay)
+}
+
+impl SchedulerInner {
+    fn prts(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, ScheduledPrt>>, MsalError> {
+        self.prts
+            .lock()
+            .map_err(|e| MsalError::GeneralFailure(format!("PRT scheduler poisoned: {}", e)))
+    }
+
+    async fn renew_on(&self, sealed_prt: &SealedData) -> Result<u64, MsalError> {
+        let mut tpm = self.tpm.lock().await;
+        let renew_on = self
+            .app
+            .inspect_prt(sealed_prt, &mut tpm, &self.machine_key)?
+            .renew_on;
+        let now = now()?;
+        Ok(renew_on + jitter(renew_on.saturating_sub(now)))
+    }
+
+    async fn renew(
+        &self,
+        username: &str,
+        sealed_prt: &SealedData,
+        generation: u64,
+    ) -> Result<(), MsalError> {
+        debug!("Renewing the PRT for {}", username);
+        let new_prt = self
+            .app
+            .exchange_prt_for_prt_shared_tpm(sealed_prt, &self.tpm, &self.machine_key, true)
+            .await?;
+        let renew_on = self.renew_on(&new_prt).await?;
+        self.complete_renewal(username, generation, &new_prt, renew_on)
+    }
+
+    /// Replace a PRT with its renewal, and publish the renewed PRT through
+    /// the callback. The renewal is discarded if the PRT was replaced or
+    /// removed while the renewal was in flight.
+    fn complete_renewal(
+        &self,
+        username: &str,
+        generation: u64,
+        new_prt: &SealedData,
+        renew_on: u64,
+    ) -> Result<(), MsalError> {
+        {
+            let mut prts = self.prts()?;
+            match prts.get_mut(username) {
+                Some(entry) if entry.generation == generation => {
+                    entry.sealed_prt = new_prt.clone();
+                    entry.renew_on = renew_on;
+                    entry.failures = 0;
+                }
+                _ => {
+                    debug!(
+                        "The PRT for {} was replaced while it was renewed, discarding the renewal",
+                        username
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        (self.callback)(username, new_prt);
+        Ok(())
+    }
+
+    /// Schedule a retry of a failed renewal, unless the PRT was replaced or
+    /// removed while the renewal was in flight.
+    fn fail_renewal(&self, username: &str, generation: u64) -> Result<(), MsalError> {
+        let now = now()?;
+        if let Some(entry) = self
+            .prts()?
+            .get_mut(username)
+            .filter(|entry| entry.generation == generation)
+        {
+            entry.failures += 1;
+            entry.renew_on = now + retry_delay(entry.failures);
+        }
+        Ok(())
+    }
+
+    async fn renew_due(&self) -> Result<(), MsalError> {
+        let now = now()?;
+        let due: Vec<(String, SealedData, u64)> = self
+            .prts()?
+            .iter()
+            .filter(|(_, entry)| entry.renew_on <= now)
+            .map(|(username, entry)| (username.clone(), entry.sealed_prt.clone(), entry.generation))
+            .collect();
+        for (username, sealed_prt, generation) in due {
+            if let Err(e) = self.renew(&username, &sealed_prt, generation).await {
+                error!("Failed to renew the PRT for {}: {:?}", username, e);
+                self.fail_renewal(&username, generation)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of seconds until the next PRT is due, or None if no PRT is
+    /// scheduled.
+    fn next_due(&self) -> Result<Option<u64>, MsalError> {
+        let now = now()?;
+        Ok(self
+            .prts()?
+            .values()
+            .map(|entry| entry.renew_on.saturating_sub(now))
+            .min())
+    }
+
+    async fn run(&self) {
+        loop {
+            let next = match self.renew_due().await.and_then(|_| self.next_due()) {
+                Ok(next) => next,
+                Err(e) => {
+                    error!("The PRT scheduler stopped: {:?}", e);
+                    return;
+                }
+            };
+            match next {
+                Some(next) => {
+                    tokio::select! {
+                        _ = sleep(Duration::from_secs(next.max(MIN_DELAY))) => {}
+                        _ = self.notify.notified() => {}
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
+
+/// Renews the Primary Refresh Tokens (PRT) of users in the background.
+///
+/// Each PRT is renewed when it is due, which is determined from the PRT
+/// itself, and the renewed PRT is published through a callback so that it
+/// can be persisted. Renewals are delayed by a random jitter, and failed
+/// renewals are retried with an exponential backoff.
+pub struct PrtRenewalScheduler {
+    inner: Arc<SchedulerInner>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl PrtRenewalScheduler {
+    /// Create a renewal scheduler. The scheduler does not renew any PRTs
+    /// until it is started.
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - An enrolled BrokerClientApplication.
+    ///
+    /// * `tpm` - The tpm object, which is locked while a PRT is unsealed or
+    ///   sealed during a renewal.
+    ///
+    /// * `machine_key` - The TPM MachineKey associated with this application.
+    ///
+    /// * `callback` - Called with the username and the new sealed PRT each
+    ///   time a PRT is renewed.
+    pub fn new<F>(
+        app: Arc<BrokerClientApplication>,
+        tpm: Arc<Mutex<BoxedDynTpm>>,
+        machine_key: MachineKey,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(&str, &SealedData) + Send + Sync + 'static,
+    {
+        PrtRenewalScheduler {
+            inner: Arc::new(SchedulerInner {
+                app,
+                tpm,
+                machine_key,
+                prts: StdMutex::new(HashMap::new()),
+                generation: AtomicU64::new(0),
+                notify: Notify::new(),
+                callback: Box::new(callback),
+            }),
+            task: None,
+        }
+    }
+
+    /// Start renewing PRTs on a tokio task. This must be called from within
+    /// a tokio runtime.
+    pub fn start(&mut self) {
+        if self.task.is_none() {
+            let inner = self.inner.clone();
+            self.task = Some(tokio::spawn(async move { inner.run().await }));
+        }
+    }
+
+    /// Stop renewing PRTs.
+    pub fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    /// Schedule the renewal of a user's PRT, replacing any PRT previously
+    /// scheduled for the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The user the PRT belongs to. This is passed to the
+    ///   callback when the PRT is renewed.
+    ///
+    /// * `sealed_prt` - An encrypted primary refresh token that was
+    ///   previously received from the server.
+    ///
+    /// # Returns
+    ///
+    /// * Success: ()
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn insert(&self, username: &str, sealed_prt: SealedData) -> Result<(), MsalError> {
+        let renew_on = self.inner.renew_on(&sealed_prt).await?;
+        let generation = self.inner.generation.fetch_add(1, Ordering::Relaxed);
+        self.inner.prts()?.insert(
+            username.to_string(),
+            ScheduledPrt {
+                sealed_prt,
+                renew_on,
+                failures: 0,
+                generation,
+            },
+        );
+        self.inner.notify.notify_one();
+        Ok(())
+    }
+
+    /// Stop renewing a user's PRT.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The user the PRT belongs to.
+    ///
+    /// # Returns
+    ///
+    /// * Success: ()
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn remove(&self, username: &str) -> Result<(), MsalError> {
+        self.inner.prts()?.remove(username);
+        self.inner.notify.notify_one();
+        Ok(())
+    }
+
+    /// Fetch the current sealed PRT of a user, which reflects any renewal.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The user the PRT belongs to.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The sealed PRT, or None if no PRT is scheduled for the user.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn get(&self, username: &str) -> Result<Option<SealedData>, MsalError> {
+        Ok(self
+            .inner
+            .prts()?
+            .get(username)
+            .map(|entry| entry.sealed_prt.clone()))
+    }
+}
+
+impl Drop for PrtRenewalScheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use kanidm_hsm_crypto::soft::SoftTpm;
+    use kanidm_hsm_crypto::{AuthValue, Tpm};
+    use std::sync::atomic::AtomicUsize;
+
+    fn sealed_prt(data: u8) -> SealedData {
+        SealedData::SoftV1 {
+            data: vec![data],
+            tag: [0; 16],
+            iv: [0; 16],
+        }
+    }
+
+    fn sealed_prt_data(sealed_prt: &SealedData) -> Vec<u8> {
+        match sealed_prt {
+            SealedData::SoftV1 { data, .. } => data.clone(),
+            _ => vec![],
+        }
+    }
+
+    /// A scheduler holding a PRT for jdoe of generation 1, and the number of
+    /// renewals published through its callback.
+    fn scheduler() -> (PrtRenewalScheduler, Arc<AtomicUsize>) {
+        let mut tpm = BoxedDynTpm::new(SoftTpm::new());
+        let auth_value = AuthValue::ephemeral().unwrap();
+        let loadable_machine_key = tpm.machine_key_create(&auth_value).unwrap();
+        let machine_key = tpm
+            .machine_key_load(&auth_value, &loadable_machine_key)
+            .unwrap();
+        let app = BrokerClientApplication::new(None, None, None).unwrap();
+        let published = Arc::new(AtomicUsize::new(0));
+        let counter = published.clone();
+        let scheduler = PrtRenewalScheduler::new(
+            Arc::new(app),
+            Arc::new(Mutex::new(tpm)),
+            machine_key,
+            move |_, _| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            },
+        );
+        scheduler.inner.prts().unwrap().insert(
+            "jdoe".to_string(),
+            ScheduledPrt {
+                sealed_prt: sealed_prt(1),
+                renew_on: 100,
+                failures: 2,
+                generation: 1,
+            },
+        );
+        (scheduler, published)
+    }
+
+    #[test]
+    fn retry_delay_backs_off_to_a_cap() {
+        for (failures, delay) in [(1, 30), (2, 60), (3, 120), (7, 1920)] {
+            let retry = retry_delay(failures);
+            assert!((delay..=delay + delay / JITTER_DIVISOR).contains(&retry));
+        }
+        for failures in [8, 64, u32::MAX] {
+            let retry = retry_delay(failures);
+            assert!(
+                (RETRY_MAX_DELAY..=RETRY_MAX_DELAY + RETRY_MAX_DELAY / JITTER_DIVISOR)
+                    .contains(&retry)
+            );
+        }
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        assert_eq!(jitter(0), 0);
+        assert_eq!(jitter(JITTER_DIVISOR - 1), 0);
+        let jitters: Vec<u64> = (0..1000).map(|_| jitter(1000)).collect();
+        assert!(jitters.iter().all(|jitter| *jitter <= 100));
+        assert!(jitters.iter().any(|jitter| *jitter != jitters[0]));
+    }
+
+    #[test]
+    fn renewal_of_replaced_prt_is_discarded() {
+        let (scheduler, published) = scheduler();
+        // The PRT was replaced by generation 1 while generation 0 was renewed
+        scheduler
+            .inner
+            .complete_renewal("jdoe", 0, &sealed_prt(2), 200)
+            .unwrap();
+        assert_eq!(published.load(Ordering::Relaxed), 0);
+        let sealed = scheduler.get("jdoe").unwrap().unwrap();
+        assert_eq!(sealed_prt_data(&sealed), vec![1]);
+
+        scheduler
+            .inner
+            .complete_renewal("jdoe", 1, &sealed_prt(3), 200)
+            .unwrap();
+        assert_eq!(published.load(Ordering::Relaxed), 1);
+        let sealed = scheduler.get("jdoe").unwrap().unwrap();
+        assert_eq!(sealed_prt_data(&sealed), vec![3]);
+        let prts = scheduler.inner.prts().unwrap();
+        assert_eq!(prts["jdoe"].renew_on, 200);
+        assert_eq!(prts["jdoe"].failures, 0);
+    }
+
+    #[test]
+    fn renewal_of_removed_prt_is_discarded() {
+        let (scheduler, published) = scheduler();
+        scheduler.remove("jdoe").unwrap();
+        scheduler
+            .inner
+            .complete_renewal("jdoe", 1, &sealed_prt(2), 200)
+            .unwrap();
+        assert_eq!(published.load(Ordering::Relaxed), 0);
+        assert!(scheduler.get("jdoe").unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_renewal_is_retried_with_backoff() {
+        let (scheduler, _) = scheduler();
+        // A failure of a replaced PRT does not delay its replacement
+        scheduler.inner.fail_renewal("jdoe", 0).unwrap();
+        assert_eq!(scheduler.inner.prts().unwrap()["jdoe"].failures, 2);
+        assert_eq!(scheduler.inner.prts().unwrap()["jdoe"].renew_on, 100);
+
+        let before = now().unwrap();
+        scheduler.inner.fail_renewal("jdoe", 1).unwrap();
+        let prts = scheduler.inner.prts().unwrap();
+        assert_eq!(prts["jdoe"].failures, 3);
+        assert!(prts["jdoe"].renew_on >= before + 120);
+        assert!(prts["jdoe"].renew_on <= now().unwrap() + 132);
+    }
+}
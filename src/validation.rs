@@ -0,0 +1,765 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::auth::IdToken;
+use crate::authority::parse_authority;
+use crate::cache::now;
+use crate::error::MsalError;
+use crate::oidc::OpenIdConfigurationCache;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+use reqwest::{header, Client};
+use serde::Deserialize;
+use serde_json::{from_slice as json_from_slice, Map, Value};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+/// The clock skew which is allowed by default when checking when a token
+/// expires and when it becomes valid.
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// A token signed by an unknown key causes the JWKS to be fetched again, but
+/// not more often than this, so that forged tokens cannot flood the
+/// authority with requests.
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The placeholder for the tenant id in the issuer of a multi-tenant
+/// authority, such as `https://login.microsoftonline.com/{tenantid}/v2.0`.
+const TENANT_ID_PLACEHOLDER: &str = "{tenantid}";
+
+#[derive(Deserialize)]
+struct JwsHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    #[serde(default)]
+    x5c: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+impl Jwk {
+    /// The RSA public key of the JWK, from its modulus and exponent, or from
+    /// its certificate.
+    fn public_key(&self) -> Result<PKey<Public>, MsalError> {
+        let rsa = match (&self.n, &self.e, self.x5c.first()) {
+            (Some(n), Some(e), _) => {
+                let n = URL_SAFE_NO_PAD
+                    .decode(n)
+                    .map_err(|e| MsalError::InvalidBase64(format!("{}", e)))?;
+                let e = URL_SAFE_NO_PAD
+                    .decode(e)
+                    .map_err(|e| MsalError::InvalidBase64(format!("{}", e)))?;
+                Rsa::from_public_components(
+                    BigNum::from_slice(&n).map_err(|e| MsalError::CryptoFail(format!("{}", e)))?,
+                    BigNum::from_slice(&e).map_err(|e| MsalError::CryptoFail(format!("{}", e)))?,
+                )
+                .map_err(|e| MsalError::CryptoFail(format!("{}", e)))?
+            }
+            (_, _, Some(cert)) => {
+                let der = STANDARD
+                    .decode(cert)
+                    .map_err(|e| MsalError::InvalidBase64(format!("{}", e)))?;
+                X509::from_der(&der)
+                    .and_then(|cert| cert.public_key())
+                    .and_then(|key| key.rsa())
+                    .map_err(|e| MsalError::CryptoFail(format!("{}", e)))?
+            }
+            _ => {
+                return Err(MsalError::InvalidParse(
+                    "The JWK has no public key".to_string(),
+                ))
+            }
+        };
+        PKey::from_rsa(rsa).map_err(|e| MsalError::CryptoFail(format!("{}", e)))
+    }
+}
+
+/// The signing keys of the authority, by key id.
+#[derive(Default)]
+struct JwksCache {
+    keys: HashMap<String, PKey<Public>>,
+    fetched_at: Option<Instant>,
+}
+
+/// Validates the ID tokens and access tokens issued by an authority. The
+/// signature of a token is verified with the signing keys published in the
+/// JWKS of the authority, which is fetched on first use and cached. The
+/// issuer, audience, expiry and nonce of the token are then checked.
+pub struct TokenValidator {
+    client: Client,
+    openid_configuration: OpenIdConfigurationCache,
+    jwks: RwLock<JwksCache>,
+    clock_skew: Duration,
+}
+
+impl TokenValidator {
+    /// Create an instance of a token validator.
+    ///
+    /// # Arguments
+    ///
+    /// * `authority` - A URL that identifies the token authority which
+    ///   issues the tokens. The signing keys and the issuer are discovered
+    ///   from its OpenID Connect metadata.
+    ///
+    /// # Returns
+    ///
+    /// * Success: A TokenValidator.
+    /// * Failure: An MsalError, indicating the failure.
+    pub fn new(authority: &str) -> Result<Self, MsalError> {
+        parse_authority(authority)?;
+        let client = reqwest::Client::builder()
+            .build()
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        Ok(TokenValidator {
+            client,
+            openid_configuration: OpenIdConfigurationCache::new(authority),
+            jwks: RwLock::new(JwksCache::default()),
+            clock_skew: DEFAULT_CLOCK_SKEW,
+        })
+    }
+
+    /// Set the clock skew which is allowed when checking when a token
+    /// expires and when it becomes valid. The default is 5 minutes.
+    ///
+    /// # Arguments
+    ///
+    /// * `clock_skew` - The allowed difference between the clocks of this
+    ///   host and of the authority.
+    pub fn set_clock_skew(&mut self, clock_skew: Duration) {
+        self.clock_skew = clock_skew;
+    }
+
+    /// Validate an ID token, which was issued to this application.
+    ///
+    /// # Arguments
+    ///
+    /// * `id_token` - The encoded ID token, such as the raw id_token of a
+    ///   token response.
+    ///
+    /// * `client_id` - The client id of the application, which must be the
+    ///   audience of the token.
+    ///
+    /// * `nonce` - The nonce which was sent in the authorization request,
+    ///   which must be echoed by the token. Pass None if no nonce was sent.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The claims of the validated ID token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn validate_id_token(
+        &self,
+        id_token: &str,
+        client_id: &str,
+        nonce: Option<&str>,
+    ) -> Result<IdToken, MsalError> {
+        let claims = self.verify_signature(id_token).await?;
+        self.check_lifetime(&claims)?;
+        check_audience(&claims, &[client_id])?;
+        let issuer = self
+            .openid_configuration
+            .v2(&self.client)
+            .await?
+            .issuer
+            .clone();
+        check_issuer(&claims, &issuer)?;
+        if let Some(nonce) = nonce {
+            if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+                return Err(MsalError::InvalidToken(
+                    "The token nonce does not match the request".to_string(),
+                ));
+            }
+        }
+        IdToken::from_str(id_token)
+    }
+
+    /// Validate an access token presented to a web API, for an API which
+    /// acts as a resource server. Both v1 and v2.0 access tokens are
+    /// accepted, as the version is chosen by the API's app registration.
+    /// Only tokens issued for the API itself can be validated; tokens for
+    /// other resources, such as Microsoft Graph, are not meant to be
+    /// validated by their clients.
+    ///
+    /// # Arguments
+    ///
+    /// * `access_token` - The encoded access token, such as the bearer token
+    ///   of a request to the API.
+    ///
+    /// * `audiences` - The audiences of the API, such as its client id and
+    ///   its application id URI. The token must be issued to one of them.
+    ///
+    /// # Returns
+    ///
+    /// * Success: The claims of the validated access token.
+    /// * Failure: An MsalError, indicating the failure.
+    pub async fn validate_access_token(
+        &self,
+        access_token: &str,
+        audiences: &[&str],
+    ) -> Result<Map<String, Value>, MsalError> {
+        let claims = self.verify_signature(access_token).await?;
+        self.check_lifetime(&claims)?;
+        check_audience(&claims, audiences)?;
+        let issuer = self
+            .openid_configuration
+            .v2(&self.client)
+            .await?
+            .issuer
+            .clone();
+        if check_issuer(&claims, &issuer).is_err() {
+            // A v1 access token is issued by the v1 endpoints, whose issuer
+            // is only known from their metadata.
+            let issuer = self
+                .openid_configuration
+                .v1(&self.client)
+                .await?
+                .issuer
+                .clone();
+            check_issuer(&claims, &issuer)?;
+        }
+        Ok(claims)
+    }
+
+    /// Verify the RS256 signature of a token, and decode its claims.
+    async fn verify_signature(&self, token: &str) -> Result<Map<String, Value>, MsalError> {
+        let mut siter = token.split('.');
+        let (header, payload, signature) = match (siter.next(), siter.next(), siter.next()) {
+            (Some(header), Some(payload), Some(signature)) if siter.next().is_none() => {
+                (header, payload, signature)
+            }
+            _ => {
+                return Err(MsalError::InvalidToken(
+                    "The token is not a signed JWT".to_string(),
+                ))
+            }
+        };
+        let jws_header: JwsHeader = decode_segment(header)?;
+        // Only RS256 is used by Entra ID. Accepting the algorithm named by
+        // the token would allow unsigned tokens.
+        if jws_header.alg != "RS256" {
+            return Err(MsalError::InvalidToken(format!(
+                "Unsupported token signing algorithm {}",
+                jws_header.alg
+            )));
+        }
+        let kid = jws_header.kid.ok_or(MsalError::InvalidToken(
+            "The token does not name its signing key".to_string(),
+        ))?;
+        let key = self.signing_key(&kid).await?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|e| MsalError::InvalidBase64(format!("{}", e)))?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)
+            .map_err(|e| MsalError::CryptoFail(format!("{}", e)))?;
+        verifier
+            .update(format!("{}.{}", header, payload).as_bytes())
+            .map_err(|e| MsalError::CryptoFail(format!("{}", e)))?;
+        if !verifier
+            .verify(&signature)
+            .map_err(|e| MsalError::CryptoFail(format!("{}", e)))?
+        {
+            return Err(MsalError::InvalidToken(
+                "The token signature is invalid".to_string(),
+            ));
+        }
+        decode_segment(payload)
+    }
+
+    /// Find a signing key of the authority. An unknown key may have been
+    /// introduced by key rollover, so the JWKS is fetched again.
+    async fn signing_key(&self, kid: &str) -> Result<PKey<Public>, MsalError> {
+        {
+            let jwks = self.jwks.read().await;
+            if let Some(key) = jwks.keys.get(kid) {
+                return Ok(key.clone());
+            }
+        }
+        let mut jwks = self.jwks.write().await;
+        // The JWKS may have been fetched while waiting for the lock
+        if let Some(key) = jwks.keys.get(kid) {
+            return Ok(key.clone());
+        }
+        let stale = jwks
+            .fetched_at
+            .map(|fetched_at| fetched_at.elapsed() >= JWKS_REFRESH_INTERVAL)
+            .unwrap_or(true);
+        if stale {
+            jwks.keys = self.fetch_jwks().await?;
+            jwks.fetched_at = Some(Instant::now());
+        }
+        jwks.keys
+            .get(kid)
+            .cloned()
+            .ok_or(MsalError::InvalidToken(format!(
+                "The token signing key {} is unknown",
+                kid
+            )))
+    }
+
+    async fn fetch_jwks(&self) -> Result<HashMap<String, PKey<Public>>, MsalError> {
+        let jwks_uri = &self.openid_configuration.v2(&self.client).await?.jwks_uri;
+        debug!("Fetching signing keys from {}", jwks_uri);
+        let resp = self
+            .client
+            .get(jwks_uri)
+            .header(header::ACCEPT, "application/json")
+            .send()
+            .await
+            .map_err(|e| MsalError::RequestFailed(format!("{}", e)))?;
+        if !resp.status().is_success() {
+            return Err(MsalError::RequestFailed(format!(
+                "Fetching signing keys failed: {}",
+                resp.status()
+            )));
+        }
+        let jwk_set: JwkSet = resp
+            .json()
+            .await
+            .map_err(|e| MsalError::InvalidJson(format!("{}", e)))?;
+        let mut keys = HashMap::new();
+        for jwk in jwk_set.keys {
+            if jwk.kty != "RSA" || jwk.key_use.as_deref().unwrap_or("sig") != "sig" {
+                continue;
+            }
+            if let Some(kid) = &jwk.kid {
+                match jwk.public_key() {
+                    Ok(key) => {
+                        keys.insert(kid.clone(), key);
+                    }
+                    Err(e) => debug!("Ignoring signing key {}: {:?}", kid, e),
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Check that the token has not expired, and is already valid.
+    fn check_lifetime(&self, claims: &Map<String, Value>) -> Result<(), MsalError> {
+        let now = now()?;
+        let skew = self.clock_skew.as_secs();
+        let exp = claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .ok_or(MsalError::InvalidToken(
+                "The token has no expiry".to_string(),
+            ))?;
+        if now > exp.saturating_add(skew) {
+            return Err(MsalError::InvalidToken("The token has expired".to_string()));
+        }
+        if let Some(nbf) = claims.get("nbf").and_then(Value::as_u64) {
+            if now.saturating_add(skew) < nbf {
+                return Err(MsalError::InvalidToken(
+                    "The token is not yet valid".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_segment<T>(segment: &str) -> Result<T, MsalError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let bytes = URL_SAFE_NO_PAD
+        .decode(segment)
+        .map_err(|e| MsalError::InvalidBase64(format!("{}", e)))?;
+    json_from_slice(&bytes).map_err(|e| MsalError::InvalidJson(format!("{}", e)))
+}
+
+/// Check that the token was issued to one of the audiences. The aud claim
+/// is either a single audience, or a list of them.
+fn check_audience(claims: &Map<String, Value>, audiences: &[&str]) -> Result<(), MsalError> {
+    let token_audiences: Vec<&str> = match claims.get("aud") {
+        Some(Value::String(aud)) => vec![aud.as_str()],
+        Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    if token_audiences.iter().any(|aud| audiences.contains(aud)) {
+        Ok(())
+    } else {
+        Err(MsalError::InvalidToken(format!(
+            "The token audience {:?} is not accepted",
+            token_audiences
+        )))
+    }
+}
+
+/// Check that the token was issued by the issuer. The issuer of a
+/// multi-tenant authority names the tenant of the token by its tid claim.
+fn check_issuer(claims: &Map<String, Value>, issuer: &str) -> Result<(), MsalError> {
+    let iss = claims
+        .get("iss")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let tid = claims
+        .get("tid")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if !iss.is_empty() && issuer.replace(TENANT_ID_PLACEHOLDER, tid) == iss {
+        Ok(())
+    } else {
+        Err(MsalError::InvalidToken(format!(
+            "The token issuer {} is not accepted",
+            iss
+        )))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    use crate::test_server::{openid_configuration, serve, Requests, Response};
+
+    const CLIENT_ID: &str = "11111111-2222-3333-4444-555555555555";
+    const TENANT_ID: &str = "aaaaaaaa-bbbb-cccc-dddd-eeeeeeeeeeee";
+    const V1_ISSUER: &str = "https://sts.windows.net/aaaaaaaa-bbbb-cccc-dddd-eeeeeeeeeeee/";
+
+    fn rsa_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    fn jwk(key: &PKey<Private>, kid: &str) -> Value {
+        let rsa = key.rsa().unwrap();
+        json!({
+            "kty": "RSA",
+            "use": "sig",
+            "kid": kid,
+            "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+            "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+        })
+    }
+
+    fn encode(value: &Value) -> String {
+        URL_SAFE_NO_PAD.encode(value.to_string())
+    }
+
+    fn sign(key: &PKey<Private>, kid: &str, claims: &Value) -> String {
+        let signing_input = format!(
+            "{}.{}",
+            encode(&json!({"alg": "RS256", "typ": "JWT", "kid": kid})),
+            encode(claims)
+        );
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(signing_input.as_bytes()).unwrap();
+        format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signer.sign_to_vec().unwrap())
+        )
+    }
+
+    /// The claims of a valid token issued by the stand-in authority.
+    fn claims(authority: &str) -> Value {
+        let now = now().unwrap();
+        json!({
+            "iss": format!("{}/v2.0", authority),
+            "aud": CLIENT_ID,
+            "tid": TENANT_ID,
+            "oid": "00000000-0000-0000-1111-222222222222",
+            "name": "John Doe",
+            "iat": now,
+            "nbf": now,
+            "exp": now + 3600,
+        })
+    }
+
+    /// A stand-in authority, which publishes the keys in its JWKS.
+    async fn authority(keys: Arc<Mutex<Vec<Value>>>) -> (String, Requests) {
+        let (base_url, requests) = serve(move |request, base| {
+            let authority = format!("{}/tenant", base);
+            match request.path() {
+                "/tenant/v2.0/.well-known/openid-configuration" => openid_configuration(&authority),
+                "/tenant/.well-known/openid-configuration" => Response::json(json!({
+                    "issuer": V1_ISSUER,
+                    "authorization_endpoint": format!("{}/oauth2/authorize", authority),
+                    "token_endpoint": format!("{}/oauth2/token", authority),
+                    "jwks_uri": format!("{}/discovery/keys", authority),
+                })),
+                "/tenant/discovery/v2.0/keys" => {
+                    Response::json(json!({ "keys": keys.lock().unwrap().clone() }))
+                }
+                _ => Response::status(404, json!({})),
+            }
+        })
+        .await;
+        (format!("{}/tenant", base_url), requests)
+    }
+
+    fn jwks_fetches(requests: &Requests) -> usize {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.path().ends_with("/keys"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn valid_token() {
+        let key = rsa_key();
+        let (authority, _) = authority(Arc::new(Mutex::new(vec![jwk(&key, "k1")]))).await;
+        let validator = TokenValidator::new(&authority).unwrap();
+
+        let mut token_claims = claims(&authority);
+        token_claims["nonce"] = json!("nonce");
+        let token = sign(&key, "k1", &token_claims);
+        let id_token = validator
+            .validate_id_token(&token, CLIENT_ID, Some("nonce"))
+            .await
+            .unwrap();
+        assert_eq!(id_token.name, "John Doe");
+        assert_eq!(id_token.tid, TENANT_ID);
+
+        let res = validator
+            .validate_id_token(&token, CLIENT_ID, Some("other nonce"))
+            .await;
+        assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("nonce")));
+
+        let res = validator
+            .validate_id_token(&token, "another client", None)
+            .await;
+        assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("audience")));
+    }
+
+    #[tokio::test]
+    async fn invalid_signatures_are_rejected() {
+        let key = rsa_key();
+        let (authority, _) = authority(Arc::new(Mutex::new(vec![jwk(&key, "k1")]))).await;
+        let validator = TokenValidator::new(&authority).unwrap();
+        let token_claims = claims(&authority);
+
+        // Signed by another key
+        let token = sign(&rsa_key(), "k1", &token_claims);
+        let res = validator.validate_id_token(&token, CLIENT_ID, None).await;
+        assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("signature")));
+
+        // A valid signature over other claims
+        let valid = sign(&key, "k1", &token_claims);
+        let mut forged_claims = token_claims.clone();
+        forged_claims["oid"] = json!("99999999-0000-0000-1111-222222222222");
+        let mut parts: Vec<String> = valid.split('.').map(|s| s.to_string()).collect();
+        parts[1] = encode(&forged_claims);
+        let res = validator
+            .validate_id_token(&parts.join("."), CLIENT_ID, None)
+            .await;
+        assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("signature")));
+    }
+
+    #[tokio::test]
+    async fn unsigned_and_hmac_tokens_are_rejected() {
+        let key = rsa_key();
+        let (authority, requests) = authority(Arc::new(Mutex::new(vec![jwk(&key, "k1")]))).await;
+        let validator = TokenValidator::new(&authority).unwrap();
+        let payload = encode(&claims(&authority));
+
+        let unsigned = format!(
+            "{}.{}.",
+            encode(&json!({"alg": "none", "typ": "JWT", "kid": "k1"})),
+            payload
+        );
+        let res = validator
+            .validate_id_token(&unsigned, CLIENT_ID, None)
+            .await;
+        assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("none")));
+
+        // An HMAC keyed with the public key, which a verifier trusting the
+        // alg of the token would accept.
+        let signing_input = format!(
+            "{}.{}",
+            encode(&json!({"alg": "HS256", "typ": "JWT", "kid": "k1"})),
+            payload
+        );
+        let secret = key.public_key_to_pem().unwrap();
+        let hmac_key = PKey::hmac(&secret).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &hmac_key).unwrap();
+        signer.update(signing_input.as_bytes()).unwrap();
+        let hmac = format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signer.sign_to_vec().unwrap())
+        );
+        let res = validator.validate_id_token(&hmac, CLIENT_ID, None).await;
+        assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("HS256")));
+
+        // Rejected before the keys are fetched
+        assert_eq!(jwks_fetches(&requests), 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_key_refetches_jwks_once() {
+        let key = rsa_key();
+        let keys = Arc::new(Mutex::new(vec![jwk(&key, "k1")]));
+        let (authority, requests) = authority(keys.clone()).await;
+        let validator = TokenValidator::new(&authority).unwrap();
+        let token_claims = claims(&authority);
+
+        validator
+            .validate_id_token(&sign(&key, "k1", &token_claims), CLIENT_ID, None)
+            .await
+            .unwrap();
+        assert_eq!(jwks_fetches(&requests), 1);
+
+        // The authority rolls over to a new key, after the keys were last
+        // fetched.
+        let rolled_key = rsa_key();
+        keys.lock().unwrap().push(jwk(&rolled_key, "k2"));
+        validator.jwks.write().await.fetched_at = Instant::now().checked_sub(JWKS_REFRESH_INTERVAL);
+
+        let rolled = sign(&rolled_key, "k2", &token_claims);
+        validator
+            .validate_id_token(&rolled, CLIENT_ID, None)
+            .await
+            .unwrap();
+        assert_eq!(jwks_fetches(&requests), 2);
+
+        // Another unknown key within the refresh interval is not fetched
+        // again.
+        for _ in 0..3 {
+            let res = validator
+                .validate_id_token(&sign(&rsa_key(), "k3", &token_claims), CLIENT_ID, None)
+                .await;
+            assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("unknown")));
+        }
+        assert_eq!(jwks_fetches(&requests), 2);
+
+        // Known keys are still accepted.
+        validator
+            .validate_id_token(&sign(&key, "k1", &token_claims), CLIENT_ID, None)
+            .await
+            .unwrap();
+        assert_eq!(jwks_fetches(&requests), 2);
+    }
+
+    #[tokio::test]
+    async fn access_token_accepts_v1_issuer() {
+        let key = rsa_key();
+        let (authority, _) = authority(Arc::new(Mutex::new(vec![jwk(&key, "k1")]))).await;
+        let validator = TokenValidator::new(&authority).unwrap();
+        let audiences = ["api://my-api", CLIENT_ID];
+
+        let mut token_claims = claims(&authority);
+        token_claims["aud"] = json!("api://my-api");
+        let v2 = sign(&key, "k1", &token_claims);
+        validator
+            .validate_access_token(&v2, &audiences)
+            .await
+            .unwrap();
+
+        token_claims["iss"] = json!(V1_ISSUER);
+        let v1 = sign(&key, "k1", &token_claims);
+        let claims = validator
+            .validate_access_token(&v1, &audiences)
+            .await
+            .unwrap();
+        assert_eq!(claims["iss"], V1_ISSUER);
+        // An ID token is only issued by the v2.0 endpoints.
+        let res = validator.validate_id_token(&v1, "api://my-api", None).await;
+        assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("issuer")));
+
+        token_claims["iss"] = json!("https://sts.windows.net/another-tenant/");
+        let other = sign(&key, "k1", &token_claims);
+        let res = validator.validate_access_token(&other, &audiences).await;
+        assert!(matches!(res, Err(MsalError::InvalidToken(msg)) if msg.contains("issuer")));
+    }
+
+    #[test]
+    fn audience_may_be_a_list() {
+        let claims = json!({"aud": ["api://other", CLIENT_ID]});
+        let claims = claims.as_object().unwrap();
+        assert!(check_audience(claims, &[CLIENT_ID]).is_ok());
+        assert!(check_audience(claims, &["api://my-api"]).is_err());
+
+        let claims = json!({"aud": []});
+        assert!(check_audience(claims.as_object().unwrap(), &[CLIENT_ID]).is_err());
+        let claims = json!({});
+        assert!(check_audience(claims.as_object().unwrap(), &[CLIENT_ID]).is_err());
+    }
+
+    #[test]
+    fn issuer_names_the_tenant() {
+        let issuer = "https://login.microsoftonline.com/{tenantid}/v2.0";
+        let claims = json!({
+            "iss": format!("https://login.microsoftonline.com/{}/v2.0", TENANT_ID),
+            "tid": TENANT_ID,
+        });
+        assert!(check_issuer(claims.as_object().unwrap(), issuer).is_ok());
+
+        // The tenant of the issuer must be the tenant of the token.
+        let claims = json!({
+            "iss": format!("https://login.microsoftonline.com/{}/v2.0", TENANT_ID),
+            "tid": "ffffffff-bbbb-cccc-dddd-eeeeeeeeeeee",
+        });
+        assert!(check_issuer(claims.as_object().unwrap(), issuer).is_err());
+
+        // Without a tid, no issuer of a multi-tenant authority matches, not
+        // even the template itself.
+        let claims = json!({
+            "iss": format!("https://login.microsoftonline.com/{}/v2.0", TENANT_ID),
+        });
+        assert!(check_issuer(claims.as_object().unwrap(), issuer).is_err());
+        let claims = json!({ "iss": issuer });
+        assert!(check_issuer(claims.as_object().unwrap(), issuer).is_err());
+
+        let claims = json!({ "tid": TENANT_ID });
+        assert!(check_issuer(claims.as_object().unwrap(), issuer).is_err());
+    }
+
+    #[test]
+    fn lifetime_allows_clock_skew() {
+        let mut validator =
+            TokenValidator::new("https://login.microsoftonline.com/tenant").unwrap();
+        validator.set_clock_skew(Duration::from_secs(60));
+        let check = |claims: Value| validator.check_lifetime(claims.as_object().unwrap());
+        // A margin of a few seconds, as the clock may tick during the test
+        let now = now().unwrap();
+
+        assert!(check(json!({"exp": now - 60 + 5})).is_ok());
+        assert!(check(json!({"exp": now - 60 - 5})).is_err());
+        assert!(check(json!({"exp": now + 3600, "nbf": now + 60 - 5})).is_ok());
+        assert!(check(json!({"exp": now + 3600, "nbf": now + 60 + 5})).is_err());
+        assert!(check(json!({"nbf": now})).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+#![allow(dead_code)]
+
+//! A stand-in HTTP server, which plays the part of the authority and other
+//! services in tests.
+
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A request received by the stand-in server.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path of the request, including the query.
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+
+    /// The value of a parameter of the query, or of a form encoded body.
+    pub fn param(&self, name: &str) -> Option<String> {
+        let query = self.target.split_once('?').map(|(_, q)| q).unwrap_or("");
+        [query, self.body.as_str()]
+            .iter()
+            .flat_map(|s| s.split('&'))
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| {
+                urlencoding::decode(&v.replace('+', " "))
+                    .unwrap()
+                    .into_owned()
+            })
+    }
+}
+
+/// A response sent by the stand-in server.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(body: serde_json::Value) -> Self {
+        Response {
+            status: 200,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn html(body: &str) -> Self {
+        Response {
+            status: 200,
+            headers: vec![("Content-Type".into(), "text/html; charset=utf-8".into())],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn status(status: u16, body: serde_json::Value) -> Self {
+        Response {
+            status,
+            ..Response::json(body)
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} Stand-in\r\n", self.status);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        [head.as_bytes(), self.body.as_bytes()].concat()
+    }
+}
+
+pub type Requests = Arc<Mutex<Vec<Request>>>;
+
+/// Start a stand-in server on a loopback port. Returns the base url of the
+/// server, and the requests it has received.
+pub async fn serve<F>(handler: F) -> (String, Requests)
+where
+    F: Fn(&Request, &str) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let requests: Requests = Arc::new(Mutex::new(vec![]));
+    let handler = Arc::new(handler);
+    let log = requests.clone();
+    let base = base_url.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => return,
+            };
+            let handler = handler.clone();
+            let log = log.clone();
+            let base = base.clone();
+            tokio::spawn(async move {
+                let request = match read_request(&mut stream).await {
+                    Some(request) => request,
+                    None => return,
+                };
+                log.lock().unwrap().push(request.clone());
+                let response = handler(&request, &base);
+                let _ = stream.write_all(&response.to_bytes()).await;
+            });
+        }
+    });
+    (base_url, requests)
+}
+
+/// Start a stand-in HTTPS server on a loopback port, for services which are
+/// only reached over TLS. The server has a self-signed certificate, so its
+/// clients must accept invalid certificates. Returns the base url of the
+/// server, and the requests it has received.
+pub fn serve_tls<F>(handler: F) -> (String, Requests)
+where
+    F: Fn(&Request, &str) -> Response + Send + Sync + 'static,
+{
+    use openssl::ssl::{SslAcceptor, SslMethod};
+    use std::io::{Read, Write};
+
+    let (certificate, key) = self_signed_certificate();
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&certificate).unwrap();
+    let acceptor = acceptor.build();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("https://{}", listener.local_addr().unwrap());
+    let requests: Requests = Arc::new(Mutex::new(vec![]));
+    let log = requests.clone();
+    let base = base_url.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream.map(|stream| acceptor.accept(stream)) {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(_)) => continue,
+                Err(_) => return,
+            };
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 4096];
+            let request = loop {
+                let n = match stream.read(&mut chunk) {
+                    Ok(0) | Err(_) => break None,
+                    Ok(n) => n,
+                };
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(request) = parse_request(&buf) {
+                    break Some(request);
+                }
+            };
+            if let Some(request) = request {
+                log.lock().unwrap().push(request.clone());
+                let response = handler(&request, &base);
+                let _ = stream.write_all(&response.to_bytes());
+                let _ = stream.shutdown();
+            }
+        }
+    });
+    (base_url, requests)
+}
+
+fn self_signed_certificate() -> (
+    openssl::x509::X509,
+    openssl::pkey::PKey<openssl::pkey::Private>,
+) {
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    (self_signed_certificate_for(&key), key)
+}
+
+/// A short lived certificate for 127.0.0.1, signed by its own key.
+pub fn self_signed_certificate_for(
+    key: &openssl::pkey::PKeyRef<openssl::pkey::Private>,
+) -> openssl::x509::X509 {
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "127.0.0.1").unwrap();
+    let name = name.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder.sign(key, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+/// Parse a request, once all of it has been received.
+fn parse_request(buf: &[u8]) -> Option<Request> {
+    let head_end = buf.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    let length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    if buf.len() < head_end + length {
+        return None;
+    }
+    Some(Request {
+        method,
+        target,
+        headers,
+        body: String::from_utf8_lossy(&buf[head_end..head_end + length]).to_string(),
+    })
+}
+
+async fn read_request(stream: &mut tokio::net::TcpStream) -> Option<Request> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(request) = parse_request(&buf) {
+            return Some(request);
+        }
+    }
+}
+
+/// The OpenID Connect metadata of a stand-in authority.
+pub fn openid_configuration(authority: &str) -> Response {
+    Response::json(serde_json::json!({
+        "issuer": format!("{}/v2.0", authority),
+        "authorization_endpoint": format!("{}/oauth2/v2.0/authorize", authority),
+        "token_endpoint": format!("{}/oauth2/v2.0/token", authority),
+        "device_authorization_endpoint": format!("{}/oauth2/v2.0/devicecode", authority),
+        "jwks_uri": format!("{}/discovery/v2.0/keys", authority),
+    }))
+}
+
+/// A token response for a user.
+pub fn user_token_response(access_token: &str) -> Response {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    let client_info = URL_SAFE_NO_PAD.encode(
+        serde_json::json!({
+            "uid": "00000000-0000-0000-1111-222222222222",
+            "utid": "33333333-4444-5555-6666-777777777777",
+        })
+        .to_string(),
+    );
+    Response::json(serde_json::json!({
+        "token_type": "Bearer",
+        "scope": "https://graph.microsoft.com/User.Read",
+        "expires_in": 3600,
+        "ext_expires_in": 3600,
+        "access_token": access_token,
+        "refresh_token": "refresh_token",
+        "client_info": client_info,
+    }))
+}
@@ -0,0 +1,196 @@
+mod common;
+
+use common::{openid_configuration, serve, user_token_response, Requests, Response};
+use msal::error::MsalError;
+use msal::{CancellationToken, DeviceFlowOutcome, DeviceFlowProgress, PublicClientApplication};
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A stand-in authority, whose token endpoint answers the polls of a device
+/// flow with the given errors in turn, and then issues a token.
+async fn authority(expires_in: u32, errors: Vec<&'static str>) -> (String, Requests) {
+    let polls = AtomicUsize::new(0);
+    serve(move |request, base| match request.path() {
+        "/tenant/v2.0/.well-known/openid-configuration" => {
+            openid_configuration(&format!("{}/tenant", base))
+        }
+        "/tenant/oauth2/v2.0/devicecode" => Response::json(json!({
+            "device_code": "GAQABAAEAAAD--DLA3VO7QrddgJg7WevrDeviceCode",
+            "user_code": "D3VFL0W42",
+            "verification_uri": "https://microsoft.com/devicelogin",
+            "expires_in": expires_in,
+            "interval": 1,
+            "message": "To sign in, use a web browser to open the page https://microsoft.com/devicelogin and enter the code D3VFL0W42 to authenticate.",
+        })),
+        "/tenant/oauth2/v2.0/token" => match errors.get(polls.fetch_add(1, Ordering::Relaxed)) {
+            Some(error) => Response::status(
+                400,
+                json!({
+                    "error": error,
+                    "error_description": format!("AADSTS: {}", error),
+                    "error_codes": [70016],
+                }),
+            ),
+            None => user_token_response("device-flow-access-token"),
+        },
+        _ => Response::status(404, json!({})),
+    })
+    .await
+}
+
+fn app(base_url: &str) -> PublicClientApplication {
+    let mut app =
+        PublicClientApplication::new("client_id", Some(&format!("{}/tenant", base_url))).unwrap();
+    app.set_validate_authority(false);
+    app
+}
+
+fn polls(requests: &Requests) -> usize {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request| request.path() == "/tenant/oauth2/v2.0/token")
+        .count()
+}
+
+#[tokio::test]
+async fn slow_down_increases_the_interval() {
+    let (base_url, requests) = authority(900, vec!["slow_down"]).await;
+    let app = app(&base_url);
+    let flow = app.initiate_device_flow(vec![]).await.unwrap();
+    let cancellation = CancellationToken::new();
+    let updates = Arc::new(Mutex::new(vec![]));
+    let progress = {
+        let cancellation = cancellation.clone();
+        let updates = updates.clone();
+        move |update: &DeviceFlowProgress| {
+            if let DeviceFlowProgress::SlowDown { interval } = update {
+                updates.lock().unwrap().push(*interval);
+            }
+            // Stop before sleeping for the slower interval
+            cancellation.cancel();
+        }
+    };
+    let outcome = app
+        .poll_device_flow(&flow, &cancellation, Some(&progress))
+        .await
+        .unwrap();
+    assert!(matches!(outcome, DeviceFlowOutcome::Cancelled));
+    assert_eq!(*updates.lock().unwrap(), vec![6]);
+    assert_eq!(polls(&requests), 1);
+}
+
+#[tokio::test]
+async fn pending_authorization_is_polled_until_success() {
+    let (base_url, requests) = authority(900, vec!["authorization_pending"]).await;
+    let app = app(&base_url);
+    let flow = app.initiate_device_flow(vec![]).await.unwrap();
+    let pending = Arc::new(AtomicUsize::new(0));
+    let progress = {
+        let pending = pending.clone();
+        move |update: &DeviceFlowProgress| {
+            if let DeviceFlowProgress::AuthorizationPending { expires_in, .. } = update {
+                assert!(*expires_in <= 900);
+                pending.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    };
+    let outcome = app
+        .poll_device_flow(&flow, &CancellationToken::new(), Some(&progress))
+        .await
+        .unwrap();
+    match outcome {
+        DeviceFlowOutcome::Success(token) => {
+            assert_eq!(
+                token.access_token.as_deref(),
+                Some("device-flow-access-token")
+            )
+        }
+        _ => panic!("The device flow did not succeed"),
+    }
+    assert_eq!(pending.load(Ordering::Relaxed), 1);
+    assert_eq!(polls(&requests), 2);
+}
+
+#[tokio::test]
+async fn expired_token_ends_polling() {
+    let (base_url, _) = authority(900, vec!["expired_token"]).await;
+    let app = app(&base_url);
+    let flow = app.initiate_device_flow(vec![]).await.unwrap();
+    let outcome = app
+        .poll_device_flow(&flow, &CancellationToken::new(), None)
+        .await
+        .unwrap();
+    assert!(matches!(outcome, DeviceFlowOutcome::Expired));
+}
+
+#[tokio::test]
+async fn expiry_counts_from_initiating_the_flow() {
+    let (base_url, requests) = authority(1, vec!["authorization_pending"]).await;
+    let app = app(&base_url);
+    let flow = app.initiate_device_flow(vec![]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+    let outcome = app
+        .poll_device_flow(&flow, &CancellationToken::new(), None)
+        .await
+        .unwrap();
+    assert!(matches!(outcome, DeviceFlowOutcome::Expired));
+    assert_eq!(polls(&requests), 0);
+}
+
+#[tokio::test]
+async fn declined_authorization_ends_polling() {
+    let (base_url, _) = authority(900, vec!["authorization_declined"]).await;
+    let app = app(&base_url);
+    let flow = app.initiate_device_flow(vec![]).await.unwrap();
+    let outcome = app
+        .poll_device_flow(&flow, &CancellationToken::new(), None)
+        .await
+        .unwrap();
+    assert!(matches!(outcome, DeviceFlowOutcome::Declined));
+}
+
+#[tokio::test]
+async fn cancellation_stops_polling() {
+    let (base_url, requests) =
+        authority(900, vec!["authorization_pending", "authorization_pending"]).await;
+    let app = app(&base_url);
+    let flow = app.initiate_device_flow(vec![]).await.unwrap();
+    let cancellation = CancellationToken::new();
+    let canceller = cancellation.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        canceller.cancel();
+    });
+    let outcome = app
+        .poll_device_flow(&flow, &cancellation, None)
+        .await
+        .unwrap();
+    assert!(matches!(outcome, DeviceFlowOutcome::Cancelled));
+    // Cancelling interrupts the wait for the next poll
+    assert_eq!(polls(&requests), 1);
+
+    // A cancelled token stops polling before the first poll
+    let outcome = app
+        .poll_device_flow(&flow, &cancellation, None)
+        .await
+        .unwrap();
+    assert!(matches!(outcome, DeviceFlowOutcome::Cancelled));
+    assert_eq!(polls(&requests), 1);
+}
+
+#[tokio::test]
+async fn unexpected_errors_are_returned() {
+    let (base_url, _) = authority(900, vec!["invalid_grant"]).await;
+    let app = app(&base_url);
+    let flow = app.initiate_device_flow(vec![]).await.unwrap();
+    let res = app
+        .poll_device_flow(&flow, &CancellationToken::new(), None)
+        .await;
+    assert!(
+        matches!(res, Err(MsalError::AcquireTokenFailed(resp)) if resp.error == "invalid_grant")
+    );
+}
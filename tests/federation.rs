@@ -0,0 +1,67 @@
+mod common;
+
+use common::{openid_configuration, serve, Requests, Response};
+use msal::error::MsalError;
+use msal::PublicClientApplication;
+use serde_json::json;
+
+async fn authority(status: u16, user_realm: serde_json::Value) -> (String, Requests) {
+    serve(move |request, base| match request.path() {
+        "/tenant/v2.0/.well-known/openid-configuration" => {
+            openid_configuration(&format!("{}/tenant", base))
+        }
+        "/common/userrealm/jdoe@contoso.com" => Response::status(status, user_realm.clone()),
+        _ => Response::status(404, json!({})),
+    })
+    .await
+}
+
+async fn acquire_token(base_url: &str) -> Result<msal::UserToken, MsalError> {
+    let mut app =
+        PublicClientApplication::new("client_id", Some(&format!("{}/tenant", base_url))).unwrap();
+    app.set_validate_authority(false);
+    app.acquire_token_by_username_password(
+        "jdoe@contoso.com",
+        "password",
+        vec!["https://graph.microsoft.com/User.Read"],
+    )
+    .await
+}
+
+fn posted(requests: &Requests, path: &str) -> bool {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|request| request.method == "POST" && request.path() == path)
+}
+
+#[tokio::test]
+async fn user_realm_failure_is_reported() {
+    let (base_url, requests) = authority(503, json!({"error": "temporarily_unavailable"})).await;
+    let res = acquire_token(&base_url).await;
+    assert!(matches!(res, Err(MsalError::RequestFailed(_))));
+    // The password is not sent to AAD for a user who may be federated
+    assert!(!posted(&requests, "/tenant/oauth2/v2.0/token"));
+}
+
+#[tokio::test]
+async fn insecure_wstrust_endpoint_is_rejected() {
+    let (base_url, requests) = serve(|_, _| Response::status(404, json!({}))).await;
+    let endpoint = format!("{}/adfs/services/trust/13/usernamemixed", base_url);
+    let (authority_url, _) = authority(
+        200,
+        json!({
+        "ver": "1.0",
+        "account_type": "Federated",
+        "domain_name": "contoso.com",
+        "federation_protocol": "WSTrust",
+        "federation_active_auth_url": endpoint,
+        "cloud_audience_urn": "urn:federation:MicrosoftOnline",
+        }),
+    )
+    .await;
+    let res = acquire_token(&authority_url).await;
+    assert!(matches!(res, Err(MsalError::GeneralFailure(msg)) if msg.contains("insecure")));
+    assert!(requests.lock().unwrap().is_empty());
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use common::{openid_configuration, serve, user_token_response, Response};
+use msal::PublicClientApplication;
+use reqwest::Url;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn interactive_redirect_ignores_idle_and_foreign_connections() {
+    let (base_url, requests) = serve(|request, base| {
+        let authority = format!("{}/tenant", base);
+        match request.path() {
+            "/tenant/v2.0/.well-known/openid-configuration" => openid_configuration(&authority),
+            "/tenant/oauth2/v2.0/token" => user_token_response("interactive_access_token"),
+            _ => Response::status(404, serde_json::json!({})),
+        }
+    })
+    .await;
+    let mut app =
+        PublicClientApplication::new("client_id", Some(&format!("{}/tenant", base_url))).unwrap();
+    app.set_validate_authority(false);
+
+    let token = tokio::time::timeout(
+        Duration::from_secs(30),
+        app.acquire_token_interactive(
+            vec!["https://graph.microsoft.com/User.Read"],
+            None,
+            None,
+            None,
+            vec![],
+            |url| {
+                let url = Url::parse(url).unwrap();
+                let param = |name: &str| {
+                    url.query_pairs()
+                        .find(|(k, _)| k == name)
+                        .map(|(_, v)| v.to_string())
+                        .unwrap()
+                };
+                let redirect_uri = param("redirect_uri");
+                let state = param("state");
+                assert!(redirect_uri.starts_with("http://127.0.0.1:"));
+                tokio::spawn(async move {
+                    // An idle preconnect socket, as opened by browsers
+                    let addr = redirect_uri.trim_start_matches("http://").to_string();
+                    let mut idle = TcpStream::connect(&addr).await.unwrap();
+                    idle.write_all(b"GET").await.unwrap();
+
+                    let client = reqwest::Client::new();
+                    // A redirect for another authentication is ignored
+                    let resp = client
+                        .get(format!("{}/?code=foreign&state=other", redirect_uri))
+                        .send()
+                        .await
+                        .unwrap();
+                    assert_eq!(resp.status(), 404);
+                    let resp = client
+                        .get(format!("{}/?code=the_code&state={}", redirect_uri, state))
+                        .send()
+                        .await
+                        .unwrap();
+                    assert_eq!(resp.status(), 200);
+                    drop(idle);
+                });
+                Ok(())
+            },
+        ),
+    )
+    .await
+    .expect("The redirect was blocked")
+    .unwrap();
+    assert_eq!(
+        token.access_token.as_deref(),
+        Some("interactive_access_token")
+    );
+
+    let requests = requests.lock().unwrap();
+    let token_request = requests
+        .iter()
+        .find(|request| request.path() == "/tenant/oauth2/v2.0/token")
+        .unwrap();
+    assert_eq!(token_request.param("code").as_deref(), Some("the_code"));
+    assert!(token_request.param("code_verifier").is_some());
+    assert!(token_request
+        .param("redirect_uri")
+        .unwrap()
+        .starts_with("http://127.0.0.1:"));
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use common::{serve, Requests, Response};
+use msal::error::MsalError;
+use msal::{MFAAuthContinue, MFARegistrationMethod, PublicClientApplication};
+use serde_json::{json, Value};
+
+const CONVERGED_MFA: &str = include_str!("fixtures/mfa/converged_mfa.html");
+const INITIALIZE_MOBILE_APP: &str = include_str!("fixtures/proofup/initialize_mobile_app.json");
+const ADD_SECURITY_INFO: &str = include_str!("fixtures/proofup/add_security_info.json");
+const VERIFY_SECURITY_INFO: &str = include_str!("fixtures/proofup/verify_security_info.json");
+const VERIFY_SECURITY_INFO_INVALID_CODE: &str =
+    include_str!("fixtures/proofup/verify_security_info_invalid_code.json");
+
+async fn proof_up_service(verify: &'static str) -> (String, Requests) {
+    serve(move |request, _| {
+        let fixture = match request.path() {
+            "/common/login" => return Response::html(CONVERGED_MFA),
+            "/api/authenticationmethods/InitializeMobileAppRegistration" => INITIALIZE_MOBILE_APP,
+            "/api/authenticationmethods/AddSecurityInfo" => ADD_SECURITY_INFO,
+            "/api/authenticationmethods/VerifySecurityInfo" => verify,
+            _ => return Response::status(404, json!({})),
+        };
+        Response::json(serde_json::from_str(fixture).unwrap())
+    })
+    .await
+}
+
+fn registration_flow(base_url: &str) -> MFAAuthContinue {
+    serde_json::from_value(json!({
+        "mfa_method": "RegisterMFA",
+        "msg": "You must register an MFA method before you can sign in.",
+        "session_id": "6b3c1a5e-4e0e-4c1f-9a8d-0f9a1c2b3d4e",
+        "flow_token": "AQABAAEAAAD--DLA3VO7QrddgJg7WevrProofUpFlowToken",
+        "ctx": "rQQIARAAhVK9aProofUp",
+        "canary": "PAQABAAEAAAD--DLA3VO7QrddgJg7WevrCanary",
+        "url_end_auth": "",
+        "url_post": format!("{}/common/login", base_url),
+        "url_proof_up": format!("{}/register?csrf_token=proofup", base_url),
+        "mfa_registration_days_remaining": 0,
+    }))
+    .unwrap()
+}
+
+fn request_body(requests: &Requests, path: &str) -> Value {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|request| request.path() == path)
+        .unwrap()
+        .json()
+}
+
+#[tokio::test]
+async fn register_authenticator_app() {
+    let (base_url, requests) = proof_up_service(VERIFY_SECURITY_INFO).await;
+    let app = PublicClientApplication::new("client_id", None).unwrap();
+    let mut flow = registration_flow(&base_url);
+
+    let registration = app
+        .begin_mfa_registration(&mut flow, MFARegistrationMethod::AuthenticatorApp)
+        .await
+        .unwrap();
+    assert_eq!(registration.secret_key.as_deref(), Some("JBSWY3DPEHPK3PXP"));
+    assert_eq!(
+        registration.otpauth_uri.as_deref(),
+        Some("otpauth://totp/Contoso:jdoe%40contoso.com?secret=JBSWY3DPEHPK3PXP&issuer=Contoso")
+    );
+
+    let init = request_body(
+        &requests,
+        "/api/authenticationmethods/InitializeMobileAppRegistration",
+    );
+    assert_eq!(init["Ctx"], "rQQIARAAhVK9aProofUp");
+    assert_eq!(
+        init["FlowToken"],
+        "AQABAAEAAAD--DLA3VO7QrddgJg7WevrProofUpFlowToken"
+    );
+    // Each request continues from the context of the previous response
+    let add = request_body(&requests, "/api/authenticationmethods/AddSecurityInfo");
+    assert_eq!(add["Type"], "AuthenticatorAppOtp");
+    assert_eq!(add["SecretKey"], "JBSWY3DPEHPK3PXP");
+    assert_eq!(add["Ctx"], "rQQIARAAhVK9aProofUp-1");
+    assert_eq!(add["FlowToken"], "AQABAAEAAAD--ProofUpFlowToken-1");
+
+    app.complete_mfa_registration(&mut flow, &registration, "123456")
+        .await
+        .unwrap();
+    let verify = request_body(&requests, "/api/authenticationmethods/VerifySecurityInfo");
+    assert_eq!(verify["Type"], "AuthenticatorAppOtp");
+    assert_eq!(verify["VerificationContext"], "c2a8e5f4-verification-ctx");
+    assert_eq!(verify["VerificationData"], "123456");
+    assert_eq!(verify["Ctx"], "rQQIARAAhVK9aProofUp-2");
+
+    // The flow continues to MFA with the registered method
+    let login = requests
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|request| request.path() == "/common/login")
+        .cloned()
+        .unwrap();
+    assert_eq!(
+        login.param("ctx").as_deref(),
+        Some("rQQIARAAhVK9aProofUp-3")
+    );
+    assert!(!flow.requires_mfa_registration());
+    assert!(!flow.mfa_methods.is_empty());
+}
+
+#[tokio::test]
+async fn register_sms_with_invalid_code() {
+    let (base_url, requests) = proof_up_service(VERIFY_SECURITY_INFO_INVALID_CODE).await;
+    let app = PublicClientApplication::new("client_id", None).unwrap();
+    let mut flow = registration_flow(&base_url);
+
+    let registration = app
+        .begin_mfa_registration(
+            &mut flow,
+            MFARegistrationMethod::Sms("+1 5555550100".to_string()),
+        )
+        .await
+        .unwrap();
+    assert!(registration.secret_key.is_none());
+    let add = request_body(&requests, "/api/authenticationmethods/AddSecurityInfo");
+    assert_eq!(add["Type"], "Sms");
+    assert_eq!(add["PhoneNumber"], "+1 5555550100");
+
+    let res = app
+        .complete_mfa_registration(&mut flow, &registration, "000000")
+        .await;
+    assert!(matches!(
+        res,
+        Err(MsalError::GeneralFailure(msg))
+            if msg == "The code you entered is incorrect. Please try again."
+    ));
+    assert!(flow.requires_mfa_registration());
+}
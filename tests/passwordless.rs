@@ -0,0 +1,191 @@
+mod common;
+
+use common::{openid_configuration, serve, user_token_response, Requests, Response};
+use msal::error::MsalError;
+use msal::{MFAAuthContinue, PublicClientApplication};
+use serde_json::json;
+
+const LOGIN: &str = include_str!("fixtures/passwordless/login.html");
+const KMSI: &str = include_str!("fixtures/passwordless/kmsi.html");
+const CODE_REJECTED: &str = include_str!("fixtures/passwordless/code_rejected.html");
+const CRED_TYPE_ACCESS_PASS: &str =
+    include_str!("fixtures/passwordless/cred_type_access_pass.json");
+const CRED_TYPE_EMAIL_OTC: &str = include_str!("fixtures/passwordless/cred_type_email_otc.json");
+const GET_ONE_TIME_CODE: &str = include_str!("fixtures/passwordless/get_one_time_code.json");
+
+const CODE: &str = "12345678";
+
+/// A stand-in authority, which accepts `CODE` as the Temporary Access Pass
+/// or the one time code of a user.
+async fn authority(cred_type: &'static str) -> (String, Requests) {
+    serve(move |request, base| {
+        // The pages name the login host in their urls
+        let page =
+            |page: &str| Response::html(&page.replace("https://login.microsoftonline.com", base));
+        match request.path() {
+            "/tenant/.well-known/openid-configuration"
+            | "/tenant/v2.0/.well-known/openid-configuration" => {
+                openid_configuration(&format!("{}/tenant", base))
+            }
+            "/tenant/oauth2/v2.0/authorize" => page(LOGIN),
+            "/tenant/GetCredentialType" => Response::json(serde_json::from_str(cred_type).unwrap()),
+            "/tenant/GetOneTimeCode.srf" => {
+                Response::json(serde_json::from_str(GET_ONE_TIME_CODE).unwrap())
+            }
+            "/common/login" => {
+                let code = request.param("accesspass").or(request.param("otc"));
+                match code.as_deref() {
+                    Some(CODE) => page(KMSI),
+                    _ => page(CODE_REJECTED),
+                }
+            }
+            "/kmsi" => Response::status(302, json!({})).header(
+                "Location",
+                "urn:ietf:wg:oauth:2.0:oob?code=passwordless-auth-code",
+            ),
+            "/tenant/oauth2/v2.0/token"
+                if request.param("code").as_deref() == Some("passwordless-auth-code") =>
+            {
+                user_token_response("passwordless-access-token")
+            }
+            _ => Response::status(404, json!({})),
+        }
+    })
+    .await
+}
+
+fn app(base_url: &str) -> PublicClientApplication {
+    let mut app =
+        PublicClientApplication::new("client_id", Some(&format!("{}/tenant", base_url))).unwrap();
+    app.set_validate_authority(false);
+    app
+}
+
+async fn initiate(app: &PublicClientApplication, username: &str) -> MFAAuthContinue {
+    app.initiate_acquire_token_by_passwordless_flow(
+        username,
+        vec!["https://graph.microsoft.com/User.Read"],
+        None,
+    )
+    .await
+    .unwrap()
+}
+
+fn posted(requests: &Requests, path: &str) -> Vec<common::Request> {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request| request.method == "POST" && request.path() == path)
+        .cloned()
+        .collect()
+}
+
+#[tokio::test]
+async fn temporary_access_pass_signs_in() {
+    let (base_url, requests) = authority(CRED_TYPE_ACCESS_PASS).await;
+    let app = app(&base_url);
+    let mut flow = initiate(&app, "jdoe@contoso.com").await;
+    let methods: Vec<&str> = flow
+        .mfa_methods
+        .iter()
+        .map(|method| method.auth_method_id.as_str())
+        .collect();
+    assert_eq!(methods, vec!["AccessPass"]);
+
+    app.begin_mfa_auth(&mut flow, "AccessPass").await.unwrap();
+    assert_eq!(flow.msg, "Please enter your Temporary Access Pass:");
+    let token = app
+        .acquire_token_by_mfa_flow("jdoe@contoso.com", Some(CODE), None, &mut flow)
+        .await
+        .unwrap();
+    assert_eq!(
+        token.access_token.as_deref(),
+        Some("passwordless-access-token")
+    );
+
+    let login = posted(&requests, "/common/login");
+    assert_eq!(login.len(), 1);
+    assert_eq!(login[0].param("login").as_deref(), Some("jdoe@contoso.com"));
+    assert_eq!(login[0].param("accesspass").as_deref(), Some(CODE));
+    assert_eq!(login[0].param("ctx").as_deref(), Some("rQQIARAAhVK9aLogin"));
+    // Staying signed in is declined before the code is redirected
+    let kmsi = posted(&requests, "/kmsi");
+    assert_eq!(kmsi[0].param("LoginOptions").as_deref(), Some("1"));
+    assert_eq!(kmsi[0].param("ctx").as_deref(), Some("rQQIARAAhVK9aKmsi"));
+    // No one time code is sent for a Temporary Access Pass
+    assert!(posted(&requests, "/tenant/GetOneTimeCode.srf").is_empty());
+}
+
+#[tokio::test]
+async fn guest_signs_in_with_email_one_time_code() {
+    let (base_url, requests) = authority(CRED_TYPE_EMAIL_OTC).await;
+    let app = app(&base_url);
+    let mut flow = initiate(&app, "guest@fabrikam.com").await;
+    assert_eq!(flow.mfa_methods.len(), 1);
+    assert_eq!(flow.mfa_methods[0].auth_method_id, "Email");
+    assert!(flow.mfa_methods[0].is_default);
+
+    app.begin_mfa_auth(&mut flow, "Email").await.unwrap();
+    assert_eq!(
+        flow.msg,
+        "We emailed a code to g****@fabrikam.com. Please enter the code to sign in:"
+    );
+    let sent = posted(&requests, "/tenant/GetOneTimeCode.srf");
+    assert_eq!(sent.len(), 1);
+    assert_eq!(
+        sent[0].param("AltEmailE").as_deref(),
+        Some("guest@fabrikam.com")
+    );
+    assert_eq!(sent[0].param("channel").as_deref(), Some("Email"));
+
+    // Beginning the method again does not send another code
+    app.begin_mfa_auth(&mut flow, "Email").await.unwrap();
+    assert_eq!(posted(&requests, "/tenant/GetOneTimeCode.srf").len(), 1);
+
+    let token = app
+        .acquire_token_by_mfa_flow("guest@fabrikam.com", Some(CODE), None, &mut flow)
+        .await
+        .unwrap();
+    assert_eq!(
+        token.access_token.as_deref(),
+        Some("passwordless-access-token")
+    );
+    let login = posted(&requests, "/common/login");
+    assert_eq!(login[0].param("otc").as_deref(), Some(CODE));
+    // The flow token returned by GetOneTimeCode continues the sign in
+    assert_eq!(
+        login[0].param("flowToken").as_deref(),
+        Some("AQABAAEAAAD--DLA3VO7QrddgJg7WevrOtcFlowToken")
+    );
+}
+
+#[tokio::test]
+async fn rejected_code_is_reported() {
+    let (base_url, requests) = authority(CRED_TYPE_ACCESS_PASS).await;
+    let app = app(&base_url);
+    let mut flow = initiate(&app, "jdoe@contoso.com").await;
+    app.begin_mfa_auth(&mut flow, "AccessPass").await.unwrap();
+    let res = app
+        .acquire_token_by_mfa_flow("jdoe@contoso.com", Some("87654321"), None, &mut flow)
+        .await;
+    assert!(
+        matches!(res, Err(MsalError::GeneralFailure(msg)) if msg.contains("incorrect or has expired"))
+    );
+    assert!(posted(&requests, "/tenant/oauth2/v2.0/token").is_empty());
+
+    let res = app
+        .acquire_token_by_mfa_flow("jdoe@contoso.com", None, None, &mut flow)
+        .await;
+    assert!(matches!(res, Err(MsalError::GeneralFailure(_))));
+}
+
+#[tokio::test]
+async fn passwordless_requires_an_eligible_credential() {
+    let (base_url, _) = authority(r#"{"Credentials": {"PrefCredential": 1, "HasPassword": true}, "ThrottleStatus": 0, "IfExistsResult": 0}"#).await;
+    let app = app(&base_url);
+    let res = app
+        .initiate_acquire_token_by_passwordless_flow("jdoe@contoso.com", vec![], None)
+        .await;
+    assert!(matches!(res, Err(MsalError::GeneralFailure(msg)) if msg.contains("not available")));
+}